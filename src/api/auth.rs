@@ -0,0 +1,28 @@
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse};
+
+use super::rest::ErrorResponse;
+
+pub fn error(status: StatusCode, code: &'static str, message: &str) -> HttpResponse {
+    HttpResponse::build(status).json(ErrorResponse::new(code, message))
+}
+
+pub fn check_api_key(req: &HttpRequest, keys: &[String]) -> Result<(), HttpResponse> {
+    if keys.is_empty() {
+        return Err(error(
+            StatusCode::FORBIDDEN,
+            "disabled",
+            "This endpoint is disabled until PROXYD_API_KEYS is configured",
+        ));
+    }
+
+    let key = req.headers().get("x-api-key").and_then(|v| v.to_str().ok());
+    match key {
+        Some(key) if keys.iter().any(|k| k == key) => Ok(()),
+        _ => Err(error(
+            StatusCode::UNAUTHORIZED,
+            "unauthorized",
+            "Missing or invalid API key",
+        )),
+    }
+}
@@ -0,0 +1,77 @@
+use std::sync::Arc;
+
+use actix_web::http::StatusCode;
+use actix_web::{post, web, HttpRequest, HttpResponse};
+
+use super::auth::{check_api_key, error};
+use super::rest::AppState;
+use crate::metrics;
+
+/// Stages a compacted copy of the LMDB file; the next start swaps it in.
+#[post("/admin/compact")]
+pub async fn compact(req: HttpRequest, state: web::Data<AppState>) -> HttpResponse {
+    if let Err(response) = check_api_key(&req, &state.config.api_keys) {
+        return response;
+    }
+    if state.config.read_only {
+        return error(
+            StatusCode::FORBIDDEN,
+            "read_only",
+            "This instance serves the database read-only; compact on the writer",
+        );
+    }
+
+    let db = Arc::clone(&state.db);
+    match web::block(move || db.stage_compaction()).await {
+        Ok(Ok(compaction)) => {
+            metrics::record_compaction(compaction.before_bytes, compaction.after_bytes);
+            HttpResponse::Ok().json(compaction)
+        }
+        Ok(Err(e)) => error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "compaction_failed",
+            &e.to_string(),
+        ),
+        Err(e) => error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal_error",
+            &e.to_string(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, App};
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::api::rest::test_state;
+    use crate::config::Config;
+    use crate::db::Database;
+
+    #[actix_web::test]
+    async fn test_compact_reports_file_sizes() {
+        let dir = TempDir::new().unwrap();
+        let state = AppState {
+            config: Arc::new(Config {
+                api_keys: vec!["secret".to_owned()],
+                ..Config::default()
+            }),
+            ..test_state(Database::open(dir.path()).unwrap())
+        };
+        let app =
+            test::init_service(App::new().app_data(web::Data::new(state)).service(compact)).await;
+
+        let req = test::TestRequest::post()
+            .uri("/admin/compact")
+            .insert_header(("x-api-key", "secret"))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let before = body["before_bytes"].as_u64().unwrap();
+        let after = body["after_bytes"].as_u64().unwrap();
+        assert!(after > 0 && after <= before);
+        assert_eq!(body["applies_on_restart"], true);
+        assert!(dir.path().join("data.mdb.compacted").exists());
+    }
+}
@@ -0,0 +1,283 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use super::flag_names::FlagNames;
+use crate::db::parse_sources_column;
+use crate::ip::{LookupResult, ReputationFlags, FLAG_BITS};
+
+type FlagTest = fn(&ReputationFlags) -> bool;
+
+const FLAG_DESCRIPTIONS: [(&str, FlagTest); 9] = [
+    ("anonymous block", |f| f.anonblock),
+    ("proxy", |f| f.proxy),
+    ("VPN", |f| f.vpn),
+    ("CDN", |f| f.cdn),
+    ("public Wi-Fi", |f| f.public_wifi),
+    ("range block", |f| f.rangeblock),
+    ("school block", |f| f.school_block),
+    ("Tor exit", |f| f.tor),
+    ("web host", |f| f.webhost),
+];
+
+#[derive(Debug, Serialize)]
+pub struct Explanation {
+    pub query: String,
+    pub normalized_query: String,
+    pub found: bool,
+    pub verdict: String,
+    pub reasons: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flag_sources: Option<BTreeMap<String, Vec<FlagSource>>>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct FlagSource {
+    pub entry: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    /// The merged feeds that set the flag on this entry.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<String>,
+}
+
+/// Describes the set flags, using the `PROXYD_FLAG_NAMES` name where a flag
+/// has one.
+fn describe_flags(flags: &ReputationFlags, renamed: &FlagNames) -> String {
+    let names: Vec<&str> = FLAG_DESCRIPTIONS
+        .iter()
+        .zip(FLAG_BITS)
+        .filter(|((_, is_set), _)| is_set(flags))
+        .map(|((description, _), flag)| renamed.get(flag).map_or(*description, String::as_str))
+        .collect();
+
+    if names.is_empty() {
+        "no flags set".to_owned()
+    } else {
+        format!("flagged: {}", names.join(", "))
+    }
+}
+
+pub fn explain(result: &LookupResult, names: &FlagNames) -> Explanation {
+    let reasons = result
+        .matched_entries
+        .iter()
+        .map(|e| {
+            let reason = format!("Matched {} ({})", e.entry, describe_flags(&e.flags, names));
+            match &e.note {
+                Some(note) => format!("{reason}; note: {note}"),
+                None => reason,
+            }
+        })
+        .collect();
+
+    let verdict = if result.found {
+        format!(
+            "{} is listed ({})",
+            result.normalized_query,
+            describe_flags(&result.flags, names)
+        )
+    } else {
+        format!("{} is not listed", result.normalized_query)
+    };
+
+    Explanation {
+        query: result.query.clone(),
+        normalized_query: result.normalized_query.clone(),
+        found: result.found,
+        verdict,
+        reasons,
+        flag_sources: None,
+    }
+}
+
+/// Maps each flag set in the merged result to the matched entries that set it.
+/// `provenance` holds each matched entry's stored `sources` column, naming
+/// the feeds behind each of its flags.
+pub fn flag_sources(
+    result: &LookupResult,
+    provenance: &[Option<String>],
+    names: &FlagNames,
+) -> BTreeMap<String, Vec<FlagSource>> {
+    let provenance: Vec<Vec<(&str, Vec<&str>)>> = provenance
+        .iter()
+        .map(|column| {
+            column
+                .as_deref()
+                .map(parse_sources_column)
+                .unwrap_or_default()
+        })
+        .collect();
+
+    let mut sources = BTreeMap::new();
+    for (bit, flag) in FLAG_BITS.iter().enumerate() {
+        let mask = 1u16 << bit;
+        let entries: Vec<FlagSource> = result
+            .matched_entries
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| e.flags.to_mask() & mask != 0)
+            .map(|(i, e)| FlagSource {
+                entry: e.entry.clone(),
+                note: e.note.clone(),
+                sources: provenance
+                    .get(i)
+                    .and_then(|flags| flags.iter().find(|(set, _)| set == flag))
+                    .map(|(_, feeds)| feeds.iter().map(|&f| f.to_owned()).collect())
+                    .unwrap_or_default(),
+            })
+            .collect();
+        if !entries.is_empty() {
+            let name = names.get(*flag).map_or(*flag, String::as_str);
+            sources.insert(name.to_owned(), entries);
+        }
+    }
+    sources
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ip::{MatchedEntry, MatchedEntryVec};
+
+    fn result(matched_entries: MatchedEntryVec) -> LookupResult {
+        let flags = matched_entries
+            .iter()
+            .fold(ReputationFlags::default(), |acc, e| acc.merge(&e.flags));
+        LookupResult {
+            found: !matched_entries.is_empty(),
+            query: "10.0.0.1".to_owned(),
+            normalized_query: "10.0.0.1".to_owned(),
+            flags,
+            flags_mask: flags.to_mask(),
+            matched_entries,
+            checked_at: 0,
+            dataset_hash: None,
+            degraded: false,
+            timed_out: false,
+        }
+    }
+
+    #[test]
+    fn test_explain_lists_each_match() {
+        let mut entries = MatchedEntryVec::new();
+        entries.push(MatchedEntry {
+            entry: "10.0.0.0/8".to_owned(),
+            flags: ReputationFlags {
+                vpn: true,
+                cdn: true,
+                ..Default::default()
+            },
+            note: None,
+        });
+        entries.push(MatchedEntry {
+            entry: "10.0.0.0/24".to_owned(),
+            flags: ReputationFlags {
+                tor: true,
+                ..Default::default()
+            },
+            note: Some("exit relay list".to_owned()),
+        });
+
+        let explanation = explain(&result(entries.clone()), &FlagNames::new());
+        assert_eq!(
+            explanation.reasons,
+            vec![
+                "Matched 10.0.0.0/8 (flagged: VPN, CDN)",
+                "Matched 10.0.0.0/24 (flagged: Tor exit); note: exit relay list",
+            ]
+        );
+        assert_eq!(
+            explanation.verdict,
+            "10.0.0.1 is listed (flagged: VPN, CDN, Tor exit)"
+        );
+
+        let names = FlagNames::from([("tor".to_owned(), "isTor".to_owned())]);
+        let explanation = explain(&result(entries), &names);
+        assert_eq!(
+            explanation.verdict,
+            "10.0.0.1 is listed (flagged: VPN, CDN, isTor)"
+        );
+    }
+
+    #[test]
+    fn test_flag_sources_attribute_overlapping_ranges() {
+        let mut entries = MatchedEntryVec::new();
+        entries.push(MatchedEntry {
+            entry: "10.0.0.0/8".to_owned(),
+            flags: ReputationFlags {
+                proxy: true,
+                vpn: true,
+                ..Default::default()
+            },
+            note: Some("hosting feed".to_owned()),
+        });
+        entries.push(MatchedEntry {
+            entry: "10.0.0.0/24".to_owned(),
+            flags: ReputationFlags {
+                proxy: true,
+                ..Default::default()
+            },
+            note: None,
+        });
+
+        let names = FlagNames::from([("vpn".to_owned(), "is_vpn".to_owned())]);
+        let provenance = [
+            Some("proxy=https://a.example/feed.csv https://b.example/feed.csv|vpn=https://b.example/feed.csv".to_owned()),
+            None,
+        ];
+        let sources = flag_sources(&result(entries), &provenance, &names);
+        assert_eq!(sources.keys().collect::<Vec<_>>(), ["is_vpn", "proxy"]);
+        assert_eq!(
+            sources["proxy"],
+            [
+                FlagSource {
+                    entry: "10.0.0.0/8".to_owned(),
+                    note: Some("hosting feed".to_owned()),
+                    sources: vec![
+                        "https://a.example/feed.csv".to_owned(),
+                        "https://b.example/feed.csv".to_owned()
+                    ],
+                },
+                FlagSource {
+                    entry: "10.0.0.0/24".to_owned(),
+                    note: None,
+                    sources: Vec::new(),
+                },
+            ]
+        );
+        assert_eq!(sources["is_vpn"].len(), 1);
+        assert_eq!(sources["is_vpn"][0].entry, "10.0.0.0/8");
+        assert_eq!(sources["is_vpn"][0].sources, ["https://b.example/feed.csv"]);
+    }
+
+    #[test]
+    fn test_explain_not_listed() {
+        let explanation = explain(&result(MatchedEntryVec::new()), &FlagNames::new());
+        assert!(!explanation.found);
+        assert!(explanation.reasons.is_empty());
+        assert_eq!(explanation.verdict, "10.0.0.1 is not listed");
+    }
+
+    #[test]
+    fn test_every_flag_has_a_description() {
+        let all = ReputationFlags {
+            anonblock: true,
+            proxy: true,
+            vpn: true,
+            cdn: true,
+            public_wifi: true,
+            rangeblock: true,
+            school_block: true,
+            tor: true,
+            webhost: true,
+        };
+        let described = FLAG_DESCRIPTIONS.iter().filter(|(_, f)| f(&all)).count();
+        let fields = serde_json::to_value(all)
+            .unwrap()
+            .as_object()
+            .unwrap()
+            .len();
+        assert_eq!(described, fields);
+    }
+}
@@ -0,0 +1,305 @@
+use std::sync::Arc;
+
+use actix_web::http::StatusCode;
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use super::auth::{check_api_key, error};
+use super::flag_names::json_with_flag_names;
+use super::rest::AppState;
+use crate::db::DbError;
+use crate::ip::ReputationFlags;
+use crate::sync::history::{is_dataset_hash, load_snapshot};
+use crate::sync::importer::{
+    diff_records, parse_for_import, stored_records_txn, CsvRecord, ImportError,
+};
+
+#[derive(Deserialize)]
+struct DeltaQuery {
+    from: String,
+}
+
+#[derive(Serialize)]
+struct ExportEntry {
+    entry: String,
+    flags: ReputationFlags,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    note: Option<String>,
+}
+
+impl From<CsvRecord> for ExportEntry {
+    fn from(record: CsvRecord) -> Self {
+        Self {
+            entry: record.ip,
+            flags: record.flags,
+            note: record.note,
+        }
+    }
+}
+
+fn export_entries(records: Vec<CsvRecord>) -> Vec<ExportEntry> {
+    records.into_iter().map(ExportEntry::from).collect()
+}
+
+#[derive(Serialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+enum Export {
+    /// Changes to apply on top of dataset `from` to reach dataset `to`.
+    Delta {
+        from: String,
+        to: Option<String>,
+        added: Vec<ExportEntry>,
+        updated: Vec<ExportEntry>,
+        deleted: Vec<String>,
+    },
+    /// `from` is unknown or no longer retained: `entries` replaces everything.
+    Full {
+        to: Option<String>,
+        entries: Vec<ExportEntry>,
+    },
+}
+
+enum ExportError {
+    TooLarge(usize),
+    Import(ImportError),
+}
+
+impl From<ImportError> for ExportError {
+    fn from(e: ImportError) -> Self {
+        Self::Import(e)
+    }
+}
+
+impl From<DbError> for ExportError {
+    fn from(e: DbError) -> Self {
+        Self::Import(e.into())
+    }
+}
+
+#[get("/v1/export/delta")]
+pub async fn export_delta(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    query: web::Query<DeltaQuery>,
+) -> HttpResponse {
+    if let Err(response) = check_api_key(&req, &state.config.api_keys) {
+        return response;
+    }
+
+    let from = query.into_inner().from.to_lowercase();
+    if !is_dataset_hash(&from) {
+        return error(
+            StatusCode::BAD_REQUEST,
+            "invalid_hash",
+            "from must be a SHA-256 dataset hash",
+        );
+    }
+
+    let to = match state.db.get_metadata() {
+        Ok(meta) => meta.csv_hash,
+        Err(e) => {
+            return error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "database_error",
+                &e.to_string(),
+            )
+        }
+    };
+    if to.as_deref() == Some(from.as_str()) {
+        return HttpResponse::Ok().json(Export::Delta {
+            from,
+            to,
+            added: Vec::new(),
+            updated: Vec::new(),
+            deleted: Vec::new(),
+        });
+    }
+    let snapshot = load_snapshot(&state.config, &from).await;
+
+    let db = Arc::clone(&state.db);
+    let config = Arc::clone(&state.config);
+    let max_entries = config.export_max_entries;
+    let export = web::block(move || -> Result<Export, ExportError> {
+        // The version and the entries come from one snapshot, so a concurrent
+        // import cannot pair one dataset's hash with another's entries.
+        let rtxn = db.read_txn()?;
+        let to = db.get_metadata_txn(&rtxn)?.csv_hash;
+        let Some(snapshot) = snapshot else {
+            let count = db.count_entries_txn(&rtxn)? as usize;
+            if count > max_entries {
+                return Err(ExportError::TooLarge(count));
+            }
+            return Ok(Export::Full {
+                to,
+                entries: export_entries(stored_records_txn(&db, &rtxn)?),
+            });
+        };
+        let current = stored_records_txn(&db, &rtxn)?;
+        drop(rtxn);
+        let delta = diff_records(parse_for_import(&snapshot, &config)?, current);
+        let count = delta.added.len() + delta.updated.len() + delta.deleted.len();
+        if count > max_entries {
+            return Err(ExportError::TooLarge(count));
+        }
+        Ok(Export::Delta {
+            from,
+            to,
+            added: export_entries(delta.added),
+            updated: export_entries(delta.updated),
+            deleted: delta.deleted,
+        })
+    })
+    .await;
+
+    match export {
+        Ok(Ok(export)) => json_with_flag_names(&state.config.flag_names, &export),
+        Ok(Err(ExportError::TooLarge(count))) => error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "export_too_large",
+            &format!(
+                "Export has {count} entries, more than PROXYD_EXPORT_MAX_ENTRIES={max_entries}; download the full feed instead"
+            ),
+        ),
+        Ok(Err(ExportError::Import(e))) => error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "export_failed",
+            &e.to_string(),
+        ),
+        Err(e) => error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal_error",
+            &e.to_string(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, App};
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::api::rest::test_state;
+    use crate::config::Config;
+    use crate::db::Database;
+    use crate::sync::downloader::compute_hash;
+    use crate::sync::importer::{full_import, incremental_import};
+
+    #[actix_web::test]
+    async fn test_delta_reports_added_updated_and_deleted() {
+        let dir = TempDir::new().unwrap();
+        let config = Arc::new(Config {
+            data_dir: dir.path().to_path_buf(),
+            api_keys: vec!["secret".to_owned()],
+            delta_history: 2,
+            flag_names: [("vpn".to_owned(), "is_vpn".to_owned())].into(),
+            ..Config::default()
+        });
+        let db = Database::open(&config.db_path()).unwrap();
+
+        let v1 = "ip,proxy,vpn\n1.2.3.4,true,false\n5.6.7.8,true,false\n10.0.0.0/8,false,true\n";
+        let v2 = "ip,proxy,vpn\n1.2.3.4,true,false\n5.6.7.8,false,true\n192.0.2.0/24,true,false\n";
+        full_import(&db, v1, &compute_hash(v1), &config)
+            .await
+            .unwrap();
+        incremental_import(&db, v2, &compute_hash(v2), &config)
+            .await
+            .unwrap();
+
+        let state = AppState {
+            config,
+            ..test_state(db)
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(export_delta),
+        )
+        .await;
+        let get = |from: &str| {
+            test::TestRequest::get()
+                .uri(&format!("/v1/export/delta?from={from}"))
+                .insert_header(("x-api-key", "secret"))
+                .to_request()
+        };
+
+        let body: serde_json::Value =
+            test::call_and_read_body_json(&app, get(&compute_hash(v1))).await;
+        assert_eq!(body["mode"], "delta");
+        assert_eq!(body["to"], compute_hash(v2));
+        assert_eq!(body["added"][0]["entry"], "192.0.2.0/24");
+        assert_eq!(body["added"].as_array().unwrap().len(), 1);
+        assert_eq!(body["updated"][0]["entry"], "5.6.7.8");
+        assert_eq!(body["updated"][0]["flags"]["is_vpn"], true);
+        assert_eq!(body["updated"].as_array().unwrap().len(), 1);
+        assert_eq!(body["deleted"], serde_json::json!(["10.0.0.0/8"]));
+
+        let body: serde_json::Value =
+            test::call_and_read_body_json(&app, get(&compute_hash(v2))).await;
+        assert_eq!(body["mode"], "delta");
+        assert!(body["added"].as_array().unwrap().is_empty());
+
+        let body: serde_json::Value =
+            test::call_and_read_body_json(&app, get(&"0".repeat(64))).await;
+        assert_eq!(body["mode"], "full");
+        assert_eq!(body["entries"].as_array().unwrap().len(), 3);
+
+        let resp = test::call_service(&app, get("..%2Fproxy_blocks")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_exports_over_the_entry_cap_are_refused() {
+        let dir = TempDir::new().unwrap();
+        let config = Arc::new(Config {
+            data_dir: dir.path().to_path_buf(),
+            api_keys: vec!["secret".to_owned()],
+            delta_history: 2,
+            export_max_entries: 2,
+            ..Config::default()
+        });
+        let db = Database::open(&config.db_path()).unwrap();
+
+        let v1 = "ip,proxy\n1.2.3.4,true\n";
+        let v2 = "ip,proxy\n1.2.3.4,true\n5.6.7.8,true\n";
+        let v3 = "ip,proxy\n1.2.3.4,false\n192.0.2.0/24,true\n198.51.100.0/24,true\n";
+        full_import(&db, v1, &compute_hash(v1), &config)
+            .await
+            .unwrap();
+        for csv in [v2, v3] {
+            incremental_import(&db, csv, &compute_hash(csv), &config)
+                .await
+                .unwrap();
+        }
+
+        let state = AppState {
+            config,
+            ..test_state(db)
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(export_delta),
+        )
+        .await;
+        let get = |from: &str| {
+            test::TestRequest::get()
+                .uri(&format!("/v1/export/delta?from={from}"))
+                .insert_header(("x-api-key", "secret"))
+                .to_request()
+        };
+
+        // v2 -> v3 updates one entry, adds two and deletes one
+        let resp = test::call_service(&app, get(&compute_hash(v2))).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"]["code"], "export_too_large");
+
+        // A full export of three entries is over the cap too
+        let resp = test::call_service(&app, get(&"0".repeat(64))).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let resp = test::call_service(&app, get(&compute_hash(v3))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+}
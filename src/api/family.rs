@@ -0,0 +1,79 @@
+use ipnetwork::IpNetwork;
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressFamily {
+    Ipv4,
+    Ipv6,
+}
+
+impl AddressFamily {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "ipv4" => Some(Self::Ipv4),
+            "ipv6" => Some(Self::Ipv6),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Ipv4 => "ipv4",
+            Self::Ipv6 => "ipv6",
+        }
+    }
+
+    /// Entries that do not parse are left for the lookup to reject.
+    fn admits(self, entry: &str) -> bool {
+        match entry.trim().parse::<IpNetwork>() {
+            Ok(network) => network.is_ipv4() == (self == Self::Ipv4),
+            Err(_) => true,
+        }
+    }
+}
+
+/// Returns an error message listing the entries outside `family`, if any.
+pub fn check_family(entries: &[String], family: Option<AddressFamily>) -> Result<(), String> {
+    let Some(family) = family else {
+        return Ok(());
+    };
+    let offenders: Vec<&str> = entries
+        .iter()
+        .map(String::as_str)
+        .filter(|entry| !family.admits(entry))
+        .collect();
+    if offenders.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "Batch is restricted to {} but contains: {}",
+            family.as_str(),
+            offenders.join(", ")
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_family_lists_offenders() {
+        let entries: Vec<String> = ["1.2.3.4", "2001:db8::1", "10.0.0.0/8", "::/0", "bogus"]
+            .map(str::to_owned)
+            .to_vec();
+
+        assert!(check_family(&entries, None).is_ok());
+        assert_eq!(
+            check_family(&entries, Some(AddressFamily::Ipv4)).unwrap_err(),
+            "Batch is restricted to ipv4 but contains: 2001:db8::1, ::/0"
+        );
+        assert_eq!(
+            check_family(&entries, Some(AddressFamily::Ipv6)).unwrap_err(),
+            "Batch is restricted to ipv6 but contains: 1.2.3.4, 10.0.0.0/8"
+        );
+        assert_eq!(AddressFamily::parse(" IPv6 "), Some(AddressFamily::Ipv6));
+        assert_eq!(AddressFamily::parse("v6"), None);
+    }
+}
@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+use serde::Serialize;
+use serde_json::Value;
+
+use super::auth::error;
+
+pub type FlagNames = HashMap<String, String>;
+
+pub fn json_with_flag_names<T: Serialize>(names: &FlagNames, body: &T) -> HttpResponse {
+    if names.is_empty() {
+        return HttpResponse::Ok().json(body);
+    }
+
+    match serde_json::to_value(body) {
+        Ok(mut value) => {
+            rename_flags(&mut value, names);
+            HttpResponse::Ok().json(value)
+        }
+        Err(e) => error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal_error",
+            &e.to_string(),
+        ),
+    }
+}
+
+fn rename_flags(value: &mut Value, names: &FlagNames) {
+    match value {
+        Value::Object(map) => {
+            if let Some(Value::Object(flags)) = map.get_mut("flags") {
+                *flags = std::mem::take(flags)
+                    .into_iter()
+                    .map(|(k, v)| (names.get(&k).cloned().unwrap_or(k), v))
+                    .collect();
+            }
+            for (key, child) in map.iter_mut() {
+                if key != "flags" {
+                    rename_flags(child, names);
+                }
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                rename_flags(item, names);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_rename_flags_in_results_and_matches() {
+        let names = FlagNames::from([
+            ("proxy".to_owned(), "isProxy".to_owned()),
+            ("vpn".to_owned(), "is_vpn".to_owned()),
+        ]);
+        let mut value = json!([{
+            "query": "1.2.3.4",
+            "flags": { "proxy": true, "vpn": false, "tor": false },
+            "matched_entries": [{ "entry": "1.2.3.4", "flags": { "proxy": true } }]
+        }]);
+
+        rename_flags(&mut value, &names);
+
+        assert_eq!(
+            value,
+            json!([{
+                "query": "1.2.3.4",
+                "flags": { "isProxy": true, "is_vpn": false, "tor": false },
+                "matched_entries": [{ "entry": "1.2.3.4", "flags": { "isProxy": true } }]
+            }])
+        );
+    }
+}
@@ -1,21 +1,41 @@
+use std::collections::HashSet;
+use std::net::IpAddr;
 use std::sync::Arc;
 use std::time::Duration;
 
+use ipnetwork::IpNetwork;
+use tokio::sync::watch;
 use tonic::codec::CompressionEncoding;
+use tonic::metadata::MetadataMap;
+use tonic::service::interceptor::InterceptedService;
+use tonic::service::Interceptor;
 use tonic::transport::Server;
-use tonic::{Request, Response, Status};
+use tonic::{Request, Response, Status, Streaming};
+use tonic_health::server::{health_reporter, HealthReporter};
+use tonic_health::ServingStatus;
 use tonic_reflection::server::Builder as ReflectionBuilder;
+use tracing::{instrument, Span};
 
-use super::LookupMetrics;
+use super::family::{check_family, AddressFamily};
+use super::{AdminRpcMetrics, LookupLimiter, LookupMetrics};
 
 const MAX_BATCH_SIZE: usize = 1000;
 
+use crate::config::Config;
 use crate::db::Database;
 use crate::ip::{
-    lookup_ip as do_lookup_ip, lookup_ips_batch, lookup_range as do_lookup_range,
-    lookup_ranges_batch, LookupError, LookupResult, MatchedEntry as DomainMatchedEntry,
-    ReputationFlags as DomainFlags,
+    log_ip, lookup_ip_with as do_lookup_ip, lookup_ips_batch_with, lookup_range as do_lookup_range,
+    lookup_ranges_batch_with, lookup_ranges_batch_with_supernets, LookupError, LookupOptions,
+    LookupResult, MatchedEntry as DomainMatchedEntry, ReputationFlags as DomainFlags,
 };
+use crate::metrics::{GrpcMethod, LookupClass};
+use crate::readiness::Readiness;
+use crate::sync::error::FailureKind;
+use crate::sync::importer::{
+    bounded_note, CsvRecord, ImportError, ImportMode as DomainImportMode,
+    ImportSummary as DomainImportSummary, StagedFeed,
+};
+use crate::sync::scheduler::{trigger_sync, SyncOutcome};
 
 pub mod proto {
     #![allow(
@@ -31,18 +51,53 @@ pub mod proto {
 
 use proto::proxy_d_server::{ProxyD, ProxyDServer};
 use proto::{
-    BatchIpRequest, BatchRangeRequest, BatchReputationResponse, IpRequest,
+    BatchIpRequest, BatchLookupRequest, BatchLookupResponse, BatchLookupResult, BatchRangeRequest,
+    BatchReputationResponse, EntryKind, ImportChunk, ImportMode, ImportSummary, IpRequest,
     MatchedEntry as ProtoMatchedEntry, RangeRequest, ReputationFlags as ProtoFlags,
-    ReputationResponse,
+    ReputationResponse, SyncSummary, TriggerSyncRequest,
 };
 
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub struct ProxyDService {
     db: Arc<Database>,
+    lookup_options: LookupOptions,
+    request_timeout: Duration,
+    import_config: Option<Arc<Config>>,
+    limiter: LookupLimiter,
+    readiness: Readiness,
 }
 
 impl ProxyDService {
-    pub fn new(db: Arc<Database>) -> Self {
-        Self { db }
+    pub fn new(db: Arc<Database>, lookup_options: LookupOptions) -> Self {
+        Self {
+            db,
+            lookup_options,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            import_config: None,
+            limiter: LookupLimiter::default(),
+            readiness: Readiness::default(),
+        }
+    }
+
+    pub fn with_import(mut self, config: Arc<Config>) -> Self {
+        self.import_config = Some(config);
+        self
+    }
+
+    pub fn with_readiness(mut self, readiness: Readiness) -> Self {
+        self.readiness = readiness;
+        self
+    }
+
+    pub fn with_limiter(mut self, limiter: LookupLimiter) -> Self {
+        self.limiter = limiter;
+        self
+    }
+
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
     }
 
     pub fn into_server(self) -> ProxyDServer<Self> {
@@ -52,6 +107,44 @@ impl ProxyDService {
             .send_compressed(CompressionEncoding::Gzip)
             .send_compressed(CompressionEncoding::Zstd)
     }
+
+    pub fn into_authenticated_server(
+        self,
+        api_keys: &[String],
+    ) -> InterceptedService<ProxyDServer<Self>, ApiKeyInterceptor> {
+        InterceptedService::new(self.into_server(), ApiKeyInterceptor::new(api_keys))
+    }
+}
+
+#[derive(Clone)]
+pub struct ApiKeyInterceptor {
+    keys: Arc<HashSet<String>>,
+}
+
+impl ApiKeyInterceptor {
+    pub fn new(keys: &[String]) -> Self {
+        Self {
+            keys: Arc::new(keys.iter().cloned().collect()),
+        }
+    }
+}
+
+impl Interceptor for ApiKeyInterceptor {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        if self.keys.is_empty() {
+            return Ok(request);
+        }
+
+        let key = request
+            .metadata()
+            .get("x-api-key")
+            .and_then(|v| v.to_str().ok());
+        match key {
+            Some(key) if self.keys.contains(key) => Ok(request),
+            Some(_) => Err(Status::unauthenticated("Invalid API key")),
+            None => Err(Status::unauthenticated("Missing x-api-key metadata")),
+        }
+    }
 }
 
 impl From<&DomainFlags> for ProtoFlags {
@@ -70,11 +163,51 @@ impl From<&DomainFlags> for ProtoFlags {
     }
 }
 
+impl From<&ProtoFlags> for DomainFlags {
+    fn from(flags: &ProtoFlags) -> Self {
+        Self {
+            anonblock: flags.anonblock,
+            proxy: flags.proxy,
+            vpn: flags.vpn,
+            cdn: flags.cdn,
+            public_wifi: flags.public_wifi,
+            rangeblock: flags.rangeblock,
+            school_block: flags.school_block,
+            tor: flags.tor,
+            webhost: flags.webhost,
+        }
+    }
+}
+
+impl From<DomainImportSummary> for ImportSummary {
+    fn from(summary: DomainImportSummary) -> Self {
+        Self {
+            mode: summary.mode.as_str().to_owned(),
+            hash: summary.hash,
+            added: summary.added,
+            updated: summary.updated,
+            deleted: summary.deleted,
+        }
+    }
+}
+
+impl From<SyncOutcome> for SyncSummary {
+    fn from(outcome: SyncOutcome) -> Self {
+        let status = outcome.as_str().to_owned();
+        let import = match outcome {
+            SyncOutcome::Imported(summary) => Some(summary.into()),
+            SyncOutcome::NotModified | SyncOutcome::Unchanged => None,
+        };
+        Self { status, import }
+    }
+}
+
 impl From<DomainMatchedEntry> for ProtoMatchedEntry {
     fn from(entry: DomainMatchedEntry) -> Self {
         Self {
             entry: entry.entry,
             flags: Some(ProtoFlags::from(&entry.flags)),
+            note: entry.note.unwrap_or_default(),
         }
     }
 }
@@ -92,19 +225,138 @@ impl From<LookupResult> for ReputationResponse {
             query: result.query,
             flags: Some(ProtoFlags::from(&result.flags)),
             matched_entries,
+            normalized_query: result.normalized_query,
+            checked_at: result.checked_at,
+            dataset_hash: result.dataset_hash.unwrap_or_default(),
+            degraded: result.degraded,
+            timed_out: result.timed_out,
+            flags_mask: u32::from(result.flags_mask),
+            index: 0,
         }
     }
 }
 
+fn too_many_lookups() -> Status {
+    Status::resource_exhausted("Too many concurrent lookups")
+}
+
+fn read_only_status() -> Status {
+    Status::failed_precondition("This instance serves the database read-only; use the writer")
+}
+
+/// An empty `family` allows mixed batches.
+fn check_batch_family(entries: &[String], family: &str) -> Result<(), String> {
+    let family = match family {
+        "" => None,
+        family => Some(
+            AddressFamily::parse(family)
+                .ok_or_else(|| format!("Invalid family: {family} (expected ipv4 or ipv6)"))?,
+        ),
+    };
+    check_family(entries, family)
+}
+
+/// Entries with a prefix length are CIDRs, anything else must be an IP.
+fn classify_entry(entry: &str) -> Result<EntryKind, LookupError> {
+    if entry.contains('/') {
+        entry
+            .parse::<IpNetwork>()
+            .map(|_| EntryKind::Cidr)
+            .map_err(|_| LookupError::InvalidCidr(entry.to_owned()))
+    } else {
+        entry
+            .parse::<IpAddr>()
+            .map(|_| EntryKind::Ip)
+            .map_err(|_| LookupError::InvalidIp(entry.to_owned()))
+    }
+}
+
+/// Looks up the valid entries of a mixed batch, returning their results in
+/// request order.
+fn lookup_mixed_batch(
+    db: &Arc<Database>,
+    entries: &[String],
+    kinds: &[Result<EntryKind, LookupError>],
+    options: &LookupOptions,
+) -> Result<Vec<LookupResult>, LookupError> {
+    let of_kind = |kind: EntryKind| -> Vec<&str> {
+        entries
+            .iter()
+            .zip(kinds)
+            .filter(|(_, k)| matches!(k, Ok(k) if *k == kind))
+            .map(|(entry, _)| entry.as_str())
+            .collect()
+    };
+    let mut ips = lookup_ips_batch_with(db, &of_kind(EntryKind::Ip), options)?.into_iter();
+    let mut cidrs = lookup_ranges_batch_with(db, &of_kind(EntryKind::Cidr), options)?.into_iter();
+    Ok(kinds
+        .iter()
+        .filter_map(|kind| match kind {
+            Ok(EntryKind::Ip) => ips.next(),
+            Ok(EntryKind::Cidr) => cidrs.next(),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Mirrors the REST mapping in `import::failure_status`.
+fn failure_to_status(kind: FailureKind, message: String) -> Status {
+    match kind {
+        FailureKind::Upstream => Status::unavailable(message),
+        FailureKind::InvalidFeed => Status::failed_precondition(message),
+        FailureKind::Conflict => Status::aborted(message),
+        FailureKind::Internal => Status::internal(message),
+    }
+}
+
 fn lookup_error_to_status(err: &LookupError) -> Status {
     match err {
-        LookupError::InvalidIp(_) | LookupError::InvalidCidr(_) => {
-            Status::invalid_argument(err.to_string())
-        }
+        LookupError::InvalidIp(_)
+        | LookupError::InvalidCidr(_)
+        | LookupError::InvalidPrefix(..) => Status::invalid_argument(err.to_string()),
         LookupError::Database(_) => Status::internal(err.to_string()),
     }
 }
 
+fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    if value.len() < 2 || value.len() > 9 {
+        return None;
+    }
+    let (digits, unit) = value.split_at(value.len() - 1);
+    let amount: u64 = digits.parse().ok()?;
+    match unit {
+        "H" => Some(Duration::from_secs(amount * 3600)),
+        "M" => Some(Duration::from_secs(amount * 60)),
+        "S" => Some(Duration::from_secs(amount)),
+        "m" => Some(Duration::from_millis(amount)),
+        "u" => Some(Duration::from_micros(amount)),
+        "n" => Some(Duration::from_nanos(amount)),
+        _ => None,
+    }
+}
+
+fn request_deadline(metadata: &MetadataMap, server_timeout: Duration) -> Duration {
+    metadata
+        .get("grpc-timeout")
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_grpc_timeout)
+        .map_or(server_timeout, |client| client.min(server_timeout))
+}
+
+async fn run_with_deadline<T, F>(deadline: Duration, f: F) -> Result<T, Status>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    match tokio::time::timeout(deadline, tokio::task::spawn_blocking(f)).await {
+        Ok(Ok(result)) => Ok(result),
+        Ok(Err(e)) => Err(Status::internal(format!("Lookup task failed: {e}"))),
+        Err(_) => Err(Status::deadline_exceeded(
+            "Lookup did not complete before the request deadline",
+        )),
+    }
+}
+
 pub fn create_reflection_service(
 ) -> tonic_reflection::server::ServerReflectionServer<impl tonic_reflection::server::ServerReflection>
 {
@@ -114,6 +366,43 @@ pub fn create_reflection_service(
         .expect("Failed to build reflection service")
 }
 
+pub async fn create_health_service(
+    serving: bool,
+) -> (
+    HealthReporter,
+    tonic_health::pb::health_server::HealthServer<impl tonic_health::pb::health_server::Health>,
+) {
+    let (mut reporter, service) = health_reporter();
+    set_health(&mut reporter, serving).await;
+    (reporter, service)
+}
+
+pub async fn set_health(reporter: &mut HealthReporter, serving: bool) {
+    let status = if serving {
+        ServingStatus::Serving
+    } else {
+        ServingStatus::NotServing
+    };
+    reporter.set_service_status("", status).await;
+    reporter
+        .set_service_status(
+            <ProxyDServer<ProxyDService> as tonic::server::NamedService>::NAME,
+            status,
+        )
+        .await;
+}
+
+/// Mirrors `readiness` into the gRPC health service until the sender is dropped.
+pub async fn follow_readiness(mut reporter: HealthReporter, mut ready: watch::Receiver<bool>) {
+    loop {
+        let serving = *ready.borrow_and_update();
+        set_health(&mut reporter, serving).await;
+        if ready.changed().await.is_err() {
+            break;
+        }
+    }
+}
+
 pub struct GrpcServerConfig {
     pub http2_keepalive_interval: Duration,
     pub http2_keepalive_timeout: Duration,
@@ -122,6 +411,7 @@ pub struct GrpcServerConfig {
     pub concurrency_limit: usize,
     pub initial_connection_window_size: u32,
     pub initial_stream_window_size: u32,
+    pub request_timeout: Duration,
 }
 
 impl Default for GrpcServerConfig {
@@ -134,6 +424,7 @@ impl Default for GrpcServerConfig {
             concurrency_limit: 1000,
             initial_connection_window_size: 4 * 1024 * 1024,
             initial_stream_window_size: 2 * 1024 * 1024,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
         }
     }
 }
@@ -147,18 +438,24 @@ pub fn configure_server(config: &GrpcServerConfig) -> Server {
         .concurrency_limit_per_connection(config.concurrency_limit)
         .initial_connection_window_size(config.initial_connection_window_size)
         .initial_stream_window_size(config.initial_stream_window_size)
+        .timeout(config.request_timeout)
 }
 
 #[tonic::async_trait]
 impl ProxyD for ProxyDService {
+    #[instrument(level = "debug", skip_all, fields(ip = %log_ip(&request.get_ref().ip), found))]
     async fn lookup_ip(
         &self,
         request: Request<IpRequest>,
     ) -> Result<Response<ReputationResponse>, Status> {
-        let metrics = LookupMetrics::start_grpc();
+        let _permit = self
+            .limiter
+            .try_acquire(LookupClass::Single)
+            .ok_or_else(too_many_lookups)?;
+        let metrics = LookupMetrics::start_grpc(GrpcMethod::LookupIp);
         let ip_str = &request.get_ref().ip;
 
-        match do_lookup_ip(&self.db, ip_str) {
+        match do_lookup_ip(&self.db, ip_str, &self.lookup_options) {
             Ok(result) => {
                 metrics.record(&result);
                 Ok(Response::new(result.into()))
@@ -167,11 +464,16 @@ impl ProxyD for ProxyDService {
         }
     }
 
+    #[instrument(level = "debug", skip_all, fields(cidr = %log_ip(&request.get_ref().cidr), found))]
     async fn lookup_range(
         &self,
         request: Request<RangeRequest>,
     ) -> Result<Response<ReputationResponse>, Status> {
-        let metrics = LookupMetrics::start_grpc();
+        let _permit = self
+            .limiter
+            .try_acquire(LookupClass::Single)
+            .ok_or_else(too_many_lookups)?;
+        let metrics = LookupMetrics::start_grpc(GrpcMethod::LookupRange);
         let cidr_str = &request.get_ref().cidr;
 
         match do_lookup_range(&self.db, cidr_str) {
@@ -183,57 +485,748 @@ impl ProxyD for ProxyDService {
         }
     }
 
+    #[instrument(level = "debug", skip_all, fields(count = request.get_ref().ips.len(), found))]
     async fn batch_lookup_ip(
         &self,
         request: Request<BatchIpRequest>,
     ) -> Result<Response<BatchReputationResponse>, Status> {
-        let ips = &request.get_ref().ips;
+        let _permit = self
+            .limiter
+            .try_acquire(LookupClass::Batch)
+            .ok_or_else(too_many_lookups)?;
+        let deadline = request_deadline(request.metadata(), self.request_timeout);
+        let BatchIpRequest {
+            ips,
+            only_found,
+            family,
+        } = request.into_inner();
 
         if ips.len() > MAX_BATCH_SIZE {
             return Err(Status::invalid_argument(format!(
                 "Batch size exceeds maximum of {MAX_BATCH_SIZE}"
             )));
         }
+        check_batch_family(&ips, &family).map_err(Status::invalid_argument)?;
 
-        let metrics = LookupMetrics::start_grpc();
-        let ip_strs: Vec<&str> = ips.iter().map(String::as_str).collect();
+        let metrics = LookupMetrics::start_grpc(GrpcMethod::BatchLookupIp);
+        let db = Arc::clone(&self.db);
+        let options = self.lookup_options;
+        let span = Span::current();
+        let lookup = run_with_deadline(deadline, move || {
+            let _entered = span.enter();
+            let ip_strs: Vec<&str> = ips.iter().map(String::as_str).collect();
+            lookup_ips_batch_with(&db, &ip_strs, &options)
+        })
+        .await?;
 
-        match lookup_ips_batch(&self.db, &ip_strs) {
+        match lookup {
             Ok(lookup_results) => {
-                let any_found = lookup_results.iter().any(|r| r.found);
-                let results: Vec<ReputationResponse> =
-                    lookup_results.into_iter().map(Into::into).collect();
-                metrics.record_batch(any_found);
+                metrics.record_batch(&lookup_results);
+                let results: Vec<ReputationResponse> = lookup_results
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(_, result)| !only_found || result.found || result.timed_out)
+                    .map(|(index, result)| ReputationResponse {
+                        index: u32::try_from(index).unwrap_or(u32::MAX),
+                        ..result.into()
+                    })
+                    .collect();
                 Ok(Response::new(BatchReputationResponse { results }))
             }
             Err(ref e) => Err(lookup_error_to_status(e)),
         }
     }
 
+    #[instrument(level = "debug", skip_all, fields(count = request.get_ref().cidrs.len(), found))]
     async fn batch_lookup_range(
         &self,
         request: Request<BatchRangeRequest>,
     ) -> Result<Response<BatchReputationResponse>, Status> {
-        let cidrs = &request.get_ref().cidrs;
+        let _permit = self
+            .limiter
+            .try_acquire(LookupClass::Batch)
+            .ok_or_else(too_many_lookups)?;
+        let deadline = request_deadline(request.metadata(), self.request_timeout);
+        let BatchRangeRequest {
+            cidrs,
+            include_supernets,
+            family,
+        } = request.into_inner();
 
         if cidrs.len() > MAX_BATCH_SIZE {
             return Err(Status::invalid_argument(format!(
                 "Batch size exceeds maximum of {MAX_BATCH_SIZE}"
             )));
         }
+        check_batch_family(&cidrs, &family).map_err(Status::invalid_argument)?;
 
-        let metrics = LookupMetrics::start_grpc();
-        let cidr_strs: Vec<&str> = cidrs.iter().map(String::as_str).collect();
+        let metrics = LookupMetrics::start_grpc(GrpcMethod::BatchLookupRange);
+        let db = Arc::clone(&self.db);
+        let options = self.lookup_options;
+        let span = Span::current();
+        let lookup = run_with_deadline(deadline, move || {
+            let _entered = span.enter();
+            let cidr_strs: Vec<&str> = cidrs.iter().map(String::as_str).collect();
+            if include_supernets {
+                lookup_ranges_batch_with_supernets(&db, &cidr_strs, &options)
+            } else {
+                lookup_ranges_batch_with(&db, &cidr_strs, &options)
+            }
+        })
+        .await?;
 
-        match lookup_ranges_batch(&self.db, &cidr_strs) {
+        match lookup {
             Ok(lookup_results) => {
-                let any_found = lookup_results.iter().any(|r| r.found);
+                metrics.record_batch(&lookup_results);
                 let results: Vec<ReputationResponse> =
                     lookup_results.into_iter().map(Into::into).collect();
-                metrics.record_batch(any_found);
                 Ok(Response::new(BatchReputationResponse { results }))
             }
             Err(ref e) => Err(lookup_error_to_status(e)),
         }
     }
+
+    #[instrument(level = "debug", skip_all, fields(count = request.get_ref().entries.len(), found))]
+    async fn batch_lookup(
+        &self,
+        request: Request<BatchLookupRequest>,
+    ) -> Result<Response<BatchLookupResponse>, Status> {
+        let _permit = self
+            .limiter
+            .try_acquire(LookupClass::Batch)
+            .ok_or_else(too_many_lookups)?;
+        let deadline = request_deadline(request.metadata(), self.request_timeout);
+        let entries = request.into_inner().entries;
+
+        if entries.len() > MAX_BATCH_SIZE {
+            return Err(Status::invalid_argument(format!(
+                "Batch size exceeds maximum of {MAX_BATCH_SIZE}"
+            )));
+        }
+
+        let metrics = LookupMetrics::start_grpc(GrpcMethod::BatchLookup);
+        let db = Arc::clone(&self.db);
+        let options = self.lookup_options;
+        let span = Span::current();
+        let (kinds, lookup) = run_with_deadline(deadline, move || {
+            let _entered = span.enter();
+            let kinds: Vec<_> = entries.iter().map(|entry| classify_entry(entry)).collect();
+            let lookup = lookup_mixed_batch(&db, &entries, &kinds, &options);
+            (kinds, lookup)
+        })
+        .await?;
+
+        let lookup_results = lookup.map_err(|ref e| lookup_error_to_status(e))?;
+        metrics.record_batch(&lookup_results);
+        let mut lookup_results = lookup_results.into_iter();
+        let results = kinds
+            .into_iter()
+            .enumerate()
+            .map(|(index, kind)| {
+                let index = u32::try_from(index).unwrap_or(u32::MAX);
+                match kind {
+                    Ok(kind) => BatchLookupResult {
+                        index,
+                        kind: kind.into(),
+                        result: lookup_results.next().map(|result| ReputationResponse {
+                            index,
+                            ..result.into()
+                        }),
+                        error: String::new(),
+                    },
+                    Err(e) => BatchLookupResult {
+                        index,
+                        kind: EntryKind::Invalid.into(),
+                        result: None,
+                        error: e.to_string(),
+                    },
+                }
+            })
+            .collect();
+        Ok(Response::new(BatchLookupResponse { results }))
+    }
+
+    async fn import(
+        &self,
+        request: Request<Streaming<ImportChunk>>,
+    ) -> Result<Response<ImportSummary>, Status> {
+        let _metrics = AdminRpcMetrics::start(GrpcMethod::Import);
+        let Some(config) = self
+            .import_config
+            .as_ref()
+            .filter(|c| !c.api_keys.is_empty())
+        else {
+            return Err(Status::permission_denied(
+                "Import is disabled until PROXYD_API_KEYS is configured",
+            ));
+        };
+        if config.read_only {
+            return Err(read_only_status());
+        }
+
+        // Each chunk goes to disk as it arrives; nothing is imported unless
+        // the stream ends cleanly.
+        let internal = |e: ImportError| failure_to_status(e.kind(), e.to_string());
+        let mut staged = StagedFeed::create_for_records(config)
+            .await
+            .map_err(internal)?;
+        let mut stream = request.into_inner();
+        let mut mode = None;
+        let mut received = 0;
+        while let Some(chunk) = stream.message().await? {
+            received += prost::Message::encoded_len(&chunk);
+            if received > config.import_max_bytes {
+                return Err(Status::resource_exhausted(
+                    "Import stream exceeds PROXYD_IMPORT_MAX_BYTES",
+                ));
+            }
+            mode = match chunk.mode() {
+                ImportMode::Auto => mode,
+                ImportMode::Full => Some(DomainImportMode::Full),
+                ImportMode::Incremental => Some(DomainImportMode::Incremental),
+            };
+            let records: Vec<CsvRecord> = chunk
+                .records
+                .into_iter()
+                .filter(|r| !r.entry.is_empty())
+                .map(|r| CsvRecord {
+                    flags: r.flags.as_ref().map(DomainFlags::from).unwrap_or_default(),
+                    ip: r.entry,
+                    note: bounded_note(&r.note),
+                    sources: None,
+                })
+                .collect();
+            staged.write_records(&records).await.map_err(internal)?;
+        }
+
+        let result = staged.import(&self.db, mode, config).await;
+        self.readiness.refresh(&self.db);
+        match result {
+            Ok(summary) => Ok(Response::new(summary.into())),
+            // The client sent the rejected feed itself
+            Err(e) if e.kind() == FailureKind::InvalidFeed => {
+                Err(Status::invalid_argument(e.to_string()))
+            }
+            Err(e) => Err(failure_to_status(e.kind(), e.to_string())),
+        }
+    }
+
+    async fn trigger_sync(
+        &self,
+        _request: Request<TriggerSyncRequest>,
+    ) -> Result<Response<SyncSummary>, Status> {
+        let _metrics = AdminRpcMetrics::start(GrpcMethod::TriggerSync);
+        let Some(config) = self
+            .import_config
+            .as_ref()
+            .filter(|c| !c.api_keys.is_empty())
+        else {
+            return Err(Status::permission_denied(
+                "TriggerSync is disabled until PROXYD_API_KEYS is configured",
+            ));
+        };
+        if config.read_only {
+            return Err(read_only_status());
+        }
+
+        let result = trigger_sync(&self.db, config).await;
+        self.readiness.refresh(&self.db);
+        match result {
+            Ok(outcome) => Ok(Response::new(outcome.into())),
+            Err(e) => Err(failure_to_status(e.kind(), e.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proto::proxy_d_client::ProxyDClient;
+    use tempfile::TempDir;
+    use tonic::transport::server::TcpIncoming;
+
+    async fn serve_with_keys(keys: &[String]) -> (TempDir, String) {
+        let dir = TempDir::new().unwrap();
+        let db = Database::open(dir.path()).unwrap();
+        let service = ProxyDService::new(db, LookupOptions::default());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("http://{}", listener.local_addr().unwrap());
+        let server = Server::builder()
+            .add_service(service.into_authenticated_server(keys))
+            .serve_with_incoming(TcpIncoming::from_listener(listener, true, None).unwrap());
+        tokio::spawn(server);
+
+        (dir, addr)
+    }
+
+    fn ip_request_for(ip: &str) -> Request<IpRequest> {
+        Request::new(IpRequest { ip: ip.to_owned() })
+    }
+
+    fn ip_request(key: Option<&str>) -> Request<IpRequest> {
+        let mut request = Request::new(IpRequest {
+            ip: "1.2.3.4".to_owned(),
+        });
+        if let Some(key) = key {
+            request
+                .metadata_mut()
+                .insert("x-api-key", key.parse().unwrap());
+        }
+        request
+    }
+
+    #[tokio::test]
+    async fn test_api_key_interceptor() {
+        let (_dir, addr) = serve_with_keys(&["secret".to_owned()]).await;
+        let mut client = ProxyDClient::connect(addr).await.unwrap();
+
+        let status = client.lookup_ip(ip_request(None)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        let status = client
+            .lookup_ip(ip_request(Some("wrong")))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        let response = client.lookup_ip(ip_request(Some("secret"))).await.unwrap();
+        assert!(!response.into_inner().found);
+    }
+
+    #[tokio::test]
+    async fn test_streaming_import() {
+        let dir = TempDir::new().unwrap();
+        let config = Arc::new(Config {
+            data_dir: dir.path().to_path_buf(),
+            api_keys: vec!["secret".to_owned()],
+            ..Config::default()
+        });
+        let db = Database::open(&config.db_path()).unwrap();
+        let service =
+            ProxyDService::new(Arc::clone(&db), LookupOptions::default()).with_import(config);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(
+            Server::builder()
+                .add_service(service.into_server())
+                .serve_with_incoming(TcpIncoming::from_listener(listener, true, None).unwrap()),
+        );
+        let mut client = ProxyDClient::connect(addr).await.unwrap();
+
+        let flags = ProtoFlags {
+            proxy: true,
+            ..Default::default()
+        };
+        let chunks: Vec<ImportChunk> = (0u32..4)
+            .map(|chunk| ImportChunk {
+                records: (0u32..1000)
+                    .map(|i| proto::ImportRecord {
+                        entry: std::net::Ipv4Addr::from(0x0a00_0000 + chunk * 1000 + i).to_string(),
+                        flags: Some(flags),
+                        note: if i == 0 {
+                            format!("chunk {chunk}")
+                        } else {
+                            String::new()
+                        },
+                    })
+                    .collect(),
+                mode: ImportMode::Auto.into(),
+            })
+            .collect();
+
+        let summary = client
+            .import(tonic::codegen::tokio_stream::iter(chunks))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(summary.mode, "full");
+        assert_eq!(summary.added, 4000);
+        let saved = std::fs::read_to_string(dir.path().join("proxy_blocks.csv")).unwrap();
+        assert_eq!(saved.lines().count(), 4001);
+        assert_eq!(crate::sync::downloader::compute_hash(&saved), summary.hash);
+
+        let response = client
+            .lookup_ip(ip_request_for("10.0.15.159"))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(response.found);
+        assert!(response.flags.unwrap().proxy);
+        assert_eq!(response.matched_entries[0].note, "");
+        assert_eq!(db.get_metadata().unwrap().record_count, 4000);
+
+        let response = client
+            .lookup_ip(ip_request_for("10.0.3.232"))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.matched_entries[0].note, "chunk 1");
+    }
+
+    #[tokio::test]
+    async fn test_import_stream_over_the_byte_cap_is_rejected() {
+        let dir = TempDir::new().unwrap();
+        let config = Arc::new(Config {
+            data_dir: dir.path().to_path_buf(),
+            api_keys: vec!["secret".to_owned()],
+            import_max_bytes: 1024,
+            ..Config::default()
+        });
+        let db = Database::open(&config.db_path()).unwrap();
+        let service =
+            ProxyDService::new(Arc::clone(&db), LookupOptions::default()).with_import(config);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(
+            Server::builder()
+                .add_service(service.into_server())
+                .serve_with_incoming(TcpIncoming::from_listener(listener, true, None).unwrap()),
+        );
+        let mut client = ProxyDClient::connect(addr).await.unwrap();
+
+        let chunks = (0u32..10).map(|chunk| ImportChunk {
+            records: (0u32..20)
+                .map(|i| proto::ImportRecord {
+                    entry: std::net::Ipv4Addr::from(0x0a00_0000 + chunk * 20 + i).to_string(),
+                    flags: Some(ProtoFlags::default()),
+                    note: String::new(),
+                })
+                .collect(),
+            mode: ImportMode::Full.into(),
+        });
+        let status = client
+            .import(tonic::codegen::tokio_stream::iter(chunks))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        assert!(db.is_empty().unwrap());
+
+        // The chunks written before the cap was hit are discarded with it.
+        let files: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.starts_with("proxy_blocks"))
+            .collect();
+        assert!(files.is_empty(), "{files:?}");
+    }
+
+    #[tokio::test]
+    async fn test_health_flips_to_not_serving() {
+        use tonic_health::pb::health_client::HealthClient;
+        use tonic_health::pb::{
+            health_check_response::ServingStatus as Status, HealthCheckRequest,
+        };
+
+        let (mut reporter, health_service) = create_health_service(true).await;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(
+            Server::builder()
+                .add_service(health_service)
+                .serve_with_incoming(TcpIncoming::from_listener(listener, true, None).unwrap()),
+        );
+        let channel = tonic::transport::Endpoint::from_shared(addr)
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut client = HealthClient::new(channel);
+        let check = |service: &str| HealthCheckRequest {
+            service: service.to_owned(),
+        };
+
+        let response = client.check(check("proxyd.ProxyD")).await.unwrap();
+        assert_eq!(response.into_inner().status(), Status::Serving);
+
+        set_health(&mut reporter, false).await;
+        for service in ["", "proxyd.ProxyD"] {
+            let response = client.check(check(service)).await.unwrap();
+            assert_eq!(response.into_inner().status(), Status::NotServing);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_health_follows_readiness_until_data_is_imported() {
+        use tonic_health::pb::health_client::HealthClient;
+        use tonic_health::pb::{
+            health_check_response::ServingStatus as Status, HealthCheckRequest,
+        };
+
+        let dir = TempDir::new().unwrap();
+        let config = Arc::new(Config {
+            data_dir: dir.path().to_path_buf(),
+            api_keys: vec!["secret".to_owned()],
+            ..Config::default()
+        });
+        let db = Database::open(&config.db_path()).unwrap();
+        let readiness = Readiness::default();
+        readiness.refresh(&db);
+        let service = ProxyDService::new(Arc::clone(&db), LookupOptions::default())
+            .with_import(config)
+            .with_readiness(readiness.clone());
+
+        let (reporter, health_service) = create_health_service(false).await;
+        tokio::spawn(follow_readiness(reporter, readiness.subscribe()));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(
+            Server::builder()
+                .add_service(health_service)
+                .add_service(service.into_server())
+                .serve_with_incoming(TcpIncoming::from_listener(listener, true, None).unwrap()),
+        );
+        let channel = tonic::transport::Endpoint::from_shared(addr)
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let health = HealthClient::new(channel.clone());
+        let mut client = ProxyDClient::new(channel);
+        let wait_for = |expected: Status| {
+            let mut health = health.clone();
+            async move {
+                for _ in 0..100 {
+                    let request = HealthCheckRequest {
+                        service: "proxyd.ProxyD".to_owned(),
+                    };
+                    let status = health.check(request).await.unwrap().into_inner().status();
+                    if status == expected {
+                        return;
+                    }
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                panic!("health never reported {expected:?}");
+            }
+        };
+        wait_for(Status::NotServing).await;
+
+        let chunk = ImportChunk {
+            records: vec![proto::ImportRecord {
+                entry: "10.0.0.0/8".to_owned(),
+                flags: Some(ProtoFlags {
+                    proxy: true,
+                    ..Default::default()
+                }),
+                note: String::new(),
+            }],
+            mode: ImportMode::Full.into(),
+        };
+        client
+            .import(tonic::codegen::tokio_stream::iter([chunk]))
+            .await
+            .unwrap();
+        assert!(readiness.is_ready());
+        wait_for(Status::Serving).await;
+
+        readiness.drain();
+        readiness.refresh(&db);
+        assert!(!readiness.is_ready());
+        wait_for(Status::NotServing).await;
+    }
+
+    #[tokio::test]
+    async fn test_import_disabled_without_keys() {
+        let dir = TempDir::new().unwrap();
+        let config = Arc::new(Config {
+            data_dir: dir.path().to_path_buf(),
+            ..Config::default()
+        });
+        let db = Database::open(&config.db_path()).unwrap();
+        let service = ProxyDService::new(db, LookupOptions::default()).with_import(config);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(
+            Server::builder()
+                .add_service(service.into_server())
+                .serve_with_incoming(TcpIncoming::from_listener(listener, true, None).unwrap()),
+        );
+        let mut client = ProxyDClient::connect(addr).await.unwrap();
+
+        let status = client
+            .import(tonic::codegen::tokio_stream::iter(Vec::<ImportChunk>::new()))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+        let status = client
+            .trigger_sync(TriggerSyncRequest {})
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn test_api_key_interceptor_without_keys_is_noop() {
+        let (_dir, addr) = serve_with_keys(&[]).await;
+        let mut client = ProxyDClient::connect(addr).await.unwrap();
+
+        assert!(client.lookup_ip(ip_request(None)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_batch_lookup_classifies_each_entry() {
+        let dir = TempDir::new().unwrap();
+        let db = Database::open(dir.path()).unwrap();
+        let flags = DomainFlags {
+            proxy: true,
+            ..Default::default()
+        };
+        let mut txn = db.begin_write().unwrap();
+        db.insert_record(&mut txn, "1.2.3.4", &flags).unwrap();
+        db.insert_record(&mut txn, "10.0.0.0/8", &flags).unwrap();
+        txn.commit().unwrap();
+        db.rebuild_trie().unwrap();
+        let service = ProxyDService::new(db, LookupOptions::default());
+
+        let entries = [
+            "10.0.0.0/8",
+            "1.2.3.4",
+            "not-an-ip",
+            "10.0.0.0/40",
+            "5.6.7.8",
+        ];
+        let response = service
+            .batch_lookup(Request::new(BatchLookupRequest {
+                entries: entries.iter().map(|e| (*e).to_owned()).collect(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+
+        let kinds: Vec<EntryKind> = response.results.iter().map(|r| r.kind()).collect();
+        assert_eq!(
+            kinds,
+            [
+                EntryKind::Cidr,
+                EntryKind::Ip,
+                EntryKind::Invalid,
+                EntryKind::Invalid,
+                EntryKind::Ip
+            ]
+        );
+        let found: Vec<Option<bool>> = response
+            .results
+            .iter()
+            .map(|r| r.result.as_ref().map(|result| result.found))
+            .collect();
+        assert_eq!(found, [Some(true), Some(true), None, None, Some(false)]);
+        for (i, result) in response.results.iter().enumerate() {
+            assert_eq!(result.index as usize, i);
+            if let Some(lookup) = &result.result {
+                assert_eq!(lookup.query, entries[i]);
+                assert_eq!(lookup.index as usize, i);
+            }
+        }
+        assert!(response.results[2].error.contains("not-an-ip"));
+        assert!(response.results[1].error.is_empty());
+
+        let status = service
+            .batch_lookup(Request::new(BatchLookupRequest {
+                entries: vec!["1.2.3.4".to_owned(); MAX_BATCH_SIZE + 1],
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_sync_failures_map_to_statuses() {
+        let dir = TempDir::new().unwrap();
+        let config = Arc::new(Config {
+            data_dir: dir.path().to_path_buf(),
+            api_keys: vec!["secret".to_owned()],
+            // Rejected before any request is made
+            csv_urls: vec!["s3:///proxy_blocks.csv".to_owned()],
+            min_import_records: 2,
+            ..Config::default()
+        });
+        let db = Database::open(&config.db_path()).unwrap();
+        let service =
+            ProxyDService::new(Arc::clone(&db), LookupOptions::default()).with_import(config);
+
+        let status = service
+            .trigger_sync(Request::new(TriggerSyncRequest {}))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Internal);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(
+            Server::builder()
+                .add_service(service.into_server())
+                .serve_with_incoming(TcpIncoming::from_listener(listener, true, None).unwrap()),
+        );
+        let mut client = ProxyDClient::connect(addr).await.unwrap();
+        let chunk = ImportChunk {
+            records: vec![proto::ImportRecord {
+                entry: "1.2.3.4".to_owned(),
+                flags: Some(ProtoFlags::default()),
+                note: String::new(),
+            }],
+            mode: ImportMode::Full.into(),
+        };
+        let status = client
+            .import(tonic::codegen::tokio_stream::iter(vec![chunk]))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert!(db.is_empty().unwrap());
+
+        let codes = [
+            (FailureKind::Upstream, tonic::Code::Unavailable),
+            (FailureKind::InvalidFeed, tonic::Code::FailedPrecondition),
+            (FailureKind::Conflict, tonic::Code::Aborted),
+            (FailureKind::Internal, tonic::Code::Internal),
+        ];
+        for (kind, code) in codes {
+            assert_eq!(failure_to_status(kind, String::new()).code(), code);
+        }
+    }
+
+    #[test]
+    fn test_parse_grpc_timeout_units() {
+        assert_eq!(parse_grpc_timeout("2S"), Some(Duration::from_secs(2)));
+        assert_eq!(parse_grpc_timeout("150m"), Some(Duration::from_millis(150)));
+        assert_eq!(parse_grpc_timeout("1H"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_grpc_timeout("10u"), Some(Duration::from_micros(10)));
+        assert_eq!(parse_grpc_timeout("S"), None);
+        assert_eq!(parse_grpc_timeout("10x"), None);
+        assert_eq!(parse_grpc_timeout("123456789S"), None);
+    }
+
+    #[test]
+    fn test_request_deadline_is_capped_by_server_timeout() {
+        let mut metadata = MetadataMap::new();
+        let server = Duration::from_secs(5);
+        assert_eq!(request_deadline(&metadata, server), server);
+
+        metadata.insert("grpc-timeout", "100m".parse().unwrap());
+        assert_eq!(
+            request_deadline(&metadata, server),
+            Duration::from_millis(100)
+        );
+
+        metadata.insert("grpc-timeout", "60S".parse().unwrap());
+        assert_eq!(request_deadline(&metadata, server), server);
+    }
+
+    #[tokio::test]
+    async fn test_slow_lookup_exceeds_deadline() {
+        let status = run_with_deadline(Duration::from_millis(20), || {
+            std::thread::sleep(Duration::from_millis(200));
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::DeadlineExceeded);
+
+        let value = run_with_deadline(Duration::from_secs(1), || 42)
+            .await
+            .unwrap();
+        assert_eq!(value, 42);
+    }
 }
@@ -0,0 +1,317 @@
+use actix_web::http::StatusCode;
+use actix_web::{post, web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use tonic::codegen::tokio_stream::StreamExt;
+
+use super::auth::{check_api_key, error};
+use super::rest::AppState;
+use crate::sync::error::FailureKind;
+use crate::sync::importer::{ImportError, ImportMode, StagedFeed};
+
+#[derive(Deserialize)]
+struct ImportQuery {
+    mode: Option<ImportMode>,
+}
+
+/// HTTP status for a failed import or sync, so clients can tell an upstream
+/// outage (502) from a rejected feed (422) from an internal fault (500).
+pub fn failure_status(kind: FailureKind) -> StatusCode {
+    match kind {
+        FailureKind::Upstream => StatusCode::BAD_GATEWAY,
+        FailureKind::InvalidFeed => StatusCode::UNPROCESSABLE_ENTITY,
+        FailureKind::Conflict => StatusCode::CONFLICT,
+        FailureKind::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Checks that an upload is UTF-8 one chunk at a time. A character split
+/// between chunks is carried over and completed by the next one.
+#[derive(Default)]
+struct Utf8Check {
+    carry: Vec<u8>,
+}
+
+impl Utf8Check {
+    fn push(&mut self, chunk: &[u8]) -> bool {
+        let joined;
+        let bytes = if self.carry.is_empty() {
+            chunk
+        } else {
+            joined = [std::mem::take(&mut self.carry).as_slice(), chunk].concat();
+            &joined
+        };
+        match std::str::from_utf8(bytes) {
+            Ok(_) => true,
+            Err(e) if e.error_len().is_none() => {
+                self.carry = bytes[e.valid_up_to()..].to_vec();
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.carry.is_empty()
+    }
+}
+
+fn not_utf8() -> HttpResponse {
+    error(
+        StatusCode::BAD_REQUEST,
+        "invalid_body",
+        "CSV body must be UTF-8",
+    )
+}
+
+#[post("/v1/import")]
+pub async fn import_csv(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    query: web::Query<ImportQuery>,
+    mut payload: web::Payload,
+) -> HttpResponse {
+    if let Err(response) = check_api_key(&req, &state.config.api_keys) {
+        return response;
+    }
+    if state.config.read_only {
+        return error(
+            StatusCode::FORBIDDEN,
+            "read_only",
+            "This instance serves the database read-only; import on the writer",
+        );
+    }
+
+    let internal =
+        |e: ImportError| error(StatusCode::INTERNAL_SERVER_ERROR, e.code(), &e.to_string());
+    let mut staged = match StagedFeed::create(&state.config).await {
+        Ok(staged) => staged,
+        Err(e) => return internal(e),
+    };
+    let mut utf8 = Utf8Check::default();
+    while let Some(chunk) = payload.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => return error(StatusCode::BAD_REQUEST, "invalid_body", &e.to_string()),
+        };
+        if staged.len() + chunk.len() > state.config.import_max_bytes {
+            return error(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "payload_too_large",
+                "CSV exceeds PROXYD_IMPORT_MAX_BYTES",
+            );
+        }
+        if !utf8.push(&chunk) {
+            return not_utf8();
+        }
+        if let Err(e) = staged.write(&chunk).await {
+            return internal(e);
+        }
+    }
+    if !utf8.is_complete() {
+        return not_utf8();
+    }
+
+    let result = staged.import(&state.db, query.mode, &state.config).await;
+    state.readiness.refresh(&state.db);
+    match result {
+        Ok(summary) => HttpResponse::Ok().json(summary),
+        Err(e) => error(failure_status(e.kind()), e.code(), &e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use actix_web::{test, App};
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::api::rest::test_state;
+    use crate::config::Config;
+    use crate::db::Database;
+
+    fn state(dir: &TempDir, api_keys: &[&str]) -> AppState {
+        let config = Config {
+            data_dir: dir.path().to_path_buf(),
+            api_keys: api_keys.iter().map(|k| (*k).to_owned()).collect(),
+            import_max_bytes: 1024,
+            ..Config::default()
+        };
+        let db = Database::open(&config.db_path()).unwrap();
+        AppState {
+            config: Arc::new(config),
+            ..test_state(db)
+        }
+    }
+
+    fn upload(key: Option<&str>, body: impl Into<String>) -> test::TestRequest {
+        let mut req = test::TestRequest::post()
+            .uri("/v1/import")
+            .insert_header(("content-type", "text/csv"))
+            .set_payload(body.into());
+        if let Some(key) = key {
+            req = req.insert_header(("x-api-key", key));
+        }
+        req
+    }
+
+    #[actix_web::test]
+    async fn test_import_requires_api_key() {
+        let dir = TempDir::new().unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state(&dir, &["secret"])))
+                .service(import_csv),
+        )
+        .await;
+
+        let resp = test::call_service(&app, upload(None, "ip,proxy").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let resp = test::call_service(&app, upload(Some("nope"), "ip,proxy").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let dir = TempDir::new().unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state(&dir, &[])))
+                .service(import_csv),
+        )
+        .await;
+        let resp = test::call_service(&app, upload(Some("secret"), "ip,proxy").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn test_import_rejected_when_read_only() {
+        let dir = TempDir::new().unwrap();
+        let mut state = state(&dir, &["secret"]);
+        Arc::make_mut(&mut state.config).read_only = true;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(import_csv),
+        )
+        .await;
+
+        let req = upload(Some("secret"), "ip,proxy\n1.2.3.4,true").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"]["code"], "read_only");
+    }
+
+    #[actix_web::test]
+    async fn test_import_full_then_incremental() {
+        let dir = TempDir::new().unwrap();
+        let state = state(&dir, &["secret"]);
+        let db = Arc::clone(&state.db);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(import_csv),
+        )
+        .await;
+
+        let req = upload(Some("secret"), "ip,proxy\n1.2.3.4,true\n10.0.0.0/8,true").to_request();
+        let summary: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(summary["mode"], "full");
+        assert_eq!(summary["added"], 2);
+        assert!(db.lookup_ip("1.2.3.4".parse().unwrap()).unwrap().is_some());
+        assert_eq!(
+            db.find_matching_cidrs_fast("10.1.1.1".parse().unwrap())
+                .len(),
+            1
+        );
+
+        let req = upload(Some("secret"), "ip,proxy\n1.2.3.4,true\n5.6.7.8,true").to_request();
+        let summary: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(summary["mode"], "incremental");
+        assert_eq!(summary["added"], 1);
+        assert_eq!(summary["deleted"], 1);
+        assert!(db
+            .find_matching_cidrs_fast("10.1.1.1".parse().unwrap())
+            .is_empty());
+
+        let oversized = format!("ip,proxy\n{}", "1.2.3.4,true\n".repeat(100));
+        let resp = test::call_service(&app, upload(Some("secret"), oversized).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let staged: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .filter(|name| name.to_string_lossy().contains("incoming"))
+            .collect();
+        assert!(staged.is_empty(), "{staged:?}");
+    }
+
+    #[actix_web::test]
+    async fn test_large_upload_is_streamed_in_chunks() {
+        let dir = TempDir::new().unwrap();
+        let mut state = state(&dir, &["secret"]);
+        let config = Arc::make_mut(&mut state.config);
+        config.import_chunked_threshold = Some(0);
+        config.import_chunk_rows = 2;
+        let csv_path = config.csv_path();
+        let db = Arc::clone(&state.db);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(import_csv),
+        )
+        .await;
+
+        let body = "ip,proxy,note\n1.2.3.4,true,caf\u{e9}\n5.6.7.8,true,\n10.0.0.0/8,false,\n";
+        let req = upload(Some("secret"), body).to_request();
+        let summary: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(summary["mode"], "full");
+        assert_eq!(summary["added"], 3);
+        assert_eq!(db.count_entries().unwrap(), 3);
+        assert_eq!(std::fs::read_to_string(csv_path).unwrap(), body);
+    }
+
+    #[actix_web::test]
+    async fn test_utf8_check_spans_chunks() {
+        let bytes = "caf\u{e9} \u{1f600}".as_bytes();
+        for split in 0..bytes.len() {
+            let mut check = Utf8Check::default();
+            assert!(check.push(&bytes[..split]) && check.push(&bytes[split..]));
+            assert!(check.is_complete(), "{split}");
+        }
+
+        let mut check = Utf8Check::default();
+        assert!(check.push(&bytes[..4]));
+        assert!(!check.is_complete());
+        assert!(!check.push(b"\xff"));
+    }
+
+    #[actix_web::test]
+    async fn test_rejected_feed_is_unprocessable() {
+        let dir = TempDir::new().unwrap();
+        let mut state = state(&dir, &["secret"]);
+        Arc::make_mut(&mut state.config).min_import_records = 5;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(import_csv),
+        )
+        .await;
+
+        let req = upload(Some("secret"), "ip,proxy\n1.2.3.4,true").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"]["code"], "too_few_records");
+
+        assert_eq!(
+            failure_status(FailureKind::Upstream),
+            StatusCode::BAD_GATEWAY
+        );
+        assert_eq!(failure_status(FailureKind::Conflict), StatusCode::CONFLICT);
+        assert_eq!(
+            failure_status(FailureKind::Internal),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+}
@@ -0,0 +1,91 @@
+use actix_web::{post, web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use super::auth::check_api_key;
+use super::rest::AppState;
+
+#[derive(Deserialize)]
+struct MaintenanceRequest {
+    enabled: bool,
+}
+
+#[derive(Serialize)]
+struct MaintenanceStatus {
+    maintenance: bool,
+}
+
+#[post("/admin/maintenance")]
+pub async fn set_maintenance(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: web::Json<MaintenanceRequest>,
+) -> HttpResponse {
+    if let Err(response) = check_api_key(&req, &state.config.api_keys) {
+        return response;
+    }
+
+    state.maintenance.set(body.enabled);
+    HttpResponse::Ok().json(MaintenanceStatus {
+        maintenance: state.maintenance.is_enabled(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::api::rest::dataset_stats;
+    use crate::api::rest::test_state;
+    use crate::config::Config;
+    use crate::db::Database;
+
+    #[actix_web::test]
+    async fn test_maintenance_toggle_reflected_in_stats() {
+        let dir = TempDir::new().unwrap();
+        let state = AppState {
+            config: Arc::new(Config {
+                api_keys: vec!["secret".to_owned()],
+                ..Config::default()
+            }),
+            ..test_state(Database::open(dir.path()).unwrap())
+        };
+        let maintenance = state.maintenance.clone();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(set_maintenance)
+                .service(dataset_stats),
+        )
+        .await;
+        let toggle = |key: &str, enabled: bool| {
+            test::TestRequest::post()
+                .uri("/admin/maintenance")
+                .insert_header(("x-api-key", key))
+                .set_json(serde_json::json!({ "enabled": enabled }))
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, toggle("wrong", true)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert!(!maintenance.is_enabled());
+
+        let body: serde_json::Value =
+            test::call_and_read_body_json(&app, toggle("secret", true)).await;
+        assert_eq!(body["maintenance"], true);
+        assert!(maintenance.is_enabled());
+        let req = test::TestRequest::get().uri("/v1/stats").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["maintenance"], true);
+        assert_eq!(body["entries"], 0);
+
+        test::call_service(&app, toggle("secret", false)).await;
+        let req = test::TestRequest::get().uri("/v1/stats").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["maintenance"], false);
+    }
+}
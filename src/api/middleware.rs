@@ -0,0 +1,192 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+
+use super::preserialized::{request_timeout_error, too_many_lookups_error};
+use super::rest::AppState;
+use crate::metrics::LookupClass;
+
+const TIMEOUT_SCOPE: &str = "/v1/";
+const TIMEOUT_EXEMPT: &[&str] = &["/v1/import"];
+const LOOKUP_SCOPES: &[&str] = &["/v1/ip/", "/v1/range", "/v1/myip"];
+
+pub async fn lookup_limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let path = req.path();
+    let limiter = LOOKUP_SCOPES
+        .iter()
+        .any(|scope| path.starts_with(scope))
+        .then(|| req.app_data::<web::Data<AppState>>())
+        .flatten()
+        .map(|state| state.limiter.clone());
+
+    let Some(limiter) = limiter else {
+        return next.call(req).await;
+    };
+
+    let class = if path.ends_with("/batch") {
+        LookupClass::Batch
+    } else {
+        LookupClass::Single
+    };
+    let Some(_permit) = limiter.try_acquire(class) else {
+        return Err(InternalError::from_response(
+            "too many concurrent lookups",
+            too_many_lookups_error().into(),
+        )
+        .into());
+    };
+    next.call(req).await
+}
+
+pub async fn handler_timeout(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let path = req.path();
+    let timeout = (path.starts_with(TIMEOUT_SCOPE) && !TIMEOUT_EXEMPT.contains(&path))
+        .then(|| req.app_data::<web::Data<AppState>>())
+        .flatten()
+        .map(|state| state.request_timeout);
+
+    let Some(timeout) = timeout else {
+        return next.call(req).await;
+    };
+
+    match tokio::time::timeout(timeout, next.call(req)).await {
+        Ok(res) => res,
+        Err(_) => Err(InternalError::from_response(
+            "request timed out",
+            request_timeout_error().into(),
+        )
+        .into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::http::StatusCode;
+    use actix_web::{middleware, test, App, HttpResponse};
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::api::rest::test_state;
+    use crate::api::LookupLimiter;
+    use crate::db::Database;
+
+    async fn slow() -> HttpResponse {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        HttpResponse::Ok().finish()
+    }
+
+    #[actix_web::test]
+    async fn test_handler_timeout_applies_only_to_v1() {
+        let dir = TempDir::new().unwrap();
+        let state = AppState {
+            request_timeout: Duration::from_millis(20),
+            ..test_state(Database::open(dir.path()).unwrap())
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .wrap(middleware::from_fn(handler_timeout))
+                .route("/v1/slow", web::get().to(slow))
+                .route("/v1/import", web::get().to(slow))
+                .route("/health", web::get().to(slow)),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/v1/slow").to_request();
+        let Err(err) = test::try_call_service(&app, req).await else {
+            panic!("expected slow /v1/ handler to time out");
+        };
+        assert_eq!(err.error_response().status(), StatusCode::GATEWAY_TIMEOUT);
+
+        let req = test::TestRequest::get().uri("/v1/import").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::get().uri("/health").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_lookup_limit_rejects_when_saturated() {
+        let dir = TempDir::new().unwrap();
+        let limiter = LookupLimiter::new(Some(1));
+        let state = AppState {
+            limiter: limiter.clone(),
+            ..test_state(Database::open(dir.path()).unwrap())
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .wrap(middleware::from_fn(lookup_limit))
+                .route("/v1/ip/{ip}", web::get().to(slow))
+                .route("/v1/myip", web::get().to(slow))
+                .route("/health", web::get().to(slow)),
+        )
+        .await;
+
+        let held = limiter.try_acquire(LookupClass::Single).unwrap();
+        for uri in ["/v1/ip/1.2.3.4", "/v1/myip"] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            let Err(err) = test::try_call_service(&app, req).await else {
+                panic!("expected {uri} to be rejected while the limit is held");
+            };
+            assert_eq!(
+                err.error_response().status(),
+                StatusCode::SERVICE_UNAVAILABLE
+            );
+        }
+
+        let req = test::TestRequest::get().uri("/health").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        drop(held);
+        let req = test::TestRequest::get().uri("/v1/ip/1.2.3.4").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_batch_limit_leaves_single_lookups_flowing() {
+        let dir = TempDir::new().unwrap();
+        let limiter = LookupLimiter::new(None).with_class_limits(None, Some(1));
+        let state = AppState {
+            limiter: limiter.clone(),
+            ..test_state(Database::open(dir.path()).unwrap())
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .wrap(middleware::from_fn(lookup_limit))
+                .route("/v1/ip/batch", web::post().to(slow))
+                .route("/v1/ip/{ip}", web::get().to(slow)),
+        )
+        .await;
+
+        let held = limiter.try_acquire(LookupClass::Batch).unwrap();
+        let req = test::TestRequest::post().uri("/v1/ip/batch").to_request();
+        let Err(err) = test::try_call_service(&app, req).await else {
+            panic!("expected batch lookup to be rejected while the batch limit is held");
+        };
+        assert_eq!(
+            err.error_response().status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        let req = test::TestRequest::get().uri("/v1/ip/1.2.3.4").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        drop(held);
+        let req = test::TestRequest::post().uri("/v1/ip/batch").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    }
+}
@@ -1,14 +1,94 @@
+pub mod auth;
+pub mod compact;
+pub mod config;
+pub mod explain;
+pub mod export;
+pub mod family;
+pub mod flag_names;
 pub mod grpc;
+pub mod import;
+pub mod listener;
+pub mod maintenance;
+pub mod middleware;
+pub mod myip;
+pub mod openapi;
 pub mod preserialized;
+pub mod record;
 pub mod rest;
+pub mod sample_log;
+pub mod selftest;
+pub mod sync_status;
+pub mod unified;
 
+use std::sync::Arc;
 use std::time::Instant;
 
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::Span;
+
 use crate::ip::LookupResult;
-use crate::metrics;
+use crate::metrics::{self, GrpcMethod, LookupClass};
+
+#[derive(Clone, Default)]
+pub struct LookupLimiter {
+    semaphore: Option<Arc<Semaphore>>,
+    single: Option<Arc<Semaphore>>,
+    batch: Option<Arc<Semaphore>>,
+}
+
+pub struct LookupPermit {
+    _class: Option<OwnedSemaphorePermit>,
+    _total: Option<OwnedSemaphorePermit>,
+}
+
+fn semaphore(max: Option<usize>) -> Option<Arc<Semaphore>> {
+    max.map(|max| Arc::new(Semaphore::new(max)))
+}
+
+fn try_permit(semaphore: Option<&Arc<Semaphore>>) -> Result<Option<OwnedSemaphorePermit>, ()> {
+    semaphore
+        .map(|s| Arc::clone(s).try_acquire_owned().map_err(drop))
+        .transpose()
+}
+
+impl LookupLimiter {
+    pub fn new(max_concurrent: Option<usize>) -> Self {
+        Self {
+            semaphore: semaphore(max_concurrent),
+            ..Self::default()
+        }
+    }
+
+    /// Adds limits that apply to one class of lookups only, on top of the
+    /// overall limit.
+    pub fn with_class_limits(mut self, single: Option<usize>, batch: Option<usize>) -> Self {
+        self.single = semaphore(single);
+        self.batch = semaphore(batch);
+        self
+    }
+
+    pub fn try_acquire(&self, class: LookupClass) -> Option<LookupPermit> {
+        let class_semaphore = match class {
+            LookupClass::Single => self.single.as_ref(),
+            LookupClass::Batch => self.batch.as_ref(),
+        };
+
+        let permit = try_permit(class_semaphore).and_then(|class_permit| {
+            Ok(LookupPermit {
+                _total: try_permit(self.semaphore.as_ref())?,
+                _class: class_permit,
+            })
+        });
+        if permit.is_err() {
+            metrics::inc_lookups_rejected(class);
+        }
+        permit.ok()
+    }
+}
 
 pub struct LookupMetrics {
     start: Instant,
+    grpc_method: Option<GrpcMethod>,
 }
 
 impl LookupMetrics {
@@ -16,29 +96,75 @@ impl LookupMetrics {
         metrics::inc_rest_requests();
         Self {
             start: Instant::now(),
+            grpc_method: None,
         }
     }
 
-    pub fn start_grpc() -> Self {
-        metrics::inc_grpc_requests();
+    pub fn start_grpc(method: GrpcMethod) -> Self {
+        metrics::inc_grpc_requests(method);
         Self {
             start: Instant::now(),
+            grpc_method: Some(method),
         }
     }
 
     pub fn record(&self, result: &LookupResult) {
         let elapsed = self.start.elapsed().as_secs_f64();
         metrics::record_lookup_latency(elapsed);
+        metrics::record_matched_entries(result.matched_entries.len());
         if result.found {
             metrics::inc_lookup_hits();
         }
+        Span::current().record("found", result.found);
+        sample_log::maybe_log(result);
     }
 
-    pub fn record_batch(&self, any_found: bool) {
+    pub fn record_batch(&self, results: &[LookupResult]) {
         let elapsed = self.start.elapsed().as_secs_f64();
         metrics::record_lookup_latency(elapsed);
-        if any_found {
+        // Entries skipped at the soft deadline were neither found nor missed.
+        let (mut found, mut looked_up) = (0, 0);
+        for result in results.iter().filter(|result| !result.timed_out) {
+            metrics::record_matched_entries(result.matched_entries.len());
+            found += usize::from(result.found);
+            looked_up += 1;
+        }
+        if found > 0 {
             metrics::inc_lookup_hits();
         }
+        metrics::record_batch_found(found, looked_up);
+        Span::current().record("found", found);
+        sample_log::maybe_log_batch(results);
+    }
+}
+
+impl Drop for LookupMetrics {
+    fn drop(&mut self) {
+        if let Some(method) = self.grpc_method {
+            metrics::record_grpc_duration(method, self.start.elapsed().as_secs_f64());
+        }
+    }
+}
+
+/// Counts and times the `Import` and `TriggerSync` RPCs. They run for seconds
+/// to minutes, so they get their own histogram instead of the lookup one.
+pub struct AdminRpcMetrics {
+    start: Instant,
+    method: GrpcMethod,
+}
+
+impl AdminRpcMetrics {
+    pub fn start(method: GrpcMethod) -> Self {
+        metrics::inc_grpc_requests(method);
+        Self {
+            start: Instant::now(),
+            method,
+        }
+    }
+}
+
+impl Drop for AdminRpcMetrics {
+    fn drop(&mut self) {
+        metrics::record_grpc_admin_duration(self.method, self.start.elapsed().as_secs_f64());
     }
 }
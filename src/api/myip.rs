@@ -0,0 +1,166 @@
+use std::net::IpAddr;
+
+use actix_web::http::StatusCode;
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use ipnetwork::IpNetwork;
+use tracing::{field, instrument, Span};
+
+use super::auth::error;
+use super::rest::{lookup_response, AppState};
+use super::unified::relayed_peer;
+use super::LookupMetrics;
+use crate::ip::{log_ip, lookup_ip_with};
+
+fn is_trusted(ip: IpAddr, trusted: &[IpNetwork]) -> bool {
+    trusted.iter().any(|network| network.contains(ip))
+}
+
+/// Address of the client that sent `req`. `X-Forwarded-For` is only read when
+/// the connection comes from a trusted proxy, and then from the right: each
+/// trusted hop vouches for the address before it, so the first untrusted
+/// address is the client. Anything further left could be forged by it.
+pub fn client_ip(req: &HttpRequest, trusted: &[IpNetwork]) -> Option<IpAddr> {
+    let peer = req.peer_addr()?;
+    let mut client = relayed_peer(peer).unwrap_or(peer).ip();
+    if !is_trusted(client, trusted) {
+        return Some(client);
+    }
+
+    let forwarded: Vec<&str> = req
+        .headers()
+        .get_all("x-forwarded-for")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+    for hop in forwarded.into_iter().rev() {
+        let Ok(ip) = hop.trim().parse::<IpAddr>() else {
+            break;
+        };
+        client = ip;
+        if !is_trusted(ip, trusted) {
+            break;
+        }
+    }
+    Some(client)
+}
+
+#[get("/v1/myip")]
+#[instrument(level = "debug", skip_all, fields(ip, found))]
+pub async fn my_ip(req: HttpRequest, state: web::Data<AppState>) -> HttpResponse {
+    let Some(ip) = client_ip(&req, &state.config.trusted_proxies) else {
+        return error(
+            StatusCode::BAD_REQUEST,
+            "unknown_client",
+            "Client address is not available",
+        );
+    };
+
+    let ip_str = ip.to_string();
+    Span::current().record("ip", field::display(log_ip(&ip_str)));
+    let metrics = LookupMetrics::start_rest();
+    match lookup_ip_with(&state.db, &ip_str, &state.lookup_options) {
+        Ok(result) => {
+            metrics.record(&result);
+            lookup_response(&state, &result)
+        }
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, e.code(), &e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use actix_web::{test, App};
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::api::rest::test_state;
+
+    use crate::config::Config;
+    use crate::db::Database;
+    use crate::ip::ReputationFlags;
+
+    fn request(peer: &str, forwarded: Option<&str>) -> HttpRequest {
+        let mut req = test::TestRequest::get()
+            .uri("/v1/myip")
+            .peer_addr(peer.parse::<SocketAddr>().unwrap());
+        if let Some(forwarded) = forwarded {
+            req = req.insert_header(("x-forwarded-for", forwarded));
+        }
+        req.to_http_request()
+    }
+
+    #[actix_web::test]
+    async fn test_client_ip_only_trusts_configured_proxies() {
+        let trusted: Vec<IpNetwork> = vec!["10.0.0.0/8".parse().unwrap()];
+        let ip = |peer, forwarded| client_ip(&request(peer, forwarded), &trusted).unwrap();
+
+        assert_eq!(
+            ip("203.0.113.9:5000", None),
+            "203.0.113.9".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            ip("203.0.113.9:5000", Some("198.51.100.1")),
+            "203.0.113.9".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            ip("10.0.0.2:5000", Some("198.51.100.1")),
+            "198.51.100.1".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            ip("10.0.0.2:5000", Some("1.1.1.1, 198.51.100.1, 10.0.0.3")),
+            "198.51.100.1".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            ip("10.0.0.2:5000", Some("garbage, 10.0.0.3")),
+            "10.0.0.3".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            ip("10.0.0.2:5000", None),
+            "10.0.0.2".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_myip_looks_up_forwarded_client() {
+        let dir = TempDir::new().unwrap();
+        let db = Database::open(dir.path()).unwrap();
+        let mut txn = db.begin_write().unwrap();
+        let proxy = ReputationFlags {
+            proxy: true,
+            ..Default::default()
+        };
+        db.insert_record(&mut txn, "198.51.100.1", &proxy).unwrap();
+        txn.commit().unwrap();
+
+        let state = AppState {
+            config: Arc::new(Config {
+                trusted_proxies: vec!["127.0.0.1/32".parse().unwrap()],
+                ..Config::default()
+            }),
+            ..test_state(db)
+        };
+        let app =
+            test::init_service(App::new().app_data(web::Data::new(state)).service(my_ip)).await;
+
+        let req = test::TestRequest::get()
+            .uri("/v1/myip")
+            .peer_addr("127.0.0.1:4000".parse().unwrap())
+            .insert_header(("x-forwarded-for", "198.51.100.1"))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["query"], "198.51.100.1");
+        assert_eq!(body["found"], true);
+
+        let req = test::TestRequest::get()
+            .uri("/v1/myip")
+            .peer_addr("192.0.2.7:4000".parse().unwrap())
+            .insert_header(("x-forwarded-for", "198.51.100.1"))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["query"], "192.0.2.7");
+        assert_eq!(body["found"], false);
+    }
+}
@@ -0,0 +1,737 @@
+use std::sync::LazyLock;
+
+use serde_json::{json, Value};
+
+use super::preserialized::PreserializedJson;
+
+fn flags_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "anonblock": { "type": "boolean" },
+            "proxy": { "type": "boolean" },
+            "vpn": { "type": "boolean" },
+            "cdn": { "type": "boolean" },
+            "public_wifi": { "type": "boolean" },
+            "rangeblock": { "type": "boolean" },
+            "school_block": { "type": "boolean" },
+            "tor": { "type": "boolean" },
+            "webhost": { "type": "boolean" }
+        }
+    })
+}
+
+fn lookup_result_schema() -> Value {
+    json!({
+        "type": "object",
+        "required": ["found", "query", "normalized_query", "flags", "matched_entries"],
+        "properties": {
+            "found": { "type": "boolean" },
+            "query": { "type": "string" },
+            "normalized_query": { "type": "string" },
+            "flags": { "$ref": "#/components/schemas/ReputationFlags" },
+            "flags_mask": {
+                "type": "integer",
+                "description": "Bit i is set when flag i is set: anonblock, proxy, vpn, cdn, public_wifi, rangeblock, school_block, tor, webhost"
+            },
+            "matched_entries": {
+                "type": "array",
+                "items": { "$ref": "#/components/schemas/MatchedEntry" }
+            },
+            "checked_at": { "type": "integer", "format": "int64" },
+            "dataset_hash": { "type": "string", "nullable": true },
+            "degraded": { "type": "boolean" },
+            "timed_out": {
+                "type": "boolean",
+                "description": "Batch entry skipped by PROXYD_BATCH_SOFT_DEADLINE_MS; only present when true"
+            }
+        }
+    })
+}
+
+fn explanation_schema() -> Value {
+    json!({
+        "type": "object",
+        "required": ["query", "normalized_query", "found", "verdict", "reasons"],
+        "properties": {
+            "query": { "type": "string" },
+            "normalized_query": { "type": "string" },
+            "found": { "type": "boolean" },
+            "verdict": { "type": "string" },
+            "reasons": { "type": "array", "items": { "type": "string" } },
+            "flag_sources": {
+                "type": "object",
+                "description": "Present with explain_sources=true: each set flag mapped to the matched entries that set it",
+                "additionalProperties": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["entry"],
+                        "properties": {
+                            "entry": { "type": "string" },
+                            "note": { "type": "string" },
+                            "sources": {
+                                "type": "array",
+                                "items": { "type": "string" },
+                                "description": "The merged feeds that set the flag on this entry"
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
+
+fn detailed_health_schema() -> Value {
+    json!({
+        "type": "object",
+        "required": ["status", "database_healthy", "draining"],
+        "properties": {
+            "status": {
+                "type": "string",
+                "enum": ["healthy", "degraded", "draining", "unhealthy"]
+            },
+            "database_healthy": { "type": "boolean" },
+            "draining": { "type": "boolean" },
+            "trie": {
+                "type": "object",
+                "nullable": true,
+                "properties": {
+                    "trie_networks": { "type": "integer" },
+                    "stored_networks": { "type": "integer" },
+                    "malformed_keys": { "type": "integer" },
+                    "consistent": { "type": "boolean" }
+                }
+            },
+            "suggestion": { "type": "string", "nullable": true }
+        }
+    })
+}
+
+fn selftest_schema() -> Value {
+    json!({
+        "type": "object",
+        "required": ["status", "stages"],
+        "properties": {
+            "status": { "type": "string", "enum": ["pass", "fail"] },
+            "stages": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "required": ["name", "status", "duration_us"],
+                    "properties": {
+                        "name": {
+                            "type": "string",
+                            "enum": ["parse", "trie", "database", "lookup", "serialize"]
+                        },
+                        "status": { "type": "string", "enum": ["pass", "fail", "skipped"] },
+                        "duration_us": { "type": "integer" },
+                        "detail": { "type": "string" }
+                    }
+                }
+            }
+        }
+    })
+}
+
+fn json_response(description: &str, schema: &Value) -> Value {
+    json!({
+        "description": description,
+        "content": { "application/json": { "schema": schema } }
+    })
+}
+
+fn build_spec() -> Value {
+    let result = json!({ "$ref": "#/components/schemas/LookupResult" });
+    let results = json!({ "type": "array", "items": result });
+    let error = json!({ "$ref": "#/components/schemas/Error" });
+    let health = json!({ "$ref": "#/components/schemas/Health" });
+    let export_entry = json!({
+        "type": "object",
+        "properties": {
+            "entry": { "type": "string" },
+            "flags": { "$ref": "#/components/schemas/ReputationFlags" },
+            "note": { "type": "string" }
+        }
+    });
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "ProxyD",
+            "description": env!("CARGO_PKG_DESCRIPTION"),
+            "version": env!("CARGO_PKG_VERSION")
+        },
+        "paths": {
+            "/v1/ip/{ip}": {
+                "get": {
+                    "summary": "Look up a single IP address",
+                    "parameters": [{
+                        "name": "ip", "in": "path", "required": true,
+                        "schema": { "type": "string" }
+                    }, {
+                        "name": "aggregate_prefix", "in": "query", "required": false,
+                        "description": "Mask the address to this prefix length and return only CIDR entries covering the whole network",
+                        "schema": { "type": "integer", "minimum": 0, "maximum": 128 }
+                    }],
+                    "responses": {
+                        "200": json_response("Lookup result", &result),
+                        "400": json_response("Invalid IP address", &error),
+                        "404": json_response("Not listed (only with PROXYD_NOT_FOUND_STATUS=404)", &result)
+                    }
+                }
+            },
+            "/v1/myip": {
+                "get": {
+                    "summary": "Look up the caller's own address; X-Forwarded-For is honoured only from PROXYD_TRUSTED_PROXIES",
+                    "responses": {
+                        "200": json_response("Lookup result", &result),
+                        "404": json_response("Not listed (only with PROXYD_NOT_FOUND_STATUS=404)", &result)
+                    }
+                }
+            },
+            "/v1/ip/{ip}/explain": {
+                "get": {
+                    "summary": "Explain in plain English why an IP address is or is not listed",
+                    "parameters": [
+                        {
+                            "name": "ip", "in": "path", "required": true,
+                            "schema": { "type": "string" }
+                        },
+                        {
+                            "name": "explain_sources", "in": "query", "required": false,
+                            "schema": { "type": "boolean", "default": false }
+                        }
+                    ],
+                    "responses": {
+                        "200": json_response(
+                            "Explanation",
+                            &json!({ "$ref": "#/components/schemas/Explanation" })
+                        ),
+                        "400": json_response("Invalid IP address", &error)
+                    }
+                }
+            },
+            "/v1/range": {
+                "get": {
+                    "summary": "Look up an exact CIDR range",
+                    "parameters": [{
+                        "name": "cidr", "in": "query", "required": true,
+                        "schema": { "type": "string" }
+                    }],
+                    "responses": {
+                        "200": json_response("Lookup result", &result),
+                        "400": json_response("Invalid CIDR", &error),
+                        "404": json_response("Not listed (only with PROXYD_NOT_FOUND_STATUS=404)", &result)
+                    }
+                }
+            },
+            "/v1/ip/batch": {
+                "post": {
+                    "summary": "Look up up to 1000 IP addresses",
+                    "parameters": [{
+                        "name": "only_found", "in": "query", "required": false,
+                        "description": "Return only found and timed-out results, each with its request `index`",
+                        "schema": { "type": "boolean", "default": false }
+                    }],
+                    "requestBody": {
+                        "required": true,
+                        "content": { "application/json": { "schema": {
+                            "type": "object",
+                            "required": ["ips"],
+                            "properties": {
+                                "ips": { "type": "array", "items": { "type": "string" } },
+                                "family": {
+                                    "type": "string",
+                                    "enum": ["ipv4", "ipv6"],
+                                    "description": "Reject the batch with `family_mismatch` if any entry is of the other family"
+                                }
+                            }
+                        } } }
+                    },
+                    "responses": {
+                        "200": json_response("Lookup results in request order", &results),
+                        "400": json_response("Invalid IP or batch too large", &error)
+                    }
+                }
+            },
+            "/v1/range/batch": {
+                "post": {
+                    "summary": "Look up up to 1000 exact CIDR ranges",
+                    "requestBody": {
+                        "required": true,
+                        "content": { "application/json": { "schema": {
+                            "type": "object",
+                            "required": ["cidrs"],
+                            "properties": {
+                                "cidrs": { "type": "array", "items": { "type": "string" } },
+                                "include_supernets": {
+                                    "type": "boolean",
+                                    "default": false,
+                                    "description": "Also match stored CIDRs that cover each range"
+                                },
+                                "family": {
+                                    "type": "string",
+                                    "enum": ["ipv4", "ipv6"],
+                                    "description": "Reject the batch with `family_mismatch` if any entry is of the other family"
+                                }
+                            }
+                        } } }
+                    },
+                    "responses": {
+                        "200": json_response("Lookup results in request order", &results),
+                        "400": json_response("Invalid CIDR or batch too large", &error)
+                    }
+                }
+            },
+            "/v1/import": {
+                "post": {
+                    "summary": "Import a CSV feed pushed in the request body",
+                    "parameters": [
+                        {
+                            "name": "x-api-key", "in": "header", "required": true,
+                            "schema": { "type": "string" }
+                        },
+                        {
+                            "name": "mode", "in": "query", "required": false,
+                            "schema": { "type": "string", "enum": ["full", "incremental"] }
+                        }
+                    ],
+                    "requestBody": {
+                        "required": true,
+                        "content": { "text/csv": { "schema": { "type": "string" } } }
+                    },
+                    "responses": {
+                        "200": json_response("Import summary", &json!({
+                            "type": "object",
+                            "properties": {
+                                "mode": { "type": "string", "enum": ["full", "incremental"] },
+                                "hash": { "type": "string" },
+                                "added": { "type": "integer" },
+                                "updated": { "type": "integer" },
+                                "deleted": { "type": "integer" }
+                            }
+                        })),
+                        "401": json_response("Missing or invalid API key", &error),
+                        "403": json_response("No API keys configured", &error),
+                        "413": json_response("CSV larger than PROXYD_IMPORT_MAX_BYTES", &error),
+                        "422": json_response(
+                            "Feed rejected: unparseable, or tripped a delete or minimum record check",
+                            &error
+                        ),
+                        "500": json_response("Internal error while importing", &error)
+                    }
+                }
+            },
+            "/v1/export/delta": {
+                "get": {
+                    "summary": "Entries changed since an earlier dataset, for followers syncing from this instance",
+                    "description": "Returns `mode: delta` with the `added`, `updated` and `deleted` entries that turn dataset `from` into the current dataset `to`. When `from` is not one of the last `PROXYD_DELTA_HISTORY` datasets, returns `mode: full` with every stored entry in `entries`, which replace the follower's data.",
+                    "parameters": [
+                        {
+                            "name": "from", "in": "query", "required": true,
+                            "description": "Dataset hash the follower currently holds",
+                            "schema": { "type": "string" }
+                        },
+                        {
+                            "name": "x-api-key", "in": "header", "required": true,
+                            "schema": { "type": "string" }
+                        }
+                    ],
+                    "responses": {
+                        "200": json_response("Delta or full export", &json!({
+                            "type": "object",
+                            "properties": {
+                                "mode": { "type": "string", "enum": ["delta", "full"] },
+                                "from": { "type": "string" },
+                                "to": { "type": "string", "nullable": true },
+                                "added": { "type": "array", "items": export_entry },
+                                "updated": { "type": "array", "items": export_entry },
+                                "deleted": { "type": "array", "items": { "type": "string" } },
+                                "entries": { "type": "array", "items": export_entry }
+                            }
+                        })),
+                        "400": json_response("from is not a dataset hash", &error),
+                        "401": json_response("Missing or invalid API key", &error),
+                        "403": json_response("No API keys configured", &error),
+                        "413": json_response("The export has more than PROXYD_EXPORT_MAX_ENTRIES entries", &error)
+                    }
+                }
+            },
+            "/v1/record/{entry}": {
+                "get": {
+                    "summary": "Fetch the record stored under an exact IP or CIDR key, without CIDR matching",
+                    "parameters": [
+                        {
+                            "name": "entry", "in": "path", "required": true,
+                            "schema": { "type": "string" }
+                        },
+                        {
+                            "name": "x-api-key", "in": "header", "required": true,
+                            "schema": { "type": "string" }
+                        }
+                    ],
+                    "responses": {
+                        "200": json_response("Stored record", &json!({
+                            "type": "object",
+                            "properties": {
+                                "entry": { "type": "string" },
+                                "table": {
+                                    "type": "string",
+                                    "enum": ["ip_v4", "ip_v6", "cidr_v4", "cidr_v6"]
+                                },
+                                "flags": { "$ref": "#/components/schemas/ReputationFlags" },
+                                "note": { "type": "string" },
+                                "raw": {
+                                    "type": "string",
+                                    "description": "The feed's original CSV line (PROXYD_STORE_RAW_ROWS)"
+                                }
+                            }
+                        })),
+                        "400": json_response("Invalid IP or CIDR", &error),
+                        "401": json_response("Missing or invalid API key", &error),
+                        "403": json_response("No API keys configured", &error),
+                        "404": json_response("Nothing stored under this key", &error)
+                    }
+                }
+            },
+            "/v1/config": {
+                "get": {
+                    "summary": "Effective runtime configuration; durations are in milliseconds, API keys and URL credentials/query strings are redacted (requires PROXYD_API_KEYS)",
+                    "responses": {
+                        "200": json_response("Configuration", &json!({ "type": "object" })),
+                        "401": json_response("Missing or invalid API key", &error),
+                        "403": json_response("No API keys configured", &error)
+                    }
+                }
+            },
+            "/v1/sync/status": {
+                "get": {
+                    "summary": "Compare the local dataset hash with the published upstream hash",
+                    "responses": {
+                        "200": json_response("Sync status", &json!({
+                            "type": "object",
+                            "properties": {
+                                "local_hash": { "type": "string", "nullable": true },
+                                "last_sync": { "type": "integer", "nullable": true },
+                                "stale_seconds": { "type": "integer", "nullable": true },
+                                "remote_status": {
+                                    "type": "string",
+                                    "enum": ["ok", "not_configured", "unavailable"]
+                                },
+                                "remote_hash": { "type": "string" },
+                                "remote_error": { "type": "string" },
+                                "in_sync": { "type": "boolean", "nullable": true }
+                            }
+                        })),
+                        "500": json_response("Database error", &error)
+                    }
+                }
+            },
+            "/v1/stats": {
+                "get": {
+                    "summary": "Dataset summary and whether maintenance mode is pausing scheduled syncs",
+                    "responses": {
+                        "200": json_response("Dataset summary", &json!({
+                            "type": "object",
+                            "properties": {
+                                "entries": { "type": "integer" },
+                                "last_sync": { "type": "integer", "nullable": true },
+                                "csv_hash": { "type": "string", "nullable": true },
+                                "maintenance": { "type": "boolean" }
+                            }
+                        })),
+                        "500": json_response("Database error", &error)
+                    }
+                }
+            },
+            "/admin/maintenance": {
+                "post": {
+                    "summary": "Enable or disable maintenance mode, which skips scheduled syncs",
+                    "parameters": [{
+                        "name": "x-api-key", "in": "header", "required": true,
+                        "schema": { "type": "string" }
+                    }],
+                    "requestBody": {
+                        "required": true,
+                        "content": { "application/json": { "schema": {
+                            "type": "object",
+                            "required": ["enabled"],
+                            "properties": { "enabled": { "type": "boolean" } }
+                        } } }
+                    },
+                    "responses": {
+                        "200": json_response("Current maintenance state", &json!({
+                            "type": "object",
+                            "properties": { "maintenance": { "type": "boolean" } }
+                        })),
+                        "401": json_response("Missing or invalid API key", &error),
+                        "403": json_response("No API keys configured", &error)
+                    }
+                }
+            },
+            "/admin/compact": {
+                "post": {
+                    "summary": "Stage a compacted copy of the LMDB file, swapped in on the next start",
+                    "parameters": [{
+                        "name": "x-api-key", "in": "header", "required": true,
+                        "schema": { "type": "string" }
+                    }],
+                    "responses": {
+                        "200": json_response("LMDB file size now and after compaction", &json!({
+                            "type": "object",
+                            "properties": {
+                                "before_bytes": { "type": "integer" },
+                                "after_bytes": { "type": "integer" },
+                                "applies_on_restart": {
+                                    "type": "boolean",
+                                    "description": "Always true: the copy replaces the file on the next start, and any write before then discards it"
+                                }
+                            }
+                        })),
+                        "401": json_response("Missing or invalid API key", &error),
+                        "403": json_response("No API keys configured, or read-only instance", &error),
+                        "500": json_response("Compaction failed", &error)
+                    }
+                }
+            },
+            "/v1/stats/hashes": {
+                "get": {
+                    "summary": "SHA-256 of the stored entries per /8 (IPv4) or /16 (IPv6) bucket",
+                    "responses": {
+                        "200": json_response("Bucket hashes from the last import", &json!({
+                            "type": "object",
+                            "properties": {
+                                "root": { "type": "string" },
+                                "buckets": {
+                                    "type": "object",
+                                    "additionalProperties": { "type": "string" }
+                                }
+                            }
+                        })),
+                        "404": json_response("PROXYD_BUCKET_HASHES is disabled or no import ran yet", &error),
+                        "500": json_response("Database error", &error)
+                    }
+                }
+            },
+            "/v1/stats/trie": {
+                "get": {
+                    "summary": "Node count and depth of the in-memory CIDR trie for each address family",
+                    "responses": {
+                        "200": json_response("Trie shape", &json!({
+                            "type": "object",
+                            "properties": {
+                                "v4": { "$ref": "#/components/schemas/TrieFamilyStats" },
+                                "v6": { "$ref": "#/components/schemas/TrieFamilyStats" }
+                            }
+                        }))
+                    }
+                }
+            },
+            "/v1/stats/prefixes": {
+                "get": {
+                    "summary": "Count stored entries by prefix length for each address family",
+                    "responses": {
+                        "200": json_response("Prefix length histogram", &json!({
+                            "type": "object",
+                            "properties": {
+                                "v4": {
+                                    "type": "object",
+                                    "additionalProperties": { "type": "integer" }
+                                },
+                                "v6": {
+                                    "type": "object",
+                                    "additionalProperties": { "type": "integer" }
+                                }
+                            }
+                        })),
+                        "500": json_response("Database error", &error)
+                    }
+                }
+            },
+            "/health": {
+                "get": {
+                    "summary": "Service health",
+                    "responses": {
+                        "200": json_response("Healthy", &health),
+                        "503": json_response("Unhealthy", &health)
+                    }
+                }
+            },
+            "/health/detailed": {
+                "get": {
+                    "summary": "Service health with optional trie/LMDB consistency check",
+                    "responses": {
+                        "200": json_response(
+                            "Serving; status is degraded when the trie and LMDB disagree",
+                            &json!({ "$ref": "#/components/schemas/DetailedHealth" })
+                        ),
+                        "503": json_response(
+                            "Unhealthy or draining",
+                            &json!({ "$ref": "#/components/schemas/DetailedHealth" })
+                        )
+                    }
+                }
+            },
+            "/health/selftest": {
+                "get": {
+                    "summary": "Round-trip self-test of CSV parsing, trie matching, a stored control entry lookup and serialization; never writes to the database",
+                    "responses": {
+                        "200": json_response(
+                            "Every stage passed or was skipped",
+                            &json!({ "$ref": "#/components/schemas/SelfTest" })
+                        ),
+                        "503": json_response(
+                            "A stage failed",
+                            &json!({ "$ref": "#/components/schemas/SelfTest" })
+                        )
+                    }
+                }
+            },
+            "/metrics": {
+                "get": {
+                    "summary": "Prometheus metrics",
+                    "responses": {
+                        "200": {
+                            "description": "Prometheus text exposition format",
+                            "content": { "text/plain": { "schema": { "type": "string" } } }
+                        }
+                    }
+                }
+            }
+        },
+        "components": {
+            "schemas": {
+                "ReputationFlags": flags_schema(),
+                "MatchedEntry": {
+                    "type": "object",
+                    "required": ["entry", "flags"],
+                    "properties": {
+                        "entry": { "type": "string" },
+                        "flags": { "$ref": "#/components/schemas/ReputationFlags" },
+                        "note": { "type": "string" }
+                    }
+                },
+                "LookupResult": lookup_result_schema(),
+                "Explanation": explanation_schema(),
+                "DetailedHealth": detailed_health_schema(),
+                "SelfTest": selftest_schema(),
+                "TrieFamilyStats": {
+                    "type": "object",
+                    "properties": {
+                        "nodes": { "type": "integer" },
+                        "entries": { "type": "integer" },
+                        "max_depth": { "type": "integer" },
+                        "avg_depth": { "type": "number" }
+                    }
+                },
+                "Error": {
+                    "type": "object",
+                    "required": ["error"],
+                    "properties": {
+                        "error": {
+                            "type": "object",
+                            "required": ["code", "message"],
+                            "properties": {
+                                "code": { "type": "string" },
+                                "message": { "type": "string" }
+                            }
+                        }
+                    }
+                },
+                "Health": {
+                    "type": "object",
+                    "properties": {
+                        "status": { "type": "string" },
+                        "database_healthy": { "type": "boolean" }
+                    }
+                }
+            }
+        }
+    })
+}
+
+pub static OPENAPI_SPEC: LazyLock<&'static [u8]> =
+    LazyLock::new(|| Box::leak(build_spec().to_string().into_bytes().into_boxed_slice()));
+
+pub fn openapi_response() -> PreserializedJson {
+    PreserializedJson::ok(*OPENAPI_SPEC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::explain::explain;
+    use crate::api::rest::DetailedHealth;
+    use crate::ip::{LookupResult, ReputationFlags};
+
+    fn schema_properties(schema: &Value) -> Vec<String> {
+        let mut keys: Vec<String> = schema["properties"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    fn serialized_keys(value: Value) -> Vec<String> {
+        let mut keys: Vec<String> = value.as_object().unwrap().keys().cloned().collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn test_schemas_match_serialized_types() {
+        let flags = serde_json::to_value(ReputationFlags::default()).unwrap();
+        assert_eq!(schema_properties(&flags_schema()), serialized_keys(flags));
+
+        let result = LookupResult {
+            found: false,
+            query: String::new(),
+            normalized_query: String::new(),
+            flags: ReputationFlags::default(),
+            flags_mask: 0,
+            matched_entries: Default::default(),
+            checked_at: 0,
+            dataset_hash: None,
+            degraded: false,
+            timed_out: true,
+        };
+        let mut explanation = explain(&result, &Default::default());
+        explanation.flag_sources = Some(Default::default());
+        let explanation = serde_json::to_value(explanation).unwrap();
+        assert_eq!(
+            schema_properties(&explanation_schema()),
+            serialized_keys(explanation)
+        );
+
+        let result = serde_json::to_value(result).unwrap();
+        assert_eq!(
+            schema_properties(&lookup_result_schema()),
+            serialized_keys(result)
+        );
+
+        let health = serde_json::to_value(DetailedHealth {
+            status: "healthy",
+            database_healthy: true,
+            draining: false,
+            trie: None,
+            suggestion: None,
+        })
+        .unwrap();
+        assert_eq!(
+            schema_properties(&detailed_health_schema()),
+            serialized_keys(health)
+        );
+    }
+
+    #[test]
+    fn test_spec_is_valid_json() {
+        let spec: Value = serde_json::from_slice(&OPENAPI_SPEC).unwrap();
+        assert_eq!(spec["openapi"], "3.0.3");
+        assert!(spec["paths"]["/v1/ip/{ip}"]["get"].is_object());
+    }
+}
@@ -27,6 +27,10 @@ impl PreserializedJson {
     pub const fn bad_request(body: &'static [u8]) -> Self {
         Self::new(body, StatusCode::BAD_REQUEST)
     }
+
+    pub const fn gateway_timeout(body: &'static [u8]) -> Self {
+        Self::new(body, StatusCode::GATEWAY_TIMEOUT)
+    }
 }
 
 impl Responder for PreserializedJson {
@@ -75,10 +79,11 @@ pub static HEALTH_UNAVAILABLE: LazyLock<&'static [u8]> = LazyLock::new(|| {
     )
 });
 
-pub static BATCH_SIZE_ERROR: LazyLock<&'static [u8]> = LazyLock::new(|| {
+pub static HEALTH_DRAINING: LazyLock<&'static [u8]> = LazyLock::new(|| {
     Box::leak(
         serde_json::json!({
-            "error": "Batch size exceeds maximum of 1000"
+            "status": "draining",
+            "database_healthy": true
         })
         .to_string()
         .into_bytes()
@@ -86,14 +91,44 @@ pub static BATCH_SIZE_ERROR: LazyLock<&'static [u8]> = LazyLock::new(|| {
     )
 });
 
-pub fn health_response(db_healthy: bool) -> PreserializedJson {
-    if db_healthy {
-        PreserializedJson::ok(*HEALTH_OK)
-    } else {
+fn error_body(code: &str, message: &str) -> &'static [u8] {
+    Box::leak(
+        serde_json::json!({
+            "error": { "code": code, "message": message }
+        })
+        .to_string()
+        .into_bytes()
+        .into_boxed_slice(),
+    )
+}
+
+pub static BATCH_SIZE_ERROR: LazyLock<&'static [u8]> =
+    LazyLock::new(|| error_body("batch_too_large", "Batch size exceeds maximum of 1000"));
+
+pub static TOO_MANY_LOOKUPS_ERROR: LazyLock<&'static [u8]> =
+    LazyLock::new(|| error_body("too_many_lookups", "Too many concurrent lookups"));
+
+pub static REQUEST_TIMEOUT_ERROR: LazyLock<&'static [u8]> =
+    LazyLock::new(|| error_body("timeout", "Request timed out"));
+
+pub fn health_response(db_healthy: bool, draining: bool) -> PreserializedJson {
+    if !db_healthy {
         PreserializedJson::service_unavailable(*HEALTH_UNAVAILABLE)
+    } else if draining {
+        PreserializedJson::service_unavailable(*HEALTH_DRAINING)
+    } else {
+        PreserializedJson::ok(*HEALTH_OK)
     }
 }
 
 pub fn batch_size_error() -> PreserializedJson {
     PreserializedJson::bad_request(*BATCH_SIZE_ERROR)
 }
+
+pub fn too_many_lookups_error() -> PreserializedJson {
+    PreserializedJson::service_unavailable(*TOO_MANY_LOOKUPS_ERROR)
+}
+
+pub fn request_timeout_error() -> PreserializedJson {
+    PreserializedJson::gateway_timeout(*REQUEST_TIMEOUT_ERROR)
+}
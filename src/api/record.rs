@@ -0,0 +1,278 @@
+use actix_web::http::StatusCode;
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use ipnetwork::IpNetwork;
+use serde::Serialize;
+
+use super::auth::{check_api_key, error};
+use super::flag_names::json_with_flag_names;
+use super::rest::AppState;
+use crate::db::{Database, DbError};
+use crate::ip::ReputationFlags;
+
+#[derive(Serialize)]
+pub struct StoredRecord {
+    pub entry: String,
+    pub table: &'static str,
+    pub flags: ReputationFlags,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    /// The feed's original CSV line, with `PROXYD_STORE_RAW_ROWS`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw: Option<String>,
+}
+
+fn fetch_record(
+    db: &Database,
+    network: IpNetwork,
+    with_raw: bool,
+) -> Result<Option<StoredRecord>, DbError> {
+    let raw = |entry: &str| {
+        if with_raw {
+            db.raw_row(entry)
+        } else {
+            Ok(None)
+        }
+    };
+
+    let is_host = match network {
+        IpNetwork::V4(n) => n.prefix() == 32,
+        IpNetwork::V6(n) => n.prefix() == 128,
+    };
+
+    if is_host {
+        let ip = network.ip();
+        // CIDR-only mode stores hosts as single-address networks.
+        let table = match (ip.is_ipv4(), db.cidr_only()) {
+            (true, false) => "ip_v4",
+            (false, false) => "ip_v6",
+            (true, true) => "cidr_v4",
+            (false, true) => "cidr_v6",
+        };
+        let entry = ip.to_string();
+        let Some(flags) = db.lookup_ip(ip)? else {
+            return Ok(None);
+        };
+        let note = db.lookup_notes([entry.as_str()])?.pop().flatten();
+        return Ok(Some(StoredRecord {
+            raw: raw(&entry)?,
+            entry,
+            table,
+            flags,
+            note,
+        }));
+    }
+
+    let network = IpNetwork::new(network.network(), network.prefix()).unwrap_or(network);
+    let table = if network.is_ipv4() {
+        "cidr_v4"
+    } else {
+        "cidr_v6"
+    };
+    let entry = network.to_string();
+    let Some(flags) = db.lookup_cidr(network)? else {
+        return Ok(None);
+    };
+    let note = db.lookup_notes([entry.as_str()])?.pop().flatten();
+    Ok(Some(StoredRecord {
+        raw: raw(&entry)?,
+        entry,
+        table,
+        flags,
+        note,
+    }))
+}
+
+#[get("/v1/record/{entry:.+}")]
+pub async fn get_record(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    if let Err(response) = check_api_key(&req, &state.config.api_keys) {
+        return response;
+    }
+
+    let entry = path.into_inner();
+    let Ok(network) = entry.parse::<IpNetwork>() else {
+        return error(
+            StatusCode::BAD_REQUEST,
+            "invalid_entry",
+            &format!("Invalid IP or CIDR: {entry}"),
+        );
+    };
+
+    match fetch_record(&state.db, network, state.config.store_raw_rows) {
+        Ok(Some(record)) => json_with_flag_names(&state.config.flag_names, &record),
+        Ok(None) => error(
+            StatusCode::NOT_FOUND,
+            "not_found",
+            &format!("No record stored under {entry}"),
+        ),
+        Err(e) => error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "database_error",
+            &e.to_string(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use actix_web::{test, App};
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::api::rest::test_state;
+    use crate::config::Config;
+    use crate::sync::downloader::compute_hash;
+    use crate::sync::importer::{full_import, incremental_import};
+
+    #[actix_web::test]
+    async fn test_get_record_returns_exact_key_only() {
+        let dir = TempDir::new().unwrap();
+        let db = Database::open(dir.path()).unwrap();
+        let flags = ReputationFlags {
+            vpn: true,
+            ..Default::default()
+        };
+        let mut txn = db.begin_write().unwrap();
+        db.insert_record(&mut txn, "10.0.0.0/8", &flags).unwrap();
+        db.insert_record(&mut txn, "2001:db8::1", &flags).unwrap();
+        txn.commit().unwrap();
+        db.rebuild_trie().unwrap();
+
+        let state = AppState {
+            config: Arc::new(Config {
+                api_keys: vec!["secret".to_owned()],
+                ..Config::default()
+            }),
+            ..test_state(db)
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(get_record),
+        )
+        .await;
+        let get = |uri: &str| {
+            test::TestRequest::get()
+                .uri(uri)
+                .insert_header(("x-api-key", "secret"))
+                .to_request()
+        };
+
+        let body: serde_json::Value =
+            test::call_and_read_body_json(&app, get("/v1/record/10.1.2.3/8")).await;
+        assert_eq!(body["entry"], "10.0.0.0/8");
+        assert_eq!(body["table"], "cidr_v4");
+        assert_eq!(body["flags"]["vpn"], true);
+
+        let body: serde_json::Value = test::call_and_read_body_json(
+            &app,
+            get("/v1/record/2001:0db8:0000:0000:0000:0000:0000:0001"),
+        )
+        .await;
+        assert_eq!(body["entry"], "2001:db8::1");
+        assert_eq!(body["table"], "ip_v6");
+
+        let resp = test::call_service(&app, get("/v1/record/10.1.2.3")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let resp = test::call_service(&app, get("/v1/record/nope")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::get()
+            .uri("/v1/record/10.0.0.0/8")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn test_get_record_reports_cidr_tables_when_cidr_only() {
+        let dir = TempDir::new().unwrap();
+        let db = Database::open(dir.path()).unwrap();
+        db.set_cidr_only(true);
+        let mut txn = db.begin_write().unwrap();
+        db.insert_record(&mut txn, "1.2.3.4", &ReputationFlags::default())
+            .unwrap();
+        db.insert_record(&mut txn, "2001:db8::1", &ReputationFlags::default())
+            .unwrap();
+        txn.commit().unwrap();
+
+        let state = AppState {
+            config: Arc::new(Config {
+                api_keys: vec!["secret".to_owned()],
+                ..Config::default()
+            }),
+            ..test_state(db)
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(get_record),
+        )
+        .await;
+
+        for (entry, table) in [("1.2.3.4", "cidr_v4"), ("2001:db8::1", "cidr_v6")] {
+            let req = test::TestRequest::get()
+                .uri(&format!("/v1/record/{entry}"))
+                .insert_header(("x-api-key", "secret"))
+                .to_request();
+            let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+            assert_eq!(body["entry"], entry);
+            assert_eq!(body["table"], table);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_get_record_serves_raw_row() {
+        let dir = TempDir::new().unwrap();
+        let config = Arc::new(Config {
+            data_dir: dir.path().to_path_buf(),
+            api_keys: vec!["secret".to_owned()],
+            store_raw_rows: true,
+            ..Config::default()
+        });
+        let db = Database::open(&config.db_path()).unwrap();
+        let v1 = "ip,proxy,asn\r\n1.2.3.4,true,AS64500\r\n\"10.0.0.0/8\",false,AS64501\r\n";
+        let v2 = "ip,proxy,asn\n1.2.3.4,true,AS64510\n";
+        full_import(&db, v1, &compute_hash(v1), &config)
+            .await
+            .unwrap();
+
+        let state = AppState {
+            config: Arc::clone(&config),
+            ..test_state(Arc::clone(&db))
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(get_record),
+        )
+        .await;
+        let get = |uri: &str| {
+            test::TestRequest::get()
+                .uri(uri)
+                .insert_header(("x-api-key", "secret"))
+                .to_request()
+        };
+
+        let body: serde_json::Value =
+            test::call_and_read_body_json(&app, get("/v1/record/1.2.3.4")).await;
+        assert_eq!(body["raw"], "1.2.3.4,true,AS64500");
+        let body: serde_json::Value =
+            test::call_and_read_body_json(&app, get("/v1/record/10.0.0.0/8")).await;
+        assert_eq!(body["raw"], "\"10.0.0.0/8\",false,AS64501");
+
+        incremental_import(&db, v2, &compute_hash(v2), &config)
+            .await
+            .unwrap();
+        let body: serde_json::Value =
+            test::call_and_read_body_json(&app, get("/v1/record/1.2.3.4")).await;
+        assert_eq!(body["raw"], "1.2.3.4,true,AS64510");
+        assert_eq!(db.raw_row("10.0.0.0/8").unwrap(), None);
+    }
+}
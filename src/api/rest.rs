@@ -1,34 +1,104 @@
+use std::io::Write;
 use std::sync::Arc;
+use std::time::Duration;
 
-use actix_web::{get, post, web, HttpResponse, Responder};
+use actix_web::error::InternalError;
+use actix_web::http::header::{self, AcceptEncoding, Encoding, Header};
+use actix_web::http::StatusCode;
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use serde::{Deserialize, Serialize};
+use tracing::{instrument, Span};
 
+use super::auth::error;
+use super::compact::compact;
+use super::config::effective_config;
+use super::explain::{explain, flag_sources};
+use super::export::export_delta;
+use super::family::{check_family, AddressFamily};
+use super::flag_names::json_with_flag_names;
+use super::import::import_csv;
+use super::maintenance::set_maintenance;
+use super::myip::my_ip;
+use super::openapi::openapi_response;
 use super::preserialized::{batch_size_error, health_response};
-use super::LookupMetrics;
-use crate::db::Database;
-use crate::ip::{lookup_ip, lookup_ips_batch, lookup_range, lookup_ranges_batch, LookupError};
+use super::record::get_record;
+use super::selftest::selftest;
+use super::sync_status::sync_status;
+use super::{LookupLimiter, LookupMetrics};
+use crate::config::Config;
+use crate::db::{Database, Metadata, TrieConsistency};
+use crate::ip::{
+    log_ip, lookup_ip_prefix, lookup_ip_with, lookup_ips_batch_with, lookup_range,
+    lookup_ranges_batch_with, lookup_ranges_batch_with_supernets, LookupError, LookupOptions,
+    LookupResult,
+};
+use crate::maintenance::Maintenance;
 use crate::metrics;
+use crate::readiness::Readiness;
+use crate::sync::scheduler::refresh_health;
 
 const MAX_BATCH_SIZE: usize = 1000;
 
 #[derive(Clone)]
 pub struct AppState {
     pub db: Arc<Database>,
+    pub lookup_options: LookupOptions,
+    pub request_timeout: Duration,
+    pub config: Arc<Config>,
+    pub limiter: LookupLimiter,
+    pub readiness: Readiness,
+    pub maintenance: Maintenance,
+}
+
+/// Handler tests start from this and override only the fields they exercise.
+#[cfg(test)]
+pub fn test_state(db: Arc<Database>) -> AppState {
+    AppState {
+        db,
+        lookup_options: LookupOptions::default(),
+        request_timeout: Duration::from_secs(5),
+        config: Arc::new(Config::default()),
+        limiter: LookupLimiter::default(),
+        readiness: Readiness::default(),
+        maintenance: Maintenance::default(),
+    }
 }
 
 #[derive(Serialize)]
-struct ErrorResponse {
-    error: String,
+pub struct ErrorDetail {
+    pub code: &'static str,
+    pub message: String,
 }
 
-impl From<LookupError> for ErrorResponse {
-    fn from(err: LookupError) -> Self {
+#[derive(Serialize)]
+pub struct ErrorResponse {
+    pub error: ErrorDetail,
+}
+
+impl ErrorResponse {
+    pub fn new(code: &'static str, message: impl Into<String>) -> Self {
         Self {
-            error: err.to_string(),
+            error: ErrorDetail {
+                code,
+                message: message.into(),
+            },
         }
     }
 }
 
+impl From<LookupError> for ErrorResponse {
+    fn from(err: LookupError) -> Self {
+        Self::new(err.code(), err.to_string())
+    }
+}
+
+#[derive(Deserialize)]
+struct IpQuery {
+    aggregate_prefix: Option<u8>,
+}
+
 #[derive(Deserialize)]
 struct RangeQuery {
     cidr: String,
@@ -37,41 +107,194 @@ struct RangeQuery {
 #[derive(Deserialize)]
 struct BatchIPRequest {
     ips: Vec<String>,
+    #[serde(default)]
+    family: Option<AddressFamily>,
+}
+
+#[derive(Deserialize)]
+struct BatchIPQuery {
+    #[serde(default)]
+    only_found: bool,
+}
+
+#[derive(Serialize)]
+struct IndexedResult<'a> {
+    index: usize,
+    #[serde(flatten)]
+    result: &'a LookupResult,
+}
+
+#[derive(Deserialize)]
+struct ExplainQuery {
+    #[serde(default)]
+    explain_sources: bool,
 }
 
 #[derive(Deserialize)]
 struct BatchRangeRequest {
     cidrs: Vec<String>,
+    #[serde(default)]
+    include_supernets: bool,
+    #[serde(default)]
+    family: Option<AddressFamily>,
+}
+
+pub(super) fn lookup_response(state: &AppState, result: &LookupResult) -> HttpResponse {
+    let mut response = json_with_flag_names(&state.config.flag_names, result);
+    if !result.found && response.status().is_success() {
+        if let Ok(status) = StatusCode::from_u16(state.config.not_found_status) {
+            *response.status_mut() = status;
+        }
+    }
+    response
 }
 
 #[get("/health")]
 pub async fn health_check(state: web::Data<AppState>) -> impl Responder {
-    health_response(state.db.is_healthy())
+    health_response(state.db.is_healthy(), state.readiness.is_draining())
+}
+
+#[derive(Serialize)]
+pub struct DetailedHealth {
+    pub status: &'static str,
+    pub database_healthy: bool,
+    pub draining: bool,
+    pub trie: Option<TrieConsistency>,
+    pub suggestion: Option<&'static str>,
+}
+
+#[get("/health/detailed")]
+pub async fn health_detailed(state: web::Data<AppState>) -> HttpResponse {
+    let database_healthy = state.db.is_healthy();
+    let draining = state.readiness.is_draining();
+    let trie = if state.config.health_consistency_check && database_healthy {
+        state.db.trie_consistency().ok()
+    } else {
+        None
+    };
+    let trie_consistent = trie.is_none_or(|t| t.consistent);
+
+    let status = if !database_healthy {
+        "unhealthy"
+    } else if draining {
+        "draining"
+    } else if !trie_consistent {
+        "degraded"
+    } else {
+        "healthy"
+    };
+    let body = DetailedHealth {
+        status,
+        database_healthy,
+        draining,
+        trie,
+        suggestion: (!trie_consistent)
+            .then_some("In-memory CIDR trie differs from LMDB; restart or re-import to rebuild it"),
+    };
+
+    if database_healthy && !draining {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+fn accepts_gzip(req: &HttpRequest) -> bool {
+    AcceptEncoding::parse(req).is_ok_and(|accept| {
+        accept.negotiate([Encoding::gzip(), Encoding::identity()].iter()) == Some(Encoding::gzip())
+    })
+}
+
+fn gzip(body: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::with_capacity(body.len() / 4), Compression::fast());
+    encoder.write_all(body)?;
+    encoder.finish()
 }
 
 #[get("/metrics")]
-pub async fn metrics_endpoint() -> impl Responder {
+pub async fn metrics_endpoint(req: HttpRequest, state: web::Data<AppState>) -> impl Responder {
+    refresh_health(&state.db, &state.config);
     let body = metrics::gather_metrics();
-    HttpResponse::Ok()
+    let mut response = HttpResponse::Ok();
+    response
         .content_type("text/plain; version=0.0.4; charset=utf-8")
-        .body(body)
+        .insert_header((header::VARY, "accept-encoding"));
+
+    if accepts_gzip(&req) {
+        if let Ok(compressed) = gzip(body.as_bytes()) {
+            return response
+                .insert_header((header::CONTENT_ENCODING, "gzip"))
+                .body(compressed);
+        }
+    }
+    response.body(body)
+}
+
+#[get("/openapi.json")]
+pub async fn openapi_spec() -> impl Responder {
+    openapi_response()
 }
 
 #[get("/v1/ip/{ip}")]
-pub async fn get_ip(state: web::Data<AppState>, path: web::Path<String>) -> impl Responder {
+#[instrument(level = "debug", skip_all, fields(ip = %log_ip(&path), found))]
+pub async fn get_ip(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<IpQuery>,
+) -> impl Responder {
     let metrics = LookupMetrics::start_rest();
     let ip_str = path.into_inner();
 
-    match lookup_ip(&state.db, &ip_str) {
+    let lookup = match query.aggregate_prefix {
+        Some(prefix) => lookup_ip_prefix(&state.db, &ip_str, prefix),
+        None => lookup_ip_with(&state.db, &ip_str, &state.lookup_options),
+    };
+    match lookup {
         Ok(result) => {
             metrics.record(&result);
-            HttpResponse::Ok().json(result)
+            lookup_response(&state, &result)
+        }
+        Err(e) => HttpResponse::BadRequest().json(ErrorResponse::from(e)),
+    }
+}
+
+#[get("/v1/ip/{ip}/explain")]
+#[instrument(level = "debug", skip_all, fields(ip = %log_ip(&path), found))]
+pub async fn explain_ip(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<ExplainQuery>,
+) -> impl Responder {
+    let metrics = LookupMetrics::start_rest();
+    let ip_str = path.into_inner();
+
+    match lookup_ip_with(&state.db, &ip_str, &state.lookup_options) {
+        Ok(result) => {
+            metrics.record(&result);
+            let mut explanation = explain(&result, &state.config.flag_names);
+            if query.explain_sources {
+                let entries = result.matched_entries.iter().map(|e| e.entry.as_str());
+                let provenance = match state.db.lookup_sources(entries) {
+                    Ok(provenance) => provenance,
+                    Err(e) => {
+                        return error(
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            "internal_error",
+                            &e.to_string(),
+                        )
+                    }
+                };
+                explanation.flag_sources =
+                    Some(flag_sources(&result, &provenance, &state.config.flag_names));
+            }
+            HttpResponse::Ok().json(explanation)
         }
         Err(e) => HttpResponse::BadRequest().json(ErrorResponse::from(e)),
     }
 }
 
 #[get("/v1/range")]
+#[instrument(level = "debug", skip_all, fields(cidr = %log_ip(&query.cidr), found))]
 pub async fn get_range(
     state: web::Data<AppState>,
     query: web::Query<RangeQuery>,
@@ -81,35 +304,69 @@ pub async fn get_range(
     match lookup_range(&state.db, &query.cidr) {
         Ok(result) => {
             metrics.record(&result);
-            HttpResponse::Ok().json(result)
+            lookup_response(&state, &result)
         }
         Err(e) => HttpResponse::BadRequest().json(ErrorResponse::from(e)),
     }
 }
 
 #[post("/v1/ip/batch")]
+#[instrument(level = "debug", skip_all, fields(count = body.ips.len(), found))]
 pub async fn batch_get_ip(
     state: web::Data<AppState>,
+    query: web::Query<BatchIPQuery>,
     body: web::Json<BatchIPRequest>,
 ) -> HttpResponse {
     if body.ips.len() > MAX_BATCH_SIZE {
         return batch_size_error().into();
     }
+    if let Err(message) = check_family(&body.ips, body.family) {
+        return HttpResponse::BadRequest().json(ErrorResponse::new("family_mismatch", message));
+    }
 
     let metrics = LookupMetrics::start_rest();
-    let ip_strs: Vec<&str> = body.ips.iter().map(String::as_str).collect();
+    let db = Arc::clone(&state.db);
+    let options = state.lookup_options;
+    let ips = body.into_inner().ips;
+    let span = Span::current();
+    let lookup = web::block(move || {
+        let _entered = span.enter();
+        let ip_strs: Vec<&str> = ips.iter().map(String::as_str).collect();
+        lookup_ips_batch_with(&db, &ip_strs, &options)
+    })
+    .await;
+
+    let lookup = match lookup {
+        Ok(lookup) => lookup,
+        Err(e) => {
+            return error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal_error",
+                &e.to_string(),
+            )
+        }
+    };
 
-    match lookup_ips_batch(&state.db, &ip_strs) {
+    match lookup {
         Ok(results) => {
-            let any_found = results.iter().any(|r| r.found);
-            metrics.record_batch(any_found);
-            HttpResponse::Ok().json(results)
+            metrics.record_batch(&results);
+            if !query.only_found {
+                return json_with_flag_names(&state.config.flag_names, &results);
+            }
+            let found: Vec<IndexedResult> = results
+                .iter()
+                .enumerate()
+                .filter(|(_, result)| result.found || result.timed_out)
+                .map(|(index, result)| IndexedResult { index, result })
+                .collect();
+            json_with_flag_names(&state.config.flag_names, &found)
         }
         Err(e) => HttpResponse::BadRequest().json(ErrorResponse::from(e)),
     }
 }
 
 #[post("/v1/range/batch")]
+#[instrument(level = "debug", skip_all, fields(count = body.cidrs.len(), found))]
 pub async fn batch_get_range(
     state: web::Data<AppState>,
     body: web::Json<BatchRangeRequest>,
@@ -117,25 +374,515 @@ pub async fn batch_get_range(
     if body.cidrs.len() > MAX_BATCH_SIZE {
         return batch_size_error().into();
     }
+    if let Err(message) = check_family(&body.cidrs, body.family) {
+        return HttpResponse::BadRequest().json(ErrorResponse::new("family_mismatch", message));
+    }
 
     let metrics = LookupMetrics::start_rest();
-    let cidr_strs: Vec<&str> = body.cidrs.iter().map(String::as_str).collect();
+    let db = Arc::clone(&state.db);
+    let options = state.lookup_options;
+    let BatchRangeRequest {
+        cidrs,
+        include_supernets,
+        ..
+    } = body.into_inner();
+    let span = Span::current();
+    let lookup = web::block(move || {
+        let _entered = span.enter();
+        let cidr_strs: Vec<&str> = cidrs.iter().map(String::as_str).collect();
+        if include_supernets {
+            lookup_ranges_batch_with_supernets(&db, &cidr_strs, &options)
+        } else {
+            lookup_ranges_batch_with(&db, &cidr_strs, &options)
+        }
+    })
+    .await;
+
+    let lookup = match lookup {
+        Ok(lookup) => lookup,
+        Err(e) => {
+            return error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal_error",
+                &e.to_string(),
+            )
+        }
+    };
 
-    match lookup_ranges_batch(&state.db, &cidr_strs) {
+    match lookup {
         Ok(results) => {
-            let any_found = results.iter().any(|r| r.found);
-            metrics.record_batch(any_found);
-            HttpResponse::Ok().json(results)
+            metrics.record_batch(&results);
+            json_with_flag_names(&state.config.flag_names, &results)
         }
         Err(e) => HttpResponse::BadRequest().json(ErrorResponse::from(e)),
     }
 }
 
+#[derive(Serialize)]
+pub struct Stats {
+    pub entries: u64,
+    pub last_sync: Option<i64>,
+    pub csv_hash: Option<String>,
+    pub maintenance: bool,
+}
+
+#[get("/v1/stats")]
+pub async fn dataset_stats(state: web::Data<AppState>) -> HttpResponse {
+    let meta = match state.db.get_metadata() {
+        Ok(meta) => meta,
+        Err(e) => {
+            return HttpResponse::InternalServerError()
+                .json(ErrorResponse::new("database_error", e.to_string()))
+        }
+    };
+    let trie = state.db.trie_stats();
+    HttpResponse::Ok().json(Stats {
+        entries: trie.v4.entries + trie.v6.entries,
+        last_sync: meta.last_sync,
+        csv_hash: meta.csv_hash,
+        maintenance: state.maintenance.is_enabled(),
+    })
+}
+
+#[get("/v1/stats/hashes")]
+pub async fn bucket_hashes(state: web::Data<AppState>) -> HttpResponse {
+    match state.db.get_metadata() {
+        Ok(Metadata {
+            bucket_hashes: Some(hashes),
+            ..
+        }) => HttpResponse::Ok().json(hashes),
+        Ok(_) => HttpResponse::NotFound().json(ErrorResponse::new(
+            "not_computed",
+            "Bucket hashes are computed on import when PROXYD_BUCKET_HASHES is enabled",
+        )),
+        Err(e) => HttpResponse::InternalServerError()
+            .json(ErrorResponse::new("database_error", e.to_string())),
+    }
+}
+
+#[get("/v1/stats/prefixes")]
+pub async fn prefix_stats(state: web::Data<AppState>) -> HttpResponse {
+    let db = Arc::clone(&state.db);
+    match web::block(move || db.prefix_stats()).await {
+        Ok(Ok(stats)) => HttpResponse::Ok().json(&*stats),
+        Ok(Err(e)) => HttpResponse::InternalServerError()
+            .json(ErrorResponse::new("database_error", e.to_string())),
+        Err(e) => error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal_error",
+            &e.to_string(),
+        ),
+    }
+}
+
+#[get("/v1/stats/trie")]
+pub async fn trie_stats(state: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(*state.db.trie_stats())
+}
+
+fn invalid_request(err: impl std::fmt::Display + std::fmt::Debug + 'static) -> actix_web::Error {
+    let response =
+        HttpResponse::BadRequest().json(ErrorResponse::new("invalid_request", err.to_string()));
+    InternalError::from_response(err, response).into()
+}
+
 pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.app_data(web::JsonConfig::default().error_handler(|err, _| invalid_request(err)))
+        .app_data(web::QueryConfig::default().error_handler(|err, _| invalid_request(err)))
+        .app_data(web::PathConfig::default().error_handler(|err, _| invalid_request(err)));
     cfg.service(health_check)
+        .service(health_detailed)
+        .service(selftest)
         .service(metrics_endpoint)
+        .service(openapi_spec)
         .service(get_ip)
+        .service(my_ip)
+        .service(explain_ip)
         .service(get_range)
         .service(batch_get_ip)
-        .service(batch_get_range);
+        .service(batch_get_range)
+        .service(dataset_stats)
+        .service(bucket_hashes)
+        .service(prefix_stats)
+        .service(trie_stats)
+        .service(get_record)
+        .service(sync_status)
+        .service(effective_config)
+        .service(import_csv)
+        .service(export_delta)
+        .service(set_maintenance)
+        .service(compact);
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, App};
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_health_reports_draining() {
+        let dir = TempDir::new().unwrap();
+        let state = test_state(Database::open(dir.path()).unwrap());
+        let readiness = state.readiness.clone();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(health_check),
+        )
+        .await;
+
+        let resp =
+            test::call_service(&app, test::TestRequest::get().uri("/health").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        readiness.drain();
+        let resp =
+            test::call_service(&app, test::TestRequest::get().uri("/health").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["status"], "draining");
+    }
+
+    #[actix_web::test]
+    async fn test_lookup_span_records_the_query() {
+        use std::sync::Mutex;
+
+        use tracing_subscriber::fmt::format::FmtSpan;
+
+        #[derive(Clone, Default)]
+        struct Captured(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for Captured {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let dir = TempDir::new().unwrap();
+        let db = Database::open(dir.path()).unwrap();
+        let mut txn = db.begin_write().unwrap();
+        db.insert_record(&mut txn, "1.2.3.4", &Default::default())
+            .unwrap();
+        txn.commit().unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_state(db)))
+                .service(get_ip),
+        )
+        .await;
+
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_span_events(FmtSpan::CLOSE)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _default = tracing::subscriber::set_default(subscriber);
+        let req = test::TestRequest::get().uri("/v1/ip/1.2.3.4").to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("get_ip{ip=1.2.3.4 found=true}"), "{output}");
+    }
+
+    #[actix_web::test]
+    async fn test_metrics_gzip_when_accepted() {
+        use std::io::Read;
+
+        metrics::init_metrics();
+        let dir = TempDir::new().unwrap();
+        let state = test_state(Database::open(dir.path()).unwrap());
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(metrics_endpoint),
+        )
+        .await;
+        let req = |encoding: &str| {
+            test::TestRequest::get()
+                .uri("/metrics")
+                .insert_header((header::ACCEPT_ENCODING, encoding))
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, req("identity")).await;
+        assert!(resp.headers().get(header::CONTENT_ENCODING).is_none());
+        let plain = test::read_body(resp).await;
+        assert!(!plain.is_empty());
+
+        let resp = test::call_service(&app, req("br;q=1.0, gzip;q=0.8")).await;
+        assert_eq!(
+            resp.headers().get(header::CONTENT_ENCODING).unwrap(),
+            "gzip"
+        );
+        assert_eq!(resp.headers().get(header::VARY).unwrap(), "accept-encoding");
+        let compressed = test::read_body(resp).await;
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(&compressed[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert!(decoded.contains("proxyd_build_info"));
+    }
+
+    #[actix_web::test]
+    async fn test_not_found_status_is_configurable() {
+        let dir = TempDir::new().unwrap();
+        let db = Database::open(dir.path()).unwrap();
+        let mut txn = db.begin_write().unwrap();
+        db.insert_record(&mut txn, "1.2.3.4", &Default::default())
+            .unwrap();
+        txn.commit().unwrap();
+        db.rebuild_trie().unwrap();
+
+        let app_with = |not_found_status| {
+            let state = AppState {
+                config: Arc::new(Config {
+                    not_found_status,
+                    ..Config::default()
+                }),
+                ..test_state(Arc::clone(&db))
+            };
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(get_ip)
+                .service(get_range)
+        };
+        let get = |uri: &str| test::TestRequest::get().uri(uri).to_request();
+
+        let app = test::init_service(app_with(200)).await;
+        let resp = test::call_service(&app, get("/v1/ip/5.6.7.8")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let app = test::init_service(app_with(404)).await;
+        let resp = test::call_service(&app, get("/v1/ip/5.6.7.8")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["found"], false);
+
+        let resp = test::call_service(&app, get("/v1/range?cidr=5.6.7.0/24")).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = test::call_service(&app, get("/v1/ip/1.2.3.4")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = test::call_service(&app, get("/v1/ip/not-an-ip")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_errors_share_one_schema() {
+        let dir = TempDir::new().unwrap();
+        let state = test_state(Database::open(dir.path()).unwrap());
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .configure(configure),
+        )
+        .await;
+        let batch = |body: serde_json::Value| {
+            test::TestRequest::post()
+                .uri("/v1/ip/batch")
+                .set_json(body)
+                .to_request()
+        };
+        let cases = [
+            (
+                test::TestRequest::get().uri("/v1/ip/nope").to_request(),
+                "invalid_ip",
+            ),
+            (
+                test::TestRequest::get()
+                    .uri("/v1/range?cidr=10.0.0.0/99")
+                    .to_request(),
+                "invalid_cidr",
+            ),
+            (
+                test::TestRequest::get().uri("/v1/range").to_request(),
+                "invalid_request",
+            ),
+            (
+                batch(serde_json::json!({ "ips": vec!["1.1.1.1"; 1001] })),
+                "batch_too_large",
+            ),
+            (
+                batch(serde_json::json!({ "addresses": [] })),
+                "invalid_request",
+            ),
+        ];
+
+        for (req, code) in cases {
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{code}");
+            let body: serde_json::Value = test::read_body_json(resp).await;
+            assert_eq!(body["error"]["code"], code);
+            assert!(body["error"]["message"].is_string());
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_batch_only_found_keeps_indices() {
+        metrics::init_metrics();
+        let dir = TempDir::new().unwrap();
+        let db = Database::open(dir.path()).unwrap();
+        let mut txn = db.begin_write().unwrap();
+        db.insert_record(&mut txn, "1.2.3.4", &Default::default())
+            .unwrap();
+        db.insert_record(&mut txn, "10.0.0.0/8", &Default::default())
+            .unwrap();
+        txn.commit().unwrap();
+        db.rebuild_trie().unwrap();
+
+        let state = test_state(db);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(batch_get_ip),
+        )
+        .await;
+        let req = |uri: &str| {
+            test::TestRequest::post()
+                .uri(uri)
+                .set_json(serde_json::json!({
+                    "ips": ["5.6.7.8", "1.2.3.4", "9.9.9.9", "10.1.2.3"]
+                }))
+                .to_request()
+        };
+
+        let body: serde_json::Value =
+            test::call_and_read_body_json(&app, req("/v1/ip/batch")).await;
+        assert_eq!(body.as_array().unwrap().len(), 4);
+        assert!(body[0].get("index").is_none());
+
+        let body: serde_json::Value =
+            test::call_and_read_body_json(&app, req("/v1/ip/batch?only_found=true")).await;
+        let results = body.as_array().unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0]["index"], 1);
+        assert_eq!(results[0]["query"], "1.2.3.4");
+        assert_eq!(results[1]["index"], 3);
+        assert_eq!(results[1]["found"], true);
+
+        let exported = metrics::gather_metrics();
+        assert!(exported.contains("proxyd_batch_items_total{result=\"found\"}"));
+        assert!(exported.contains("proxyd_batch_items_total{result=\"not_found\"}"));
+        assert!(exported.contains("proxyd_batch_found_ratio_bucket{le=\"0.5\"}"));
+    }
+
+    #[actix_web::test]
+    async fn test_batch_only_found_keeps_timed_out_entries() {
+        let dir = TempDir::new().unwrap();
+        let state = AppState {
+            lookup_options: LookupOptions {
+                parallel_threshold: 1,
+                batch_soft_deadline: Some(std::time::Duration::ZERO),
+                ..LookupOptions::default()
+            },
+            ..test_state(Database::open(dir.path()).unwrap())
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(batch_get_ip),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/v1/ip/batch?only_found=true")
+            .set_json(serde_json::json!({ "ips": ["5.6.7.8", "1.2.3.4"] }))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let results = body.as_array().unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[1]["index"], 1);
+        assert_eq!(results[1]["timed_out"], true);
+    }
+
+    #[actix_web::test]
+    async fn test_batch_family_rejects_mixed_batch() {
+        let dir = TempDir::new().unwrap();
+        let state = test_state(Database::open(dir.path()).unwrap());
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(batch_get_ip)
+                .service(batch_get_range),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/v1/ip/batch")
+            .set_json(serde_json::json!({
+                "ips": ["1.2.3.4", "2001:db8::1", "5.6.7.8", "::1"],
+                "family": "ipv4"
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"]["code"], "family_mismatch");
+        let message = body["error"]["message"].as_str().unwrap();
+        assert!(message.ends_with("2001:db8::1, ::1"), "{message}");
+
+        let req = test::TestRequest::post()
+            .uri("/v1/range/batch")
+            .set_json(serde_json::json!({
+                "cidrs": ["10.0.0.0/8", "2001:db8::/32"],
+                "family": "ipv6"
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::post()
+            .uri("/v1/ip/batch")
+            .set_json(serde_json::json!({ "ips": ["1.2.3.4", "2001:db8::1"] }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_health_detailed_reports_trie_drift() {
+        let dir = TempDir::new().unwrap();
+        let db = Database::open(dir.path()).unwrap();
+        let state = AppState {
+            config: Arc::new(Config {
+                health_consistency_check: true,
+                ..Config::default()
+            }),
+            ..test_state(Arc::clone(&db))
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(health_detailed),
+        )
+        .await;
+        let req = || {
+            test::TestRequest::get()
+                .uri("/health/detailed")
+                .to_request()
+        };
+
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req()).await;
+        assert_eq!(body["status"], "healthy");
+        assert_eq!(body["trie"]["consistent"], true);
+
+        let mut txn = db.begin_write().unwrap();
+        db.insert_record(&mut txn, "10.0.0.0/8", &Default::default())
+            .unwrap();
+        txn.commit().unwrap();
+
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req()).await;
+        assert_eq!(body["status"], "degraded");
+        assert_eq!(body["trie"]["consistent"], false);
+        assert!(body["suggestion"].is_string());
+    }
 }
@@ -0,0 +1,159 @@
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tracing::info;
+
+use crate::ip::{log_ip, LookupResult};
+
+/// Lookups are logged when a random `u64` falls below this; 0 disables sampling.
+static THRESHOLD: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    static RNG_STATE: Cell<u64> = Cell::new(RandomState::new().hash_one(0u64) | 1);
+}
+
+pub fn set_sample_rate(rate: f64) {
+    let threshold = if rate >= 1.0 {
+        u64::MAX
+    } else {
+        (rate.max(0.0) * u64::MAX as f64) as u64
+    };
+    THRESHOLD.store(threshold, Ordering::Relaxed);
+}
+
+fn next_random() -> u64 {
+    RNG_STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        state.set(x);
+        x
+    })
+}
+
+fn sampled(threshold: u64) -> bool {
+    threshold == u64::MAX || next_random() < threshold
+}
+
+pub fn maybe_log(result: &LookupResult) {
+    let threshold = THRESHOLD.load(Ordering::Relaxed);
+    if threshold != 0 && sampled(threshold) {
+        log(result);
+    }
+}
+
+pub fn maybe_log_batch(results: &[LookupResult]) {
+    let threshold = THRESHOLD.load(Ordering::Relaxed);
+    if threshold == 0 {
+        return;
+    }
+    for result in results.iter().filter(|_| sampled(threshold)) {
+        log(result);
+    }
+}
+
+fn log(result: &LookupResult) {
+    let entries: Vec<_> = result
+        .matched_entries
+        .iter()
+        .map(|e| log_ip(&e.entry))
+        .collect();
+    info!(
+        query = %log_ip(&result.query),
+        found = result.found,
+        flags_mask = result.flags_mask,
+        matched_entries = ?entries,
+        degraded = result.degraded,
+        "Sampled lookup"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    use tracing_subscriber::fmt::format::FmtSpan;
+
+    use super::*;
+    use crate::db::Database;
+    use crate::ip::{lookup_ip_with, set_anonymize_ips, LookupOptions, ReputationFlags};
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Keeps IP anonymization on until dropped, so the global setting does
+    /// not leak into other tests even if the test panics.
+    struct AnonymizeIps;
+
+    impl AnonymizeIps {
+        fn enable() -> Self {
+            set_anonymize_ips(true);
+            Self
+        }
+    }
+
+    impl Drop for AnonymizeIps {
+        fn drop(&mut self) {
+            set_anonymize_ips(false);
+        }
+    }
+
+    #[test]
+    fn test_anonymized_ips_in_log_output() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let db = Database::open(dir.path()).unwrap();
+        let flags = ReputationFlags {
+            proxy: true,
+            ..Default::default()
+        };
+        let mut txn = db.begin_write().unwrap();
+        db.insert_record(&mut txn, "203.0.113.77", &flags).unwrap();
+        txn.commit().unwrap();
+
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_span_events(FmtSpan::CLOSE)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _anonymize = AnonymizeIps::enable();
+        tracing::subscriber::with_default(subscriber, || {
+            let options = LookupOptions::default();
+            log(&lookup_ip_with(&db, "203.0.113.77", &options).unwrap());
+            log(&lookup_ip_with(&db, "2001:db8:1234:5678::1", &options).unwrap());
+        });
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("Sampled lookup"), "{output}");
+        assert!(output.contains("query=203.0.113.0"), "{output}");
+        assert!(output.contains("ip=203.0.113.0"), "{output}");
+        assert!(output.contains("2001:db8:1234::"), "{output}");
+        assert!(!output.contains("203.0.113.77"), "{output}");
+        assert!(!output.contains("5678"), "{output}");
+    }
+
+    #[test]
+    fn test_sampling_rate_bounds() {
+        assert!((0..1000).all(|_| sampled(u64::MAX)));
+
+        let hits = (0..10_000).filter(|_| sampled(u64::MAX / 10)).count();
+        assert!((700..1300).contains(&hits), "{hits}");
+    }
+}
@@ -0,0 +1,243 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use actix_web::http::StatusCode;
+use actix_web::{get, web, HttpResponse};
+use ipnetwork::IpNetwork;
+use serde::Serialize;
+
+use super::auth::error;
+use super::rest::AppState;
+use crate::db::Database;
+use crate::ip::{lookup_ip_with, IpTrie, LookupOptions, LookupResult, ReputationFlags};
+use crate::sync::importer::parse_csv_parallel;
+
+/// Documentation range address that the parse and trie stages round-trip.
+const SENTINEL_IP: &str = "192.0.2.1";
+
+#[derive(Serialize)]
+pub struct Stage {
+    pub name: &'static str,
+    /// `pass`, `fail` or `skipped`.
+    pub status: &'static str,
+    pub duration_us: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct SelfTest {
+    pub status: &'static str,
+    pub stages: Vec<Stage>,
+}
+
+struct Stages(Vec<Stage>);
+
+impl Stages {
+    /// Runs and times one stage, returning its value if it passed.
+    fn run<T>(
+        &mut self,
+        name: &'static str,
+        stage: impl FnOnce() -> Result<T, String>,
+    ) -> Option<T> {
+        let start = Instant::now();
+        let result = stage();
+        let duration_us = start.elapsed().as_micros() as u64;
+        let (status, detail, value) = match result {
+            Ok(value) => ("pass", None, Some(value)),
+            Err(e) => ("fail", Some(e), None),
+        };
+        self.0.push(Stage {
+            name,
+            status,
+            duration_us,
+            detail,
+        });
+        value
+    }
+
+    fn skip(&mut self, name: &'static str, reason: &str) {
+        self.0.push(Stage {
+            name,
+            status: "skipped",
+            duration_us: 0,
+            detail: Some(reason.to_owned()),
+        });
+    }
+}
+
+fn sentinel_flags() -> ReputationFlags {
+    ReputationFlags {
+        tor: true,
+        ..Default::default()
+    }
+}
+
+fn check_parse(ip_column: &str) -> Result<IpNetwork, String> {
+    let csv = format!("{ip_column},tor\n{SENTINEL_IP},true\n");
+    let records = parse_csv_parallel(&csv, ip_column).map_err(|e| e.to_string())?;
+    let [record] = records.as_slice() else {
+        return Err(format!("expected 1 record, parsed {}", records.len()));
+    };
+    if record.ip != SENTINEL_IP || record.flags != sentinel_flags() {
+        return Err(format!("sentinel row parsed as {}", record.ip));
+    }
+    record.ip.parse().map_err(|e| format!("{e}"))
+}
+
+/// Uses a throwaway trie so the real one is never touched.
+fn check_trie(network: IpNetwork) -> Result<(), String> {
+    let mut trie = IpTrie::new();
+    trie.insert(network, sentinel_flags());
+    let matches = trie.find_all_matches(network.ip());
+    match matches.as_slice() {
+        [(found, flags)] if *found == network && *flags == sentinel_flags() => Ok(()),
+        other => Err(format!(
+            "expected the sentinel, trie matched {}",
+            other.len()
+        )),
+    }
+}
+
+fn check_lookup(
+    db: &Arc<Database>,
+    control: (IpNetwork, ReputationFlags),
+    options: &LookupOptions,
+) -> Result<LookupResult, String> {
+    let (network, flags) = control;
+    let ip: IpAddr = network.network();
+    let result = lookup_ip_with(db, &ip.to_string(), options).map_err(|e| e.to_string())?;
+    let matched = result.matched_entries.iter().any(|m| {
+        m.flags == flags
+            && m.entry.parse::<IpNetwork>().is_ok_and(|entry| {
+                entry.network() == network.network() && entry.prefix() == network.prefix()
+            })
+    });
+    if result.found && matched {
+        Ok(result)
+    } else {
+        Err(format!("control entry {network} was not matched"))
+    }
+}
+
+fn check_serialize(result: &LookupResult) -> Result<(), String> {
+    let bytes = serde_json::to_vec(result).map_err(|e| e.to_string())?;
+    let value: serde_json::Value = serde_json::from_slice(&bytes).map_err(|e| e.to_string())?;
+    if value["found"] == result.found && value["query"] == result.query.as_str() {
+        Ok(())
+    } else {
+        Err("serialized result does not round-trip".to_owned())
+    }
+}
+
+/// Read-only against the database: the only write is to a throwaway trie.
+fn run_selftest(db: &Arc<Database>, ip_column: &str, options: &LookupOptions) -> SelfTest {
+    let mut stages = Stages(Vec::new());
+
+    if let Some(network) = stages.run("parse", || check_parse(ip_column)) {
+        stages.run("trie", || check_trie(network));
+    } else {
+        stages.skip("trie", "parse failed");
+    }
+
+    let control = stages.run("database", || db.first_entry().map_err(|e| e.to_string()));
+    let result = match control {
+        Some(Some(control)) => stages.run("lookup", || check_lookup(db, control, options)),
+        Some(None) => {
+            stages.skip("lookup", "database is empty");
+            None
+        }
+        None => {
+            stages.skip("lookup", "database read failed");
+            None
+        }
+    };
+
+    match result {
+        Some(result) => {
+            stages.run("serialize", || check_serialize(&result));
+        }
+        None => stages.skip("serialize", "no lookup result"),
+    }
+
+    let failed = stages.0.iter().any(|stage| stage.status == "fail");
+    SelfTest {
+        status: if failed { "fail" } else { "pass" },
+        stages: stages.0,
+    }
+}
+
+#[get("/health/selftest")]
+pub async fn selftest(state: web::Data<AppState>) -> HttpResponse {
+    let db = Arc::clone(&state.db);
+    let config = Arc::clone(&state.config);
+    let options = state.lookup_options;
+    match web::block(move || run_selftest(&db, &config.ip_column, &options)).await {
+        Ok(report) if report.status == "pass" => HttpResponse::Ok().json(report),
+        Ok(report) => HttpResponse::ServiceUnavailable().json(report),
+        Err(e) => error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal_error",
+            &e.to_string(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::api::rest::test_state;
+
+    #[actix_web::test]
+    async fn test_selftest_reports_each_stage() {
+        let dir = TempDir::new().unwrap();
+        let db = Database::open(dir.path()).unwrap();
+        let state = test_state(Arc::clone(&db));
+        let app =
+            test::init_service(App::new().app_data(web::Data::new(state)).service(selftest)).await;
+        let get = || {
+            test::TestRequest::get()
+                .uri("/health/selftest")
+                .to_request()
+        };
+
+        let body: serde_json::Value = test::call_and_read_body_json(&app, get()).await;
+        assert_eq!(body["status"], "pass");
+        let statuses: Vec<&str> = body["stages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|stage| stage["status"].as_str().unwrap())
+            .collect();
+        assert_eq!(statuses, ["pass", "pass", "pass", "skipped", "skipped"]);
+
+        let flags = ReputationFlags {
+            proxy: true,
+            ..Default::default()
+        };
+        let mut txn = db.begin_write().unwrap();
+        db.insert_record(&mut txn, "10.0.0.0/8", &flags).unwrap();
+        txn.commit().unwrap();
+        let before = format!("{:?}", db.get_metadata().unwrap());
+
+        // LMDB has the entry but the trie was never rebuilt: the lookup misses it
+        let resp = test::call_service(&app, get()).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["stages"][3]["name"], "lookup");
+        assert_eq!(body["stages"][3]["status"], "fail");
+
+        db.rebuild_trie().unwrap();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, get()).await;
+        assert_eq!(body["status"], "pass");
+        assert_eq!(body["stages"][4]["status"], "pass");
+        assert!(body["stages"][3]["duration_us"].is_u64());
+        assert_eq!(db.count_entries().unwrap(), 1);
+        assert_eq!(format!("{:?}", db.get_metadata().unwrap()), before);
+    }
+}
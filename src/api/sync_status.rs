@@ -0,0 +1,229 @@
+use std::time::{Duration, Instant};
+
+use actix_web::http::StatusCode;
+use actix_web::{get, web, HttpResponse};
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use super::auth::error;
+use super::rest::AppState;
+use crate::sync::downloader::fetch_remote_hash;
+
+struct CachedHash {
+    url: String,
+    fetched_at: Instant,
+    result: Result<String, String>,
+}
+
+static REMOTE_HASH: Mutex<Option<CachedHash>> = Mutex::const_new(None);
+
+#[derive(Serialize)]
+pub struct SyncStatus {
+    pub local_hash: Option<String>,
+    pub last_sync: Option<i64>,
+    pub stale_seconds: Option<i64>,
+    /// `ok`, `not_configured` (no `PROXYD_HASH_URL`) or `unavailable`.
+    pub remote_status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote_hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote_error: Option<String>,
+    pub in_sync: Option<bool>,
+}
+
+/// Returns the remote hash, refetching it only once the cached value
+/// (including a failed fetch) is older than `ttl`.
+async fn remote_hash(url: &str, ttl: Duration) -> Result<String, String> {
+    let mut cached = REMOTE_HASH.lock().await;
+    if let Some(entry) = cached
+        .as_ref()
+        .filter(|c| c.url == url && c.fetched_at.elapsed() < ttl)
+    {
+        return entry.result.clone();
+    }
+
+    let result = fetch_remote_hash(url).await.map_err(|e| e.to_string());
+    *cached = Some(CachedHash {
+        url: url.to_owned(),
+        fetched_at: Instant::now(),
+        result: result.clone(),
+    });
+    result
+}
+
+#[get("/v1/sync/status")]
+pub async fn sync_status(state: web::Data<AppState>) -> HttpResponse {
+    let meta = match state.db.get_metadata() {
+        Ok(meta) => meta,
+        Err(e) => {
+            return error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "database_error",
+                &e.to_string(),
+            )
+        }
+    };
+
+    let remote = match &state.config.hash_url {
+        Some(url) => Some(remote_hash(url, state.config.remote_hash_cache).await),
+        None => None,
+    };
+    let (remote_status, remote_hash, remote_error) = match remote {
+        None => ("not_configured", None, None),
+        Some(Ok(hash)) => ("ok", Some(hash), None),
+        Some(Err(e)) => ("unavailable", None, Some(e)),
+    };
+    // A tar.gz source is published with the archive's hash, which is kept
+    // per source next to the hash of the extracted dataset.
+    let in_sync = remote_hash.as_ref().map(|remote| {
+        meta.csv_hash.as_ref() == Some(remote) || meta.source_hashes.values().any(|h| h == remote)
+    });
+
+    HttpResponse::Ok().json(SyncStatus {
+        stale_seconds: meta
+            .last_sync
+            .map(|ts| (chrono::Utc::now().timestamp() - ts).max(0)),
+        local_hash: meta.csv_hash,
+        last_sync: meta.last_sync,
+        remote_status,
+        remote_hash,
+        remote_error,
+        in_sync,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use actix_web::{test, App};
+    use tempfile::TempDir;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::api::rest::test_state;
+
+    use crate::config::Config;
+    use crate::db::{Database, Metadata};
+
+    async fn serve_hash_file(body: &'static str) -> (String, Arc<AtomicUsize>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/proxy_blocks.csv.sha256",
+            listener.local_addr().unwrap()
+        );
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::Relaxed);
+                let mut buf = [0u8; 1024];
+                let _ = socket.read(&mut buf).await;
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+        (url, hits)
+    }
+
+    #[actix_web::test]
+    async fn test_sync_status_compares_cached_remote_hash() {
+        let dir = TempDir::new().unwrap();
+        let db = Database::open(dir.path()).unwrap();
+        let mut txn = db.begin_write().unwrap();
+        db.set_metadata(
+            &mut txn,
+            &Metadata {
+                last_sync: Some(chrono::Utc::now().timestamp() - 30),
+                csv_hash: Some("abc123".to_owned()),
+                ..Metadata::default()
+            },
+        )
+        .unwrap();
+        txn.commit().unwrap();
+
+        let (url, hits) = serve_hash_file("ABC123  proxy_blocks.csv\n").await;
+        let state = |hash_url: Option<String>| AppState {
+            config: Arc::new(Config {
+                hash_url,
+                remote_hash_cache: Duration::from_secs(60),
+                ..Config::default()
+            }),
+            ..test_state(db.clone())
+        };
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state(Some(url))))
+                .service(sync_status),
+        )
+        .await;
+        for _ in 0..2 {
+            let req = test::TestRequest::get().uri("/v1/sync/status").to_request();
+            let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+            assert_eq!(body["remote_status"], "ok");
+            assert_eq!(body["remote_hash"], "abc123");
+            assert_eq!(body["in_sync"], true);
+            assert!(body["stale_seconds"].as_i64().unwrap() >= 30);
+        }
+        assert_eq!(hits.load(Ordering::Relaxed), 1);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state(None)))
+                .service(sync_status),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/v1/sync/status").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["remote_status"], "not_configured");
+        assert_eq!(body["local_hash"], "abc123");
+        assert!(body["in_sync"].is_null());
+    }
+
+    #[actix_web::test]
+    async fn test_sync_status_matches_an_archive_source_hash() {
+        let dir = TempDir::new().unwrap();
+        let db = Database::open(dir.path()).unwrap();
+        let mut txn = db.begin_write().unwrap();
+        db.set_metadata(
+            &mut txn,
+            &Metadata {
+                csv_hash: Some("abc123".to_owned()),
+                source_hashes: [(
+                    "https://feed/proxy_blocks.tar.gz".to_owned(),
+                    "def456".to_owned(),
+                )]
+                .into(),
+                ..Metadata::default()
+            },
+        )
+        .unwrap();
+        txn.commit().unwrap();
+
+        let (url, _) = serve_hash_file("def456  proxy_blocks.tar.gz\n").await;
+        let state = AppState {
+            config: Arc::new(Config {
+                hash_url: Some(url),
+                ..Config::default()
+            }),
+            ..test_state(db)
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(sync_status),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/v1/sync/status").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["remote_hash"], "def456");
+        assert_eq!(body["in_sync"], true);
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info};
+
+use crate::ip::log_ip;
+
+const H2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+const SNIFF_TIMEOUT: Duration = Duration::from_secs(5);
+const SNIFF_RETRY: Duration = Duration::from_millis(5);
+
+/// Client address of each HTTP/1 connection relayed to the REST server, keyed
+/// by the relay's own address as the REST server sees it.
+static RELAYED_PEERS: LazyLock<Mutex<HashMap<SocketAddr, SocketAddr>>> =
+    LazyLock::new(Mutex::default);
+
+/// Returns the client behind a connection the unified port relayed to the
+/// REST server, which otherwise only sees a loopback peer.
+pub fn relayed_peer(rest_peer: SocketAddr) -> Option<SocketAddr> {
+    RELAYED_PEERS.lock().unwrap().get(&rest_peer).copied()
+}
+
+struct RelayedPeer(SocketAddr);
+
+impl RelayedPeer {
+    fn register(relay: SocketAddr, client: SocketAddr) -> Self {
+        RELAYED_PEERS.lock().unwrap().insert(relay, client);
+        Self(relay)
+    }
+}
+
+impl Drop for RelayedPeer {
+    fn drop(&mut self) {
+        RELAYED_PEERS.lock().unwrap().remove(&self.0);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    Http1,
+    Http2,
+}
+
+/// Peeks at the first bytes without consuming them, so the chosen server
+/// still reads the full request.
+async fn sniff(stream: &TcpStream) -> io::Result<Protocol> {
+    let mut buf = [0u8; H2_PREFACE.len()];
+    loop {
+        let n = stream.peek(&mut buf).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if !H2_PREFACE.starts_with(&buf[..n]) {
+            return Ok(Protocol::Http1);
+        }
+        if n == H2_PREFACE.len() {
+            return Ok(Protocol::Http2);
+        }
+        tokio::time::sleep(SNIFF_RETRY).await;
+    }
+}
+
+async fn route(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    rest_addr: SocketAddr,
+    grpc: mpsc::Sender<TcpStream>,
+) -> io::Result<()> {
+    let protocol = tokio::time::timeout(SNIFF_TIMEOUT, sniff(&stream))
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+    match protocol {
+        Protocol::Http2 => {
+            stream.set_nodelay(true)?;
+            grpc.send(stream)
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+        }
+        Protocol::Http1 => {
+            let mut upstream = TcpStream::connect(rest_addr).await?;
+            let _relayed = RelayedPeer::register(upstream.local_addr()?, peer);
+            tokio::io::copy_bidirectional(&mut stream, &mut upstream).await?;
+            Ok(())
+        }
+    }
+}
+
+/// Accepts connections on the unified port, hands HTTP/2 (gRPC) connections to
+/// the tonic server through `grpc` and forwards everything else to `rest_addr`.
+pub async fn serve_unified(
+    listener: TcpListener,
+    rest_addr: SocketAddr,
+    grpc: mpsc::Sender<TcpStream>,
+    cancel: CancellationToken,
+) {
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    let grpc = grpc.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = route(stream, peer, rest_addr, grpc).await {
+                            debug!(
+                                "Unified port connection from {} closed: {}",
+                                log_ip(&peer.ip().to_string()),
+                                e
+                            );
+                        }
+                    });
+                }
+                Err(e) => debug!("Unified port accept failed: {}", e),
+            },
+            () = cancel.cancelled() => break,
+        }
+    }
+    info!("Unified listener stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_unified_port_routes_by_protocol() {
+        let rest = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let rest_addr = rest.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, relay) = rest.accept().await.unwrap();
+            let mut buf = [0u8; 64];
+            let n = socket.read(&mut buf).await.unwrap();
+            assert!(buf[..n].starts_with(b"GET /health"));
+            socket
+                .write_all(relayed_peer(relay).unwrap().to_string().as_bytes())
+                .await
+                .unwrap();
+            socket.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await.unwrap();
+        });
+
+        let unified = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let unified_addr = unified.local_addr().unwrap();
+        let (grpc_tx, mut grpc_rx) = mpsc::channel(1);
+        let cancel = CancellationToken::new();
+        tokio::spawn(serve_unified(unified, rest_addr, grpc_tx, cancel.clone()));
+
+        let mut client = TcpStream::connect(unified_addr).await.unwrap();
+        client
+            .write_all(b"GET /health HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        let client_addr = client.local_addr().unwrap().to_string();
+        assert_eq!(response, format!("{client_addr}HTTP/1.1 200 OK\r\n\r\n"));
+
+        let mut client = TcpStream::connect(unified_addr).await.unwrap();
+        client.write_all(&H2_PREFACE[..10]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        client.write_all(&H2_PREFACE[10..]).await.unwrap();
+        let mut handed_off = grpc_rx.recv().await.unwrap();
+        let mut preface = [0u8; H2_PREFACE.len()];
+        handed_off.read_exact(&mut preface).await.unwrap();
+        assert_eq!(preface, H2_PREFACE);
+
+        cancel.cancel();
+    }
+}
@@ -1,20 +1,174 @@
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
+use ipnetwork::IpNetwork;
+use serde::{Serialize, Serializer};
 use tracing::warn;
 
+use crate::db::DEFAULT_MAX_DBS;
+use crate::ip::{
+    LookupOptions, ReputationFlags, DEFAULT_BATCH_CHUNK_SIZE, DEFAULT_PARALLEL_THRESHOLD, FLAG_BITS,
+};
+use crate::sync::downloader::{DownloadSettings, DEFAULT_USER_AGENT, MAX_RETRY_AFTER_MS};
+use crate::sync::importer::MergePolicy;
+
 pub const REST_PORT: u16 = 7891;
 pub const GRPC_PORT: u16 = 7892;
 pub const SYNC_HOUR_UTC: u8 = 2;
+pub const GRPC_REQUEST_TIMEOUT_MS: u64 = 10_000;
+pub const REST_REQUEST_TIMEOUT_MS: u64 = 5_000;
+pub const SHUTDOWN_TIMEOUT_MS: u64 = 10_000;
+pub const DATA_DIR_MODE: u32 = 0o700;
+pub const IP_COLUMN: &str = "ip";
+pub const IMPORT_MAX_BYTES: usize = 64 * 1024 * 1024;
+pub const IMPORT_CHUNK_ROWS: usize = 50_000;
+pub const EXPORT_MAX_ENTRIES: usize = 1_000_000;
+pub const MAX_DELETE_FRACTION: f64 = 0.5;
+pub const SELF_HEAL_TOLERANCE: f64 = 0.05;
+pub const REMOTE_HASH_CACHE_MS: u64 = 60_000;
+pub const SYNC_FAILURE_THRESHOLD: usize = 3;
+pub const MAX_DATA_AGE_MS: u64 = 3 * 24 * 60 * 60 * 1000;
+pub const DOWNLOAD_CIRCUIT_THRESHOLD: usize = 3;
+pub const DOWNLOAD_CIRCUIT_BACKOFF_MS: u64 = 24 * 60 * 60 * 1000;
+pub const REPLICA_REFRESH_MS: u64 = 30_000;
 pub const CSV_URL: &str =
     "https://github.com/NetworkCats/OpenProxyDB/releases/latest/download/proxy_blocks.csv";
 
-#[derive(Clone)]
+/// Serializes as the effective configuration served by `GET /v1/config`:
+/// durations in milliseconds, and API keys and URL credentials redacted.
+#[derive(Clone, Serialize)]
 pub struct Config {
     pub data_dir: PathBuf,
     pub rest_port: u16,
     pub grpc_port: u16,
+    pub unified_port: Option<u16>,
     pub sync_hour_utc: u8,
-    pub csv_url: String,
+    pub sync_failure_threshold: usize,
+    #[serde(serialize_with = "optional_millis")]
+    pub max_data_age: Option<Duration>,
+    pub download_circuit_threshold: usize,
+    #[serde(serialize_with = "millis")]
+    pub download_circuit_backoff: Duration,
+    #[serde(serialize_with = "redacted_urls")]
+    pub csv_urls: Vec<String>,
+    pub bootstrap_csv: Option<PathBuf>,
+    #[serde(serialize_with = "redacted_optional_url")]
+    pub hash_url: Option<String>,
+    #[serde(serialize_with = "millis")]
+    pub remote_hash_cache: Duration,
+    pub user_agent: String,
+    #[serde(serialize_with = "millis")]
+    pub max_retry_after: Duration,
+    pub ip_column: String,
+    pub merge_policy: MergePolicy,
+    #[serde(serialize_with = "redacted_url_keys")]
+    pub source_flags: HashMap<String, ReputationFlags>,
+    pub aggregate_cidrs: bool,
+    pub require_all_flags: bool,
+    pub flag_names: HashMap<String, String>,
+    pub serve_stale: bool,
+    pub lookup_log_sample_rate: f64,
+    pub anonymize_ips: bool,
+    pub log_spans: bool,
+    pub maintenance: bool,
+    pub bucket_hashes: bool,
+    pub scheduled_compaction: bool,
+    pub store_raw_rows: bool,
+    pub delta_history: usize,
+    pub export_max_entries: usize,
+    pub read_only: bool,
+    #[serde(serialize_with = "millis")]
+    pub replica_refresh: Duration,
+    pub not_found_status: u16,
+    pub health_consistency_check: bool,
+    pub reuse_port: bool,
+    pub trusted_proxies: Vec<IpNetwork>,
+    #[serde(serialize_with = "redacted_secrets")]
+    pub api_keys: Vec<String>,
+    pub import_max_bytes: usize,
+    pub import_chunked_threshold: Option<usize>,
+    pub import_chunk_rows: usize,
+    pub import_parse_threads: Option<usize>,
+    pub max_delete_fraction: f64,
+    pub self_heal_tolerance: f64,
+    pub min_import_records: usize,
+    pub allow_mass_delete: bool,
+    pub rayon_threads: Option<usize>,
+    pub max_concurrent_lookups: Option<usize>,
+    pub max_concurrent_single_lookups: Option<usize>,
+    pub max_concurrent_batch_lookups: Option<usize>,
+    pub batch_parallel_threshold: usize,
+    pub sequential_batch: bool,
+    pub batch_chunk_size: usize,
+    #[serde(serialize_with = "optional_millis")]
+    pub batch_soft_deadline: Option<Duration>,
+    #[serde(serialize_with = "millis")]
+    pub grpc_request_timeout: Duration,
+    #[serde(serialize_with = "millis")]
+    pub rest_request_timeout: Duration,
+    #[serde(serialize_with = "millis")]
+    pub shutdown_grace: Duration,
+    #[serde(serialize_with = "millis")]
+    pub shutdown_timeout: Duration,
+    #[serde(serialize_with = "octal_mode")]
+    pub data_dir_mode: u32,
+    pub lmdb_max_dbs: u32,
+    pub lmdb_warmup: bool,
+    pub cidr_only: bool,
+}
+
+const REDACTED: &str = "redacted";
+
+fn millis<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u128(duration.as_millis())
+}
+
+fn optional_millis<S: Serializer>(
+    duration: &Option<Duration>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    duration.map(|d| d.as_millis()).serialize(serializer)
+}
+
+fn octal_mode<S: Serializer>(mode: &u32, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format!("{mode:04o}"))
+}
+
+/// Hides a URL's password and query string, where feed tokens usually live.
+pub fn redact_url(url: &str) -> String {
+    let Ok(mut parsed) = reqwest::Url::parse(url) else {
+        return url.to_owned();
+    };
+    if parsed.password().is_some() {
+        let _ = parsed.set_password(Some(REDACTED));
+    }
+    if parsed.query().is_some() {
+        parsed.set_query(Some(REDACTED));
+    }
+    parsed.to_string()
+}
+
+fn redacted_urls<S: Serializer>(urls: &[String], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(urls.iter().map(|url| redact_url(url)))
+}
+
+fn redacted_optional_url<S: Serializer>(
+    url: &Option<String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    url.as_deref().map(redact_url).serialize(serializer)
+}
+
+fn redacted_url_keys<S: Serializer>(
+    map: &HashMap<String, ReputationFlags>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(map.iter().map(|(url, flags)| (redact_url(url), flags)))
+}
+
+fn redacted_secrets<S: Serializer>(secrets: &[String], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(secrets.iter().map(|_| REDACTED))
 }
 
 fn parse_port(var: &str, default: u16) -> u16 {
@@ -50,6 +204,227 @@ fn parse_sync_hour(default: u8) -> u8 {
         .unwrap_or(default)
 }
 
+fn parse_list(var: &str) -> Vec<String> {
+    std::env::var(var)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+fn parse_trusted_proxies() -> Vec<IpNetwork> {
+    parse_list("PROXYD_TRUSTED_PROXIES")
+        .into_iter()
+        .filter_map(|entry| {
+            let network = entry.parse::<IpNetwork>().ok();
+            if network.is_none() {
+                warn!(
+                    "PROXYD_TRUSTED_PROXIES entries must be IPs or CIDRs, ignoring {}",
+                    entry
+                );
+            }
+            network
+        })
+        .collect()
+}
+
+fn parse_csv_urls(default: &str) -> Vec<String> {
+    let urls = parse_list("PROXYD_CSV_URL");
+
+    if urls.is_empty() {
+        vec![default.to_owned()]
+    } else {
+        urls
+    }
+}
+
+fn parse_merge_policy(default: MergePolicy) -> MergePolicy {
+    std::env::var("PROXYD_MERGE_POLICY")
+        .ok()
+        .and_then(|s| {
+            MergePolicy::parse(&s).or_else(|| {
+                warn!(
+                    "PROXYD_MERGE_POLICY must be union, intersection or priority, got {}, using default {:?}",
+                    s, default
+                );
+                None
+            })
+        })
+        .unwrap_or(default)
+}
+
+fn parse_not_found_status(default: u16) -> u16 {
+    std::env::var("PROXYD_NOT_FOUND_STATUS")
+        .ok()
+        .and_then(|s| match s.trim() {
+            "200" => Some(200),
+            "404" => Some(404),
+            _ => {
+                warn!(
+                    "PROXYD_NOT_FOUND_STATUS must be 200 or 404, got {}, using default {}",
+                    s, default
+                );
+                None
+            }
+        })
+        .unwrap_or(default)
+}
+
+/// Rejects names that another flag already uses, whether as a new name or
+/// its own, since two flags would then collide in the JSON output.
+fn parse_flag_names() -> HashMap<String, String> {
+    let mut names = HashMap::new();
+    for pair in parse_list("PROXYD_FLAG_NAMES") {
+        let parsed = pair
+            .split_once('=')
+            .map(|(flag, name)| (flag.trim(), name.trim()))
+            .filter(|(flag, name)| FLAG_BITS.contains(flag) && !name.is_empty());
+        let Some((flag, name)) = parsed else {
+            warn!(
+                "PROXYD_FLAG_NAMES entries must look like proxy=isProxy, ignoring {}",
+                pair
+            );
+            continue;
+        };
+        let taken = names.contains_key(flag)
+            || names.values().any(|n: &String| n == name)
+            || FLAG_BITS
+                .iter()
+                .any(|other| *other != flag && *other == name);
+        if taken {
+            warn!(
+                "PROXYD_FLAG_NAMES names must be unique across flags, ignoring {}",
+                pair
+            );
+            continue;
+        }
+        names.insert(flag.to_owned(), name.to_owned());
+    }
+    names
+}
+
+/// Entries look like `https://feed.example/tor.csv=tor|vpn`; the URL must
+/// match a `PROXYD_CSV_URL` entry exactly.
+fn parse_source_flags() -> HashMap<String, ReputationFlags> {
+    parse_list("PROXYD_SOURCE_FLAGS")
+        .into_iter()
+        .filter_map(|entry| {
+            let parsed = entry.rsplit_once('=').and_then(|(url, flags)| {
+                let mask = flags.split('|').try_fold(0u16, |mask, flag| {
+                    let bit = FLAG_BITS.iter().position(|&name| name == flag.trim())?;
+                    Some(mask | (1 << bit))
+                })?;
+                let url = url.trim();
+                (!url.is_empty()).then(|| (url.to_owned(), ReputationFlags::from_mask(mask)))
+            });
+            if parsed.is_none() {
+                warn!(
+                    "PROXYD_SOURCE_FLAGS entries must look like <url>=tor|vpn, ignoring {}",
+                    entry
+                );
+            }
+            parsed
+        })
+        .collect()
+}
+
+fn parse_optional_count(var: &str) -> Option<usize> {
+    let s = std::env::var(var).ok()?;
+    match s.parse::<usize>() {
+        Ok(count) if count > 0 => Some(count),
+        _ => {
+            warn!("{} must be a positive integer, got {}, ignoring", var, s);
+            None
+        }
+    }
+}
+
+fn parse_usize(var: &str, default: usize) -> usize {
+    std::env::var(var)
+        .ok()
+        .and_then(|s| {
+            s.parse().ok().or_else(|| {
+                warn!(
+                    "{} must be a non-negative integer, got {}, using default {}",
+                    var, s, default
+                );
+                None
+            })
+        })
+        .unwrap_or(default)
+}
+
+fn parse_duration_ms(var: &str, default_ms: u64) -> Duration {
+    let ms = std::env::var(var)
+        .ok()
+        .and_then(|s| match s.parse::<u64>() {
+            Ok(ms) if ms > 0 => Some(ms),
+            _ => {
+                warn!(
+                    "{} must be a positive number of milliseconds, got {}, using default {}",
+                    var, s, default_ms
+                );
+                None
+            }
+        })
+        .unwrap_or(default_ms);
+    Duration::from_millis(ms)
+}
+
+fn parse_mode(var: &str, default: u32) -> u32 {
+    std::env::var(var)
+        .ok()
+        .and_then(|s| {
+            let digits = s.trim().trim_start_matches("0o");
+            match u32::from_str_radix(digits, 8) {
+                Ok(mode) if mode <= 0o777 => Some(mode),
+                _ => {
+                    warn!(
+                        "{} must be an octal mode such as 0700, got {}, using default {:o}",
+                        var, s, default
+                    );
+                    None
+                }
+            }
+        })
+        .unwrap_or(default)
+}
+
+fn parse_fraction(var: &str, default: f64) -> f64 {
+    std::env::var(var)
+        .ok()
+        .and_then(|s| match s.trim().parse::<f64>() {
+            Ok(fraction) if (0.0..=1.0).contains(&fraction) => Some(fraction),
+            _ => {
+                warn!(
+                    "{} must be a fraction between 0 and 1, got {}, using default {}",
+                    var, s, default
+                );
+                None
+            }
+        })
+        .unwrap_or(default)
+}
+
+fn parse_bool(var: &str, default: bool) -> bool {
+    std::env::var(var)
+        .ok()
+        .and_then(|s| match s.trim().to_lowercase().as_str() {
+            "true" | "1" | "yes" => Some(true),
+            "false" | "0" | "no" => Some(false),
+            _ => {
+                warn!(
+                    "{} must be true or false, got {}, using default {}",
+                    var, s, default
+                );
+                None
+            }
+        })
+        .unwrap_or(default)
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self {
@@ -58,13 +433,145 @@ impl Default for Config {
             ),
             rest_port: parse_port("PROXYD_REST_PORT", REST_PORT),
             grpc_port: parse_port("PROXYD_GRPC_PORT", GRPC_PORT),
+            unified_port: parse_optional_count("PROXYD_UNIFIED_PORT")
+                .and_then(|port| u16::try_from(port).ok()),
             sync_hour_utc: parse_sync_hour(SYNC_HOUR_UTC),
-            csv_url: std::env::var("PROXYD_CSV_URL").unwrap_or_else(|_| CSV_URL.to_string()),
+            sync_failure_threshold: parse_usize(
+                "PROXYD_SYNC_FAILURE_THRESHOLD",
+                SYNC_FAILURE_THRESHOLD,
+            ),
+            max_data_age: Some(parse_usize(
+                "PROXYD_MAX_DATA_AGE_MS",
+                MAX_DATA_AGE_MS as usize,
+            ))
+            .filter(|&ms| ms > 0)
+            .map(|ms| Duration::from_millis(ms as u64)),
+            download_circuit_threshold: parse_usize(
+                "PROXYD_DOWNLOAD_CIRCUIT_THRESHOLD",
+                DOWNLOAD_CIRCUIT_THRESHOLD,
+            ),
+            download_circuit_backoff: parse_duration_ms(
+                "PROXYD_DOWNLOAD_CIRCUIT_BACKOFF_MS",
+                DOWNLOAD_CIRCUIT_BACKOFF_MS,
+            ),
+            csv_urls: parse_csv_urls(CSV_URL),
+            bootstrap_csv: std::env::var("PROXYD_BOOTSTRAP_CSV")
+                .ok()
+                .filter(|path| !path.trim().is_empty())
+                .map(PathBuf::from),
+            hash_url: std::env::var("PROXYD_HASH_URL")
+                .ok()
+                .filter(|url| !url.trim().is_empty()),
+            remote_hash_cache: parse_duration_ms(
+                "PROXYD_REMOTE_HASH_CACHE_MS",
+                REMOTE_HASH_CACHE_MS,
+            ),
+            user_agent: std::env::var("PROXYD_USER_AGENT")
+                .ok()
+                .filter(|ua| !ua.trim().is_empty())
+                .unwrap_or_else(|| DEFAULT_USER_AGENT.to_owned()),
+            max_retry_after: parse_duration_ms("PROXYD_MAX_RETRY_AFTER_MS", MAX_RETRY_AFTER_MS),
+            ip_column: std::env::var("PROXYD_IP_COLUMN").unwrap_or_else(|_| IP_COLUMN.to_string()),
+            merge_policy: parse_merge_policy(MergePolicy::Union),
+            source_flags: parse_source_flags(),
+            aggregate_cidrs: parse_bool("PROXYD_AGGREGATE_CIDRS", false),
+            require_all_flags: parse_bool("PROXYD_REQUIRE_ALL_FLAGS", false),
+            flag_names: parse_flag_names(),
+            serve_stale: parse_bool("PROXYD_SERVE_STALE", false),
+            lookup_log_sample_rate: parse_fraction("PROXYD_LOOKUP_LOG_SAMPLE_RATE", 0.0),
+            anonymize_ips: parse_bool("PROXYD_ANONYMIZE_IPS", false),
+            log_spans: parse_bool("PROXYD_LOG_SPANS", false),
+            maintenance: parse_bool("PROXYD_MAINTENANCE", false),
+            bucket_hashes: parse_bool("PROXYD_BUCKET_HASHES", false),
+            scheduled_compaction: parse_bool("PROXYD_SCHEDULED_COMPACTION", false),
+            store_raw_rows: parse_bool("PROXYD_STORE_RAW_ROWS", false),
+            delta_history: parse_usize("PROXYD_DELTA_HISTORY", 0),
+            export_max_entries: parse_optional_count("PROXYD_EXPORT_MAX_ENTRIES")
+                .unwrap_or(EXPORT_MAX_ENTRIES),
+            read_only: parse_bool("PROXYD_READ_ONLY", false),
+            replica_refresh: parse_duration_ms("PROXYD_REPLICA_REFRESH_MS", REPLICA_REFRESH_MS),
+            not_found_status: parse_not_found_status(200),
+            health_consistency_check: parse_bool("PROXYD_HEALTH_CONSISTENCY_CHECK", false),
+            reuse_port: parse_bool("PROXYD_REUSEPORT", false),
+            trusted_proxies: parse_trusted_proxies(),
+            api_keys: parse_list("PROXYD_API_KEYS"),
+            import_max_bytes: parse_usize("PROXYD_IMPORT_MAX_BYTES", IMPORT_MAX_BYTES),
+            import_chunked_threshold: parse_optional_count("PROXYD_IMPORT_CHUNKED_THRESHOLD_BYTES"),
+            import_chunk_rows: parse_usize("PROXYD_IMPORT_CHUNK_ROWS", IMPORT_CHUNK_ROWS),
+            import_parse_threads: parse_optional_count("PROXYD_IMPORT_PARSE_THREADS"),
+            max_delete_fraction: parse_fraction("PROXYD_MAX_DELETE_FRACTION", MAX_DELETE_FRACTION),
+            self_heal_tolerance: parse_fraction("PROXYD_SELF_HEAL_TOLERANCE", SELF_HEAL_TOLERANCE),
+            min_import_records: parse_usize("PROXYD_MIN_IMPORT_RECORDS", 1),
+            allow_mass_delete: parse_bool("PROXYD_ALLOW_MASS_DELETE", false),
+            rayon_threads: parse_optional_count("PROXYD_RAYON_THREADS"),
+            max_concurrent_lookups: parse_optional_count("PROXYD_MAX_CONCURRENT_LOOKUPS"),
+            max_concurrent_single_lookups: parse_optional_count(
+                "PROXYD_MAX_CONCURRENT_SINGLE_LOOKUPS",
+            ),
+            max_concurrent_batch_lookups: parse_optional_count(
+                "PROXYD_MAX_CONCURRENT_BATCH_LOOKUPS",
+            ),
+            batch_parallel_threshold: parse_usize(
+                "PROXYD_BATCH_PARALLEL_THRESHOLD",
+                DEFAULT_PARALLEL_THRESHOLD,
+            ),
+            sequential_batch: parse_bool("PROXYD_SEQUENTIAL_BATCH", false),
+            batch_chunk_size: parse_optional_count("PROXYD_BATCH_CHUNK_SIZE")
+                .unwrap_or(DEFAULT_BATCH_CHUNK_SIZE),
+            batch_soft_deadline: parse_optional_count("PROXYD_BATCH_SOFT_DEADLINE_MS")
+                .map(|ms| Duration::from_millis(ms as u64)),
+            grpc_request_timeout: parse_duration_ms(
+                "PROXYD_GRPC_TIMEOUT_MS",
+                GRPC_REQUEST_TIMEOUT_MS,
+            ),
+            rest_request_timeout: parse_duration_ms(
+                "PROXYD_REST_TIMEOUT_MS",
+                REST_REQUEST_TIMEOUT_MS,
+            ),
+            shutdown_grace: parse_duration_ms("PROXYD_SHUTDOWN_GRACE_MS", 0),
+            shutdown_timeout: parse_duration_ms("PROXYD_SHUTDOWN_TIMEOUT_MS", SHUTDOWN_TIMEOUT_MS),
+            data_dir_mode: parse_mode("PROXYD_DATA_DIR_MODE", DATA_DIR_MODE),
+            lmdb_max_dbs: u32::try_from(parse_usize(
+                "PROXYD_LMDB_MAX_DBS",
+                DEFAULT_MAX_DBS as usize,
+            ))
+            .unwrap_or(DEFAULT_MAX_DBS),
+            lmdb_warmup: parse_bool("PROXYD_LMDB_WARMUP", false),
+            cidr_only: parse_bool("PROXYD_CIDR_ONLY", false),
         }
     }
 }
 
 impl Config {
+    pub fn lookup_options(&self) -> LookupOptions {
+        LookupOptions {
+            serve_stale: self.serve_stale,
+            parallel_threshold: self.batch_parallel_threshold,
+            sequential: self.sequential_batch,
+            batch_chunk_size: self.batch_chunk_size,
+            batch_soft_deadline: self.batch_soft_deadline,
+        }
+    }
+
+    #[cfg(unix)]
+    pub fn restrict_permissions(&self, dir: &Path) -> std::io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        std::fs::set_permissions(dir, std::fs::Permissions::from_mode(self.data_dir_mode))
+    }
+
+    #[cfg(not(unix))]
+    pub fn restrict_permissions(&self, _dir: &Path) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    pub fn download_settings(&self) -> DownloadSettings {
+        DownloadSettings {
+            user_agent: self.user_agent.clone(),
+            max_retry_after: self.max_retry_after,
+        }
+    }
+
     pub fn db_path(&self) -> PathBuf {
         self.data_dir.join("lmdb")
     }
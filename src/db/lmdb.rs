@@ -1,16 +1,21 @@
-use std::net::IpAddr;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, TryLockError};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 
-use arc_swap::ArcSwap;
-use heed::types::{Bytes, SerdeBincode};
-use heed::{Database as HeedDb, Env, EnvOpenOptions, RwTxn};
+use arc_swap::{ArcSwap, ArcSwapOption};
+use bincode::Options;
+use heed::types::{Bytes, SerdeBincode, Str};
+use heed::{CompactionOption, Database as HeedDb, Env, EnvFlags, EnvOpenOptions, RoTxn, RwTxn};
 use ipnetwork::IpNetwork;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use thiserror::Error;
-use tracing::warn;
+use tracing::{info, warn};
 
-use crate::ip::{IpTrie, MatchVec, ReputationFlags};
+use crate::ip::{IpTrie, MatchVec, ReputationFlags, TrieStats, FLAG_BITS};
 
 #[derive(Error, Debug)]
 pub enum DbError {
@@ -18,32 +23,197 @@ pub enum DbError {
     Heed(#[from] heed::Error),
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
+    #[error("Missing LMDB table: {0}")]
+    MissingTable(&'static str),
+    #[error("Unreadable metadata: {0}")]
+    Metadata(String),
 }
 
+/// Stored with bincode, which has no field names or defaults: any change to
+/// the fields needs a new `METADATA_VERSION` and a decoder for the old layout.
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Metadata {
     pub last_sync: Option<i64>,
     pub csv_hash: Option<String>,
     pub record_count: u64,
+    pub source_hashes: BTreeMap<String, String>,
+    pub last_modified: BTreeMap<String, String>,
+    pub bucket_hashes: Option<BucketHashes>,
+    /// Set by the first commit of an import and cleared by its last, so
+    /// read-only replicas can tell a half-applied import apart.
+    pub import_in_progress: bool,
 }
 
+/// Key of the versioned metadata: a version byte followed by the bincode body.
+const METADATA_KEY: &[u8] = b"metadata";
+/// Key of the untagged metadata written by earlier versions.
+const LEGACY_METADATA_KEY: &[u8] = b"meta";
+const METADATA_VERSION: u8 = 1;
+
+fn bincode_options() -> impl bincode::Options + Copy {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+}
+
+fn encode_metadata(meta: &Metadata) -> Result<Vec<u8>, DbError> {
+    let mut bytes = vec![METADATA_VERSION];
+    bincode_options()
+        .serialize_into(&mut bytes, meta)
+        .map_err(|e| DbError::Metadata(e.to_string()))?;
+    Ok(bytes)
+}
+
+fn decode_metadata(bytes: &[u8]) -> Result<Metadata, DbError> {
+    match bytes.split_first() {
+        Some((&METADATA_VERSION, body)) => bincode_options()
+            .deserialize(body)
+            .map_err(|e| DbError::Metadata(e.to_string())),
+        Some((version, _)) => Err(DbError::Metadata(format!(
+            "unknown metadata version {version}"
+        ))),
+        None => Err(DbError::Metadata("empty metadata".to_owned())),
+    }
+}
+
+/// Untagged layouts only ever grew by appending fields, so an old record is
+/// the current fields read in order until the bytes run out.
+fn decode_legacy_metadata(mut bytes: &[u8]) -> Result<Metadata, DbError> {
+    let options = bincode_options();
+    let bytes = &mut bytes;
+    let err = |e: bincode::Error| DbError::Metadata(e.to_string());
+    let mut meta = Metadata {
+        last_sync: options.deserialize_from(&mut *bytes).map_err(err)?,
+        csv_hash: options.deserialize_from(&mut *bytes).map_err(err)?,
+        record_count: options.deserialize_from(&mut *bytes).map_err(err)?,
+        ..Metadata::default()
+    };
+    if !bytes.is_empty() {
+        meta.source_hashes = options.deserialize_from(&mut *bytes).map_err(err)?;
+    }
+    if !bytes.is_empty() {
+        meta.last_modified = options.deserialize_from(&mut *bytes).map_err(err)?;
+    }
+    if !bytes.is_empty() {
+        meta.bucket_hashes = options.deserialize_from(&mut *bytes).map_err(err)?;
+    }
+    if !bytes.is_empty() {
+        meta.import_in_progress = options.deserialize_from(&mut *bytes).map_err(err)?;
+    }
+    if bytes.is_empty() {
+        Ok(meta)
+    } else {
+        Err(DbError::Metadata(format!(
+            "{} unexpected trailing bytes",
+            bytes.len()
+        )))
+    }
+}
+
+impl Metadata {
+    fn same_dataset(&self, other: &Self) -> bool {
+        self.csv_hash == other.csv_hash
+            && self.last_sync == other.last_sync
+            && self.record_count == other.record_count
+    }
+}
+
+/// SHA-256 hashes of the stored entries grouped by the /8 (IPv4) or /16 (IPv6)
+/// containing each entry's network address, so replicas can compare subsets.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BucketHashes {
+    /// Hash over every `"<bucket> <hash>\n"` line, in bucket order.
+    pub root: String,
+    pub buckets: BTreeMap<String, String>,
+}
+
+/// File sizes around a compaction, in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Compaction {
+    pub before_bytes: u64,
+    pub after_bytes: u64,
+    /// Always set: LMDB cannot shrink a file it has open, so the copy only
+    /// replaces it on the writer's next start, and a write before then
+    /// discards it.
+    pub applies_on_restart: bool,
+}
+
+/// A compacted copy of `data.mdb`, swapped in by the next [`Database::open`].
+/// Any write after it was staged deletes it.
+const STAGED_COMPACTION: &str = "data.mdb.compacted";
+
+/// Locked shared by every read-only process while it has the environment
+/// open. The writer swaps a staged compaction in only while it can lock it
+/// exclusively, so the file is never replaced under a running replica.
+const READERS_LOCK: &str = "data.mdb.readers";
+
+fn open_readers_lock(path: &Path) -> Result<File, DbError> {
+    Ok(std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path.join(READERS_LOCK))?)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct TrieConsistency {
+    pub trie_networks: u64,
+    pub stored_networks: u64,
+    /// Stored keys the last rebuild skipped; they account for the difference
+    /// rather than counting as drift.
+    pub malformed_keys: u64,
+    pub consistent: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct PrefixStats {
+    pub v4: BTreeMap<u8, u64>,
+    pub v6: BTreeMap<u8, u64>,
+}
+
+pub const DEFAULT_MAX_DBS: u32 = 16;
+/// Tables added after the first release, opened by name through
+/// [`Database::open_or_create_table`]. Replicas of an older writer run
+/// without them.
+const OPTIONAL_TABLES: [&str; 3] = ["notes", "raw_rows", "sources"];
+const TABLE_COUNT: u32 = 5 + OPTIONAL_TABLES.len() as u32;
+
 pub struct Database {
     env: Env,
     ip_v4: HeedDb<Bytes, SerdeBincode<ReputationFlags>>,
     ip_v6: HeedDb<Bytes, SerdeBincode<ReputationFlags>>,
     cidr_v4: HeedDb<Bytes, SerdeBincode<ReputationFlags>>,
     cidr_v6: HeedDb<Bytes, SerdeBincode<ReputationFlags>>,
-    metadata: HeedDb<Bytes, SerdeBincode<Metadata>>,
+    metadata: HeedDb<Bytes, Bytes>,
+    notes: Option<HeedDb<Bytes, Str>>,
+    raw_rows: Option<HeedDb<Bytes, Str>>,
+    sources: Option<HeedDb<Bytes, Str>>,
+    has_notes: AtomicBool,
     cidr_trie: ArcSwap<IpTrie>,
+    dataset_hash: ArcSwapOption<String>,
+    malformed_keys: AtomicU64,
+    compaction_staged: AtomicBool,
+    cidr_only: AtomicBool,
+    /// Held for as long as a read-only instance is open; see [`READERS_LOCK`].
+    _readers_lock: Option<File>,
+    trie_generation: AtomicU64,
+    prefix_stats: ArcSwapOption<(u64, Arc<PrefixStats>)>,
+    trie_stats: ArcSwap<TrieStats>,
 }
 
 impl Database {
     pub fn open(path: &Path) -> Result<Arc<Self>, DbError> {
+        Self::open_with_max_dbs(path, DEFAULT_MAX_DBS)
+    }
+
+    pub fn open_with_max_dbs(path: &Path, max_dbs: u32) -> Result<Arc<Self>, DbError> {
         std::fs::create_dir_all(path)?;
+        Self::apply_staged_compaction(path)?;
 
         let env = unsafe {
             EnvOpenOptions::new()
-                .max_dbs(5)
+                .max_dbs(max_dbs.max(TABLE_COUNT))
                 .map_size(1024 * 1024 * 1024)
                 .open(path)?
         };
@@ -55,7 +225,136 @@ impl Database {
         let cidr_v6 = env.create_database(&mut wtxn, Some("cidr_v6"))?;
         let metadata = env.create_database(&mut wtxn, Some("metadata"))?;
         wtxn.commit()?;
+        let optional = Self::open_optional_tables(&env, true)?;
+
+        let db = Self::load(
+            env,
+            [ip_v4, ip_v6, cidr_v4, cidr_v6],
+            metadata,
+            optional,
+            None,
+        )?;
+        db.migrate_legacy_metadata()?;
+        Ok(db)
+    }
+
+    /// Rewrites untagged metadata from an earlier version under the versioned
+    /// key. Read-only opens decode the old record in place instead.
+    fn migrate_legacy_metadata(&self) -> Result<(), DbError> {
+        let mut txn = self.begin_write()?;
+        if self.metadata.get(&txn, METADATA_KEY)?.is_some() {
+            return Ok(());
+        }
+        let Some(legacy) = self.metadata.get(&txn, LEGACY_METADATA_KEY)? else {
+            return Ok(());
+        };
+        let meta = decode_legacy_metadata(legacy)?;
+        self.set_metadata(&mut txn, &meta)?;
+        txn.commit()?;
+        info!("Migrated metadata to version {}", METADATA_VERSION);
+        Ok(())
+    }
+
+    pub fn open_read_only(path: &Path) -> Result<Arc<Self>, DbError> {
+        Self::open_read_only_with_max_dbs(path, DEFAULT_MAX_DBS)
+    }
+
+    pub fn open_read_only_with_max_dbs(path: &Path, max_dbs: u32) -> Result<Arc<Self>, DbError> {
+        // Waits out a writer swapping in a compacted file.
+        let readers_lock = open_readers_lock(path)?;
+        readers_lock.lock_shared()?;
+
+        let env = unsafe {
+            EnvOpenOptions::new()
+                .max_dbs(max_dbs.max(TABLE_COUNT))
+                .map_size(1024 * 1024 * 1024)
+                .flags(EnvFlags::READ_ONLY)
+                .open(path)?
+        };
+
+        let rtxn = env.read_txn()?;
+        let ip_v4 = Self::open_table(&env, &rtxn, "ip_v4")?;
+        let ip_v6 = Self::open_table(&env, &rtxn, "ip_v6")?;
+        let cidr_v4 = Self::open_table(&env, &rtxn, "cidr_v4")?;
+        let cidr_v6 = Self::open_table(&env, &rtxn, "cidr_v6")?;
+        let metadata = Self::open_table(&env, &rtxn, "metadata")?;
+        rtxn.commit()?;
+        let optional = Self::open_optional_tables(&env, false)?;
+
+        Self::load(
+            env,
+            [ip_v4, ip_v6, cidr_v4, cidr_v6],
+            metadata,
+            optional,
+            Some(readers_lock),
+        )
+    }
+
+    pub fn open_or_create_table<KC: 'static, DC: 'static>(
+        &self,
+        name: &str,
+    ) -> Result<HeedDb<KC, DC>, DbError> {
+        Self::open_or_create(&self.env, name)
+    }
+
+    fn open_or_create<KC: 'static, DC: 'static>(
+        env: &Env,
+        name: &str,
+    ) -> Result<HeedDb<KC, DC>, DbError> {
+        let rtxn = env.read_txn()?;
+        let existing = env.open_database(&rtxn, Some(name))?;
+        rtxn.commit()?;
+        if let Some(table) = existing {
+            return Ok(table);
+        }
+
+        // A staged compaction may lack the new table; it is created again
+        // on the next open, and writing to it discards the staged copy.
+        let mut wtxn = env.write_txn()?;
+        let table = env.create_database(&mut wtxn, Some(name))?;
+        wtxn.commit()?;
+        Ok(table)
+    }
+
+    /// Opens the [`OPTIONAL_TABLES`], creating the missing ones if `create`.
+    fn open_optional_tables(
+        env: &Env,
+        create: bool,
+    ) -> Result<[Option<HeedDb<Bytes, Str>>; OPTIONAL_TABLES.len()], DbError> {
+        let mut tables = [None; OPTIONAL_TABLES.len()];
+        for (table, name) in tables.iter_mut().zip(OPTIONAL_TABLES) {
+            *table = if create {
+                Some(Self::open_or_create(env, name)?)
+            } else {
+                let rtxn = env.read_txn()?;
+                let existing = env.open_database(&rtxn, Some(name))?;
+                rtxn.commit()?;
+                existing
+            };
+        }
+        Ok(tables)
+    }
 
+    fn open_table<T: 'static>(
+        env: &Env,
+        rtxn: &RoTxn,
+        name: &'static str,
+    ) -> Result<HeedDb<Bytes, T>, DbError> {
+        env.open_database(rtxn, Some(name))?
+            .ok_or(DbError::MissingTable(name))
+    }
+
+    fn load(
+        env: Env,
+        [ip_v4, ip_v6, cidr_v4, cidr_v6]: [HeedDb<Bytes, SerdeBincode<ReputationFlags>>; 4],
+        metadata: HeedDb<Bytes, Bytes>,
+        [notes, raw_rows, sources]: [Option<HeedDb<Bytes, Str>>; 3],
+        readers_lock: Option<File>,
+    ) -> Result<Arc<Self>, DbError> {
+        let has_notes = match notes {
+            Some(notes) => !notes.is_empty(&env.read_txn()?)?,
+            None => false,
+        };
         let db = Arc::new(Self {
             env,
             ip_v4,
@@ -63,46 +362,322 @@ impl Database {
             cidr_v4,
             cidr_v6,
             metadata,
+            notes,
+            raw_rows,
+            sources,
+            has_notes: AtomicBool::new(has_notes),
             cidr_trie: ArcSwap::from_pointee(IpTrie::new()),
+            dataset_hash: ArcSwapOption::empty(),
+            malformed_keys: AtomicU64::new(0),
+            compaction_staged: AtomicBool::new(false),
+            cidr_only: AtomicBool::new(false),
+            _readers_lock: readers_lock,
+            trie_generation: AtomicU64::new(0),
+            prefix_stats: ArcSwapOption::empty(),
+            trie_stats: ArcSwap::from_pointee(TrieStats::default()),
         });
 
         db.rebuild_trie()?;
+        db.set_dataset_hash(db.get_metadata()?.csv_hash);
 
         Ok(db)
     }
 
-    pub fn rebuild_trie(&self) -> Result<(), DbError> {
+    /// Swaps a staged compacted copy in before the environment is opened.
+    /// Skipped while this process still has the environment open, since heed
+    /// would hand back the existing handle on the replaced file. While read
+    /// replicas have it open the copy is discarded instead: the writer's next
+    /// commit would make it stale anyway.
+    fn apply_staged_compaction(path: &Path) -> Result<(), DbError> {
+        let staged = path.join(STAGED_COMPACTION);
+        if !staged.is_file() || heed::env_closing_event(path.canonicalize()?).is_some() {
+            return Ok(());
+        }
+        let readers_lock = open_readers_lock(path)?;
+        match readers_lock.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                warn!("Read replicas have the database open, discarding the staged compaction; stop them before restarting to apply one");
+                std::fs::remove_file(&staged)?;
+                return Ok(());
+            }
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+        let data = path.join("data.mdb");
+        let before = std::fs::metadata(&data).map(|m| m.len()).unwrap_or(0);
+        let after = std::fs::metadata(&staged)?.len();
+        std::fs::rename(&staged, &data)?;
+        info!("Applied staged compaction: {} -> {} bytes", before, after);
+        Ok(())
+    }
+
+    /// Writes a compacted copy of the environment next to it, which the next
+    /// open swaps in; LMDB never shrinks a file it has open. Writers are held
+    /// off while copying, so the copy is the latest committed state.
+    pub fn stage_compaction(&self) -> Result<Compaction, DbError> {
+        let txn = self.begin_write()?;
+        let dir = self.env.path();
+        let temp_path = dir.join("data.mdb.compacting");
+        if temp_path.exists() {
+            std::fs::remove_file(&temp_path)?;
+        }
+
+        let before_bytes = self.env.real_disk_size()?;
+        let copy = self
+            .env
+            .copy_to_file(&temp_path, CompactionOption::Enabled)?;
+        copy.sync_all()?;
+        let after_bytes = copy.metadata()?.len();
+        std::fs::rename(&temp_path, dir.join(STAGED_COMPACTION))?;
+        self.compaction_staged.store(true, Ordering::Relaxed);
+        txn.abort();
+
+        Ok(Compaction {
+            before_bytes,
+            after_bytes,
+            applies_on_restart: true,
+        })
+    }
+
+    pub fn exists(path: &Path) -> bool {
+        path.join("data.mdb").is_file()
+    }
+
+    pub fn rebuild_trie(&self) -> Result<u64, DbError> {
+        let (trie, malformed) = self.build_trie()?;
+        self.install_trie(trie, malformed);
+        Ok(malformed)
+    }
+
+    /// Scans the CIDR tables into a new trie without installing it. The read
+    /// transaction is opened and closed within this call, on the caller's
+    /// thread, as LMDB requires.
+    fn build_trie(&self) -> Result<(IpTrie, u64), DbError> {
         let rtxn = self.env.read_txn()?;
-        let mut trie = IpTrie::new();
+        self.build_trie_in(&rtxn)
+    }
 
-        for result in self.cidr_v4.iter(&rtxn)? {
-            let (key, flags) = result?;
-            if let Some(network) = key_to_cidr(key) {
-                trie.insert(network, flags);
+    fn build_trie_in(&self, rtxn: &RoTxn) -> Result<(IpTrie, u64), DbError> {
+        let cidrs = self.cidr_v4.len(rtxn)? + self.cidr_v6.len(rtxn)?;
+        let mut trie = IpTrie::with_capacity(usize::try_from(cidrs).unwrap_or(0));
+        let mut malformed = 0u64;
+
+        for table in [&self.cidr_v4, &self.cidr_v6] {
+            for result in table.iter(rtxn)? {
+                let (key, flags) = result?;
+                if let Some(network) = key_to_cidr(key) {
+                    trie.insert(network, flags);
+                } else {
+                    warn!("Skipping malformed CIDR key: {}", hex::encode(key));
+                    malformed += 1;
+                }
             }
         }
 
-        for result in self.cidr_v6.iter(&rtxn)? {
-            let (key, flags) = result?;
-            if let Some(network) = key_to_cidr(key) {
-                trie.insert(network, flags);
-            }
+        if malformed > 0 {
+            warn!("Trie rebuild skipped {} malformed CIDR keys", malformed);
+        }
+        Ok((trie, malformed))
+    }
+
+    /// Installs a trie built from every stored CIDR, `malformed` of which
+    /// could not be decoded.
+    pub fn install_trie(&self, trie: IpTrie, malformed: u64) {
+        self.swap_trie(trie);
+        self.malformed_keys.store(malformed, Ordering::Relaxed);
+    }
+
+    /// Picks up another process's writes on a read-only replica. Rebuilds
+    /// the trie when the metadata shows a dataset other than `seen`, reading
+    /// the metadata and the CIDRs from one snapshot. Returns `None` when
+    /// nothing changed or the writer is still part-way through an import.
+    pub fn reload_if_changed(&self, seen: Option<&Metadata>) -> Result<Option<Metadata>, DbError> {
+        let rtxn = self.env.read_txn()?;
+        let meta = self.read_metadata(&rtxn)?;
+        if meta.import_in_progress || seen.is_some_and(|seen| seen.same_dataset(&meta)) {
+            return Ok(None);
         }
 
-        self.cidr_trie.store(Arc::new(trie));
-        Ok(())
+        let (trie, malformed) = self.build_trie_in(&rtxn)?;
+        self.refresh_has_notes_in(&rtxn)?;
+        drop(rtxn);
+
+        self.install_trie(trie, malformed);
+        self.set_dataset_hash(meta.csv_hash.clone());
+        Ok(Some(meta))
+    }
+
+    pub fn malformed_key_count(&self) -> u64 {
+        self.malformed_keys.load(Ordering::Relaxed)
+    }
+
+    pub fn trie(&self) -> Arc<IpTrie> {
+        self.cidr_trie.load_full()
     }
 
     pub fn swap_trie(&self, new_trie: IpTrie) {
+        self.trie_stats.store(Arc::new(new_trie.stats()));
         self.cidr_trie.store(Arc::new(new_trie));
+        self.trie_generation.fetch_add(1, Ordering::Release);
+    }
+
+    /// Shape of the current trie, computed once whenever a new trie is installed.
+    pub fn trie_stats(&self) -> Arc<TrieStats> {
+        self.trie_stats.load_full()
+    }
+
+    pub fn trie_consistency(&self) -> Result<TrieConsistency, DbError> {
+        let rtxn = self.env.read_txn()?;
+        let stored_networks = self.cidr_v4.len(&rtxn)? + self.cidr_v6.len(&rtxn)?;
+        let stats = self.trie_stats();
+        let trie_networks = stats.v4.entries + stats.v6.entries;
+        let malformed_keys = self.malformed_key_count();
+
+        Ok(TrieConsistency {
+            trie_networks,
+            stored_networks,
+            malformed_keys,
+            consistent: trie_networks + malformed_keys == stored_networks,
+        })
+    }
+
+    /// Cached until the next [`swap_trie`](Self::swap_trie). The cache is
+    /// tagged with the trie generation it was counted under, so a count that
+    /// races an import is never served afterwards.
+    pub fn prefix_stats(&self) -> Result<Arc<PrefixStats>, DbError> {
+        let generation = self.trie_generation.load(Ordering::Acquire);
+        if let Some(cached) = self.prefix_stats.load_full() {
+            if cached.0 == generation {
+                return Ok(Arc::clone(&cached.1));
+            }
+        }
+
+        let stats = Arc::new(self.count_prefixes()?);
+        self.prefix_stats
+            .store(Some(Arc::new((generation, Arc::clone(&stats)))));
+        Ok(stats)
+    }
+
+    fn count_prefixes(&self) -> Result<PrefixStats, DbError> {
+        let rtxn = self.env.read_txn()?;
+        let mut stats = PrefixStats::default();
+
+        for (table, counts) in [
+            (&self.cidr_v4, &mut stats.v4),
+            (&self.cidr_v6, &mut stats.v6),
+        ] {
+            for result in table.iter(&rtxn)? {
+                let (key, _) = result?;
+                if let Some(&prefix) = key.last() {
+                    *counts.entry(prefix).or_default() += 1;
+                }
+            }
+        }
+
+        let hosts_v4 = self.ip_v4.len(&rtxn)?;
+        if hosts_v4 > 0 {
+            *stats.v4.entry(32).or_default() += hosts_v4;
+        }
+        let hosts_v6 = self.ip_v6.len(&rtxn)?;
+        if hosts_v6 > 0 {
+            *stats.v6.entry(128).or_default() += hosts_v6;
+        }
+
+        Ok(stats)
+    }
+
+    pub fn set_dataset_hash(&self, hash: Option<String>) {
+        self.dataset_hash.store(hash.map(Arc::new));
+    }
+
+    pub fn dataset_hash(&self) -> Option<Arc<String>> {
+        self.dataset_hash.load_full()
     }
 
     pub fn find_matching_cidrs_fast(&self, ip: IpAddr) -> MatchVec {
         self.cidr_trie.load().find_all_matches(ip)
     }
 
+    pub fn find_supernets(
+        &self,
+        network: IpNetwork,
+    ) -> Result<Vec<(IpNetwork, ReputationFlags)>, DbError> {
+        let network = IpNetwork::new(network.network(), network.prefix()).unwrap_or(network);
+        let mut supernets = self.trie().find_supernets(network).into_vec();
+
+        if network.prefix() == network.ip().max_prefix_len() && !self.cidr_only() {
+            if let Some(flags) = self.lookup_ip(network.ip())? {
+                supernets.push((network, flags));
+            }
+        }
+
+        Ok(supernets)
+    }
+
+    pub fn cidr_only(&self) -> bool {
+        self.cidr_only.load(Ordering::Relaxed)
+    }
+
+    /// In CIDR-only mode single IPs are stored as /32 and /128 CIDRs, so every
+    /// match comes from the trie and lookups skip the exact-IP tables. Call
+    /// [`Self::migrate_host_entries`] on the writer after switching modes.
+    pub fn set_cidr_only(&self, enabled: bool) {
+        self.cidr_only.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Moves single-IP rows into the tables the current mode stores them in
+    /// and rebuilds the trie if any moved. Returns the number of rows moved.
+    pub fn migrate_host_entries(&self) -> Result<u64, DbError> {
+        let mut txn = self.begin_write()?;
+        let mut moved = 0u64;
+
+        if self.cidr_only() {
+            for (table, cidr_table) in [(&self.ip_v4, &self.cidr_v4), (&self.ip_v6, &self.cidr_v6)]
+            {
+                let rows = table
+                    .iter(&txn)?
+                    .collect::<Result<Vec<(&[u8], ReputationFlags)>, _>>()?
+                    .into_iter()
+                    .filter_map(|(key, flags)| Some((key_to_ip(key)?, flags)))
+                    .collect::<Vec<_>>();
+                for (ip, flags) in &rows {
+                    cidr_table.put(&mut txn, cidr_to_key(IpNetwork::from(*ip)).as_ref(), flags)?;
+                }
+                table.clear(&mut txn)?;
+                moved += rows.len() as u64;
+            }
+        } else {
+            for table in [&self.cidr_v4, &self.cidr_v6] {
+                let rows = table
+                    .iter(&txn)?
+                    .collect::<Result<Vec<(&[u8], ReputationFlags)>, _>>()?
+                    .into_iter()
+                    .filter_map(|(key, flags)| Some((key_to_cidr(key)?, flags)))
+                    .filter(|(network, _)| network.prefix() == network.ip().max_prefix_len())
+                    .collect::<Vec<_>>();
+                for (network, flags) in &rows {
+                    self.delete_cidr(&mut txn, *network)?;
+                    self.insert_ip(&mut txn, network.ip(), flags)?;
+                }
+                moved += rows.len() as u64;
+            }
+        }
+
+        txn.commit()?;
+        if moved > 0 {
+            self.rebuild_trie()?;
+        }
+        Ok(moved)
+    }
+
     pub fn begin_write(&self) -> Result<RwTxn<'_>, DbError> {
-        Ok(self.env.write_txn()?)
+        let txn = self.env.write_txn()?;
+        // Checked under the write lock so a compaction staged meanwhile is seen.
+        if self.compaction_staged.swap(false, Ordering::Relaxed) {
+            std::fs::remove_file(self.env.path().join(STAGED_COMPACTION))?;
+            warn!("A write discarded the staged compaction before a restart applied it");
+        }
+        Ok(txn)
     }
 
     pub fn insert_record(
@@ -113,18 +688,31 @@ impl Database {
     ) -> Result<(), DbError> {
         if let Ok(network) = entry.parse::<IpNetwork>() {
             if network.prefix() == network.ip().max_prefix_len() {
-                self.insert_ip(txn, network.ip(), flags)
+                self.insert_host(txn, network.ip(), flags)
             } else {
                 self.insert_cidr(txn, network, flags)
             }
         } else if let Ok(ip) = entry.parse::<IpAddr>() {
-            self.insert_ip(txn, ip, flags)
+            self.insert_host(txn, ip, flags)
         } else {
             warn!("Failed to parse entry as IP or CIDR: {}", entry);
             Ok(())
         }
     }
 
+    fn insert_host(
+        &self,
+        txn: &mut RwTxn,
+        ip: IpAddr,
+        flags: &ReputationFlags,
+    ) -> Result<(), DbError> {
+        if self.cidr_only() {
+            self.insert_cidr(txn, IpNetwork::from(ip), flags)
+        } else {
+            self.insert_ip(txn, ip, flags)
+        }
+    }
+
     fn insert_ip(
         &self,
         txn: &mut RwTxn,
@@ -161,19 +749,30 @@ impl Database {
     }
 
     pub fn delete_record(&self, txn: &mut RwTxn, entry: &str) -> Result<bool, DbError> {
+        self.set_note(txn, entry, None)?;
+        self.set_raw_row(txn, entry, None)?;
+        self.set_sources(txn, entry, None)?;
         if let Ok(network) = entry.parse::<IpNetwork>() {
             if network.prefix() == network.ip().max_prefix_len() {
-                self.delete_ip(txn, network.ip())
+                self.delete_host(txn, network.ip())
             } else {
                 self.delete_cidr(txn, network)
             }
         } else if let Ok(ip) = entry.parse::<IpAddr>() {
-            self.delete_ip(txn, ip)
+            self.delete_host(txn, ip)
         } else {
             Ok(false)
         }
     }
 
+    /// Single IPs may sit in either table if the mode changed since they were
+    /// written, so both are cleared.
+    fn delete_host(&self, txn: &mut RwTxn, ip: IpAddr) -> Result<bool, DbError> {
+        let exact = self.delete_ip(txn, ip)?;
+        let cidr = self.delete_cidr(txn, IpNetwork::from(ip))?;
+        Ok(exact || cidr)
+    }
+
     fn delete_ip(&self, txn: &mut RwTxn, ip: IpAddr) -> Result<bool, DbError> {
         let deleted = match ip {
             IpAddr::V4(v4) => self.ip_v4.delete(txn, &v4.octets())?,
@@ -196,114 +795,560 @@ impl Database {
         self.ip_v6.clear(txn)?;
         self.cidr_v4.clear(txn)?;
         self.cidr_v6.clear(txn)?;
+        for table in [self.notes, self.raw_rows, self.sources]
+            .into_iter()
+            .flatten()
+        {
+            table.clear(txn)?;
+        }
         Ok(())
     }
 
-    pub fn lookup_ip(&self, ip: IpAddr) -> Result<Option<ReputationFlags>, DbError> {
-        let rtxn = self.env.read_txn()?;
-        match ip {
-            IpAddr::V4(v4) => Ok(self.ip_v4.get(&rtxn, &v4.octets())?),
-            IpAddr::V6(v6) => Ok(self.ip_v6.get(&rtxn, &v6.octets())?),
+    pub fn set_note(
+        &self,
+        txn: &mut RwTxn,
+        entry: &str,
+        note: Option<&str>,
+    ) -> Result<(), DbError> {
+        let (Some(notes), Some(key)) = (self.notes, note_key(entry)) else {
+            return Ok(());
+        };
+        match note {
+            Some(note) => {
+                notes.put(txn, &key, note)?;
+                self.has_notes.store(true, Ordering::Relaxed);
+            }
+            None => {
+                notes.delete(txn, &key)?;
+            }
         }
+        Ok(())
     }
 
-    pub fn lookup_ips_batch(
+    /// Stores the feed's original CSV line for `entry`, or removes it.
+    pub fn set_raw_row(
         &self,
-        ips: &[IpAddr],
-    ) -> Result<Vec<Option<ReputationFlags>>, DbError> {
-        let rtxn = self.env.read_txn()?;
-        let mut results = Vec::with_capacity(ips.len());
-
-        for ip in ips {
-            let flags = match ip {
-                IpAddr::V4(v4) => self.ip_v4.get(&rtxn, &v4.octets())?,
-                IpAddr::V6(v6) => self.ip_v6.get(&rtxn, &v6.octets())?,
-            };
-            results.push(flags);
+        txn: &mut RwTxn,
+        entry: &str,
+        row: Option<&str>,
+    ) -> Result<(), DbError> {
+        let (Some(raw_rows), Some(key)) = (self.raw_rows, note_key(entry)) else {
+            return Ok(());
+        };
+        match row {
+            Some(row) => raw_rows.put(txn, &key, row)?,
+            None => {
+                raw_rows.delete(txn, &key)?;
+            }
         }
-
-        Ok(results)
+        Ok(())
     }
 
-    pub fn lookup_cidr(&self, network: IpNetwork) -> Result<Option<ReputationFlags>, DbError> {
-        let rtxn = self.env.read_txn()?;
-        let key = cidr_to_key(network);
-        match network {
-            IpNetwork::V4(_) => Ok(self.cidr_v4.get(&rtxn, key.as_ref())?),
-            IpNetwork::V6(_) => Ok(self.cidr_v6.get(&rtxn, key.as_ref())?),
+    pub fn clear_raw_rows(&self, txn: &mut RwTxn) -> Result<(), DbError> {
+        if let Some(raw_rows) = self.raw_rows {
+            raw_rows.clear(txn)?;
         }
+        Ok(())
     }
 
-    pub fn lookup_cidrs_batch(
-        &self,
-        networks: &[IpNetwork],
-    ) -> Result<Vec<Option<ReputationFlags>>, DbError> {
+    pub fn raw_row(&self, entry: &str) -> Result<Option<String>, DbError> {
         let rtxn = self.env.read_txn()?;
-        let mut results = Vec::with_capacity(networks.len());
-
-        for network in networks {
-            let key = cidr_to_key(*network);
-            let flags = match network {
-                IpNetwork::V4(_) => self.cidr_v4.get(&rtxn, key.as_ref())?,
-                IpNetwork::V6(_) => self.cidr_v6.get(&rtxn, key.as_ref())?,
-            };
-            results.push(flags);
-        }
-
-        Ok(results)
+        self.raw_row_txn(&rtxn, entry)
     }
 
-    pub fn get_metadata(&self) -> Result<Metadata, DbError> {
-        let rtxn = self.env.read_txn()?;
-        Ok(self.metadata.get(&rtxn, b"meta")?.unwrap_or_default())
+    pub fn raw_row_txn(&self, txn: &RoTxn, entry: &str) -> Result<Option<String>, DbError> {
+        let (Some(raw_rows), Some(key)) = (self.raw_rows, note_key(entry)) else {
+            return Ok(None);
+        };
+        Ok(raw_rows.get(txn, &key)?.map(str::to_owned))
     }
 
-    pub fn set_metadata(&self, txn: &mut RwTxn, meta: &Metadata) -> Result<(), DbError> {
-        self.metadata.put(txn, b"meta", meta)?;
+    /// Stores which merged feeds set each flag of `entry`, in the merged
+    /// CSV's `sources` column format, or removes it.
+    pub fn set_sources(
+        &self,
+        txn: &mut RwTxn,
+        entry: &str,
+        sources: Option<&str>,
+    ) -> Result<(), DbError> {
+        let (Some(table), Some(key)) = (self.sources, note_key(entry)) else {
+            return Ok(());
+        };
+        match sources {
+            Some(sources) => table.put(txn, &key, sources)?,
+            None => {
+                table.delete(txn, &key)?;
+            }
+        }
         Ok(())
     }
 
-    pub fn get_all_entries(&self) -> Result<Vec<(String, ReputationFlags)>, DbError> {
+    pub fn lookup_sources<'a>(
+        &self,
+        entries: impl IntoIterator<Item = &'a str>,
+    ) -> Result<Vec<Option<String>>, DbError> {
+        let Some(table) = self.sources else {
+            return Ok(entries.into_iter().map(|_| None).collect());
+        };
         let rtxn = self.env.read_txn()?;
-        let mut entries = Vec::new();
+        let mut results = Vec::new();
+        for entry in entries {
+            let sources = match note_key(entry) {
+                Some(key) => table.get(&rtxn, &key)?.map(str::to_owned),
+                None => None,
+            };
+            results.push(sources);
+        }
+        Ok(results)
+    }
 
-        for result in self.ip_v4.iter(&rtxn)? {
-            let (key, flags) = result?;
-            if key.len() == 4 {
-                let octets: [u8; 4] = key.try_into().unwrap();
-                let ip = std::net::Ipv4Addr::from(octets);
-                entries.push((ip.to_string(), flags));
+    /// Replaces what `source` contributed to a merged dataset with `records`
+    /// in one transaction, then patches the trie. Flags only `source` set are
+    /// cleared and the flags of `records` are added, so the flags other
+    /// sources set are kept; entries left without flags are deleted. Returns
+    /// the number of entries written or deleted.
+    pub fn replace_source(
+        &self,
+        source: &str,
+        records: &[(String, ReputationFlags)],
+    ) -> Result<u64, DbError> {
+        let table = self.sources.ok_or(DbError::MissingTable("sources"))?;
+        let mut txn = self.begin_write()?;
+
+        // Sources per flag bit of every affected entry, and the bits it gains.
+        let mut affected: BTreeMap<Vec<u8>, (Vec<Vec<String>>, u16)> = BTreeMap::new();
+        for result in table.iter(&txn)? {
+            let (key, column) = result?;
+            let sets = source_sets(column);
+            if sets.iter().flatten().any(|name| name == source) {
+                affected.insert(key.to_vec(), (sets, 0));
             }
         }
-
-        for result in self.ip_v6.iter(&rtxn)? {
-            let (key, flags) = result?;
-            if key.len() == 16 {
-                let octets: [u8; 16] = key.try_into().unwrap();
-                let ip = std::net::Ipv6Addr::from(octets);
-                entries.push((ip.to_string(), flags));
+        for (entry, flags) in records {
+            let Some(key) = note_key(entry) else {
+                warn!("Failed to parse entry as IP or CIDR: {}", entry);
+                continue;
+            };
+            if !affected.contains_key(&key) {
+                let sets = source_sets(table.get(&txn, &key)?.unwrap_or_default());
+                affected.insert(key.clone(), (sets, 0));
+            }
+            if let Some((_, added)) = affected.get_mut(&key) {
+                *added |= flags.to_mask();
             }
         }
 
-        for result in self.cidr_v4.iter(&rtxn)? {
-            let (key, flags) = result?;
-            if let Some(network) = key_to_cidr(key) {
-                entries.push((network.to_string(), flags));
+        let mut trie = IpTrie::clone(&self.trie());
+        let mut changed = 0u64;
+        for (key, (mut sets, added)) in affected {
+            let (Some(entry), Some(network)) = (
+                key_to_entry(&key),
+                key_to_ip(&key)
+                    .map(IpNetwork::from)
+                    .or_else(|| key_to_cidr(&key)),
+            ) else {
+                continue;
+            };
+            let mut mask = self.stored_flags(&txn, network)?.map_or(0, |f| f.to_mask());
+            for (bit, names) in sets.iter_mut().enumerate() {
+                let before = names.len();
+                names.retain(|name| name != source);
+                if names.len() < before && names.is_empty() {
+                    mask &= !(1 << bit);
+                }
+                if added >> bit & 1 == 1 {
+                    mask |= 1 << bit;
+                    names.push(source.to_owned());
+                }
             }
-        }
 
-        for result in self.cidr_v6.iter(&rtxn)? {
-            let (key, flags) = result?;
-            if let Some(network) = key_to_cidr(key) {
-                entries.push((network.to_string(), flags));
+            let in_trie = network.prefix() < network.ip().max_prefix_len() || self.cidr_only();
+            if mask == 0 {
+                self.delete_record(&mut txn, &entry)?;
+                if in_trie {
+                    trie.remove(network);
+                }
+            } else {
+                let flags = ReputationFlags::from_mask(mask);
+                self.insert_record(&mut txn, &entry, &flags)?;
+                self.set_sources(&mut txn, &entry, sources_column(&sets).as_deref())?;
+                if in_trie {
+                    trie.replace(network, flags);
+                }
             }
+            changed += 1;
         }
 
-        Ok(entries)
+        let mut meta = self.read_metadata(&txn)?;
+        meta.record_count = self.count_entries_txn(&txn)?;
+        // A new sync time tells replicas the dataset changed.
+        meta.last_sync = Some(chrono::Utc::now().timestamp());
+        if meta.bucket_hashes.is_some() {
+            meta.bucket_hashes = Some(self.bucket_hashes_txn(&txn)?);
+        }
+        self.set_metadata(&mut txn, &meta)?;
+        self.refresh_has_notes_in(&txn)?;
+        txn.commit()?;
+        self.swap_trie(trie);
+        Ok(changed)
     }
 
-    pub fn is_empty(&self) -> Result<bool, DbError> {
-        let rtxn = self.env.read_txn()?;
+    /// The flags stored for exactly `network`, a single address being read
+    /// from the table the current mode writes it to.
+    fn stored_flags(
+        &self,
+        txn: &RoTxn,
+        network: IpNetwork,
+    ) -> Result<Option<ReputationFlags>, DbError> {
+        if network.prefix() == network.ip().max_prefix_len() {
+            return self.lookup_ip_txn(txn, network.ip());
+        }
+        let key = cidr_to_key(network);
+        match network {
+            IpNetwork::V4(_) => Ok(self.cidr_v4.get(txn, key.as_ref())?),
+            IpNetwork::V6(_) => Ok(self.cidr_v6.get(txn, key.as_ref())?),
+        }
+    }
+
+    pub fn get_all_sources(&self) -> Result<HashMap<String, String>, DbError> {
+        let rtxn = self.env.read_txn()?;
+        self.get_all_sources_txn(&rtxn)
+    }
+
+    pub fn get_all_sources_txn(&self, txn: &RoTxn) -> Result<HashMap<String, String>, DbError> {
+        let Some(table) = self.sources else {
+            return Ok(HashMap::new());
+        };
+        let mut all = HashMap::new();
+        for result in table.iter(txn)? {
+            let (key, sources) = result?;
+            if let Some(entry) = key_to_entry(key) {
+                all.insert(entry, sources.to_owned());
+            }
+        }
+        Ok(all)
+    }
+
+    pub fn has_notes(&self) -> bool {
+        self.has_notes.load(Ordering::Relaxed)
+    }
+
+    /// Rechecks whether any note is stored, e.g. after an import that may
+    /// have removed the last one.
+    pub fn refresh_has_notes(&self) -> Result<(), DbError> {
+        let rtxn = self.env.read_txn()?;
+        self.refresh_has_notes_in(&rtxn)
+    }
+
+    fn refresh_has_notes_in(&self, txn: &RoTxn) -> Result<(), DbError> {
+        if let Some(notes) = self.notes {
+            self.has_notes
+                .store(!notes.is_empty(txn)?, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    pub fn lookup_notes<'a>(
+        &self,
+        entries: impl IntoIterator<Item = &'a str>,
+    ) -> Result<Vec<Option<String>>, DbError> {
+        let rtxn = self.env.read_txn()?;
+        self.lookup_notes_txn(&rtxn, entries)
+    }
+
+    pub fn lookup_notes_txn<'a>(
+        &self,
+        txn: &RoTxn,
+        entries: impl IntoIterator<Item = &'a str>,
+    ) -> Result<Vec<Option<String>>, DbError> {
+        let Some(notes) = self.notes else {
+            return Ok(entries.into_iter().map(|_| None).collect());
+        };
+        let mut results = Vec::new();
+        for entry in entries {
+            let note = match note_key(entry) {
+                Some(key) => notes.get(txn, &key)?.map(str::to_owned),
+                None => None,
+            };
+            results.push(note);
+        }
+        Ok(results)
+    }
+
+    pub fn get_all_notes(&self) -> Result<HashMap<String, String>, DbError> {
+        let rtxn = self.env.read_txn()?;
+        self.get_all_notes_txn(&rtxn)
+    }
+
+    pub fn get_all_notes_txn(&self, txn: &RoTxn) -> Result<HashMap<String, String>, DbError> {
+        let Some(notes) = self.notes else {
+            return Ok(HashMap::new());
+        };
+        let mut all = HashMap::new();
+        for result in notes.iter(txn)? {
+            let (key, note) = result?;
+            if let Some(entry) = key_to_entry(key) {
+                all.insert(entry, note.to_owned());
+            }
+        }
+        Ok(all)
+    }
+
+    /// Opens a read transaction for use with [`Database::lookup_ip_txn`].
+    ///
+    /// The transaction sees a snapshot of the database taken when it was opened,
+    /// so lookups made through it will not observe imports committed afterwards.
+    /// It holds an LMDB reader slot and pins that snapshot's pages, which keeps
+    /// writers from reusing them, so keep it short-lived and drop it before
+    /// blocking for long. It must not be sent to or used from another thread.
+    pub fn read_txn(&self) -> Result<RoTxn<'_>, DbError> {
+        Ok(self.env.read_txn()?)
+    }
+
+    pub fn lookup_ip(&self, ip: IpAddr) -> Result<Option<ReputationFlags>, DbError> {
+        let rtxn = self.read_txn()?;
+        self.lookup_ip_txn(&rtxn, ip)
+    }
+
+    pub fn lookup_ip_txn(
+        &self,
+        txn: &RoTxn,
+        ip: IpAddr,
+    ) -> Result<Option<ReputationFlags>, DbError> {
+        if self.cidr_only() {
+            let key = cidr_to_key(IpNetwork::from(ip));
+            return match ip {
+                IpAddr::V4(_) => Ok(self.cidr_v4.get(txn, key.as_ref())?),
+                IpAddr::V6(_) => Ok(self.cidr_v6.get(txn, key.as_ref())?),
+            };
+        }
+        match ip {
+            IpAddr::V4(v4) => Ok(self.ip_v4.get(txn, &v4.octets())?),
+            IpAddr::V6(v6) => Ok(self.ip_v6.get(txn, &v6.octets())?),
+        }
+    }
+
+    pub fn lookup_ips_batch(
+        &self,
+        txn: &RoTxn,
+        ips: &[IpAddr],
+    ) -> Result<Vec<Option<ReputationFlags>>, DbError> {
+        let mut results = Vec::with_capacity(ips.len());
+
+        for ip in ips {
+            results.push(self.lookup_ip_txn(txn, *ip)?);
+        }
+
+        Ok(results)
+    }
+
+    pub fn lookup_cidr(&self, network: IpNetwork) -> Result<Option<ReputationFlags>, DbError> {
+        let rtxn = self.env.read_txn()?;
+        let key = cidr_to_key(network);
+        match network {
+            IpNetwork::V4(_) => Ok(self.cidr_v4.get(&rtxn, key.as_ref())?),
+            IpNetwork::V6(_) => Ok(self.cidr_v6.get(&rtxn, key.as_ref())?),
+        }
+    }
+
+    pub fn lookup_cidrs_batch(
+        &self,
+        txn: &RoTxn,
+        networks: &[IpNetwork],
+    ) -> Result<Vec<Option<ReputationFlags>>, DbError> {
+        let mut results = Vec::with_capacity(networks.len());
+
+        for network in networks {
+            let key = cidr_to_key(*network);
+            let flags = match network {
+                IpNetwork::V4(_) => self.cidr_v4.get(txn, key.as_ref())?,
+                IpNetwork::V6(_) => self.cidr_v6.get(txn, key.as_ref())?,
+            };
+            results.push(flags);
+        }
+
+        Ok(results)
+    }
+
+    pub fn get_metadata(&self) -> Result<Metadata, DbError> {
+        let rtxn = self.env.read_txn()?;
+        self.read_metadata(&rtxn)
+    }
+
+    pub fn get_metadata_txn(&self, txn: &RoTxn) -> Result<Metadata, DbError> {
+        self.read_metadata(txn)
+    }
+
+    fn read_metadata(&self, rtxn: &RoTxn) -> Result<Metadata, DbError> {
+        if let Some(bytes) = self.metadata.get(rtxn, METADATA_KEY)? {
+            return decode_metadata(bytes);
+        }
+        match self.metadata.get(rtxn, LEGACY_METADATA_KEY)? {
+            Some(bytes) => decode_legacy_metadata(bytes),
+            None => Ok(Metadata::default()),
+        }
+    }
+
+    pub fn set_metadata(&self, txn: &mut RwTxn, meta: &Metadata) -> Result<(), DbError> {
+        self.metadata
+            .put(txn, METADATA_KEY, &encode_metadata(meta)?)?;
+        self.metadata.delete(txn, LEGACY_METADATA_KEY)?;
+        Ok(())
+    }
+
+    pub fn update_metadata(&self, update: impl FnOnce(&mut Metadata)) -> Result<(), DbError> {
+        let mut txn = self.begin_write()?;
+        let mut meta = self.read_metadata(&txn)?;
+        update(&mut meta);
+        self.set_metadata(&mut txn, &meta)?;
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// Calls `visit` with the network address, display form and flags of every
+    /// stored entry, table by table in key order.
+    fn visit_entries(
+        &self,
+        rtxn: &RoTxn,
+        mut visit: impl FnMut(IpAddr, String, ReputationFlags),
+    ) -> Result<(), DbError> {
+        for result in self.ip_v4.iter(rtxn)? {
+            let (key, flags) = result?;
+            if key.len() == 4 {
+                let octets: [u8; 4] = key.try_into().unwrap();
+                let ip = IpAddr::from(Ipv4Addr::from(octets));
+                visit(ip, ip.to_string(), flags);
+            }
+        }
+
+        for result in self.ip_v6.iter(rtxn)? {
+            let (key, flags) = result?;
+            if key.len() == 16 {
+                let octets: [u8; 16] = key.try_into().unwrap();
+                let ip = IpAddr::from(Ipv6Addr::from(octets));
+                visit(ip, ip.to_string(), flags);
+            }
+        }
+
+        for table in [&self.cidr_v4, &self.cidr_v6] {
+            for result in table.iter(rtxn)? {
+                let (key, flags) = result?;
+                if let Some(network) = key_to_cidr(key) {
+                    // Single IPs stored in CIDR-only mode keep their bare form
+                    let entry = if network.prefix() == network.ip().max_prefix_len() {
+                        network.ip().to_string()
+                    } else {
+                        network.to_string()
+                    };
+                    visit(network.network(), entry, flags);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_all_entries(&self) -> Result<Vec<(String, ReputationFlags)>, DbError> {
+        let rtxn = self.env.read_txn()?;
+        self.get_all_entries_txn(&rtxn)
+    }
+
+    pub fn get_all_entries_txn(
+        &self,
+        txn: &RoTxn,
+    ) -> Result<Vec<(String, ReputationFlags)>, DbError> {
+        let mut entries = Vec::new();
+        self.visit_entries(txn, |_, entry, flags| entries.push((entry, flags)))?;
+        Ok(entries)
+    }
+
+    /// Returns some stored entry without scanning the tables, or `None` when
+    /// the database is empty.
+    pub fn first_entry(&self) -> Result<Option<(IpNetwork, ReputationFlags)>, DbError> {
+        let rtxn = self.env.read_txn()?;
+        for table in [&self.ip_v4, &self.ip_v6] {
+            if let Some((key, flags)) = table.first(&rtxn)? {
+                let ip = match key.len() {
+                    4 => IpAddr::from(<[u8; 4]>::try_from(key).unwrap()),
+                    16 => IpAddr::from(<[u8; 16]>::try_from(key).unwrap()),
+                    _ => continue,
+                };
+                return Ok(Some((IpNetwork::from(ip), flags)));
+            }
+        }
+        for table in [&self.cidr_v4, &self.cidr_v6] {
+            if let Some((key, flags)) = table.first(&rtxn)? {
+                if let Some(network) = key_to_cidr(key) {
+                    return Ok(Some((network, flags)));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn bucket_hashes(&self) -> Result<BucketHashes, DbError> {
+        let rtxn = self.env.read_txn()?;
+        self.bucket_hashes_txn(&rtxn)
+    }
+
+    /// Hashes the entries as `txn` sees them, so an import can store the
+    /// hashes of its own writes before committing.
+    pub fn bucket_hashes_txn(&self, txn: &RoTxn) -> Result<BucketHashes, DbError> {
+        let mut hashers: BTreeMap<IpNetwork, Sha256> = BTreeMap::new();
+        self.visit_entries(txn, |addr, entry, flags| {
+            let hasher = hashers.entry(hash_bucket(addr)).or_default();
+            hasher.update(format!("{entry} {}\n", flags.to_mask()));
+        })?;
+
+        let buckets: BTreeMap<String, String> = hashers
+            .into_iter()
+            .map(|(bucket, hasher)| (bucket.to_string(), hex::encode(hasher.finalize())))
+            .collect();
+        let mut root = Sha256::new();
+        for (bucket, hash) in &buckets {
+            root.update(format!("{bucket} {hash}\n"));
+        }
+        Ok(BucketHashes {
+            root: hex::encode(root.finalize()),
+            buckets,
+        })
+    }
+
+    /// Reads every key and value once so the pages are in the page cache
+    /// before the first lookups. Returns the number of bytes touched.
+    pub fn warmup(&self) -> Result<u64, DbError> {
+        let rtxn = self.env.read_txn()?;
+        let mut bytes = 0u64;
+
+        for table in [&self.ip_v4, &self.ip_v6, &self.cidr_v4, &self.cidr_v6] {
+            for result in table.remap_data_type::<Bytes>().iter(&rtxn)? {
+                let (key, value) = result?;
+                bytes += (key.len() + value.len()) as u64;
+            }
+        }
+        if let Some(notes) = self.notes {
+            for result in notes.remap_data_type::<Bytes>().iter(&rtxn)? {
+                let (key, value) = result?;
+                bytes += (key.len() + value.len()) as u64;
+            }
+        }
+
+        Ok(bytes)
+    }
+
+    pub fn count_entries(&self) -> Result<u64, DbError> {
+        let rtxn = self.env.read_txn()?;
+        self.count_entries_txn(&rtxn)
+    }
+
+    pub fn count_entries_txn(&self, txn: &RoTxn) -> Result<u64, DbError> {
+        Ok(self.ip_v4.len(txn)?
+            + self.ip_v6.len(txn)?
+            + self.cidr_v4.len(txn)?
+            + self.cidr_v6.len(txn)?)
+    }
+
+    pub fn is_empty(&self) -> Result<bool, DbError> {
+        let rtxn = self.env.read_txn()?;
         Ok(self.ip_v4.is_empty(&rtxn)?
             && self.ip_v6.is_empty(&rtxn)?
             && self.cidr_v4.is_empty(&rtxn)?
@@ -345,16 +1390,86 @@ fn cidr_to_key(network: IpNetwork) -> CidrKey {
     }
 }
 
+fn note_key(entry: &str) -> Option<Vec<u8>> {
+    let network: IpNetwork = entry.parse().ok()?;
+    if network.prefix() == network.ip().max_prefix_len() {
+        return Some(match network.ip() {
+            IpAddr::V4(v4) => v4.octets().to_vec(),
+            IpAddr::V6(v6) => v6.octets().to_vec(),
+        });
+    }
+    Some(cidr_to_key(network).as_ref().to_vec())
+}
+
+/// Splits a `sources` column into each flag and the sources that set it.
+pub fn parse_sources_column(column: &str) -> Vec<(&str, Vec<&str>)> {
+    column
+        .split('|')
+        .filter_map(|part| {
+            let (flag, names) = part.split_once('=')?;
+            Some((flag, names.split(' ').filter(|n| !n.is_empty()).collect()))
+        })
+        .collect()
+}
+
+/// The sources of each flag bit in a `sources` column.
+fn source_sets(column: &str) -> Vec<Vec<String>> {
+    let mut sets = vec![Vec::new(); FLAG_BITS.len()];
+    for (flag, names) in parse_sources_column(column) {
+        if let Some(bit) = FLAG_BITS.iter().position(|&f| f == flag) {
+            sets[bit] = names.into_iter().map(str::to_owned).collect();
+        }
+    }
+    sets
+}
+
+fn sources_column(sets: &[Vec<String>]) -> Option<String> {
+    let column: Vec<String> = FLAG_BITS
+        .iter()
+        .zip(sets)
+        .filter(|(_, names)| !names.is_empty())
+        .map(|(flag, names)| format!("{flag}={}", names.join(" ")))
+        .collect();
+    (!column.is_empty()).then(|| column.join("|"))
+}
+
+fn key_to_ip(key: &[u8]) -> Option<IpAddr> {
+    match key.len() {
+        4 => Some(IpAddr::from(<[u8; 4]>::try_from(key).ok()?)),
+        16 => Some(IpAddr::from(<[u8; 16]>::try_from(key).ok()?)),
+        _ => None,
+    }
+}
+
+fn key_to_entry(key: &[u8]) -> Option<String> {
+    match key.len() {
+        4 => Some(Ipv4Addr::from(<[u8; 4]>::try_from(key).ok()?).to_string()),
+        16 => Some(Ipv6Addr::from(<[u8; 16]>::try_from(key).ok()?).to_string()),
+        _ => key_to_cidr(key).map(|network| network.to_string()),
+    }
+}
+
+fn hash_bucket(addr: IpAddr) -> IpNetwork {
+    match addr {
+        IpAddr::V4(v4) => IpNetwork::new(Ipv4Addr::new(v4.octets()[0], 0, 0, 0).into(), 8),
+        IpAddr::V6(v6) => IpNetwork::new(
+            Ipv6Addr::new(v6.segments()[0], 0, 0, 0, 0, 0, 0, 0).into(),
+            16,
+        ),
+    }
+    .expect("bucket prefix is valid")
+}
+
 fn key_to_cidr(key: &[u8]) -> Option<IpNetwork> {
     if key.len() == 5 {
         let octets: [u8; 4] = key[..4].try_into().ok()?;
         let prefix = key[4];
-        let addr = std::net::Ipv4Addr::from(octets);
+        let addr = Ipv4Addr::from(octets);
         IpNetwork::new(IpAddr::V4(addr), prefix).ok()
     } else if key.len() == 17 {
         let octets: [u8; 16] = key[..16].try_into().ok()?;
         let prefix = key[16];
-        let addr = std::net::Ipv6Addr::from(octets);
+        let addr = Ipv6Addr::from(octets);
         IpNetwork::new(IpAddr::V6(addr), prefix).ok()
     } else {
         None
@@ -385,6 +1500,42 @@ mod tests {
         (dir, db)
     }
 
+    #[test]
+    fn test_open_or_create_table_after_open() {
+        let (_dir, db) = create_test_db();
+        let table: HeedDb<Bytes, Str> = db.open_or_create_table("allowlist").unwrap();
+        let mut txn = db.begin_write().unwrap();
+        table.put(&mut txn, b"k", "v").unwrap();
+        txn.commit().unwrap();
+
+        let again: HeedDb<Bytes, Str> = db.open_or_create_table("allowlist").unwrap();
+        let rtxn = db.read_txn().unwrap();
+        assert_eq!(again.get(&rtxn, b"k").unwrap(), Some("v"));
+        drop(rtxn);
+        drop(db);
+
+        let full = TempDir::new().unwrap();
+        let db = Database::open_with_max_dbs(full.path(), TABLE_COUNT).unwrap();
+        assert!(db.open_or_create_table::<Bytes, Str>("allowlist").is_err());
+    }
+
+    #[test]
+    fn test_warmup_reads_all_tables() {
+        let (_dir, db) = create_test_db();
+        assert_eq!(db.warmup().unwrap(), 0);
+
+        let mut txn = db.begin_write().unwrap();
+        db.insert_record(&mut txn, "192.168.1.1", &ReputationFlags::default())
+            .unwrap();
+        db.insert_record(&mut txn, "10.0.0.0/8", &ReputationFlags::default())
+            .unwrap();
+        db.set_note(&mut txn, "10.0.0.0/8", Some("abc")).unwrap();
+        txn.commit().unwrap();
+
+        let bytes = db.warmup().unwrap();
+        assert!(bytes > 4 + 5 + 5 + 3, "{bytes}");
+    }
+
     #[test]
     fn test_insert_and_lookup_ipv4() {
         let (_dir, db) = create_test_db();
@@ -402,6 +1553,373 @@ mod tests {
         assert!(result.unwrap().proxy);
     }
 
+    #[test]
+    fn test_find_supernets_includes_exact_entries() {
+        let (_dir, db) = create_test_db();
+        let flags = ReputationFlags {
+            vpn: true,
+            ..Default::default()
+        };
+
+        let mut txn = db.begin_write().unwrap();
+        for entry in ["10.0.0.0/8", "10.1.0.0/16", "10.1.2.0/24", "10.1.2.3"] {
+            db.insert_record(&mut txn, entry, &flags).unwrap();
+        }
+        txn.commit().unwrap();
+        db.rebuild_trie().unwrap();
+
+        let supernets = db.find_supernets("10.1.0.0/16".parse().unwrap()).unwrap();
+        assert_eq!(supernets.len(), 2);
+
+        let supernets = db.find_supernets("10.1.2.3/32".parse().unwrap()).unwrap();
+        assert_eq!(supernets.len(), 4);
+        assert_eq!(supernets[3].0, "10.1.2.3/32".parse::<IpNetwork>().unwrap());
+
+        let supernets = db.find_supernets("10.1.2.128/25".parse().unwrap()).unwrap();
+        assert_eq!(supernets.len(), 3);
+        assert!(db
+            .find_supernets("11.0.0.0/8".parse().unwrap())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_prefix_stats_counts_by_family_and_length() {
+        let (_dir, db) = create_test_db();
+        let flags = ReputationFlags::default();
+
+        let mut txn = db.begin_write().unwrap();
+        for entry in [
+            "10.0.0.0/8",
+            "11.0.0.0/8",
+            "10.1.0.0/16",
+            "1.2.3.4",
+            "5.6.7.8/32",
+            "2001:db8::/32",
+            "2001:db8::1",
+        ] {
+            db.insert_record(&mut txn, entry, &flags).unwrap();
+        }
+        txn.commit().unwrap();
+        db.rebuild_trie().unwrap();
+
+        let stats = db.prefix_stats().unwrap();
+        assert_eq!(stats.v4, BTreeMap::from([(8, 2), (16, 1), (32, 2)]));
+        assert_eq!(stats.v6, BTreeMap::from([(32, 1), (128, 1)]));
+
+        let mut txn = db.begin_write().unwrap();
+        db.insert_record(&mut txn, "12.0.0.0/8", &flags).unwrap();
+        txn.commit().unwrap();
+        assert_eq!(db.prefix_stats().unwrap().v4[&8], 2);
+        db.swap_trie(IpTrie::clone(&db.trie()));
+        assert_eq!(db.prefix_stats().unwrap().v4[&8], 3);
+
+        // A count that started before the next import and is cached after it.
+        let generation = db.trie_generation.load(Ordering::Acquire);
+        let stale = Arc::new(db.count_prefixes().unwrap());
+        let mut txn = db.begin_write().unwrap();
+        db.insert_record(&mut txn, "13.0.0.0/8", &flags).unwrap();
+        txn.commit().unwrap();
+        db.swap_trie(IpTrie::clone(&db.trie()));
+        db.prefix_stats.store(Some(Arc::new((generation, stale))));
+        assert_eq!(db.prefix_stats().unwrap().v4[&8], 4);
+    }
+
+    fn close(db: Arc<Database>) {
+        let env = db.env.clone();
+        drop(db);
+        env.prepare_for_closing().wait();
+    }
+
+    #[test]
+    fn test_legacy_metadata_survives_upgrade() {
+        let (dir, db) = create_test_db();
+        let sources = BTreeMap::from([("a".to_owned(), "h1".to_owned())]);
+        let layouts = [
+            bincode::serialize(&(Some(7i64), Some("abc"), 3u64)).unwrap(),
+            bincode::serialize(&(Some(7i64), Some("abc"), 3u64, &sources, &sources)).unwrap(),
+            bincode::serialize(&(
+                Some(7i64),
+                Some("abc"),
+                3u64,
+                &sources,
+                &sources,
+                None::<BucketHashes>,
+                false,
+            ))
+            .unwrap(),
+        ];
+
+        let mut db = db;
+        for layout in layouts {
+            let mut txn = db.begin_write().unwrap();
+            db.metadata.delete(&mut txn, METADATA_KEY).unwrap();
+            db.metadata
+                .put(&mut txn, LEGACY_METADATA_KEY, &layout)
+                .unwrap();
+            txn.commit().unwrap();
+            let meta = db.get_metadata().unwrap();
+            assert_eq!(meta.last_sync, Some(7));
+            assert_eq!(meta.csv_hash.as_deref(), Some("abc"));
+            assert_eq!(meta.record_count, 3);
+
+            close(db);
+            db = Database::open(dir.path()).unwrap();
+            let rtxn = db.env.read_txn().unwrap();
+            assert!(db
+                .metadata
+                .get(&rtxn, LEGACY_METADATA_KEY)
+                .unwrap()
+                .is_none());
+            drop(rtxn);
+            assert_eq!(db.get_metadata().unwrap().record_count, 3);
+        }
+        assert_eq!(db.get_metadata().unwrap().source_hashes, sources);
+
+        let mut txn = db.begin_write().unwrap();
+        db.metadata.put(&mut txn, METADATA_KEY, &[9, 1, 2]).unwrap();
+        txn.commit().unwrap();
+        assert!(matches!(db.get_metadata(), Err(DbError::Metadata(_))));
+    }
+
+    #[test]
+    fn test_compaction_preserves_records_and_shrinks_file() {
+        let (dir, db) = create_test_db();
+        let flags = ReputationFlags {
+            proxy: true,
+            ..Default::default()
+        };
+        let mut txn = db.begin_write().unwrap();
+        for i in 0..20_000u32 {
+            let ip = Ipv4Addr::from(0x0a00_0000 + i).to_string();
+            db.insert_record(&mut txn, &ip, &flags).unwrap();
+        }
+        db.insert_record(&mut txn, "192.0.2.0/24", &flags).unwrap();
+        txn.commit().unwrap();
+        let mut txn = db.begin_write().unwrap();
+        for i in 100..20_000u32 {
+            let ip = Ipv4Addr::from(0x0a00_0000 + i).to_string();
+            db.delete_record(&mut txn, &ip).unwrap();
+        }
+        txn.commit().unwrap();
+        db.rebuild_trie().unwrap();
+        let entries = db.get_all_entries().unwrap();
+
+        let compaction = db.stage_compaction().unwrap();
+        assert!(compaction.after_bytes < compaction.before_bytes / 2);
+        close(db);
+
+        let db = Database::open(dir.path()).unwrap();
+        assert_eq!(db.get_all_entries().unwrap(), entries);
+        assert!(db.env.real_disk_size().unwrap() < compaction.before_bytes / 2);
+        assert_eq!(
+            db.find_matching_cidrs_fast("192.0.2.1".parse().unwrap())
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_open_replicas_block_applying_a_compaction() {
+        let (dir, db) = create_test_db();
+        let mut txn = db.begin_write().unwrap();
+        db.insert_record(&mut txn, "1.2.3.4", &ReputationFlags::default())
+            .unwrap();
+        txn.commit().unwrap();
+        db.stage_compaction().unwrap();
+        close(db);
+
+        // A replica in another process holds the lock shared
+        let replica = open_readers_lock(dir.path()).unwrap();
+        replica.lock_shared().unwrap();
+        let db = Database::open(dir.path()).unwrap();
+        assert!(!dir.path().join(STAGED_COMPACTION).exists());
+        assert_eq!(db.count_entries().unwrap(), 1);
+
+        db.stage_compaction().unwrap();
+        close(db);
+        drop(replica);
+        let db = Database::open(dir.path()).unwrap();
+        assert!(!dir.path().join(STAGED_COMPACTION).exists());
+        assert_eq!(db.count_entries().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_write_after_staging_discards_compaction() {
+        let (dir, db) = create_test_db();
+        db.stage_compaction().unwrap();
+        assert!(dir.path().join(STAGED_COMPACTION).exists());
+
+        let mut txn = db.begin_write().unwrap();
+        db.insert_record(&mut txn, "1.2.3.4", &ReputationFlags::default())
+            .unwrap();
+        txn.commit().unwrap();
+        assert!(!dir.path().join(STAGED_COMPACTION).exists());
+        close(db);
+
+        let db = Database::open(dir.path()).unwrap();
+        assert_eq!(db.count_entries().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_reload_waits_for_import_to_finish() {
+        let (_dir, db) = create_test_db();
+        let seen = db.get_metadata().unwrap();
+        assert!(db.reload_if_changed(Some(&seen)).unwrap().is_none());
+
+        let network: IpNetwork = "10.0.0.0/8".parse().unwrap();
+        let mut txn = db.begin_write().unwrap();
+        db.insert_record(&mut txn, "10.0.0.0/8", &ReputationFlags::default())
+            .unwrap();
+        let mut meta = Metadata {
+            last_sync: Some(1),
+            csv_hash: Some("abc".to_owned()),
+            record_count: 1,
+            import_in_progress: true,
+            ..Metadata::default()
+        };
+        db.set_metadata(&mut txn, &meta).unwrap();
+        txn.commit().unwrap();
+
+        assert!(db.reload_if_changed(Some(&seen)).unwrap().is_none());
+        assert!(db
+            .find_matching_cidrs_fast("10.1.2.3".parse().unwrap())
+            .is_empty());
+
+        meta.import_in_progress = false;
+        let mut txn = db.begin_write().unwrap();
+        db.set_metadata(&mut txn, &meta).unwrap();
+        txn.commit().unwrap();
+
+        let reloaded = db.reload_if_changed(Some(&seen)).unwrap().unwrap();
+        assert_eq!(reloaded.csv_hash.as_deref(), Some("abc"));
+        assert_eq!(
+            db.find_matching_cidrs_fast("10.1.2.3".parse().unwrap())[0].0,
+            network
+        );
+        assert_eq!(
+            db.dataset_hash().as_deref().map(String::as_str),
+            Some("abc")
+        );
+        assert!(db.reload_if_changed(Some(&reloaded)).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_bucket_hashes_change_only_for_touched_bucket() {
+        let (_dir, db) = create_test_db();
+        let flags = ReputationFlags::default();
+
+        let mut txn = db.begin_write().unwrap();
+        for entry in ["10.0.0.0/8", "10.1.2.3", "192.168.0.0/16", "2001:db8::/32"] {
+            db.insert_record(&mut txn, entry, &flags).unwrap();
+        }
+        txn.commit().unwrap();
+
+        let before = db.bucket_hashes().unwrap();
+        assert_eq!(
+            before.buckets.keys().collect::<Vec<_>>(),
+            ["10.0.0.0/8", "192.0.0.0/8", "2001::/16"]
+        );
+        assert_eq!(db.bucket_hashes().unwrap(), before);
+
+        let mut txn = db.begin_write().unwrap();
+        let proxy = ReputationFlags {
+            proxy: true,
+            ..flags
+        };
+        db.insert_record(&mut txn, "10.1.2.3", &proxy).unwrap();
+        txn.commit().unwrap();
+
+        let after = db.bucket_hashes().unwrap();
+        assert_ne!(after.root, before.root);
+        assert_ne!(after.buckets["10.0.0.0/8"], before.buckets["10.0.0.0/8"]);
+        assert_eq!(after.buckets["192.0.0.0/8"], before.buckets["192.0.0.0/8"]);
+        assert_eq!(after.buckets["2001::/16"], before.buckets["2001::/16"]);
+    }
+
+    #[test]
+    fn test_replace_source_leaves_other_sources_untouched() {
+        let (_dir, db) = create_test_db();
+        let flags = |mask| ReputationFlags::from_mask(mask);
+        let (proxy, vpn, tor, webhost) = (1 << 1, 1 << 2, 1 << 7, 1 << 8);
+
+        let mut txn = db.begin_write().unwrap();
+        for (entry, mask, sources) in [
+            ("1.0.0.0/24", proxy | vpn, "proxy=a b|vpn=a"),
+            ("2.0.0.0/24", tor, "tor=b"),
+            ("3.0.0.0/24", proxy, "proxy=a"),
+        ] {
+            db.insert_record(&mut txn, entry, &flags(mask)).unwrap();
+            db.set_sources(&mut txn, entry, Some(sources)).unwrap();
+        }
+        db.set_note(&mut txn, "2.0.0.0/24", Some("kept")).unwrap();
+        txn.commit().unwrap();
+        db.rebuild_trie().unwrap();
+
+        let records = [
+            ("1.0.0.0/24".to_owned(), flags(webhost)),
+            ("5.5.5.0/24".to_owned(), flags(proxy)),
+        ];
+        assert_eq!(db.replace_source("a", &records).unwrap(), 3);
+
+        let entries = ["1.0.0.0/24", "2.0.0.0/24", "3.0.0.0/24", "5.5.5.0/24"];
+        let stored: Vec<_> = entries
+            .iter()
+            .map(|e| db.lookup_cidr(e.parse().unwrap()).unwrap())
+            .collect();
+        assert_eq!(
+            stored,
+            [
+                Some(flags(proxy | webhost)),
+                Some(flags(tor)),
+                None,
+                Some(flags(proxy))
+            ]
+        );
+        assert_eq!(
+            db.lookup_sources(entries).unwrap(),
+            [
+                Some("proxy=b|webhost=a".to_owned()),
+                Some("tor=b".to_owned()),
+                None,
+                Some("proxy=a".to_owned())
+            ]
+        );
+        assert_eq!(
+            db.lookup_notes(["2.0.0.0/24"]).unwrap(),
+            [Some("kept".to_owned())]
+        );
+        assert!(db
+            .find_matching_cidrs_fast("3.0.0.1".parse().unwrap())
+            .is_empty());
+        assert_eq!(db.get_metadata().unwrap().record_count, 3);
+        assert!(db.trie_consistency().unwrap().consistent);
+    }
+
+    #[test]
+    fn test_trie_consistency_detects_skipped_trie_update() {
+        let (_dir, db) = create_test_db();
+        let flags = ReputationFlags::default();
+
+        let mut txn = db.begin_write().unwrap();
+        db.insert_record(&mut txn, "10.0.0.0/8", &flags).unwrap();
+        db.insert_record(&mut txn, "1.2.3.4", &flags).unwrap();
+        txn.commit().unwrap();
+        db.rebuild_trie().unwrap();
+        assert!(db.trie_consistency().unwrap().consistent);
+
+        let mut txn = db.begin_write().unwrap();
+        db.insert_record(&mut txn, "2001:db8::/32", &flags).unwrap();
+        txn.commit().unwrap();
+        let consistency = db.trie_consistency().unwrap();
+        assert!(!consistency.consistent);
+        assert_eq!(consistency.trie_networks, 1);
+        assert_eq!(consistency.stored_networks, 2);
+
+        db.rebuild_trie().unwrap();
+        assert!(db.trie_consistency().unwrap().consistent);
+    }
+
     #[test]
     fn test_insert_and_lookup_cidr() {
         let (_dir, db) = create_test_db();
@@ -420,6 +1938,63 @@ mod tests {
         assert!(matches[0].1.cdn);
     }
 
+    #[test]
+    fn test_lookup_ip_txn_reads_snapshot() {
+        let (_dir, db) = create_test_db();
+        let flags = ReputationFlags {
+            vpn: true,
+            ..Default::default()
+        };
+
+        let mut txn = db.begin_write().unwrap();
+        db.insert_record(&mut txn, "10.0.0.1", &flags).unwrap();
+        txn.commit().unwrap();
+
+        let rtxn = db.read_txn().unwrap();
+        let mut txn = db.begin_write().unwrap();
+        db.insert_record(&mut txn, "10.0.0.2", &flags).unwrap();
+        txn.commit().unwrap();
+
+        assert!(db
+            .lookup_ip_txn(&rtxn, "10.0.0.1".parse().unwrap())
+            .unwrap()
+            .is_some());
+        assert!(db
+            .lookup_ip_txn(&rtxn, "10.0.0.2".parse().unwrap())
+            .unwrap()
+            .is_none());
+        drop(rtxn);
+
+        assert!(db.lookup_ip("10.0.0.2".parse().unwrap()).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_open_read_only_serves_existing_data() {
+        let (dir, db) = create_test_db();
+        let flags = ReputationFlags {
+            tor: true,
+            ..Default::default()
+        };
+        let mut txn = db.begin_write().unwrap();
+        db.insert_record(&mut txn, "10.0.0.0/8", &flags).unwrap();
+        db.insert_record(&mut txn, "1.1.1.1", &flags).unwrap();
+        txn.commit().unwrap();
+
+        let copy = TempDir::new().unwrap();
+        assert!(!Database::exists(copy.path()));
+        std::fs::copy(dir.path().join("data.mdb"), copy.path().join("data.mdb")).unwrap();
+        assert!(Database::exists(copy.path()));
+
+        let db = Database::open_read_only(copy.path()).unwrap();
+        assert!(db.lookup_ip("1.1.1.1".parse().unwrap()).unwrap().is_some());
+        assert_eq!(
+            db.find_matching_cidrs_fast("10.1.2.3".parse().unwrap())
+                .len(),
+            1
+        );
+        assert!(db.begin_write().is_err());
+    }
+
     #[test]
     fn test_ipv6_support() {
         let (_dir, db) = create_test_db();
@@ -440,4 +2015,35 @@ mod tests {
         let matches = db.find_matching_cidrs_fast("2001:db8::2".parse().unwrap());
         assert_eq!(matches.len(), 1);
     }
+
+    #[test]
+    fn test_rebuild_trie_counts_malformed_keys() {
+        let (_dir, db) = create_test_db();
+        let flags = ReputationFlags {
+            proxy: true,
+            ..Default::default()
+        };
+
+        let mut txn = db.begin_write().unwrap();
+        db.insert_record(&mut txn, "10.0.0.0/8", &flags).unwrap();
+        db.cidr_v4
+            .put(&mut txn, &[10, 0, 0, 0, 40], &flags)
+            .unwrap();
+        db.cidr_v6.put(&mut txn, &[0u8; 3], &flags).unwrap();
+        txn.commit().unwrap();
+
+        assert_eq!(db.rebuild_trie().unwrap(), 2);
+        assert_eq!(db.malformed_key_count(), 2);
+        let matches = db.find_matching_cidrs_fast("10.1.2.3".parse().unwrap());
+        assert_eq!(matches.len(), 1);
+        assert!(db.trie_consistency().unwrap().consistent);
+
+        let mut txn = db.begin_write().unwrap();
+        db.clear_all(&mut txn).unwrap();
+        db.insert_record(&mut txn, "10.0.0.0/8", &flags).unwrap();
+        txn.commit().unwrap();
+        assert_eq!(db.rebuild_trie().unwrap(), 0);
+        assert_eq!(db.malformed_key_count(), 0);
+        assert!(db.trie_consistency().unwrap().consistent);
+    }
 }
@@ -1,3 +1,7 @@
 mod lmdb;
 
-pub use lmdb::{Database, DbError, Metadata};
+pub use heed::RoTxn;
+pub use lmdb::{
+    parse_sources_column, BucketHashes, Compaction, Database, DbError, Metadata, PrefixStats,
+    TrieConsistency, DEFAULT_MAX_DBS,
+};
@@ -0,0 +1,55 @@
+use std::borrow::Cow;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use ipnetwork::IpNetwork;
+
+static ANONYMIZE: AtomicBool = AtomicBool::new(false);
+
+pub fn set_anonymize_ips(enabled: bool) {
+    ANONYMIZE.store(enabled, Ordering::Relaxed);
+}
+
+pub fn anonymize_ips() -> bool {
+    ANONYMIZE.load(Ordering::Relaxed)
+}
+
+/// Zeroes the last octet of an IPv4 address, or the last 80 bits of an IPv6 one.
+pub fn mask_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(v4) => IpAddr::V4(Ipv4Addr::from(u32::from(v4) & !0xff)),
+        IpAddr::V6(v6) => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !((1u128 << 80) - 1))),
+    }
+}
+
+/// Renders an IP or CIDR for logs, masked while `PROXYD_ANONYMIZE_IPS` is set.
+/// Strings that are neither are logged as they are.
+pub fn log_ip(value: &str) -> Cow<'_, str> {
+    if !anonymize_ips() {
+        return Cow::Borrowed(value);
+    }
+    if let Ok(ip) = value.parse::<IpAddr>() {
+        return Cow::Owned(mask_ip(ip).to_string());
+    }
+    match value.parse::<IpNetwork>() {
+        Ok(network) => Cow::Owned(format!("{}/{}", mask_ip(network.ip()), network.prefix())),
+        Err(_) => Cow::Borrowed(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mask_ip() {
+        assert_eq!(
+            mask_ip("203.0.113.77".parse().unwrap()),
+            "203.0.113.0".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            mask_ip("2001:db8:1234:5678:9abc::1".parse().unwrap()),
+            "2001:db8:1234::".parse::<IpAddr>().unwrap()
+        );
+    }
+}
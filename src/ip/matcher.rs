@@ -1,12 +1,17 @@
 use std::net::IpAddr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use chrono::Utc;
+use heed::RoTxn;
 use ipnetwork::IpNetwork;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use smallvec::SmallVec;
 use thiserror::Error;
+use tracing::{instrument, warn, Span};
 
+use super::{log_ip, MatchVec};
 use crate::db::{Database, DbError};
 
 #[derive(Error, Debug)]
@@ -15,10 +20,35 @@ pub enum LookupError {
     InvalidIp(String),
     #[error("Invalid CIDR notation: {0}")]
     InvalidCidr(String),
+    #[error("Invalid prefix length {0} for {1}")]
+    InvalidPrefix(u8, String),
     #[error("Database error: {0}")]
     Database(#[from] DbError),
 }
 
+impl LookupError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::InvalidIp(_) => "invalid_ip",
+            Self::InvalidCidr(_) => "invalid_cidr",
+            Self::InvalidPrefix(..) => "invalid_prefix",
+            Self::Database(_) => "database_error",
+        }
+    }
+}
+
+pub const FLAG_BITS: [&str; 9] = [
+    "anonblock",
+    "proxy",
+    "vpn",
+    "cdn",
+    "public_wifi",
+    "rangeblock",
+    "school_block",
+    "tor",
+    "webhost",
+];
+
 #[allow(clippy::struct_excessive_bools)]
 #[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
 pub struct ReputationFlags {
@@ -34,6 +64,43 @@ pub struct ReputationFlags {
 }
 
 impl ReputationFlags {
+    fn bits(&self) -> [bool; 9] {
+        [
+            self.anonblock,
+            self.proxy,
+            self.vpn,
+            self.cdn,
+            self.public_wifi,
+            self.rangeblock,
+            self.school_block,
+            self.tor,
+            self.webhost,
+        ]
+    }
+
+    pub fn to_mask(&self) -> u16 {
+        self.bits()
+            .iter()
+            .enumerate()
+            .filter(|(_, &set)| set)
+            .fold(0, |mask, (bit, _)| mask | (1 << bit))
+    }
+
+    pub fn from_mask(mask: u16) -> Self {
+        let bit = |i: u16| mask & (1 << i) != 0;
+        Self {
+            anonblock: bit(0),
+            proxy: bit(1),
+            vpn: bit(2),
+            cdn: bit(3),
+            public_wifi: bit(4),
+            rangeblock: bit(5),
+            school_block: bit(6),
+            tor: bit(7),
+            webhost: bit(8),
+        }
+    }
+
     pub fn merge(&self, other: &ReputationFlags) -> ReputationFlags {
         ReputationFlags {
             anonblock: self.anonblock || other.anonblock,
@@ -47,12 +114,28 @@ impl ReputationFlags {
             webhost: self.webhost || other.webhost,
         }
     }
+
+    pub fn intersect(&self, other: &ReputationFlags) -> ReputationFlags {
+        ReputationFlags {
+            anonblock: self.anonblock && other.anonblock,
+            proxy: self.proxy && other.proxy,
+            vpn: self.vpn && other.vpn,
+            cdn: self.cdn && other.cdn,
+            public_wifi: self.public_wifi && other.public_wifi,
+            rangeblock: self.rangeblock && other.rangeblock,
+            school_block: self.school_block && other.school_block,
+            tor: self.tor && other.tor,
+            webhost: self.webhost && other.webhost,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize)]
 pub struct MatchedEntry {
     pub entry: String,
     pub flags: ReputationFlags,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
 }
 
 pub type MatchedEntryVec = SmallVec<[MatchedEntry; 4]>;
@@ -61,72 +144,432 @@ pub type MatchedEntryVec = SmallVec<[MatchedEntry; 4]>;
 pub struct LookupResult {
     pub found: bool,
     pub query: String,
+    pub normalized_query: String,
     pub flags: ReputationFlags,
+    pub flags_mask: u16,
     pub matched_entries: MatchedEntryVec,
+    pub checked_at: i64,
+    pub dataset_hash: Option<String>,
+    pub degraded: bool,
+    /// Batch entry left unprocessed because the batch hit its soft deadline.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub timed_out: bool,
+}
+
+/// The verdict of a lookup alone, for embedders that do not need the query
+/// echo or the matched entries.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct LookupSummary {
+    pub found: bool,
+    pub flags: ReputationFlags,
+}
+
+pub const DEFAULT_PARALLEL_THRESHOLD: usize = 32;
+pub const DEFAULT_BATCH_CHUNK_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+pub struct LookupOptions {
+    pub serve_stale: bool,
+    pub parallel_threshold: usize,
+    /// Process every batch on the calling thread, regardless of its size.
+    pub sequential: bool,
+    /// Entries per unit of work when a batch is split across the rayon pool.
+    pub batch_chunk_size: usize,
+    /// Time after which a large batch stops starting new chunks and returns
+    /// the remaining entries marked `timed_out`.
+    pub batch_soft_deadline: Option<Duration>,
+}
+
+impl Default for LookupOptions {
+    fn default() -> Self {
+        Self {
+            serve_stale: false,
+            parallel_threshold: DEFAULT_PARALLEL_THRESHOLD,
+            sequential: false,
+            batch_chunk_size: DEFAULT_BATCH_CHUNK_SIZE,
+            batch_soft_deadline: None,
+        }
+    }
+}
+
+/// Maps batches below the parallel threshold directly. Larger ones are split
+/// into chunks, spread over the rayon pool unless `sequential`; a chunk that
+/// would start after `deadline` is skipped and its items come back as `None`.
+fn map_batch<T, R, F>(
+    items: &[T],
+    options: &LookupOptions,
+    deadline: Option<Instant>,
+    f: F,
+) -> Vec<Option<R>>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(usize, &T) -> R + Sync + Send,
+{
+    if items.len() < options.parallel_threshold {
+        return items
+            .iter()
+            .enumerate()
+            .map(|(i, item)| Some(f(i, item)))
+            .collect();
+    }
+
+    let chunk_size = options.batch_chunk_size.max(1);
+    let f = &f;
+    let expired = || deadline.is_some_and(|deadline| Instant::now() >= deadline);
+    if options.sequential {
+        items
+            .chunks(chunk_size)
+            .enumerate()
+            .flat_map(|(chunk_index, chunk)| {
+                let expired = expired();
+                chunk.iter().enumerate().map(move |(offset, item)| {
+                    (!expired).then(|| f(chunk_index * chunk_size + offset, item))
+                })
+            })
+            .collect()
+    } else {
+        items
+            .par_chunks(chunk_size)
+            .enumerate()
+            .flat_map_iter(|(chunk_index, chunk)| {
+                let expired = expired();
+                chunk.iter().enumerate().map(move |(offset, item)| {
+                    (!expired).then(|| f(chunk_index * chunk_size + offset, item))
+                })
+            })
+            .collect()
+    }
+}
+
+/// Fills the entries a batch's soft deadline skipped with `timed_out` results.
+fn complete_batch(
+    results: Vec<Option<LookupResult>>,
+    timed_out: impl Fn(usize) -> LookupResult,
+) -> Vec<LookupResult> {
+    let skipped = results.iter().filter(|result| result.is_none()).count();
+    if skipped > 0 {
+        warn!(
+            "Batch soft deadline reached, {} of {} entries left unprocessed",
+            skipped,
+            results.len()
+        );
+    }
+    results
+        .into_iter()
+        .enumerate()
+        .map(|(i, result)| result.unwrap_or_else(|| timed_out(i)))
+        .collect()
+}
+
+fn timed_out_result(
+    query: &str,
+    normalized_query: String,
+    checked_at: i64,
+    dataset_hash: Option<String>,
+) -> LookupResult {
+    LookupResult {
+        found: false,
+        query: query.to_owned(),
+        normalized_query,
+        flags: ReputationFlags::default(),
+        flags_mask: 0,
+        matched_entries: MatchedEntryVec::new(),
+        checked_at,
+        dataset_hash,
+        degraded: false,
+        timed_out: true,
+    }
+}
+
+/// Reads the exact matches of a range batch chunk by chunk, stopping at the
+/// soft deadline like [`map_batch`]; the networks past the returned length
+/// were not read. The read transaction cannot leave this thread, so the
+/// reads stay sequential.
+fn read_cidrs_until(
+    db: &Database,
+    txn: &RoTxn,
+    networks: &[IpNetwork],
+    options: &LookupOptions,
+    deadline: Option<Instant>,
+) -> Result<Vec<Option<ReputationFlags>>, LookupError> {
+    if networks.len() < options.parallel_threshold {
+        return Ok(db.lookup_cidrs_batch(txn, networks)?);
+    }
+    let mut results = Vec::with_capacity(networks.len());
+    for chunk in networks.chunks(options.batch_chunk_size.max(1)) {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            break;
+        }
+        results.extend(db.lookup_cidrs_batch(txn, chunk)?);
+    }
+    Ok(results)
+}
+
+fn record_found(result: LookupResult) -> LookupResult {
+    Span::current().record("found", result.found);
+    result
+}
+
+fn record_found_count(results: &[LookupResult]) {
+    Span::current().record("found", results.iter().filter(|r| r.found).count());
+}
+
+fn current_dataset_hash(db: &Database) -> Option<String> {
+    db.dataset_hash().map(|hash| (*hash).clone())
+}
+
+#[derive(Debug, Clone)]
+pub struct IpMatches {
+    pub matches: MatchVec,
+    pub degraded: bool,
+}
+
+impl IpMatches {
+    pub fn found(&self) -> bool {
+        !self.matches.is_empty()
+    }
+
+    pub fn flags(&self) -> ReputationFlags {
+        self.matches
+            .iter()
+            .fold(ReputationFlags::default(), |acc, (_, flags)| {
+                acc.merge(flags)
+            })
+    }
+}
+
+pub fn match_ip(
+    db: &Database,
+    ip: IpAddr,
+    options: &LookupOptions,
+) -> Result<IpMatches, LookupError> {
+    let exact = if db.cidr_only() {
+        Ok(None)
+    } else {
+        db.lookup_ip(ip)
+    };
+    let (exact, degraded) = match exact {
+        Ok(flags) => (flags, false),
+        Err(e) if options.serve_stale => {
+            warn!("Exact IP read failed, serving CIDR matches only: {}", e);
+            (None, true)
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut matches = MatchVec::new();
+    if let Some(flags) = exact {
+        matches.push((IpNetwork::from(ip), flags));
+    }
+    matches.extend(db.find_matching_cidrs_fast(ip));
+
+    Ok(IpMatches { matches, degraded })
+}
+
+impl From<&IpMatches> for LookupSummary {
+    fn from(matches: &IpMatches) -> Self {
+        Self {
+            found: matches.found(),
+            flags: matches.flags(),
+        }
+    }
+}
+
+/// Like [`lookup_ip`], but builds no strings and no matched entries. Lookups
+/// that match at most a handful of entries do not allocate.
+pub fn lookup_ip_summary(db: &Database, ip: IpAddr) -> Result<LookupSummary, LookupError> {
+    let matches = match_ip(db, ip, &LookupOptions::default())?;
+    Ok(LookupSummary::from(&matches))
+}
+
+fn attach_notes(db: &Database, entries: &mut MatchedEntryVec) {
+    if entries.is_empty() || !db.has_notes() {
+        return;
+    }
+    match db.lookup_notes(entries.iter().map(|e| e.entry.as_str())) {
+        Ok(notes) => {
+            for (entry, note) in entries.iter_mut().zip(notes) {
+                entry.note = note;
+            }
+        }
+        Err(e) => warn!("Failed to read entry notes: {}", e),
+    }
+}
+
+/// Reads a batch's notes from `txn`, the snapshot its exact matches came
+/// from, rather than from a fresh transaction per lookup.
+fn attach_batch_notes(db: &Database, txn: Option<&RoTxn>, results: &mut [Option<LookupResult>]) {
+    let Some(txn) = txn.filter(|_| db.has_notes()) else {
+        return;
+    };
+    for result in results.iter_mut().flatten() {
+        let entries = &mut result.matched_entries;
+        if entries.is_empty() {
+            continue;
+        }
+        match db.lookup_notes_txn(txn, entries.iter().map(|e| e.entry.as_str())) {
+            Ok(notes) => {
+                for (entry, note) in entries.iter_mut().zip(notes) {
+                    entry.note = note;
+                }
+            }
+            Err(e) => {
+                warn!("Failed to read entry notes: {}", e);
+                return;
+            }
+        }
+    }
 }
 
 pub fn lookup_ip(db: &Arc<Database>, ip_str: &str) -> Result<LookupResult, LookupError> {
+    lookup_ip_with(db, ip_str, &LookupOptions::default())
+}
+
+#[instrument(level = "debug", skip(db, ip_str, options), fields(ip = %log_ip(ip_str), found))]
+pub fn lookup_ip_with(
+    db: &Arc<Database>,
+    ip_str: &str,
+    options: &LookupOptions,
+) -> Result<LookupResult, LookupError> {
     let ip: IpAddr = ip_str
         .parse()
         .map_err(|_| LookupError::InvalidIp(ip_str.to_owned()))?;
 
-    let mut matched_entries = MatchedEntryVec::new();
+    let IpMatches { matches, degraded } = match_ip(db, ip, options)?;
+
+    let normalized_query = ip.to_string();
+    let host = IpNetwork::from(ip);
     let mut merged_flags = ReputationFlags::default();
+    let mut matched_entries: MatchedEntryVec = matches
+        .into_iter()
+        .map(|(network, flags)| {
+            merged_flags = merged_flags.merge(&flags);
+            let entry = if network == host {
+                normalized_query.clone()
+            } else {
+                network.to_string()
+            };
+            MatchedEntry {
+                entry,
+                flags,
+                note: None,
+            }
+        })
+        .collect();
+    attach_notes(db, &mut matched_entries);
 
-    if let Some(flags) = db.lookup_ip(ip)? {
-        matched_entries.push(MatchedEntry {
-            entry: ip.to_string(),
-            flags,
-        });
-        merged_flags = merged_flags.merge(&flags);
-    }
+    Ok(record_found(LookupResult {
+        found: !matched_entries.is_empty(),
+        query: ip_str.to_owned(),
+        normalized_query,
+        flags: merged_flags,
+        flags_mask: merged_flags.to_mask(),
+        matched_entries,
+        checked_at: Utc::now().timestamp(),
+        dataset_hash: current_dataset_hash(db),
+        degraded,
+        timed_out: false,
+    }))
+}
 
-    for (network, flags) in db.find_matching_cidrs_fast(ip) {
-        matched_entries.push(MatchedEntry {
-            entry: network.to_string(),
-            flags,
-        });
-        merged_flags = merged_flags.merge(&flags);
-    }
+/// Looks up the network of `prefix` bits containing the address, matching
+/// only stored CIDRs that cover all of it. Exact IP entries are not consulted.
+#[instrument(level = "debug", skip(db, ip_str), fields(ip = %log_ip(ip_str), found))]
+pub fn lookup_ip_prefix(
+    db: &Arc<Database>,
+    ip_str: &str,
+    prefix: u8,
+) -> Result<LookupResult, LookupError> {
+    let ip: IpAddr = ip_str
+        .parse()
+        .map_err(|_| LookupError::InvalidIp(ip_str.to_owned()))?;
+    let network = IpNetwork::new(ip, prefix)
+        .and_then(|n| IpNetwork::new(n.network(), prefix))
+        .map_err(|_| LookupError::InvalidPrefix(prefix, ip_str.to_owned()))?;
+
+    let mut merged_flags = ReputationFlags::default();
+    let mut matched_entries: MatchedEntryVec = db
+        .trie()
+        .find_supernets(network)
+        .into_iter()
+        .map(|(network, flags)| {
+            merged_flags = merged_flags.merge(&flags);
+            MatchedEntry {
+                entry: network.to_string(),
+                flags,
+                note: None,
+            }
+        })
+        .collect();
+    attach_notes(db, &mut matched_entries);
 
-    Ok(LookupResult {
+    Ok(record_found(LookupResult {
         found: !matched_entries.is_empty(),
         query: ip_str.to_owned(),
+        normalized_query: network.to_string(),
         flags: merged_flags,
+        flags_mask: merged_flags.to_mask(),
         matched_entries,
-    })
+        checked_at: Utc::now().timestamp(),
+        dataset_hash: current_dataset_hash(db),
+        degraded: false,
+        timed_out: false,
+    }))
 }
 
+#[instrument(level = "debug", skip(db, cidr_str), fields(cidr = %log_ip(cidr_str), found))]
 pub fn lookup_range(db: &Arc<Database>, cidr_str: &str) -> Result<LookupResult, LookupError> {
     let network: IpNetwork = cidr_str
         .parse()
         .map_err(|_| LookupError::InvalidCidr(cidr_str.to_owned()))?;
 
+    let normalized_query = network.to_string();
     let mut matched_entries = MatchedEntryVec::new();
 
     if let Some(flags) = db.lookup_cidr(network)? {
         matched_entries.push(MatchedEntry {
-            entry: network.to_string(),
+            entry: normalized_query.clone(),
             flags,
+            note: None,
         });
     }
+    attach_notes(db, &mut matched_entries);
 
     let merged_flags = matched_entries
         .iter()
         .fold(ReputationFlags::default(), |acc, e| acc.merge(&e.flags));
 
-    Ok(LookupResult {
+    Ok(record_found(LookupResult {
         found: !matched_entries.is_empty(),
         query: cidr_str.to_owned(),
+        normalized_query,
         flags: merged_flags,
+        flags_mask: merged_flags.to_mask(),
         matched_entries,
-    })
+        checked_at: Utc::now().timestamp(),
+        dataset_hash: current_dataset_hash(db),
+        degraded: false,
+        timed_out: false,
+    }))
 }
 
 pub fn lookup_ips_batch(
     db: &Arc<Database>,
     ip_strs: &[&str],
 ) -> Result<Vec<LookupResult>, LookupError> {
+    lookup_ips_batch_with(db, ip_strs, &LookupOptions::default())
+}
+
+#[instrument(level = "debug", skip_all, fields(count = ip_strs.len(), found))]
+pub fn lookup_ips_batch_with(
+    db: &Arc<Database>,
+    ip_strs: &[&str],
+    options: &LookupOptions,
+) -> Result<Vec<LookupResult>, LookupError> {
+    let deadline = options
+        .batch_soft_deadline
+        .map(|budget| Instant::now() + budget);
     let ips: Vec<IpAddr> = ip_strs
         .iter()
         .map(|s| {
@@ -135,41 +578,86 @@ pub fn lookup_ips_batch(
         })
         .collect::<Result<Vec<_>, _>>()?;
 
-    let db_results = db.lookup_ips_batch(&ips)?;
-
-    let results: Vec<LookupResult> = ips
-        .par_iter()
-        .zip(db_results.par_iter())
-        .zip(ip_strs.par_iter())
-        .map(|((ip, db_result), query)| {
-            let mut matched_entries = MatchedEntryVec::new();
-            let mut merged_flags = ReputationFlags::default();
-
-            if let Some(flags) = db_result {
-                matched_entries.push(MatchedEntry {
-                    entry: ip.to_string(),
-                    flags: *flags,
-                });
-                merged_flags = merged_flags.merge(flags);
-            }
-
-            for (network, flags) in db.find_matching_cidrs_fast(*ip) {
-                matched_entries.push(MatchedEntry {
-                    entry: network.to_string(),
-                    flags,
-                });
-                merged_flags = merged_flags.merge(&flags);
-            }
+    let rtxn = match db.read_txn() {
+        Ok(txn) => Some(txn),
+        Err(e) if options.serve_stale => {
+            warn!("LMDB read failed, serving CIDR matches only: {}", e);
+            None
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let exact = match &rtxn {
+        Some(txn) if !db.cidr_only() => db.lookup_ips_batch(txn, &ips),
+        _ => Ok(vec![None; ips.len()]),
+    };
+    let (db_results, degraded) = match exact {
+        Ok(results) => (results, rtxn.is_none()),
+        Err(e) if options.serve_stale => {
+            warn!(
+                "Exact IP batch read failed, serving CIDR matches only: {}",
+                e
+            );
+            (vec![None; ips.len()], true)
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let checked_at = Utc::now().timestamp();
+    let dataset_hash = current_dataset_hash(db);
+
+    let mut results = map_batch(&ips, options, deadline, |i, ip| {
+        let normalized_query = ip.to_string();
+        let mut matched_entries = MatchedEntryVec::new();
+        let mut merged_flags = ReputationFlags::default();
+
+        if let Some(flags) = db_results[i] {
+            matched_entries.push(MatchedEntry {
+                entry: normalized_query.clone(),
+                flags,
+                note: None,
+            });
+            merged_flags = merged_flags.merge(&flags);
+        }
 
-            LookupResult {
-                found: !matched_entries.is_empty(),
-                query: (*query).to_owned(),
-                flags: merged_flags,
-                matched_entries,
-            }
-        })
-        .collect();
+        let host = IpNetwork::from(*ip);
+        for (network, flags) in db.find_matching_cidrs_fast(*ip) {
+            matched_entries.push(MatchedEntry {
+                entry: if network == host {
+                    normalized_query.clone()
+                } else {
+                    network.to_string()
+                },
+                flags,
+                note: None,
+            });
+            merged_flags = merged_flags.merge(&flags);
+        }
 
+        LookupResult {
+            found: !matched_entries.is_empty(),
+            query: ip_strs[i].to_owned(),
+            normalized_query,
+            flags: merged_flags,
+            flags_mask: merged_flags.to_mask(),
+            matched_entries,
+            checked_at,
+            dataset_hash: dataset_hash.clone(),
+            degraded,
+            timed_out: false,
+        }
+    });
+
+    attach_batch_notes(db, rtxn.as_ref(), &mut results);
+    drop(rtxn);
+
+    let results = complete_batch(results, |i| {
+        timed_out_result(
+            ip_strs[i],
+            ips[i].to_string(),
+            checked_at,
+            dataset_hash.clone(),
+        )
+    });
+    record_found_count(&results);
     Ok(results)
 }
 
@@ -177,6 +665,37 @@ pub fn lookup_ranges_batch(
     db: &Arc<Database>,
     cidr_strs: &[&str],
 ) -> Result<Vec<LookupResult>, LookupError> {
+    lookup_ranges_batch_with(db, cidr_strs, &LookupOptions::default())
+}
+
+pub fn lookup_ranges_batch_with(
+    db: &Arc<Database>,
+    cidr_strs: &[&str],
+    options: &LookupOptions,
+) -> Result<Vec<LookupResult>, LookupError> {
+    lookup_ranges_batch_impl(db, cidr_strs, options, false)
+}
+
+/// Like [`lookup_ranges_batch_with`], but each result also lists the stored
+/// CIDRs that cover the queried range, after the exact match if there is one.
+pub fn lookup_ranges_batch_with_supernets(
+    db: &Arc<Database>,
+    cidr_strs: &[&str],
+    options: &LookupOptions,
+) -> Result<Vec<LookupResult>, LookupError> {
+    lookup_ranges_batch_impl(db, cidr_strs, options, true)
+}
+
+#[instrument(level = "debug", skip_all, fields(count = cidr_strs.len(), found))]
+fn lookup_ranges_batch_impl(
+    db: &Arc<Database>,
+    cidr_strs: &[&str],
+    options: &LookupOptions,
+    include_supernets: bool,
+) -> Result<Vec<LookupResult>, LookupError> {
+    let deadline = options
+        .batch_soft_deadline
+        .map(|budget| Instant::now() + budget);
     let networks: Vec<IpNetwork> = cidr_strs
         .iter()
         .map(|s| {
@@ -185,35 +704,68 @@ pub fn lookup_ranges_batch(
         })
         .collect::<Result<Vec<_>, _>>()?;
 
-    let db_results = db.lookup_cidrs_batch(&networks)?;
-
-    let results: Vec<LookupResult> = networks
-        .par_iter()
-        .zip(db_results.par_iter())
-        .zip(cidr_strs.par_iter())
-        .map(|((network, db_result), query)| {
-            let mut matched_entries = MatchedEntryVec::new();
-
-            if let Some(flags) = db_result {
-                matched_entries.push(MatchedEntry {
-                    entry: network.to_string(),
-                    flags: *flags,
-                });
-            }
-
-            let merged_flags = matched_entries
-                .iter()
-                .fold(ReputationFlags::default(), |acc, e| acc.merge(&e.flags));
-
-            LookupResult {
-                found: !matched_entries.is_empty(),
-                query: (*query).to_owned(),
-                flags: merged_flags,
-                matched_entries,
-            }
-        })
-        .collect();
+    let rtxn = db.read_txn()?;
+    let db_results = read_cidrs_until(db, &rtxn, &networks, options, deadline)?;
+    let checked_at = Utc::now().timestamp();
+    let dataset_hash = current_dataset_hash(db);
+    let trie = include_supernets.then(|| db.trie());
+
+    let read = &networks[..db_results.len()];
+    let mut results = map_batch(read, options, deadline, |i, network| {
+        let normalized_query = network.to_string();
+        let mut matched_entries = MatchedEntryVec::new();
+
+        if let Some(flags) = db_results[i] {
+            matched_entries.push(MatchedEntry {
+                entry: normalized_query.clone(),
+                flags,
+                note: None,
+            });
+        }
+        if let Some(trie) = &trie {
+            matched_entries.extend(
+                trie.find_supernets(*network)
+                    .into_iter()
+                    .filter(|(supernet, _)| supernet.prefix() < network.prefix())
+                    .map(|(supernet, flags)| MatchedEntry {
+                        entry: supernet.to_string(),
+                        flags,
+                        note: None,
+                    }),
+            );
+        }
 
+        let merged_flags = matched_entries
+            .iter()
+            .fold(ReputationFlags::default(), |acc, e| acc.merge(&e.flags));
+
+        LookupResult {
+            found: !matched_entries.is_empty(),
+            query: cidr_strs[i].to_owned(),
+            normalized_query,
+            flags: merged_flags,
+            flags_mask: merged_flags.to_mask(),
+            matched_entries,
+            checked_at,
+            dataset_hash: dataset_hash.clone(),
+            degraded: false,
+            timed_out: false,
+        }
+    });
+
+    results.resize_with(networks.len(), || None);
+    attach_batch_notes(db, Some(&rtxn), &mut results);
+    drop(rtxn);
+
+    let results = complete_batch(results, |i| {
+        timed_out_result(
+            cidr_strs[i],
+            networks[i].to_string(),
+            checked_at,
+            dataset_hash.clone(),
+        )
+    });
+    record_found_count(&results);
     Ok(results)
 }
 
@@ -221,6 +773,25 @@ pub fn lookup_ranges_batch(
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_flags_mask_round_trip() {
+        let flags = ReputationFlags {
+            anonblock: true,
+            tor: true,
+            webhost: true,
+            ..Default::default()
+        };
+        assert_eq!(flags.to_mask(), 0b1_1000_0001);
+        assert_eq!(ReputationFlags::from_mask(flags.to_mask()), flags);
+        assert_eq!(ReputationFlags::default().to_mask(), 0);
+
+        for bit in 0..FLAG_BITS.len() {
+            let mask = 1u16 << bit;
+            assert_eq!(ReputationFlags::from_mask(mask).to_mask(), mask);
+        }
+        assert_eq!(ReputationFlags::from_mask(u16::MAX).to_mask(), 0x1ff);
+    }
+
     #[test]
     fn test_reputation_flags_merge() {
         let a = ReputationFlags {
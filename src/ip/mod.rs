@@ -1,8 +1,13 @@
+mod anonymize;
 mod matcher;
 mod trie;
 
+pub use anonymize::{anonymize_ips, log_ip, mask_ip, set_anonymize_ips};
 pub use matcher::{
-    lookup_ip, lookup_ips_batch, lookup_range, lookup_ranges_batch, LookupError, LookupResult,
-    MatchedEntry, ReputationFlags,
+    lookup_ip, lookup_ip_prefix, lookup_ip_summary, lookup_ip_with, lookup_ips_batch,
+    lookup_ips_batch_with, lookup_range, lookup_ranges_batch, lookup_ranges_batch_with,
+    lookup_ranges_batch_with_supernets, match_ip, IpMatches, LookupError, LookupOptions,
+    LookupResult, LookupSummary, MatchedEntry, MatchedEntryVec, ReputationFlags,
+    DEFAULT_BATCH_CHUNK_SIZE, DEFAULT_PARALLEL_THRESHOLD, FLAG_BITS,
 };
-pub use trie::{IpTrie, MatchVec};
+pub use trie::{FamilyTrieStats, IpTrie, MatchVec, TrieStats};
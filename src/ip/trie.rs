@@ -1,17 +1,25 @@
 use std::net::IpAddr;
 
 use ipnetwork::IpNetwork;
+use serde::Serialize;
 use smallvec::SmallVec;
 
 use super::ReputationFlags;
 
 pub type MatchVec = SmallVec<[(IpNetwork, ReputationFlags); 4]>;
 
+/// Index of a node in [`IpTrie::nodes`].
+type NodeId = u32;
+
+/// Marks an absent child or an empty family.
+const NIL: NodeId = NodeId::MAX;
+
+#[derive(Clone)]
 struct PatriciaNode {
     prefix_bits: u128,
     prefix_len: u8,
     data: Option<(IpNetwork, ReputationFlags)>,
-    children: [Option<Box<PatriciaNode>>; 2],
+    children: [NodeId; 2],
 }
 
 impl PatriciaNode {
@@ -20,7 +28,7 @@ impl PatriciaNode {
             prefix_bits,
             prefix_len,
             data: None,
-            children: [None, None],
+            children: [NIL, NIL],
         }
     }
 
@@ -34,14 +42,37 @@ impl PatriciaNode {
             prefix_bits,
             prefix_len,
             data: Some((network, flags)),
-            children: [None, None],
+            children: [NIL, NIL],
         }
     }
 }
 
+/// Shape of one address family's trie; depths count nodes from the root (depth 1).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct FamilyTrieStats {
+    pub nodes: u64,
+    pub entries: u64,
+    pub max_depth: u32,
+    /// Mean depth of the nodes holding an entry.
+    pub avg_depth: f64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct TrieStats {
+    pub v4: FamilyTrieStats,
+    pub v6: FamilyTrieStats,
+}
+
+/// Both families share one node arena, so building a trie costs a handful of
+/// `Vec` growths rather than an allocation per node, and dropping or
+/// cloning it is a single buffer operation.
+#[derive(Clone)]
 pub struct IpTrie {
-    v4_root: Option<Box<PatriciaNode>>,
-    v6_root: Option<Box<PatriciaNode>>,
+    nodes: Vec<PatriciaNode>,
+    /// Slots of removed nodes, reused by later inserts.
+    free: Vec<NodeId>,
+    v4_root: NodeId,
+    v6_root: NodeId,
 }
 
 impl Default for IpTrie {
@@ -52,87 +83,259 @@ impl Default for IpTrie {
 
 impl IpTrie {
     pub fn new() -> Self {
+        Self::with_capacity(0)
+    }
+
+    /// Preallocates room for `entries` networks. Each rebuild starts from a
+    /// new trie sized this way, allocating its arena once instead of
+    /// regrowing it; the old trie's arena is freed with its last reader.
+    pub fn with_capacity(entries: usize) -> Self {
         Self {
-            v4_root: None,
-            v6_root: None,
+            // A Patricia trie needs at most one branch node per entry
+            nodes: Vec::with_capacity(entries.saturating_mul(2)),
+            free: Vec::new(),
+            v4_root: NIL,
+            v6_root: NIL,
         }
     }
 
+    fn alloc(&mut self, node: PatriciaNode) -> NodeId {
+        if let Some(id) = self.free.pop() {
+            self.nodes[id as usize] = node;
+            return id;
+        }
+        let id = NodeId::try_from(self.nodes.len())
+            .ok()
+            .filter(|&id| id != NIL)
+            .expect("trie node arena exceeds u32 indices");
+        self.nodes.push(node);
+        id
+    }
+
+    fn release(&mut self, id: NodeId) {
+        let node = &mut self.nodes[id as usize];
+        node.data = None;
+        node.children = [NIL, NIL];
+        self.free.push(id);
+    }
+
+    fn node(&self, id: NodeId) -> Option<&PatriciaNode> {
+        (id != NIL).then(|| &self.nodes[id as usize])
+    }
+
+    /// Inserts `network`; inserting the same network again ORs the flags,
+    /// matching how overlapping entries are merged at lookup time.
     pub fn insert(&mut self, network: IpNetwork, flags: ReputationFlags) {
+        self.insert_with(network, flags, true);
+    }
+
+    /// Inserts `network`, overwriting the flags of an existing entry.
+    pub fn replace(&mut self, network: IpNetwork, flags: ReputationFlags) {
+        self.insert_with(network, flags, false);
+    }
+
+    fn insert_with(&mut self, network: IpNetwork, flags: ReputationFlags, merge: bool) {
         match network {
             IpNetwork::V4(n) => {
                 let bits = u128::from(u32::from(n.network()));
                 let prefix = n.prefix();
-                Self::insert_node(&mut self.v4_root, bits, prefix, 32, network, flags);
+                self.v4_root =
+                    self.insert_node(self.v4_root, bits, prefix, 32, network, flags, merge);
             }
             IpNetwork::V6(n) => {
                 let bits = u128::from(n.network());
                 let prefix = n.prefix();
-                Self::insert_node(&mut self.v6_root, bits, prefix, 128, network, flags);
+                self.v6_root =
+                    self.insert_node(self.v6_root, bits, prefix, 128, network, flags, merge);
             }
         }
     }
 
+    /// Inserts below `id` and returns the id of the subtree's new root.
+    #[allow(clippy::too_many_arguments)]
     fn insert_node(
-        root: &mut Option<Box<PatriciaNode>>,
+        &mut self,
+        id: NodeId,
         bits: u128,
         prefix_len: u8,
         total_bits: u8,
         network: IpNetwork,
         flags: ReputationFlags,
-    ) {
-        if root.is_none() {
-            *root = Some(Box::new(PatriciaNode::new_leaf(
-                bits, prefix_len, network, flags,
-            )));
-            return;
-        }
-
-        let node = root.as_mut().unwrap();
-        let common_len = Self::common_prefix_len(
-            node.prefix_bits,
-            bits,
-            node.prefix_len.min(prefix_len),
-            total_bits,
-        );
-
-        if common_len == node.prefix_len && common_len == prefix_len {
+        merge: bool,
+    ) -> NodeId {
+        let Some(node) = self.node(id) else {
+            return self.alloc(PatriciaNode::new_leaf(bits, prefix_len, network, flags));
+        };
+        let (node_bits, node_len) = (node.prefix_bits, node.prefix_len);
+        let common_len =
+            Self::common_prefix_len(node_bits, bits, node_len.min(prefix_len), total_bits);
+
+        if common_len == node_len && common_len == prefix_len {
+            let node = &mut self.nodes[id as usize];
+            let flags = match &node.data {
+                Some((_, existing)) if merge => existing.merge(&flags),
+                _ => flags,
+            };
             node.data = Some((network, flags));
-            return;
+            return id;
         }
 
-        if common_len == node.prefix_len {
+        if common_len == node_len {
             let child_bit = Self::get_bit(bits, common_len, total_bits);
-            Self::insert_node(
-                &mut node.children[child_bit],
-                bits,
-                prefix_len,
-                total_bits,
-                network,
-                flags,
-            );
-            return;
+            let child = self.nodes[id as usize].children[child_bit];
+            let child =
+                self.insert_node(child, bits, prefix_len, total_bits, network, flags, merge);
+            self.nodes[id as usize].children[child_bit] = child;
+            return id;
         }
 
-        let old_node = root.take().unwrap();
         let common_prefix_bits = Self::mask_prefix(bits, common_len, total_bits);
-        let mut new_parent = Box::new(PatriciaNode::new(common_prefix_bits, common_len));
+        let mut new_parent = PatriciaNode::new(common_prefix_bits, common_len);
 
         if common_len == prefix_len {
             new_parent.data = Some((network, flags));
-            let old_bit = Self::get_bit(old_node.prefix_bits, common_len, total_bits);
-            new_parent.children[old_bit] = Some(old_node);
+            let old_bit = Self::get_bit(node_bits, common_len, total_bits);
+            new_parent.children[old_bit] = id;
         } else {
             let new_bit = Self::get_bit(bits, common_len, total_bits);
             let old_bit = 1 - new_bit;
 
-            new_parent.children[new_bit] = Some(Box::new(PatriciaNode::new_leaf(
-                bits, prefix_len, network, flags,
-            )));
-            new_parent.children[old_bit] = Some(old_node);
+            new_parent.children[new_bit] =
+                self.alloc(PatriciaNode::new_leaf(bits, prefix_len, network, flags));
+            new_parent.children[old_bit] = id;
         }
 
-        *root = Some(new_parent);
+        self.alloc(new_parent)
+    }
+
+    pub fn remove(&mut self, network: IpNetwork) -> bool {
+        let removed = match network {
+            IpNetwork::V4(n) => {
+                let bits = u128::from(u32::from(n.network()));
+                let (removed, root) = self.remove_node(self.v4_root, bits, n.prefix(), 32);
+                self.v4_root = root;
+                removed
+            }
+            IpNetwork::V6(n) => {
+                let bits = u128::from(n.network());
+                let (removed, root) = self.remove_node(self.v6_root, bits, n.prefix(), 128);
+                self.v6_root = root;
+                removed
+            }
+        };
+        if self.is_empty() {
+            // No node is reachable any more, so the free list can go too
+            self.nodes.clear();
+            self.free.clear();
+        }
+        removed
+    }
+
+    /// Removes below `id`, returning whether an entry was removed and the id
+    /// of the subtree's new root.
+    fn remove_node(
+        &mut self,
+        id: NodeId,
+        bits: u128,
+        prefix_len: u8,
+        total_bits: u8,
+    ) -> (bool, NodeId) {
+        let Some(node) = self.node(id) else {
+            return (false, NIL);
+        };
+
+        if node.prefix_len > prefix_len
+            || Self::common_prefix_len(node.prefix_bits, bits, node.prefix_len, total_bits)
+                < node.prefix_len
+        {
+            return (false, id);
+        }
+
+        let removed = if node.prefix_len == prefix_len {
+            self.nodes[id as usize].data.take().is_some()
+        } else {
+            let child_bit = Self::get_bit(bits, node.prefix_len, total_bits);
+            let (removed, child) =
+                self.remove_node(node.children[child_bit], bits, prefix_len, total_bits);
+            self.nodes[id as usize].children[child_bit] = child;
+            removed
+        };
+
+        let node = &self.nodes[id as usize];
+        if removed && node.data.is_none() {
+            match node.children {
+                [NIL, NIL] => {
+                    self.release(id);
+                    return (true, NIL);
+                }
+                [child, NIL] | [NIL, child] => {
+                    self.release(id);
+                    return (true, child);
+                }
+                _ => {}
+            }
+        }
+
+        (removed, id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.iter().filter(|node| node.data.is_some()).count()
+    }
+
+    pub fn stats(&self) -> TrieStats {
+        TrieStats {
+            v4: self.family_stats(self.v4_root),
+            v6: self.family_stats(self.v6_root),
+        }
+    }
+
+    fn family_stats(&self, root: NodeId) -> FamilyTrieStats {
+        let mut stats = FamilyTrieStats::default();
+        let mut entry_depth_sum = 0u64;
+        let mut stack: Vec<(NodeId, u32)> = vec![(root, 1)];
+
+        while let Some((id, depth)) = stack.pop() {
+            let Some(node) = self.node(id) else {
+                continue;
+            };
+            stats.nodes += 1;
+            stats.max_depth = stats.max_depth.max(depth);
+            if node.data.is_some() {
+                stats.entries += 1;
+                entry_depth_sum += u64::from(depth);
+            }
+            stack.extend(node.children.iter().map(|&child| (child, depth + 1)));
+        }
+
+        if stats.entries > 0 {
+            #[allow(clippy::cast_precision_loss)]
+            let avg = entry_depth_sum as f64 / stats.entries as f64;
+            stats.avg_depth = avg;
+        }
+        stats
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.v4_root == NIL && self.v6_root == NIL
+    }
+
+    /// Entries in pre-order, IPv4 first.
+    pub fn entries(&self) -> Vec<(IpNetwork, ReputationFlags)> {
+        let mut entries = Vec::new();
+        for root in [self.v4_root, self.v6_root] {
+            let mut stack = vec![root];
+            while let Some(id) = stack.pop() {
+                let Some(node) = self.node(id) else {
+                    continue;
+                };
+                if let Some(data) = node.data {
+                    entries.push(data);
+                }
+                stack.extend([node.children[1], node.children[0]]);
+            }
+        }
+        entries
     }
 
     fn common_prefix_len(a: u128, b: u128, max_len: u8, total_bits: u8) -> u8 {
@@ -171,22 +374,49 @@ impl IpTrie {
 
     pub fn find_all_matches(&self, ip: IpAddr) -> MatchVec {
         match ip {
-            IpAddr::V4(v4) => self.find_matches_impl(&self.v4_root, u128::from(u32::from(v4)), 32),
-            IpAddr::V6(v6) => self.find_matches_impl(&self.v6_root, u128::from(v6), 128),
+            IpAddr::V4(v4) => self.find_matches_impl(self.v4_root, u128::from(u32::from(v4)), 32),
+            IpAddr::V6(v6) => self.find_matches_impl(self.v6_root, u128::from(v6), 128),
         }
     }
 
-    #[allow(clippy::ref_option, clippy::unused_self)]
-    fn find_matches_impl(
-        &self,
-        root: &Option<Box<PatriciaNode>>,
-        ip_bits: u128,
-        total_bits: u8,
-    ) -> MatchVec {
+    pub fn find_supernets(&self, network: IpNetwork) -> MatchVec {
+        let (root, bits, total_bits) = match network {
+            IpNetwork::V4(n) => (self.v4_root, u128::from(u32::from(n.network())), 32),
+            IpNetwork::V6(n) => (self.v6_root, u128::from(n.network()), 128),
+        };
+        let prefix_len = network.prefix();
+
+        let mut matches = MatchVec::new();
+        let mut current = root;
+
+        while let Some(node) = self.node(current) {
+            if node.prefix_len > prefix_len
+                || Self::common_prefix_len(node.prefix_bits, bits, node.prefix_len, total_bits)
+                    < node.prefix_len
+            {
+                break;
+            }
+
+            if let Some(data) = node.data {
+                matches.push(data);
+            }
+
+            if node.prefix_len == prefix_len {
+                break;
+            }
+
+            let child_bit = Self::get_bit(bits, node.prefix_len, total_bits);
+            current = node.children[child_bit];
+        }
+
+        matches
+    }
+
+    fn find_matches_impl(&self, root: NodeId, ip_bits: u128, total_bits: u8) -> MatchVec {
         let mut matches = MatchVec::new();
         let mut current = root;
 
-        while let Some(node) = current {
+        while let Some(node) = self.node(current) {
             let common =
                 Self::common_prefix_len(node.prefix_bits, ip_bits, node.prefix_len, total_bits);
             if common < node.prefix_len {
@@ -202,7 +432,7 @@ impl IpTrie {
             }
 
             let child_bit = Self::get_bit(ip_bits, node.prefix_len, total_bits);
-            current = &node.children[child_bit];
+            current = node.children[child_bit];
         }
 
         matches
@@ -254,6 +484,57 @@ mod tests {
         assert_eq!(matches.len(), 2);
     }
 
+    #[test]
+    fn test_duplicate_insert_merges_flags() {
+        let mut trie = IpTrie::new();
+        for network in ["10.0.0.0/8", "0.0.0.0/0", "::/0"] {
+            trie.insert(
+                network.parse().unwrap(),
+                ReputationFlags {
+                    proxy: true,
+                    ..Default::default()
+                },
+            );
+            trie.insert(
+                network.parse().unwrap(),
+                ReputationFlags {
+                    vpn: true,
+                    ..Default::default()
+                },
+            );
+        }
+
+        let matches = trie.find_all_matches("10.1.2.3".parse().unwrap());
+        assert_eq!(matches.len(), 2);
+        assert!(matches.iter().all(|(_, f)| f.proxy && f.vpn));
+
+        let matches = trie.find_all_matches("2001:db8::1".parse().unwrap());
+        assert_eq!(matches.len(), 1);
+        assert!(matches[0].1.proxy && matches[0].1.vpn);
+    }
+
+    #[test]
+    fn test_stats_reports_depths_per_family() {
+        let mut trie = IpTrie::new();
+        assert_eq!(trie.stats(), TrieStats::default());
+
+        let flags = ReputationFlags::default();
+        trie.insert("10.0.0.0/8".parse().unwrap(), flags);
+        trie.insert("10.1.0.0/16".parse().unwrap(), flags);
+        trie.insert("10.1.2.0/24".parse().unwrap(), flags);
+        trie.insert("10.128.0.0/16".parse().unwrap(), flags);
+        trie.insert("2001:db8::/32".parse().unwrap(), flags);
+
+        let stats = trie.stats();
+        assert_eq!(stats.v4.entries, 4);
+        assert_eq!(stats.v4.nodes, 4);
+        assert_eq!(stats.v4.max_depth, 3);
+        assert!((stats.v4.avg_depth - 2.0).abs() < f64::EPSILON);
+        assert_eq!(stats.v6.nodes, 1);
+        assert_eq!(stats.v6.max_depth, 1);
+        assert!((stats.v6.avg_depth - 1.0).abs() < f64::EPSILON);
+    }
+
     #[test]
     fn test_v6() {
         let mut trie = IpTrie::new();
@@ -269,6 +550,85 @@ mod tests {
         assert!(matches[0].1.tor);
     }
 
+    #[test]
+    fn test_remove_prunes_and_keeps_other_entries() {
+        let mut trie = IpTrie::new();
+        let flags = ReputationFlags {
+            vpn: true,
+            ..Default::default()
+        };
+
+        trie.insert("10.0.0.0/8".parse().unwrap(), flags);
+        trie.insert("10.1.0.0/16".parse().unwrap(), flags);
+        trie.insert("10.2.0.0/16".parse().unwrap(), flags);
+
+        assert!(trie.remove("10.0.0.0/8".parse().unwrap()));
+        assert!(!trie.remove("10.0.0.0/8".parse().unwrap()));
+        assert!(!trie.remove("10.3.0.0/16".parse().unwrap()));
+
+        assert!(trie
+            .find_all_matches("10.0.0.1".parse().unwrap())
+            .is_empty());
+        assert_eq!(trie.find_all_matches("10.1.0.1".parse().unwrap()).len(), 1);
+        assert_eq!(trie.find_all_matches("10.2.0.1".parse().unwrap()).len(), 1);
+
+        assert_eq!(trie.len(), 2);
+        assert!(trie.remove("10.1.0.0/16".parse().unwrap()));
+        assert!(trie.remove("10.2.0.0/16".parse().unwrap()));
+        assert!(trie.entries().is_empty());
+        assert!(trie.is_empty());
+        assert_eq!(trie.len(), 0);
+        assert_eq!(trie.v4_root, NIL);
+        assert!(trie.nodes.is_empty());
+    }
+
+    #[test]
+    fn test_removed_nodes_are_reused() {
+        let mut trie = IpTrie::with_capacity(2);
+        let flags = ReputationFlags::default();
+
+        trie.insert("10.0.0.0/8".parse().unwrap(), flags);
+        trie.insert("10.1.0.0/16".parse().unwrap(), flags);
+        trie.insert("10.128.0.0/16".parse().unwrap(), flags);
+        let slots = trie.nodes.len();
+
+        assert!(trie.remove("10.1.0.0/16".parse().unwrap()));
+        assert_eq!(trie.free.len(), 1);
+        trie.insert("10.3.0.0/16".parse().unwrap(), flags);
+        assert_eq!(trie.nodes.len(), slots);
+        assert!(trie.free.is_empty());
+        assert_eq!(trie.find_all_matches("10.3.0.1".parse().unwrap()).len(), 2);
+        assert!(trie.find_all_matches("10.1.0.1".parse().unwrap()).len() == 1);
+    }
+
+    #[test]
+    fn test_find_supernets_nested() {
+        let mut trie = IpTrie::new();
+        let flags = ReputationFlags::default();
+
+        trie.insert("10.0.0.0/8".parse().unwrap(), flags);
+        trie.insert("10.1.0.0/16".parse().unwrap(), flags);
+        trie.insert("10.1.2.0/24".parse().unwrap(), flags);
+        trie.insert("10.1.2.0/25".parse().unwrap(), flags);
+
+        let supernets: Vec<IpNetwork> = trie
+            .find_supernets("10.1.2.0/24".parse().unwrap())
+            .iter()
+            .map(|(n, _)| *n)
+            .collect();
+        assert_eq!(
+            supernets,
+            vec![
+                "10.0.0.0/8".parse().unwrap(),
+                "10.1.0.0/16".parse().unwrap(),
+                "10.1.2.0/24".parse().unwrap(),
+            ]
+        );
+
+        assert_eq!(trie.find_supernets("10.2.0.0/16".parse().unwrap()).len(), 1);
+        assert!(trie.find_supernets("0.0.0.0/0".parse().unwrap()).is_empty());
+    }
+
     #[test]
     fn test_exact_match() {
         let mut trie = IpTrie::new();
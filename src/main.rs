@@ -1,75 +1,287 @@
 mod api;
 mod config;
-mod db;
-mod ip;
+mod maintenance;
 mod metrics;
+mod readiness;
 mod sync;
 
+use proxyd::{db, ip};
+
 use mimalloc::MiMalloc;
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
+use std::net::SocketAddr;
 use std::sync::Arc;
 
-use actix_web::{web, App, HttpServer};
+use actix_web::{middleware, web, App, HttpServer};
 use tokio_util::sync::CancellationToken;
-use tracing::{error, info};
+use tonic::codegen::tokio_stream::wrappers::ReceiverStream;
+use tonic::codegen::tokio_stream::StreamExt;
+use tonic::transport::server::TcpIncoming;
+use tracing::{error, info, warn};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::EnvFilter;
 
-use api::grpc::{configure_server, create_reflection_service, GrpcServerConfig, ProxyDService};
+use api::grpc::{
+    configure_server, create_health_service, create_reflection_service, follow_readiness,
+    set_health, GrpcServerConfig, ProxyDService,
+};
+use api::listener::bind_tcp;
+use api::middleware::{handler_timeout, lookup_limit};
 use api::rest::{configure, AppState};
+use api::unified::serve_unified;
+use api::LookupLimiter;
 use config::Config;
 use db::Database;
-use sync::scheduler::{initial_sync, run_scheduler};
+use maintenance::Maintenance;
+use readiness::Readiness;
+use sync::replica::run_replica_refresh;
+use sync::scheduler::{initial_sync, record_sync_outcome, refresh_health, run_scheduler};
+
+fn prepare_data_dir(config: &Config) -> std::io::Result<()> {
+    std::fs::create_dir_all(&config.data_dir)?;
+    config.restrict_permissions(&config.data_dir)?;
+
+    let probe = config.data_dir.join(".write-probe");
+    std::fs::write(&probe, b"")?;
+    std::fs::remove_file(&probe)
+}
+
+/// Held for the life of the process, so a second writer on the same data
+/// directory fails at startup instead of syncing alongside this one.
+fn lock_writer(config: &Config) -> Result<std::fs::File, Box<dyn std::error::Error>> {
+    let path = config.data_dir.join("writer.lock");
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(&path)?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(std::fs::TryLockError::WouldBlock) => Err(format!(
+            "another ProxyD writer holds {}; start further instances with PROXYD_READ_ONLY=true",
+            path.display()
+        )
+        .into()),
+        Err(std::fs::TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+/// Waits for SIGTERM or SIGINT. actix's own handlers are disabled so both go
+/// through the drain in `main` instead of stopping the REST server directly.
+async fn shutdown_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
+
+fn log_subscriber(
+    span_events: FmtSpan,
+) -> Result<impl tracing::Subscriber + Send + Sync, Box<dyn std::error::Error>> {
+    Ok(tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env().add_directive("proxyd=info".parse()?))
+        .with_span_events(span_events)
+        .finish())
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env().add_directive("proxyd=info".parse()?))
-        .init();
+    // The config decides how spans are logged, so parse it under a plain
+    // subscriber first to keep its warnings.
+    let mut config =
+        tracing::subscriber::with_default(log_subscriber(FmtSpan::NONE)?, Config::default);
+    let span_events = if config.log_spans {
+        FmtSpan::CLOSE
+    } else {
+        FmtSpan::NONE
+    };
+    log_subscriber(span_events)?.init();
 
     info!("ProxyD starting...");
 
-    let config = Config::default();
+    if let Some(threads) = config.rayon_threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("proxyd-rayon-{i}"))
+            .build_global()?;
+        info!(
+            "Using {} rayon threads for parsing and batch lookups",
+            threads
+        );
+    }
 
-    std::fs::create_dir_all(&config.data_dir)?;
+    let read_only = if config.read_only {
+        if !Database::exists(&config.db_path()) {
+            error!(
+                "PROXYD_READ_ONLY is set but {} holds no database; start the writer first",
+                config.db_path().display()
+            );
+            return Err("no database to serve in read-only mode".into());
+        }
+        info!("Read-only replica mode, reloading the dataset written by another process");
+        true
+    } else {
+        match prepare_data_dir(&config) {
+            Ok(()) => false,
+            Err(e) if Database::exists(&config.db_path()) => {
+                warn!(
+                    "Data directory {} is not writable ({}), serving the existing dataset in read-only mode with syncing disabled",
+                    config.data_dir.display(),
+                    e
+                );
+                true
+            }
+            Err(e) => {
+                error!(
+                    "Data directory {} is not writable and holds no database",
+                    config.data_dir.display()
+                );
+                return Err(e.into());
+            }
+        }
+    };
 
-    let db = Database::open(&config.db_path())?;
+    config.read_only = read_only;
+    let _writer_lock = if read_only {
+        None
+    } else {
+        Some(lock_writer(&config)?)
+    };
+    let db = if read_only {
+        Database::open_read_only_with_max_dbs(&config.db_path(), config.lmdb_max_dbs)?
+    } else {
+        let db = Database::open_with_max_dbs(&config.db_path(), config.lmdb_max_dbs)?;
+        config.restrict_permissions(&config.db_path())?;
+        db
+    };
+    db.set_cidr_only(config.cidr_only);
+    if !read_only {
+        let moved = db.migrate_host_entries()?;
+        if moved > 0 {
+            info!(
+                "Moved {} single-IP entries to the {} tables",
+                moved,
+                if config.cidr_only { "CIDR" } else { "exact-IP" }
+            );
+        }
+    }
 
     metrics::init_metrics();
+    sync::downloader::configure(config.download_settings());
+    api::sample_log::set_sample_rate(config.lookup_log_sample_rate);
+    ip::set_anonymize_ips(config.anonymize_ips);
 
-    if let Err(e) = initial_sync(&db, &config).await {
-        error!("Initial sync failed: {}", e);
-        metrics::set_health_status(false);
+    let maintenance = Maintenance::new(config.maintenance);
+
+    if read_only {
+        refresh_health(&db, &config);
+    } else if maintenance.is_enabled() && db.is_empty().is_ok_and(|empty| !empty) {
+        info!("Maintenance mode enabled, skipping initial sync");
+        refresh_health(&db, &config);
     } else {
-        metrics::set_health_status(true);
+        let result = initial_sync(&db, &config).await;
+        if let Err(e) = &result {
+            error!("Initial sync failed: {}", e);
+        }
+        record_sync_outcome(&db, &config, result.is_ok());
+    }
+    let readiness = Readiness::default();
+    readiness.refresh(&db);
+    if !readiness.is_ready() {
+        warn!("No data loaded yet, gRPC health reports NOT_SERVING until an import succeeds");
+    }
+
+    if config.lmdb_warmup {
+        let started = std::time::Instant::now();
+        match db.warmup() {
+            Ok(bytes) => info!(
+                "LMDB warmup read {} bytes in {:?}",
+                bytes,
+                started.elapsed()
+            ),
+            Err(e) => warn!("LMDB warmup failed: {}", e),
+        }
     }
 
     let db_for_rest = Arc::clone(&db);
     let db_for_grpc = Arc::clone(&db);
     let db_for_scheduler = Arc::clone(&db);
     let config_for_scheduler = config.clone();
+    let readiness_for_scheduler = readiness.clone();
+    let maintenance_for_scheduler = maintenance.clone();
 
     let shutdown_token = CancellationToken::new();
     let scheduler_token = shutdown_token.clone();
 
     let scheduler_handle = tokio::spawn(async move {
-        run_scheduler(db_for_scheduler, config_for_scheduler, scheduler_token).await;
+        if read_only {
+            run_replica_refresh(
+                db_for_scheduler,
+                config_for_scheduler.replica_refresh,
+                readiness_for_scheduler,
+                scheduler_token,
+            )
+            .await;
+            return;
+        }
+        run_scheduler(
+            db_for_scheduler,
+            config_for_scheduler,
+            readiness_for_scheduler,
+            maintenance_for_scheduler,
+            scheduler_token,
+        )
+        .await;
     });
 
     let grpc_addr = format!("0.0.0.0:{}", config.grpc_port).parse()?;
-    let grpc_service = ProxyDService::new(db_for_grpc);
+    let grpc_listener = tokio::net::TcpListener::from_std(bind_tcp(grpc_addr, config.reuse_port)?)?;
+    let limiter = LookupLimiter::new(config.max_concurrent_lookups).with_class_limits(
+        config.max_concurrent_single_lookups,
+        config.max_concurrent_batch_lookups,
+    );
+    let grpc_service = ProxyDService::new(db_for_grpc, config.lookup_options())
+        .with_limiter(limiter.clone())
+        .with_request_timeout(config.grpc_request_timeout)
+        .with_import(Arc::new(config.clone()))
+        .with_readiness(readiness.clone());
 
+    let grpc_api_keys = config.api_keys.clone();
     let grpc_token = shutdown_token.clone();
-    let grpc_config = GrpcServerConfig::default();
+    let grpc_config = GrpcServerConfig {
+        request_timeout: config.grpc_request_timeout,
+        ..Default::default()
+    };
+    let grpc_incoming = TcpIncoming::from_listener(
+        grpc_listener,
+        grpc_config.tcp_nodelay,
+        Some(grpc_config.tcp_keepalive),
+    )
+    .map_err(|e| e as Box<dyn std::error::Error>)?;
     let reflection_service = create_reflection_service();
+    let (mut grpc_health, grpc_health_service) = create_health_service(false).await;
+    tokio::spawn(follow_readiness(grpc_health.clone(), readiness.subscribe()));
+    let (unified_tx, unified_rx) = tokio::sync::mpsc::channel(64);
+    let grpc_incoming = grpc_incoming.merge(ReceiverStream::new(unified_rx).map(Ok));
     let grpc_handle = tokio::spawn(async move {
         info!("gRPC server listening on {}", grpc_addr);
         if let Err(e) = configure_server(&grpc_config)
             .add_service(reflection_service)
-            .add_service(grpc_service.into_server())
-            .serve_with_shutdown(grpc_addr, grpc_token.cancelled())
+            .add_service(grpc_health_service)
+            .add_service(grpc_service.into_authenticated_server(&grpc_api_keys))
+            .serve_with_incoming_shutdown(grpc_incoming, grpc_token.cancelled())
             .await
         {
             error!("gRPC server error: {}", e);
@@ -77,21 +289,61 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         info!("gRPC server stopped");
     });
 
-    let rest_addr = format!("0.0.0.0:{}", config.rest_port);
+    let rest_addr = format!("0.0.0.0:{}", config.rest_port).parse()?;
+    let rest_listener = bind_tcp(rest_addr, config.reuse_port)?;
     info!("REST server listening on {}", rest_addr);
+    if config.reuse_port {
+        info!("SO_REUSEPORT enabled, listening ports may be shared with other instances");
+    }
 
+    let lookup_options = config.lookup_options();
+    let rest_request_timeout = config.rest_request_timeout;
+    let rest_config = Arc::new(config.clone());
+    let rest_readiness = readiness.clone();
+    let rest_maintenance = maintenance.clone();
     let rest_server = HttpServer::new(move || {
         let state = AppState {
             db: Arc::clone(&db_for_rest),
+            lookup_options,
+            request_timeout: rest_request_timeout,
+            config: Arc::clone(&rest_config),
+            limiter: limiter.clone(),
+            readiness: rest_readiness.clone(),
+            maintenance: rest_maintenance.clone(),
         };
         App::new()
             .app_data(web::Data::new(state))
+            .wrap(middleware::from_fn(handler_timeout))
+            .wrap(middleware::from_fn(lookup_limit))
             .configure(configure)
     })
     .workers(num_cpus::get())
-    .bind(&rest_addr)?
+    .disable_signals()
+    // actix takes whole seconds; the timeout around the join below enforces
+    // the exact value.
+    .shutdown_timeout(config.shutdown_timeout.as_secs_f64().ceil() as u64)
+    .listen(rest_listener)?
     .run();
 
+    if let Some(port) = config.unified_port {
+        let unified_addr: SocketAddr = format!("0.0.0.0:{port}").parse()?;
+        let unified_listener =
+            tokio::net::TcpListener::from_std(bind_tcp(unified_addr, config.reuse_port)?)?;
+        let rest_loopback = SocketAddr::from(([127, 0, 0, 1], config.rest_port));
+        info!(
+            "Unified listener on {} (HTTP/2 to gRPC, HTTP/1.1 to REST)",
+            unified_addr
+        );
+        tokio::spawn(serve_unified(
+            unified_listener,
+            rest_loopback,
+            unified_tx,
+            shutdown_token.clone(),
+        ));
+    } else {
+        drop(unified_tx);
+    }
+
     let rest_handle = rest_server.handle();
     let rest_token = shutdown_token.clone();
 
@@ -108,13 +360,22 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         info!("REST server stopped");
     });
 
-    tokio::signal::ctrl_c().await?;
+    shutdown_signal().await?;
     info!("Received shutdown signal, initiating graceful shutdown");
 
+    readiness.drain();
+    set_health(&mut grpc_health, false).await;
+    if !config.shutdown_grace.is_zero() {
+        info!(
+            "Reporting not serving for {:?} before closing listeners",
+            config.shutdown_grace
+        );
+        tokio::time::sleep(config.shutdown_grace).await;
+    }
+
     shutdown_token.cancel();
 
-    let shutdown_timeout = std::time::Duration::from_secs(10);
-    let _ = tokio::time::timeout(shutdown_timeout, async {
+    let _ = tokio::time::timeout(config.shutdown_timeout, async {
         let _ = tokio::join!(
             scheduler_handle,
             grpc_handle,
@@ -0,0 +1,36 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use tracing::info;
+
+use crate::metrics;
+
+/// Operator switch that freezes the dataset: scheduled syncs are skipped while
+/// lookups keep being served. On-demand syncs and imports still run.
+#[derive(Clone, Default)]
+pub struct Maintenance {
+    enabled: Arc<AtomicBool>,
+}
+
+impl Maintenance {
+    pub fn new(enabled: bool) -> Self {
+        let maintenance = Self::default();
+        maintenance.set(enabled);
+        maintenance
+    }
+
+    pub fn set(&self, enabled: bool) {
+        let previous = self.enabled.swap(enabled, Ordering::Relaxed);
+        metrics::set_maintenance_mode(enabled);
+        if previous != enabled {
+            info!(
+                "Maintenance mode {}",
+                if enabled { "enabled" } else { "disabled" }
+            );
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+}
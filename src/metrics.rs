@@ -2,12 +2,63 @@ use metrics::{counter, describe_counter, describe_gauge, describe_histogram, gau
 use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
 use std::sync::OnceLock;
 
+use crate::ip::{TrieStats, FLAG_BITS};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GrpcMethod {
+    LookupIp,
+    LookupRange,
+    BatchLookupIp,
+    BatchLookupRange,
+    BatchLookup,
+    Import,
+    TriggerSync,
+}
+
+impl GrpcMethod {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::LookupIp => "lookup_ip",
+            Self::LookupRange => "lookup_range",
+            Self::BatchLookupIp => "batch_lookup_ip",
+            Self::BatchLookupRange => "batch_lookup_range",
+            Self::BatchLookup => "batch_lookup",
+            Self::Import => "import",
+            Self::TriggerSync => "trigger_sync",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LookupClass {
+    Single,
+    Batch,
+}
+
+impl LookupClass {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Single => "single",
+            Self::Batch => "batch",
+        }
+    }
+}
+
 static PROMETHEUS_HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
 
 const LOOKUP_LATENCY_BUCKETS: &[f64] = &[0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
 
 const SYNC_DURATION_BUCKETS: &[f64] = &[1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0];
 
+const ADMIN_RPC_DURATION_BUCKETS: &[f64] =
+    &[0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0];
+
+const MATCHED_ENTRIES_BUCKETS: &[f64] = &[0.0, 1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0];
+
+const BATCH_FOUND_RATIO_BUCKETS: &[f64] = &[0.0, 0.01, 0.05, 0.1, 0.25, 0.5, 0.75, 1.0];
+
+const IMPORT_PHASE_BUCKETS: &[f64] = &[0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0];
+
 pub fn init_metrics() -> &'static PrometheusHandle {
     PROMETHEUS_HANDLE.get_or_init(|| {
         let handle = PrometheusBuilder::new()
@@ -16,16 +67,48 @@ pub fn init_metrics() -> &'static PrometheusHandle {
                 LOOKUP_LATENCY_BUCKETS,
             )
             .expect("failed to set lookup latency buckets")
+            .set_buckets_for_metric(
+                Matcher::Full("proxyd_grpc_request_duration_seconds".to_string()),
+                LOOKUP_LATENCY_BUCKETS,
+            )
+            .expect("failed to set gRPC request duration buckets")
+            .set_buckets_for_metric(
+                Matcher::Full("proxyd_grpc_admin_duration_seconds".to_string()),
+                ADMIN_RPC_DURATION_BUCKETS,
+            )
+            .expect("failed to set gRPC admin duration buckets")
             .set_buckets_for_metric(
                 Matcher::Full("proxyd_sync_duration_seconds".to_string()),
                 SYNC_DURATION_BUCKETS,
             )
             .expect("failed to set sync duration buckets")
+            .set_buckets_for_metric(
+                Matcher::Full("proxyd_matched_entries".to_string()),
+                MATCHED_ENTRIES_BUCKETS,
+            )
+            .expect("failed to set matched entries buckets")
+            .set_buckets_for_metric(
+                Matcher::Full("proxyd_batch_found_ratio".to_string()),
+                BATCH_FOUND_RATIO_BUCKETS,
+            )
+            .expect("failed to set batch found ratio buckets")
+            .set_buckets_for_metric(
+                Matcher::Full("proxyd_import_parse_duration_seconds".to_string()),
+                IMPORT_PHASE_BUCKETS,
+            )
+            .expect("failed to set import parse duration buckets")
+            .set_buckets_for_metric(
+                Matcher::Full("proxyd_import_commit_duration_seconds".to_string()),
+                IMPORT_PHASE_BUCKETS,
+            )
+            .expect("failed to set import commit duration buckets")
             .install_recorder()
             .expect("failed to install Prometheus recorder");
 
         register_metric_descriptions();
         set_build_info();
+        // The breaker starts closed; export that before any sync records it
+        set_download_circuit_open(false);
 
         handle
     })
@@ -45,15 +128,75 @@ fn register_metric_descriptions() {
         "proxyd_last_sync_timestamp",
         "Unix timestamp of the last successful sync"
     );
+    describe_gauge!(
+        "proxyd_trie_malformed_keys",
+        "Number of malformed CIDR keys skipped during the last trie rebuild"
+    );
+    describe_gauge!(
+        "proxyd_trie_nodes",
+        "Number of nodes in the in-memory CIDR trie, by address family"
+    );
+    describe_gauge!(
+        "proxyd_trie_max_depth",
+        "Deepest node in the in-memory CIDR trie, by address family"
+    );
+    describe_gauge!(
+        "proxyd_trie_avg_depth",
+        "Mean depth of the CIDR trie nodes holding an entry, by address family"
+    );
+    describe_gauge!(
+        "proxyd_sync_consecutive_failures",
+        "Number of syncs that have failed in a row since the last successful one"
+    );
+    describe_gauge!(
+        "proxyd_download_circuit_open",
+        "Whether scheduled syncs are backing off after repeated download failures (1 = open)"
+    );
+    describe_gauge!(
+        "proxyd_lmdb_file_bytes",
+        "Size of the LMDB data file when it was last compacted"
+    );
+    describe_gauge!(
+        "proxyd_lmdb_compacted_bytes",
+        "Size of the compacted copy staged by the last compaction"
+    );
+    describe_gauge!(
+        "proxyd_maintenance_mode",
+        "Whether maintenance mode is pausing scheduled syncs (1 = paused)"
+    );
+    describe_gauge!(
+        "proxyd_import_flag_count",
+        "Number of records carrying each flag in the last imported feed"
+    );
+    describe_gauge!(
+        "proxyd_import_aggregated_entries",
+        "Number of entries collapsed by CIDR aggregation during the last import"
+    );
     describe_counter!(
         "proxyd_sync_success_total",
         "Total number of successful syncs"
     );
     describe_counter!("proxyd_sync_failures_total", "Total number of failed syncs");
+    describe_counter!(
+        "proxyd_sync_aborted_total",
+        "Total number of imports aborted by the empty-feed or mass-delete guard"
+    );
+    describe_counter!(
+        "proxyd_lookups_rejected_total",
+        "Total number of lookups rejected by a concurrency limit, by lookup class"
+    );
     describe_counter!("proxyd_lookup_hits_total", "Total number of lookup hits");
     describe_counter!(
         "proxyd_grpc_requests_total",
-        "Total number of gRPC requests"
+        "Total number of gRPC requests by method"
+    );
+    describe_histogram!(
+        "proxyd_grpc_request_duration_seconds",
+        "gRPC request duration in seconds by method"
+    );
+    describe_histogram!(
+        "proxyd_grpc_admin_duration_seconds",
+        "Import and TriggerSync RPC duration in seconds by method"
     );
     describe_counter!(
         "proxyd_rest_requests_total",
@@ -63,10 +206,34 @@ fn register_metric_descriptions() {
         "proxyd_lookup_latency_seconds",
         "Lookup request latency in seconds"
     );
+    describe_histogram!(
+        "proxyd_matched_entries",
+        "Number of matched entries per lookup result"
+    );
+    describe_counter!(
+        "proxyd_batch_items_total",
+        "Items looked up in batch requests, by whether they were found"
+    );
+    describe_histogram!(
+        "proxyd_batch_found_ratio",
+        "Fraction of items found in each batch request"
+    );
     describe_histogram!(
         "proxyd_sync_duration_seconds",
         "Sync operation duration in seconds"
     );
+    describe_counter!(
+        "proxyd_import_parse_strategy_total",
+        "Imports parsed in one parallel pass or in bounded chunks, by strategy"
+    );
+    describe_histogram!(
+        "proxyd_import_parse_duration_seconds",
+        "Time spent parsing the CSV during an import in seconds"
+    );
+    describe_histogram!(
+        "proxyd_import_commit_duration_seconds",
+        "Time spent writing and committing records to LMDB during an import in seconds"
+    );
 }
 
 fn set_build_info() {
@@ -81,6 +248,29 @@ pub fn set_last_sync_timestamp(timestamp: i64) {
     gauge!("proxyd_last_sync_timestamp").set(timestamp as f64);
 }
 
+pub fn set_trie_malformed_keys(count: u64) {
+    gauge!("proxyd_trie_malformed_keys").set(count as f64);
+}
+
+pub fn set_trie_stats(stats: &TrieStats) {
+    for (family, family_stats) in [("v4", &stats.v4), ("v6", &stats.v6)] {
+        gauge!("proxyd_trie_nodes", "family" => family).set(family_stats.nodes as f64);
+        gauge!("proxyd_trie_max_depth", "family" => family).set(f64::from(family_stats.max_depth));
+        gauge!("proxyd_trie_avg_depth", "family" => family).set(family_stats.avg_depth);
+    }
+}
+
+pub fn set_aggregated_entries(count: u64) {
+    gauge!("proxyd_import_aggregated_entries").set(count as f64);
+}
+
+/// `counts` is in `FLAG_BITS` order.
+pub fn set_import_flag_counts(counts: &[u64]) {
+    for (flag, count) in FLAG_BITS.iter().zip(counts) {
+        gauge!("proxyd_import_flag_count", "flag" => *flag).set(*count as f64);
+    }
+}
+
 pub fn inc_sync_success() {
     counter!("proxyd_sync_success_total").increment(1);
 }
@@ -89,6 +279,27 @@ pub fn inc_sync_failures() {
     counter!("proxyd_sync_failures_total").increment(1);
 }
 
+pub fn inc_sync_aborted() {
+    counter!("proxyd_sync_aborted_total").increment(1);
+}
+
+pub fn set_maintenance_mode(enabled: bool) {
+    gauge!("proxyd_maintenance_mode").set(if enabled { 1.0 } else { 0.0 });
+}
+
+pub fn record_compaction(before_bytes: u64, after_bytes: u64) {
+    gauge!("proxyd_lmdb_file_bytes").set(before_bytes as f64);
+    gauge!("proxyd_lmdb_compacted_bytes").set(after_bytes as f64);
+}
+
+pub fn set_sync_consecutive_failures(failures: usize) {
+    gauge!("proxyd_sync_consecutive_failures").set(failures as f64);
+}
+
+pub fn set_download_circuit_open(open: bool) {
+    gauge!("proxyd_download_circuit_open").set(if open { 1.0 } else { 0.0 });
+}
+
 pub fn set_health_status(healthy: bool) {
     gauge!("proxyd_up").set(if healthy { 1.0 } else { 0.0 });
 }
@@ -97,6 +308,18 @@ pub fn record_sync_duration(seconds: f64) {
     histogram!("proxyd_sync_duration_seconds").record(seconds);
 }
 
+pub fn inc_import_parse_strategy(strategy: &'static str) {
+    counter!("proxyd_import_parse_strategy_total", "strategy" => strategy).increment(1);
+}
+
+pub fn record_import_parse_duration(seconds: f64) {
+    histogram!("proxyd_import_parse_duration_seconds").record(seconds);
+}
+
+pub fn record_import_commit_duration(seconds: f64) {
+    histogram!("proxyd_import_commit_duration_seconds").record(seconds);
+}
+
 pub fn inc_lookup_hits() {
     counter!("proxyd_lookup_hits_total").increment(1);
 }
@@ -105,8 +328,33 @@ pub fn record_lookup_latency(seconds: f64) {
     histogram!("proxyd_lookup_latency_seconds").record(seconds);
 }
 
-pub fn inc_grpc_requests() {
-    counter!("proxyd_grpc_requests_total").increment(1);
+pub fn record_matched_entries(count: usize) {
+    histogram!("proxyd_matched_entries").record(count as f64);
+}
+
+pub fn record_batch_found(found: usize, total: usize) {
+    if total == 0 {
+        return;
+    }
+    counter!("proxyd_batch_items_total", "result" => "found").increment(found as u64);
+    counter!("proxyd_batch_items_total", "result" => "not_found").increment((total - found) as u64);
+    histogram!("proxyd_batch_found_ratio").record(found as f64 / total as f64);
+}
+
+pub fn inc_lookups_rejected(class: LookupClass) {
+    counter!("proxyd_lookups_rejected_total", "class" => class.as_str()).increment(1);
+}
+
+pub fn inc_grpc_requests(method: GrpcMethod) {
+    counter!("proxyd_grpc_requests_total", "method" => method.as_str()).increment(1);
+}
+
+pub fn record_grpc_duration(method: GrpcMethod, seconds: f64) {
+    histogram!("proxyd_grpc_request_duration_seconds", "method" => method.as_str()).record(seconds);
+}
+
+pub fn record_grpc_admin_duration(method: GrpcMethod, seconds: f64) {
+    histogram!("proxyd_grpc_admin_duration_seconds", "method" => method.as_str()).record(seconds);
 }
 
 pub fn inc_rest_requests() {
@@ -0,0 +1,70 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::watch;
+use tracing::info;
+
+use crate::db::Database;
+
+struct Inner {
+    ready: watch::Sender<bool>,
+    draining: AtomicBool,
+}
+
+/// Whether the instance has data to serve. Starts not ready and is
+/// re-evaluated against the database after the initial sync and every import.
+#[derive(Clone)]
+pub struct Readiness {
+    inner: Arc<Inner>,
+}
+
+impl Default for Readiness {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                ready: watch::Sender::new(false),
+                draining: AtomicBool::new(false),
+            }),
+        }
+    }
+}
+
+impl Readiness {
+    pub fn refresh(&self, db: &Database) {
+        let ready =
+            !self.is_draining() && db.is_healthy() && db.is_empty().is_ok_and(|empty| !empty);
+        self.set(ready);
+    }
+
+    /// Marks the instance not ready for good, ahead of shutdown.
+    pub fn drain(&self) {
+        self.inner.draining.store(true, Ordering::Relaxed);
+        self.set(false);
+    }
+
+    /// Whether [`Self::drain`] has been called.
+    pub fn is_draining(&self) -> bool {
+        self.inner.draining.load(Ordering::Relaxed)
+    }
+
+    pub fn is_ready(&self) -> bool {
+        *self.inner.ready.borrow()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.inner.ready.subscribe()
+    }
+
+    fn set(&self, ready: bool) {
+        let changed = self
+            .inner
+            .ready
+            .send_if_modified(|current| std::mem::replace(current, ready) != ready);
+        if changed {
+            info!(
+                "Readiness changed: {}",
+                if ready { "serving" } else { "not serving" }
+            );
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use ipnetwork::IpNetwork;
+
+use super::importer::CsvRecord;
+use crate::ip::ReputationFlags;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Block {
+    start: u128,
+    prefix: u8,
+}
+
+impl Block {
+    fn size(self, bits: u8) -> Option<u128> {
+        1u128.checked_shl(u32::from(bits - self.prefix))
+    }
+
+    fn contains(self, other: Block, bits: u8) -> bool {
+        other.prefix >= self.prefix
+            && other.start >= self.start
+            && self
+                .size(bits)
+                .is_none_or(|size| other.start - self.start < size)
+    }
+
+    fn sibling_parent(self, next: Block, bits: u8) -> Option<Block> {
+        let size = self.size(bits).filter(|_| self.prefix > 0)?;
+        (self.prefix == next.prefix && self.start & size == 0 && next.start == self.start + size)
+            .then_some(Block {
+                start: self.start,
+                prefix: self.prefix - 1,
+            })
+    }
+}
+
+type GroupKey = (bool, u16, Option<String>, Option<String>);
+
+fn to_block(entry: &str) -> Option<(bool, Block)> {
+    let network: IpNetwork = entry.parse().ok()?;
+    let (is_v6, start) = match network.network() {
+        IpAddr::V4(v4) => (false, u128::from(u32::from(v4))),
+        IpAddr::V6(v6) => (true, u128::from(v6)),
+    };
+    Some((
+        is_v6,
+        Block {
+            start,
+            prefix: network.prefix(),
+        },
+    ))
+}
+
+fn to_entry(is_v6: bool, block: Block) -> String {
+    let ip = if is_v6 {
+        IpAddr::V6(Ipv6Addr::from(block.start))
+    } else {
+        #[allow(clippy::cast_possible_truncation)]
+        IpAddr::V4(Ipv4Addr::from(block.start as u32))
+    };
+    let bits = if is_v6 { 128 } else { 32 };
+    if block.prefix == bits {
+        ip.to_string()
+    } else {
+        format!("{}/{}", ip, block.prefix)
+    }
+}
+
+fn collapse(mut blocks: Vec<Block>, bits: u8) -> Vec<Block> {
+    blocks.sort_unstable();
+    blocks.dedup();
+
+    let mut merged: Vec<Block> = Vec::with_capacity(blocks.len());
+    for block in blocks {
+        if merged
+            .last()
+            .is_some_and(|&last| last.contains(block, bits))
+        {
+            continue;
+        }
+
+        let mut block = block;
+        while let Some(parent) = merged
+            .last()
+            .and_then(|&last| last.sibling_parent(block, bits))
+        {
+            merged.pop();
+            block = parent;
+        }
+        merged.push(block);
+    }
+    merged
+}
+
+/// Merges adjacent sibling networks and drops networks covered by another one
+/// with the same flags, note and sources. The merged flags of any address are unchanged.
+pub fn aggregate_records(records: Vec<CsvRecord>) -> Vec<CsvRecord> {
+    let mut groups: BTreeMap<GroupKey, Vec<Block>> = BTreeMap::new();
+    let mut aggregated = Vec::new();
+
+    for record in records {
+        match to_block(&record.ip) {
+            Some((is_v6, block)) => groups
+                .entry((is_v6, record.flags.to_mask(), record.note, record.sources))
+                .or_default()
+                .push(block),
+            None => aggregated.push(record),
+        }
+    }
+
+    for ((is_v6, mask, note, sources), blocks) in groups {
+        let bits = if is_v6 { 128 } else { 32 };
+        let flags = ReputationFlags::from_mask(mask);
+        aggregated.extend(collapse(blocks, bits).into_iter().map(|block| CsvRecord {
+            ip: to_entry(is_v6, block),
+            flags,
+            note: note.clone(),
+            sources: sources.clone(),
+        }));
+    }
+
+    aggregated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Database;
+    use crate::ip::lookup_ip;
+
+    fn record(ip: &str, flags: ReputationFlags) -> CsvRecord {
+        CsvRecord {
+            ip: ip.to_owned(),
+            flags,
+            note: None,
+            sources: None,
+        }
+    }
+
+    fn entries(records: &[CsvRecord]) -> Vec<&str> {
+        let mut entries: Vec<&str> = records.iter().map(|r| r.ip.as_str()).collect();
+        entries.sort_unstable();
+        entries
+    }
+
+    #[test]
+    fn test_aggregate_merges_siblings_and_covered() {
+        let proxy = ReputationFlags {
+            proxy: true,
+            ..Default::default()
+        };
+        let records = vec![
+            record("10.0.0.0/25", proxy),
+            record("10.0.0.128/25", proxy),
+            record("10.0.1.0/24", proxy),
+            record("10.0.1.7", proxy),
+            record("2001:db8::/33", proxy),
+            record("2001:db8:8000::/33", proxy),
+        ];
+
+        assert_eq!(
+            entries(&aggregate_records(records)),
+            ["10.0.0.0/23", "2001:db8::/32"]
+        );
+    }
+
+    #[test]
+    fn test_aggregate_keeps_differing_flags_apart() {
+        let proxy = ReputationFlags {
+            proxy: true,
+            ..Default::default()
+        };
+        let vpn = ReputationFlags {
+            vpn: true,
+            ..Default::default()
+        };
+        let records = vec![
+            record("10.0.0.0/25", proxy),
+            record("10.0.0.128/25", vpn),
+            record("10.0.0.4", vpn),
+            record("10.0.1.0/24", proxy),
+            record("not-an-ip", proxy),
+        ];
+
+        assert_eq!(
+            entries(&aggregate_records(records)),
+            [
+                "10.0.0.0/25",
+                "10.0.0.128/25",
+                "10.0.0.4",
+                "10.0.1.0/24",
+                "not-an-ip"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_aggregated_lookups_match_original() {
+        let flag_sets = [
+            ReputationFlags {
+                proxy: true,
+                ..Default::default()
+            },
+            ReputationFlags {
+                vpn: true,
+                ..Default::default()
+            },
+        ];
+        let mut records = Vec::new();
+        for i in 0u32..64 {
+            let flags = flag_sets[(i / 5 % 2) as usize];
+            records.push(record(&format!("10.{}.0.0/16", i), flags));
+            records.push(record(&format!("10.0.{}.0/24", i), flags));
+            records.push(record(&format!("192.168.0.{}", i * 3), flags));
+        }
+        let aggregated = aggregate_records(records.clone());
+        assert!(aggregated.len() < records.len());
+
+        let load = |records: &[CsvRecord]| {
+            let dir = tempfile::TempDir::new().unwrap();
+            let db = Database::open(dir.path()).unwrap();
+            let mut txn = db.begin_write().unwrap();
+            for r in records {
+                db.insert_record(&mut txn, &r.ip, &r.flags).unwrap();
+            }
+            txn.commit().unwrap();
+            db.rebuild_trie().unwrap();
+            (dir, db)
+        };
+        let (_original_dir, original) = load(&records);
+        let (_aggregated_dir, collapsed) = load(&aggregated);
+
+        for probe in (0u32..70)
+            .flat_map(|i| [format!("10.{i}.3.4"), format!("10.0.{i}.9")])
+            .chain((0u32..200).map(|i| format!("192.168.0.{i}")))
+        {
+            assert_eq!(
+                lookup_ip(&original, &probe).unwrap().flags,
+                lookup_ip(&collapsed, &probe).unwrap().flags,
+                "{probe}"
+            );
+        }
+    }
+}
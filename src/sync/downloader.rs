@@ -1,14 +1,23 @@
+use std::io::Read;
 use std::path::Path;
 use std::sync::OnceLock;
 use std::time::Duration;
 
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
 use sha2::{Digest, Sha256};
 use thiserror::Error;
 use tokio::io::AsyncWriteExt;
 use tracing::{info, warn};
 
+use crate::sync::error::FailureKind;
+
 const MAX_RETRIES: u32 = 3;
 const INITIAL_BACKOFF_MS: u64 = 1000;
+const REMOTE_HASH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest CSV accepted out of a tar.gz feed, so a small archive cannot
+/// decompress into an unbounded string.
+pub(super) const MAX_EXTRACTED_CSV_BYTES: u64 = 1 << 30;
 
 #[derive(Error, Debug)]
 pub enum DownloadError {
@@ -18,18 +27,83 @@ pub enum DownloadError {
     Io(#[from] std::io::Error),
     #[error("Download failed after {0} retries")]
     MaxRetriesExceeded(u32),
+    #[error("Invalid source URL: {0}")]
+    InvalidUrl(String),
+    #[error("Rate limited by the source (429)")]
+    RateLimited(Option<Duration>),
+    #[error("Remote hash file is empty")]
+    EmptyHash,
+    #[error("Archive contains no CSV file")]
+    NoCsvInArchive,
+    #[error("Archive contains {0} CSV files, expected exactly one")]
+    MultipleCsvsInArchive(usize),
+    #[error("CSV in archive exceeds {0} bytes")]
+    ArchiveTooLarge(u64),
+    #[cfg(feature = "s3")]
+    #[error("S3 request failed: {0}")]
+    S3(String),
+}
+
+impl DownloadError {
+    pub fn kind(&self) -> FailureKind {
+        match self {
+            Self::InvalidUrl(_) => FailureKind::Internal,
+            _ => FailureKind::Upstream,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::InvalidUrl(_) => "invalid_source_url",
+            Self::RateLimited(_) => "source_rate_limited",
+            _ => "download_failed",
+        }
+    }
 }
 
 pub struct DownloadResult {
     pub content: String,
     pub hash: String,
+    /// Hash of the tar.gz the CSV came from. An upstream hash file covers
+    /// the archive rather than the extracted CSV.
+    pub archive_hash: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+pub const DEFAULT_USER_AGENT: &str = concat!("ProxyD/", env!("CARGO_PKG_VERSION"));
+pub const MAX_RETRY_AFTER_MS: u64 = 300_000;
+
+pub struct DownloadSettings {
+    pub user_agent: String,
+    pub max_retry_after: Duration,
+}
+
+impl Default for DownloadSettings {
+    fn default() -> Self {
+        Self {
+            user_agent: DEFAULT_USER_AGENT.to_owned(),
+            max_retry_after: Duration::from_millis(MAX_RETRY_AFTER_MS),
+        }
+    }
+}
+
+static SETTINGS: OnceLock<DownloadSettings> = OnceLock::new();
+
+pub fn configure(settings: DownloadSettings) {
+    if SETTINGS.set(settings).is_err() {
+        warn!("Download settings already configured, ignoring");
+    }
+}
+
+fn settings() -> &'static DownloadSettings {
+    SETTINGS.get_or_init(DownloadSettings::default)
 }
 
 fn get_http_client() -> &'static reqwest::Client {
     static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
     CLIENT.get_or_init(|| {
         reqwest::Client::builder()
-            .user_agent("ProxyD/1.0")
+            .user_agent(&settings().user_agent)
             .timeout(Duration::from_secs(300))
             .connect_timeout(Duration::from_secs(30))
             .build()
@@ -38,6 +112,15 @@ fn get_http_client() -> &'static reqwest::Client {
 }
 
 pub async fn download_csv(url: &str) -> Result<DownloadResult, DownloadError> {
+    download_csv_if_modified(url, None)
+        .await?
+        .ok_or(DownloadError::MaxRetriesExceeded(MAX_RETRIES))
+}
+
+pub async fn download_csv_if_modified(
+    url: &str,
+    last_modified: Option<&str>,
+) -> Result<Option<DownloadResult>, DownloadError> {
     info!("Downloading CSV from {}", url);
 
     let mut last_error = None;
@@ -45,15 +128,31 @@ pub async fn download_csv(url: &str) -> Result<DownloadResult, DownloadError> {
     for attempt in 0..MAX_RETRIES {
         if attempt > 0 {
             let backoff = Duration::from_millis(INITIAL_BACKOFF_MS * 2u64.pow(attempt - 1));
-            warn!(
-                "Download attempt {} failed, retrying in {:?}",
-                attempt, backoff
-            );
-            tokio::time::sleep(backoff).await;
+            if let Some(DownloadError::RateLimited(Some(retry_after))) = &last_error {
+                let wait = (*retry_after).min(settings().max_retry_after);
+                warn!(
+                    "Source {} returned 429, honoring Retry-After and waiting {:?}",
+                    url, wait
+                );
+                tokio::time::sleep(wait).await;
+            } else {
+                warn!(
+                    "Download attempt {} failed, retrying in {:?}",
+                    attempt, backoff
+                );
+                tokio::time::sleep(backoff).await;
+            }
         }
 
-        match download_csv_once(url).await {
+        match download_csv_once(url, last_modified).await {
             Ok(result) => return Ok(result),
+            Err(
+                e @ (DownloadError::InvalidUrl(_)
+                | DownloadError::NoCsvInArchive
+                | DownloadError::MultipleCsvsInArchive(_)),
+            ) => {
+                return Err(e);
+            }
             Err(e) => {
                 last_error = Some(e);
             }
@@ -70,16 +169,173 @@ pub async fn download_csv(url: &str) -> Result<DownloadResult, DownloadError> {
     Err(DownloadError::MaxRetriesExceeded(MAX_RETRIES))
 }
 
-async fn download_csv_once(url: &str) -> Result<DownloadResult, DownloadError> {
+async fn download_csv_once(
+    url: &str,
+    last_modified: Option<&str>,
+) -> Result<Option<DownloadResult>, DownloadError> {
+    if url.starts_with("s3://") {
+        return download_s3_once(url, last_modified).await;
+    }
+    download_http_once(url, last_modified).await
+}
+
+#[cfg(feature = "s3")]
+use super::s3::download_s3_once;
+
+#[cfg(not(feature = "s3"))]
+async fn download_s3_once(
+    url: &str,
+    _last_modified: Option<&str>,
+) -> Result<Option<DownloadResult>, DownloadError> {
+    Err(DownloadError::InvalidUrl(format!(
+        "{url} (built without the s3 feature)"
+    )))
+}
+
+async fn download_http_once(
+    url: &str,
+    last_modified: Option<&str>,
+) -> Result<Option<DownloadResult>, DownloadError> {
     let client = get_http_client();
 
-    let response = client.get(url).send().await?.error_for_status()?;
-    let content = response.text().await?;
+    let mut request = client.get(url);
+    if let Some(since) = last_modified {
+        request = request.header(reqwest::header::IF_MODIFIED_SINCE, since);
+    }
+
+    let response = request.send().await?;
+    if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+        info!(
+            "CSV at {} not modified since {}",
+            url,
+            last_modified.unwrap_or("-")
+        );
+        return Ok(None);
+    }
+
+    if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| parse_retry_after(v, Utc::now()));
+        return Err(DownloadError::RateLimited(retry_after));
+    }
+
+    let response = response.error_for_status()?;
+    let last_modified = response
+        .headers()
+        .get(reqwest::header::LAST_MODIFIED)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned);
+    let (content, archive_hash) = if is_tar_gz(url, content_type(&response)) {
+        let archive = response.bytes().await?;
+        (
+            extract_csv(&archive, MAX_EXTRACTED_CSV_BYTES)?,
+            Some(hash_bytes(&archive)),
+        )
+    } else {
+        (response.text().await?, None)
+    };
 
     let hash = compute_hash(&content);
     info!("Downloaded CSV, hash: {}", hash);
 
-    Ok(DownloadResult { content, hash })
+    Ok(Some(DownloadResult {
+        content,
+        hash,
+        archive_hash,
+        last_modified,
+    }))
+}
+
+fn content_type(response: &reqwest::Response) -> Option<&str> {
+    response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+}
+
+const TAR_GZ_CONTENT_TYPES: [&str; 3] = [
+    "application/x-tar+gzip",
+    "application/x-gtar",
+    "application/x-compressed-tar",
+];
+
+pub(super) fn is_tar_gz(url: &str, content_type: Option<&str>) -> bool {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    if path.ends_with(".tar.gz") || path.ends_with(".tgz") {
+        return true;
+    }
+    content_type
+        .and_then(|ct| ct.split(';').next())
+        .is_some_and(|ct| TAR_GZ_CONTENT_TYPES.contains(&ct.trim()))
+}
+
+/// Returns the single `.csv` member of a gzipped tarball, failing once it
+/// decompresses past `limit` bytes.
+pub(super) fn extract_csv(archive: &[u8], limit: u64) -> Result<String, DownloadError> {
+    let mut archive = tar::Archive::new(GzDecoder::new(archive));
+    let mut csv = None;
+    let mut count = 0;
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let is_csv = entry.header().entry_type().is_file()
+            && entry
+                .path()?
+                .extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("csv"));
+        if !is_csv {
+            continue;
+        }
+
+        count += 1;
+        if csv.is_none() {
+            let mut content = String::new();
+            (&mut entry).take(limit + 1).read_to_string(&mut content)?;
+            if content.len() as u64 > limit {
+                return Err(DownloadError::ArchiveTooLarge(limit));
+            }
+            csv = Some(content);
+        }
+    }
+
+    match (count, csv) {
+        (1, Some(content)) => Ok(content),
+        (0, _) => Err(DownloadError::NoCsvInArchive),
+        (count, _) => Err(DownloadError::MultipleCsvsInArchive(count)),
+    }
+}
+
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let at = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (at.with_timezone(&Utc) - now)
+            .to_std()
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// Fetches a published hash file; accepts a bare hex digest or `sha256sum` output.
+pub async fn fetch_remote_hash(url: &str) -> Result<String, DownloadError> {
+    let body = get_http_client()
+        .get(url)
+        .timeout(REMOTE_HASH_TIMEOUT)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    body.split_whitespace()
+        .next()
+        .map(str::to_ascii_lowercase)
+        .ok_or(DownloadError::EmptyHash)
 }
 
 pub async fn save_csv(path: &Path, content: &str) -> Result<(), DownloadError> {
@@ -93,7 +349,12 @@ pub async fn save_hash(path: &Path, hash: &str) -> Result<(), DownloadError> {
 async fn atomic_write(path: &Path, content: &[u8]) -> Result<(), DownloadError> {
     let temp_path = path.with_extension("tmp");
 
-    let mut file = tokio::fs::File::create(&temp_path).await?;
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut file = options.open(&temp_path).await?;
     file.write_all(content).await?;
     file.sync_all().await?;
     drop(file);
@@ -111,7 +372,131 @@ pub async fn load_csv(path: &Path) -> Result<String, DownloadError> {
 }
 
 pub fn compute_hash(content: &str) -> String {
+    hash_bytes(content.as_bytes())
+}
+
+pub(super) fn hash_bytes(bytes: &[u8]) -> String {
     let mut hasher = Sha256::new();
-    hasher.update(content.as_bytes());
+    hasher.update(bytes);
     hex::encode(hasher.finalize())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_saved_files_are_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("proxy_blocks.csv");
+        save_csv(&path, "ip,proxy\n1.2.3.4,true").await.unwrap();
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(load_csv(&path).await.unwrap(), "ip,proxy\n1.2.3.4,true");
+    }
+
+    fn tar_gz(members: &[(&str, &str)]) -> Vec<u8> {
+        let encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        let mut builder = tar::Builder::new(encoder);
+        for (path, content) in members {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, content.as_bytes())
+                .unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn test_extract_csv_from_tar_gz() {
+        let csv = "ip,proxy\n1.2.3.4,true";
+        let archive = tar_gz(&[("README.txt", "feed"), ("data/proxy_blocks.csv", csv)]);
+        assert_eq!(extract_csv(&archive, 64).unwrap(), csv);
+
+        assert!(matches!(
+            extract_csv(&tar_gz(&[("README.txt", "feed")]), 64),
+            Err(DownloadError::NoCsvInArchive)
+        ));
+        assert!(matches!(
+            extract_csv(&tar_gz(&[("a.csv", csv), ("b.CSV", csv)]), 64),
+            Err(DownloadError::MultipleCsvsInArchive(2))
+        ));
+        assert!(matches!(
+            extract_csv(&archive, 10),
+            Err(DownloadError::ArchiveTooLarge(10))
+        ));
+    }
+
+    #[test]
+    fn test_is_tar_gz_detection() {
+        assert!(is_tar_gz("https://example.com/feed.tar.gz", None));
+        assert!(is_tar_gz("https://example.com/feed.tgz?token=x", None));
+        assert!(is_tar_gz(
+            "https://example.com/download",
+            Some("application/x-tar+gzip; charset=binary")
+        ));
+        assert!(!is_tar_gz("https://example.com/feed.csv", Some("text/csv")));
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = DateTime::parse_from_rfc2822("Wed, 21 Oct 2015 07:28:00 GMT")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:29:30 GMT", now),
+            Some(Duration::from_secs(90))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:00:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[tokio::test]
+    async fn test_downloads_send_the_user_agent() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/proxy_blocks.csv", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let read = socket.read(&mut buf).await.unwrap();
+                if read == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..read]);
+            }
+            let body = "ip,proxy\n1.2.3.4,true\n";
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let result = download_csv(&url).await.unwrap();
+        assert!(result.content.contains("1.2.3.4"));
+        let request = server.await.unwrap().to_lowercase();
+        let expected = format!("user-agent: proxyd/{}\r\n", env!("CARGO_PKG_VERSION"));
+        assert!(request.contains(&expected), "{request}");
+    }
+}
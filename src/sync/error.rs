@@ -0,0 +1,78 @@
+/// What a failed import or sync means for the caller, so the API can tell a
+/// transient upstream problem from a bad feed from an internal fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    /// The source could not be downloaded; retrying later may succeed.
+    Upstream,
+    /// The feed was read but rejected: unparseable, or tripped a safety check.
+    InvalidFeed,
+    /// Another sync is already running.
+    Conflict,
+    Internal,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::DbError;
+    use crate::sync::downloader::DownloadError;
+    use crate::sync::importer::ImportError;
+    use crate::sync::scheduler::SyncError;
+
+    #[test]
+    fn test_failure_kinds() {
+        let download = || DownloadError::MaxRetriesExceeded(3);
+        let cases = [
+            (SyncError::Download(download()), FailureKind::Upstream),
+            (
+                SyncError::Download(DownloadError::RateLimited(None)),
+                FailureKind::Upstream,
+            ),
+            (
+                SyncError::Download(DownloadError::InvalidUrl("ftp://x".to_owned())),
+                FailureKind::Internal,
+            ),
+            (
+                SyncError::Import(ImportError::Download(download())),
+                FailureKind::Upstream,
+            ),
+            (
+                SyncError::Import(ImportError::CsvParse("bad row".to_owned())),
+                FailureKind::InvalidFeed,
+            ),
+            (
+                SyncError::Import(ImportError::MissingFlagColumns(vec!["tor"])),
+                FailureKind::InvalidFeed,
+            ),
+            (
+                SyncError::Import(ImportError::TooManyDeletes {
+                    deleted: 10,
+                    existing: 12,
+                }),
+                FailureKind::InvalidFeed,
+            ),
+            (
+                SyncError::Import(ImportError::TooFewRecords {
+                    count: 1,
+                    minimum: 100,
+                }),
+                FailureKind::InvalidFeed,
+            ),
+            (
+                SyncError::Import(ImportError::Io(std::io::Error::other("disk"))),
+                FailureKind::Internal,
+            ),
+            (
+                SyncError::Database(DbError::Io(std::io::Error::other("disk"))),
+                FailureKind::Internal,
+            ),
+            (SyncError::AlreadyRunning, FailureKind::Conflict),
+        ];
+        for (error, kind) in cases {
+            assert_eq!(error.kind(), kind, "{error}");
+        }
+
+        assert_eq!(ImportError::CsvParse(String::new()).code(), "invalid_csv");
+        assert_eq!(download().code(), "download_failed");
+    }
+}
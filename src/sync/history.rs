@@ -0,0 +1,114 @@
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use tokio::fs;
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::sync::downloader::{compute_hash, load_csv, load_hash};
+
+fn snapshot_dir(config: &Config) -> PathBuf {
+    config.data_dir.join("history")
+}
+
+fn snapshot_path(config: &Config, hash: &str) -> PathBuf {
+    snapshot_dir(config).join(format!("{hash}.csv"))
+}
+
+/// Dataset hashes are lowercase hex SHA-256, which also keeps them safe to
+/// use as file names.
+pub fn is_dataset_hash(s: &str) -> bool {
+    s.len() == 64 && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Keeps a copy of the CSV that is about to be replaced by the dataset
+/// `new_hash`, so deltas can still be computed from it. Keeps at most
+/// `PROXYD_DELTA_HISTORY` snapshots.
+pub async fn retain_current(config: &Config, new_hash: &str) -> std::io::Result<()> {
+    if config.delta_history == 0 {
+        return Ok(());
+    }
+    let Some(current) = load_hash(&config.csv_hash_path()).await else {
+        return Ok(());
+    };
+    let current = current.trim();
+    if current == new_hash || !is_dataset_hash(current) || !config.csv_path().exists() {
+        return Ok(());
+    }
+
+    let dir = snapshot_dir(config);
+    fs::create_dir_all(&dir).await?;
+    let path = snapshot_path(config, current);
+    let temp_path = path.with_extension("tmp");
+    fs::copy(config.csv_path(), &temp_path).await?;
+    fs::rename(&temp_path, &path).await?;
+    info!("Retained dataset {} for delta exports", current);
+
+    prune(&dir, config.delta_history).await
+}
+
+async fn prune(dir: &Path, keep: usize) -> std::io::Result<()> {
+    let mut snapshots: Vec<(SystemTime, PathBuf)> = Vec::new();
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_some_and(|ext| ext == "csv") {
+            snapshots.push((entry.metadata().await?.modified()?, path));
+        }
+    }
+
+    snapshots.sort_unstable_by_key(|(modified, _)| std::cmp::Reverse(*modified));
+    for (_, path) in snapshots.into_iter().skip(keep) {
+        fs::remove_file(&path).await?;
+    }
+    Ok(())
+}
+
+/// Returns the retained CSV of dataset `hash`, if it is still kept and intact.
+pub async fn load_snapshot(config: &Config, hash: &str) -> Option<String> {
+    if !is_dataset_hash(hash) {
+        return None;
+    }
+    let content = load_csv(&snapshot_path(config, hash)).await.ok()?;
+    if compute_hash(&content) != hash {
+        warn!("Discarding corrupt dataset snapshot {}", hash);
+        return None;
+    }
+    Some(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::downloader::{save_csv, save_hash};
+
+    #[tokio::test]
+    async fn test_retains_previous_snapshots_up_to_limit() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let config = Config {
+            data_dir: dir.path().to_path_buf(),
+            delta_history: 2,
+            ..Config::default()
+        };
+
+        let mut hashes = Vec::new();
+        for i in 0..4 {
+            let content = format!("ip,proxy\n10.0.0.{i},true\n");
+            let hash = compute_hash(&content);
+            retain_current(&config, &hash).await.unwrap();
+            save_csv(&config.csv_path(), &content).await.unwrap();
+            save_hash(&config.csv_hash_path(), &hash).await.unwrap();
+            hashes.push(hash);
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        assert!(load_snapshot(&config, &hashes[0]).await.is_none());
+        assert!(load_snapshot(&config, &hashes[1]).await.is_some());
+        assert_eq!(
+            load_snapshot(&config, &hashes[2]).await.unwrap(),
+            "ip,proxy\n10.0.0.2,true\n"
+        );
+        assert!(load_snapshot(&config, &hashes[3]).await.is_none());
+        assert!(load_snapshot(&config, "../proxy_blocks").await.is_none());
+    }
+}
@@ -1,15 +1,29 @@
+use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
-use std::sync::Arc;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 
 use chrono::Utc;
+use ipnetwork::IpNetwork;
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use thiserror::Error;
-use tracing::info;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
 
 use crate::config::Config;
-use crate::db::{Database, DbError, Metadata};
-use crate::ip::{IpTrie, ReputationFlags};
+use crate::db::{BucketHashes, Database, DbError};
+use crate::ip::{IpTrie, ReputationFlags, FLAG_BITS};
+use crate::metrics;
+use crate::sync::aggregate::aggregate_records;
 use crate::sync::downloader::{compute_hash, load_csv, load_hash, save_csv, save_hash};
+use crate::sync::error::FailureKind;
+use crate::sync::history;
 
 #[derive(Error, Debug)]
 pub enum ImportError {
@@ -23,50 +37,394 @@ pub enum ImportError {
     Download(#[from] crate::sync::downloader::DownloadError),
     #[error("LMDB error: {0}")]
     Heed(#[from] heed::Error),
+    #[error("Import would delete {deleted} of {existing} records; set PROXYD_ALLOW_MASS_DELETE=true to apply it")]
+    TooManyDeletes { deleted: u64, existing: u64 },
+    #[error("Full import has {count} records, fewer than PROXYD_MIN_IMPORT_RECORDS={minimum}")]
+    TooFewRecords { count: usize, minimum: usize },
+    #[error("Import task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+    #[error("CSV is missing flag columns: {}", .0.join(", "))]
+    MissingFlagColumns(Vec<&'static str>),
+}
+
+impl ImportError {
+    pub fn kind(&self) -> FailureKind {
+        match self {
+            Self::Download(e) => e.kind(),
+            Self::CsvParse(_)
+            | Self::MissingFlagColumns(_)
+            | Self::TooManyDeletes { .. }
+            | Self::TooFewRecords { .. } => FailureKind::InvalidFeed,
+            Self::Database(_) | Self::Io(_) | Self::Heed(_) | Self::Task(_) => {
+                FailureKind::Internal
+            }
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Download(e) => e.code(),
+            Self::CsvParse(_) => "invalid_csv",
+            Self::MissingFlagColumns(_) => "missing_flag_columns",
+            Self::TooManyDeletes { .. } => "too_many_deletes",
+            Self::TooFewRecords { .. } => "too_few_records",
+            Self::Database(_) | Self::Io(_) | Self::Heed(_) | Self::Task(_) => "import_failed",
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct CsvRecord {
     pub ip: String,
     pub flags: ReputationFlags,
+    pub note: Option<String>,
+    /// The merged feeds that set each flag, as written by [`merge_sources`].
+    pub sources: Option<String>,
+}
+
+pub const MAX_NOTE_LEN: usize = 256;
+
+pub fn bounded_note(raw: &str) -> Option<String> {
+    let note = raw.trim();
+    if note.is_empty() {
+        return None;
+    }
+    let mut end = note.len().min(MAX_NOTE_LEN);
+    while !note.is_char_boundary(end) {
+        end -= 1;
+    }
+    Some(note[..end].to_owned())
 }
 
 fn parse_bool(s: &str) -> bool {
     matches!(s.trim().to_lowercase().as_str(), "true" | "1" | "yes")
 }
 
-pub fn parse_csv_parallel(content: &str) -> Result<Vec<CsvRecord>, ImportError> {
-    let mut reader = csv::ReaderBuilder::new()
-        .has_headers(true)
-        .flexible(true)
-        .from_reader(content.as_bytes());
+pub fn parse_csv_parallel(content: &str, ip_column: &str) -> Result<Vec<CsvRecord>, ImportError> {
+    parse_csv_chunked(content, ip_column, usize::MAX)
+}
 
-    let headers = reader
-        .headers()
-        .map_err(|e| ImportError::CsvParse(e.to_string()))?
-        .clone();
+/// Parses at most `chunk_rows` raw rows at a time, so only one chunk of
+/// unparsed rows is held in memory next to the parsed records.
+pub fn parse_csv_chunked(
+    content: &str,
+    ip_column: &str,
+    chunk_rows: usize,
+) -> Result<Vec<CsvRecord>, ImportError> {
+    let mut chunks = CsvChunks::new(content.as_bytes(), ip_column, chunk_rows)?;
+    let mut records: Vec<CsvRecord> = Vec::new();
+    while let Some(chunk) = chunks.next_chunk() {
+        records.extend(chunk);
+    }
+    Ok(records)
+}
+
+/// Reads a CSV feed `chunk_rows` raw rows at a time, parsing each chunk in
+/// parallel on the current rayon pool.
+struct CsvChunks<R> {
+    reader: csv::Reader<R>,
+    header_indices: HeaderIndices,
+    chunk_rows: usize,
+}
 
-    let header_indices = HeaderIndices::from_headers(&headers);
+impl<R: Read> CsvChunks<R> {
+    fn new(source: R, ip_column: &str, chunk_rows: usize) -> Result<Self, ImportError> {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .flexible(true)
+            .from_reader(source);
+        let headers = reader
+            .headers()
+            .map_err(|e| ImportError::CsvParse(e.to_string()))?;
+        let header_indices = HeaderIndices::from_headers(headers, ip_column);
+        Ok(Self {
+            reader,
+            header_indices,
+            chunk_rows: chunk_rows.max(1),
+        })
+    }
+
+    /// Fails like [`check_flag_columns`] when the header lacks a flag column.
+    fn check_flag_columns(&mut self, config: &Config) -> Result<(), ImportError> {
+        let headers = self
+            .reader
+            .headers()
+            .map_err(|e| ImportError::CsvParse(e.to_string()))?;
+        require_flag_columns(missing_in(headers), config)
+    }
+
+    /// The records of the next chunk, or `None` once every row is read.
+    fn next_chunk(&mut self) -> Option<Vec<CsvRecord>> {
+        let raw_records: Vec<csv::StringRecord> = self
+            .reader
+            .records()
+            .filter_map(Result::ok)
+            .take(self.chunk_rows)
+            .collect();
+        if raw_records.is_empty() {
+            return None;
+        }
+
+        let header_indices = &self.header_indices;
+        Some(
+            raw_records
+                .par_iter()
+                .filter_map(|record| {
+                    let ip = record.get(header_indices.ip)?.to_owned();
+                    if ip.is_empty() {
+                        return None;
+                    }
+
+                    let flags = header_indices.extract_flags(record);
+                    let note = header_indices
+                        .note
+                        .and_then(|i| record.get(i))
+                        .and_then(bounded_note);
+                    let sources = header_indices
+                        .sources
+                        .and_then(|i| record.get(i))
+                        .filter(|s| !s.is_empty())
+                        .map(str::to_owned);
+                    Some(CsvRecord {
+                        ip,
+                        flags,
+                        note,
+                        sources,
+                    })
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Import parsing runs here rather than on the global pool, so a large feed
+/// cannot occupy every thread that batch lookups also use.
+fn parse_pool(config: &Config) -> &'static rayon::ThreadPool {
+    static POOL: OnceLock<rayon::ThreadPool> = OnceLock::new();
+    POOL.get_or_init(|| {
+        let threads = config
+            .import_parse_threads
+            .unwrap_or_else(|| (num_cpus::get() / 2).max(1));
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("proxyd-parse-{i}"))
+            .build()
+            .expect("failed to start the import parse pool")
+    })
+}
 
-    let raw_records: Vec<csv::StringRecord> = reader.records().filter_map(Result::ok).collect();
+const FLAG_COLUMNS: [&str; 9] = [
+    "anonblock",
+    "proxy",
+    "vpn",
+    "cdn",
+    "public-wifi",
+    "rangeblock",
+    "school-block",
+    "tor",
+    "webhost",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MergePolicy {
+    Union,
+    Intersection,
+    Priority,
+}
+
+impl MergePolicy {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "union" => Some(Self::Union),
+            "intersection" => Some(Self::Intersection),
+            "priority" => Some(Self::Priority),
+            _ => None,
+        }
+    }
 
-    let records: Vec<CsvRecord> = raw_records
-        .par_iter()
-        .filter_map(|record| {
-            let ip = record.get(0)?.to_owned();
-            if ip.is_empty() {
-                return None;
+    /// Combines the bits of `next` its source is authoritative for into
+    /// `current`, leaving the others alone. `decided` holds the bits an
+    /// earlier source listing the entry was authoritative for.
+    fn combine(self, current: u16, next: u16, authority: u16, decided: u16) -> u16 {
+        let undecided = next & authority & !decided;
+        match self {
+            Self::Union => current | (next & authority),
+            Self::Intersection => {
+                (current & !authority) | (current & next & authority & decided) | undecided
             }
+            Self::Priority => current | undecided,
+        }
+    }
+}
 
-            let flags = header_indices.extract_flags(record);
-            Some(CsvRecord { ip, flags })
-        })
-        .collect();
+pub struct MergeSource<'a> {
+    pub name: &'a str,
+    pub content: &'a str,
+    /// Flags this source is authoritative for; any other flag it sets is
+    /// dropped. `None` allows every flag.
+    pub allowed_flags: Option<ReputationFlags>,
+}
 
-    Ok(records)
+/// Clears the flags outside `allowed`, returning how many records lost one.
+fn restrict_flags(records: &mut [CsvRecord], allowed: &ReputationFlags) -> usize {
+    let mut restricted = 0;
+    for record in records {
+        let flags = record.flags.intersect(allowed);
+        if flags != record.flags {
+            record.flags = flags;
+            restricted += 1;
+        }
+    }
+    restricted
+}
+
+/// Formats the `sources` column of a merged entry: each set flag followed by
+/// the sources that set it, as in `proxy=<url> <url>|vpn=<url>`.
+fn sources_column(flags: &ReputationFlags, contributors: &[(&str, u16)]) -> Option<String> {
+    let mask = flags.to_mask();
+    let mut column: Vec<String> = Vec::new();
+    for (bit, flag) in FLAG_BITS.iter().enumerate() {
+        if mask >> bit & 1 == 0 {
+            continue;
+        }
+        let mut names: Vec<&str> = Vec::new();
+        for &(name, set) in contributors {
+            if set >> bit & 1 == 1 && !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        if !names.is_empty() {
+            column.push(format!("{flag}={}", names.join(" ")));
+        }
+    }
+    (!column.is_empty()).then(|| column.join("|"))
+}
+
+/// Merges the feeds into one CSV. A feed restricted to some flags only takes
+/// part in merging those. Each entry's `sources` column records which feeds
+/// set each of its merged flags; under [`MergePolicy::Priority`] that is only
+/// the first feed listing the entry that is authoritative for the flag.
+pub fn merge_sources(
+    sources: &[MergeSource<'_>],
+    ip_column: &str,
+    policy: MergePolicy,
+) -> Result<String, ImportError> {
+    let mut merged: Vec<CsvRecord> = Vec::new();
+    let mut contributors: Vec<Vec<(&str, u16)>> = Vec::new();
+    let mut decided: Vec<u16> = Vec::new();
+    let mut positions: HashMap<String, usize> = HashMap::new();
+
+    for source in sources {
+        let mut records = parse_csv_parallel(source.content, ip_column)?;
+        if let Some(allowed) = &source.allowed_flags {
+            let restricted = restrict_flags(&mut records, allowed);
+            if restricted > 0 {
+                warn!(
+                    "Source {} set flags it is not allowed to on {} records; ignored them",
+                    source.name, restricted
+                );
+            }
+        }
+        let authority = source
+            .allowed_flags
+            .map_or(u16::MAX, |flags| flags.to_mask());
+        for record in records {
+            let mask = record.flags.to_mask();
+            // `1.1.1.1` and `1.1.1.1/32` are the same entry
+            let key = canonical_key(&record.ip).into_owned();
+            if let Some(&pos) = positions.get(&key) {
+                let current = merged[pos].flags.to_mask();
+                let combined = policy.combine(current, mask, authority, decided[pos]);
+                merged[pos].flags = ReputationFlags::from_mask(combined);
+                if merged[pos].note.is_none() {
+                    merged[pos].note = record.note;
+                }
+                let set = match policy {
+                    MergePolicy::Priority => mask & !decided[pos],
+                    _ => mask,
+                };
+                contributors[pos].push((source.name, set));
+                decided[pos] |= authority;
+            } else {
+                positions.insert(key, merged.len());
+                merged.push(record);
+                contributors.push(vec![(source.name, mask)]);
+                decided.push(authority);
+            }
+        }
+    }
+
+    for (record, contributors) in merged.iter_mut().zip(&contributors) {
+        record.sources = sources_column(&record.flags, contributors);
+    }
+    records_to_csv(&merged)
+}
+
+pub fn records_to_csv(records: &[CsvRecord]) -> Result<String, ImportError> {
+    let bytes = write_records(Vec::new(), records)?;
+    String::from_utf8(bytes).map_err(|e| ImportError::CsvParse(e.to_string()))
+}
+
+/// Writes `records` in the layout [`records_to_csv`] produces, returning the
+/// flushed writer.
+fn write_records<W: Write>(out: W, records: &[CsvRecord]) -> Result<W, ImportError> {
+    let with_sources = records.iter().any(|r| r.sources.is_some());
+    write_rows(out, records, true, with_sources)
+}
+
+/// Writes `records` as CSV rows, after the header when `header` is set. The
+/// `sources` column is only written when `with_sources` is set.
+fn write_rows<W: Write>(
+    out: W,
+    records: &[CsvRecord],
+    header: bool,
+    with_sources: bool,
+) -> Result<W, ImportError> {
+    let to_csv_err = |e: csv::Error| ImportError::CsvParse(e.to_string());
+    let mut writer = csv::Writer::from_writer(out);
+
+    if header {
+        writer
+            .write_record(
+                std::iter::once("ip")
+                    .chain(FLAG_COLUMNS)
+                    .chain(std::iter::once("note"))
+                    .chain(with_sources.then_some("sources")),
+            )
+            .map_err(to_csv_err)?;
+    }
+
+    for record in records {
+        let f = &record.flags;
+        let values = [
+            f.anonblock,
+            f.proxy,
+            f.vpn,
+            f.cdn,
+            f.public_wifi,
+            f.rangeblock,
+            f.school_block,
+            f.tor,
+            f.webhost,
+        ];
+        writer
+            .write_record(
+                std::iter::once(record.ip.as_str())
+                    .chain(values.iter().map(|&v| if v { "true" } else { "false" }))
+                    .chain(std::iter::once(record.note.as_deref().unwrap_or_default()))
+                    .chain(with_sources.then(|| record.sources.as_deref().unwrap_or_default())),
+            )
+            .map_err(to_csv_err)?;
+    }
+
+    writer
+        .into_inner()
+        .map_err(|e| ImportError::CsvParse(e.to_string()))
 }
 
 struct HeaderIndices {
+    ip: usize,
     anonblock: Option<usize>,
     proxy: Option<usize>,
     vpn: Option<usize>,
@@ -76,22 +434,27 @@ struct HeaderIndices {
     school_block: Option<usize>,
     tor: Option<usize>,
     webhost: Option<usize>,
+    note: Option<usize>,
+    sources: Option<usize>,
 }
 
 impl HeaderIndices {
-    fn from_headers(headers: &csv::StringRecord) -> Self {
+    fn from_headers(headers: &csv::StringRecord, ip_column: &str) -> Self {
         let find_index = |name: &str| headers.iter().position(|h| h == name);
 
         Self {
-            anonblock: find_index("anonblock"),
-            proxy: find_index("proxy"),
-            vpn: find_index("vpn"),
-            cdn: find_index("cdn"),
-            public_wifi: find_index("public-wifi"),
-            rangeblock: find_index("rangeblock"),
-            school_block: find_index("school-block"),
-            tor: find_index("tor"),
-            webhost: find_index("webhost"),
+            ip: find_index(ip_column).unwrap_or(0),
+            anonblock: find_index(FLAG_COLUMNS[0]),
+            proxy: find_index(FLAG_COLUMNS[1]),
+            vpn: find_index(FLAG_COLUMNS[2]),
+            cdn: find_index(FLAG_COLUMNS[3]),
+            public_wifi: find_index(FLAG_COLUMNS[4]),
+            rangeblock: find_index(FLAG_COLUMNS[5]),
+            school_block: find_index(FLAG_COLUMNS[6]),
+            tor: find_index(FLAG_COLUMNS[7]),
+            webhost: find_index(FLAG_COLUMNS[8]),
+            note: find_index("note").or_else(|| find_index("comment")),
+            sources: find_index("sources"),
         }
     }
 
@@ -119,82 +482,389 @@ impl HeaderIndices {
 
 const BATCH_COMMIT_SIZE: usize = 10_000;
 
+static IMPORT_LOCK: Mutex<()> = Mutex::const_new(());
+
+pub fn missing_flag_columns(content: &str) -> Result<Vec<&'static str>, ImportError> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(true)
+        .flexible(true)
+        .from_reader(content.as_bytes());
+    let headers = reader
+        .headers()
+        .map_err(|e| ImportError::CsvParse(e.to_string()))?;
+    Ok(missing_in(headers))
+}
+
+fn missing_in(headers: &csv::StringRecord) -> Vec<&'static str> {
+    FLAG_COLUMNS
+        .into_iter()
+        .filter(|column| !headers.iter().any(|h| h == *column))
+        .collect()
+}
+
+/// Fails with the missing columns when `PROXYD_REQUIRE_ALL_FLAGS` is set and
+/// the feed lacks any flag column.
+pub fn check_flag_columns(content: &str, config: &Config) -> Result<(), ImportError> {
+    if !config.require_all_flags {
+        return Ok(());
+    }
+    require_flag_columns(missing_flag_columns(content)?, config)
+}
+
+fn require_flag_columns(missing: Vec<&'static str>, config: &Config) -> Result<(), ImportError> {
+    if !config.require_all_flags || missing.is_empty() {
+        Ok(())
+    } else {
+        Err(ImportError::MissingFlagColumns(missing))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ParseStrategy {
+    Parallel,
+    Chunked,
+}
+
+impl ParseStrategy {
+    fn for_size(bytes: usize, config: &Config) -> Self {
+        match config.import_chunked_threshold {
+            Some(threshold) if bytes > threshold => Self::Chunked,
+            _ => Self::Parallel,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Parallel => "parallel",
+            Self::Chunked => "chunked",
+        }
+    }
+}
+
+pub fn parse_for_import(content: &str, config: &Config) -> Result<Vec<CsvRecord>, ImportError> {
+    let strategy = ParseStrategy::for_size(content.len(), config);
+    info!(
+        "Parsing {} bytes with the {} strategy",
+        content.len(),
+        strategy.as_str()
+    );
+    metrics::inc_import_parse_strategy(strategy.as_str());
+    let records = parse_pool(config).install(|| match strategy {
+        ParseStrategy::Parallel => parse_csv_parallel(content, &config.ip_column),
+        ParseStrategy::Chunked => {
+            parse_csv_chunked(content, &config.ip_column, config.import_chunk_rows)
+        }
+    })?;
+    if !config.aggregate_cidrs {
+        return Ok(records);
+    }
+
+    let before = records.len();
+    let records = aggregate_records(records);
+    let collapsed = before - records.len();
+    info!(
+        "Aggregated {} entries into {} ({} collapsed)",
+        before,
+        records.len(),
+        collapsed
+    );
+    metrics::set_aggregated_entries(collapsed as u64);
+    Ok(records)
+}
+
+fn timed_parse(content: &str, config: &Config) -> Result<Vec<CsvRecord>, ImportError> {
+    check_flag_columns(content, config)?;
+    let start = Instant::now();
+    let records = parse_for_import(content, config)?;
+    metrics::record_import_parse_duration(start.elapsed().as_secs_f64());
+    Ok(records)
+}
+
+/// Number of records carrying each flag, in [`FLAG_BITS`] order.
+fn flag_counts(records: &[CsvRecord]) -> [u64; FLAG_BITS.len()] {
+    let mut counts = [0u64; FLAG_BITS.len()];
+    for record in records {
+        let mask = record.flags.to_mask();
+        for (bit, count) in counts.iter_mut().enumerate() {
+            *count += u64::from(mask >> bit & 1);
+        }
+    }
+    counts
+}
+
+fn stored_cidr(entry: &str) -> Option<IpNetwork> {
+    let network: IpNetwork = entry.parse().ok()?;
+    let max_prefix = match network {
+        IpNetwork::V4(_) => 32,
+        IpNetwork::V6(_) => 128,
+    };
+    if network.prefix() == max_prefix {
+        return None;
+    }
+    IpNetwork::new(network.network(), network.prefix()).ok()
+}
+
+/// The network an entry occupies in the trie: every CIDR, plus single IPs
+/// in CIDR-only mode.
+fn trie_network(db: &Database, entry: &str) -> Option<IpNetwork> {
+    stored_cidr(entry).or_else(|| entry.parse().ok().filter(|_| db.cidr_only()))
+}
+
+fn canonical_key(entry: &str) -> Cow<'_, str> {
+    let canonical = match stored_cidr(entry) {
+        Some(network) => network.to_string(),
+        None => match entry.parse::<IpNetwork>() {
+            Ok(network) => network.ip().to_string(),
+            Err(_) => return Cow::Borrowed(entry),
+        },
+    };
+    if canonical == entry {
+        Cow::Borrowed(entry)
+    } else {
+        Cow::Owned(canonical)
+    }
+}
+
+/// Entries that differ between two parsed datasets, keyed like the stored
+/// entries.
+#[derive(Debug, Default)]
+pub struct RecordDelta {
+    pub added: Vec<CsvRecord>,
+    pub updated: Vec<CsvRecord>,
+    pub deleted: Vec<String>,
+}
+
+pub fn diff_records(old: Vec<CsvRecord>, new: Vec<CsvRecord>) -> RecordDelta {
+    let mut old_map: HashMap<String, CsvRecord> = old
+        .into_iter()
+        .map(|r| (canonical_key(&r.ip).into_owned(), r))
+        .collect();
+
+    let mut delta = RecordDelta::default();
+    for mut record in new {
+        let key = canonical_key(&record.ip).into_owned();
+        match old_map.remove(&key) {
+            None => {
+                record.ip = key;
+                delta.added.push(record);
+            }
+            Some(previous)
+                if previous.flags != record.flags
+                    || previous.note != record.note
+                    || previous.sources != record.sources =>
+            {
+                record.ip = key;
+                delta.updated.push(record);
+            }
+            Some(_) => {}
+        }
+    }
+    delta.deleted = old_map.into_keys().collect();
+    delta.deleted.sort_unstable();
+    delta
+}
+
+/// Imports commit in batches; the marker lets read-only replicas ignore the
+/// intermediate states until the final commit clears it.
+fn mark_import_started(db: &Database, txn: &mut heed::RwTxn) -> Result<(), ImportError> {
+    let mut metadata = db.get_metadata()?;
+    metadata.import_in_progress = true;
+    db.set_metadata(txn, &metadata)?;
+    Ok(())
+}
+
 fn do_full_import(
     db: &Arc<Database>,
     records: &[CsvRecord],
+    rows: Option<&RawRows>,
     hash: &str,
+    bucket_hashes: bool,
 ) -> Result<u64, ImportError> {
-    let count = records.len() as u64;
+    let mut import = FullImport::begin(db)?;
+    import.write(records)?;
+    import.finish(rows, hash, bucket_hashes)
+}
 
-    {
+/// A full import in progress. The tables are cleared up front and records
+/// can then be written in as many calls as the feed is parsed in.
+struct FullImport<'a> {
+    db: &'a Database,
+    txn: Option<heed::RwTxn<'a>>,
+    trie: IpTrie,
+    batch_count: usize,
+    start: Instant,
+}
+
+impl<'a> FullImport<'a> {
+    fn begin(db: &'a Database) -> Result<Self, ImportError> {
+        let start = Instant::now();
         let mut txn = db.begin_write()?;
         db.clear_all(&mut txn)?;
+        mark_import_started(db, &mut txn)?;
         txn.commit()?;
+
+        Ok(Self {
+            db,
+            txn: Some(db.begin_write()?),
+            trie: IpTrie::new(),
+            batch_count: 0,
+            start,
+        })
     }
 
-    let mut trie = IpTrie::new();
-    let mut batch_count = 0;
-    let mut txn = db.begin_write()?;
+    fn write(&mut self, records: &[CsvRecord]) -> Result<(), ImportError> {
+        let db = self.db;
+        for record in records {
+            let txn = self.txn.as_mut().expect("write txn is open until finish");
+            db.insert_record(txn, &record.ip, &record.flags)?;
+            if let Some(note) = &record.note {
+                db.set_note(txn, &record.ip, Some(note))?;
+            }
+            if let Some(sources) = &record.sources {
+                db.set_sources(txn, &record.ip, Some(sources))?;
+            }
 
-    for record in records {
-        db.insert_record(&mut txn, &record.ip, &record.flags)?;
+            if let Some(network) = trie_network(db, &record.ip) {
+                self.trie.replace(network, record.flags);
+            }
 
-        if let Ok(network) = record.ip.parse() {
-            trie.insert(network, record.flags);
+            self.batch_count += 1;
+            if self.batch_count >= BATCH_COMMIT_SIZE {
+                if let Some(txn) = self.txn.take() {
+                    txn.commit()?;
+                }
+                self.txn = Some(db.begin_write()?);
+                self.batch_count = 0;
+            }
         }
+        Ok(())
+    }
 
-        batch_count += 1;
-        if batch_count >= BATCH_COMMIT_SIZE {
-            txn.commit()?;
-            txn = db.begin_write()?;
-            batch_count = 0;
-        }
+    fn finish(
+        mut self,
+        rows: Option<&RawRows>,
+        hash: &str,
+        bucket_hashes: bool,
+    ) -> Result<u64, ImportError> {
+        let db = self.db;
+        let mut txn = self.txn.take().expect("write txn is open until finish");
+        write_raw_rows(db, &mut txn, rows)?;
+
+        // Duplicate and unparsable rows store nothing, so count the keys instead
+        // of the records; the startup self-heal check compares against this.
+        let count = db.count_entries_txn(&txn)?;
+        let mut metadata = db.get_metadata()?;
+        metadata.last_sync = Some(Utc::now().timestamp());
+        metadata.csv_hash = Some(hash.to_owned());
+        metadata.record_count = count;
+        metadata.import_in_progress = false;
+        metadata.bucket_hashes = import_bucket_hashes(db, &txn, bucket_hashes)?;
+        db.set_metadata(&mut txn, &metadata)?;
+        txn.commit()?;
+        metrics::record_import_commit_duration(self.start.elapsed().as_secs_f64());
+
+        db.refresh_has_notes()?;
+        // Every key was rewritten above, so none of the malformed ones remain.
+        db.install_trie(self.trie, 0);
+        db.set_dataset_hash(metadata.csv_hash);
+
+        Ok(count)
     }
+}
 
-    let metadata = Metadata {
-        last_sync: Some(Utc::now().timestamp()),
-        csv_hash: Some(hash.to_owned()),
-        record_count: count,
-    };
-    db.set_metadata(&mut txn, &metadata)?;
-    txn.commit()?;
+fn check_record_minimum(count: usize, config: &Config) -> Result<(), ImportError> {
+    if count >= config.min_import_records {
+        return Ok(());
+    }
 
-    db.swap_trie(trie);
+    error!(
+        "Aborting full import: feed has {} records, minimum is {}; keeping the current dataset",
+        count, config.min_import_records
+    );
+    metrics::inc_sync_aborted();
+    Err(ImportError::TooFewRecords {
+        count,
+        minimum: config.min_import_records,
+    })
+}
 
-    Ok(count)
+fn check_delete_guard(
+    existing: &[CsvRecord],
+    new_records: &[CsvRecord],
+    config: &Config,
+) -> Result<(), ImportError> {
+    if config.allow_mass_delete || existing.is_empty() {
+        return Ok(());
+    }
+
+    let new_keys: HashSet<Cow<str>> = new_records.iter().map(|r| canonical_key(&r.ip)).collect();
+    let deleted = existing
+        .iter()
+        .filter(|r| !new_keys.contains(&canonical_key(&r.ip)))
+        .count() as u64;
+    let existing = existing.len() as u64;
+
+    if deleted as f64 > existing as f64 * config.max_delete_fraction {
+        error!(
+            "Aborting import: it would delete {} of {} records (limit {})",
+            deleted, existing, config.max_delete_fraction
+        );
+        metrics::inc_sync_aborted();
+        return Err(ImportError::TooManyDeletes { deleted, existing });
+    }
+
+    Ok(())
 }
 
 fn do_incremental_import(
     db: &Arc<Database>,
+    existing: &[CsvRecord],
     new_records: &[CsvRecord],
+    rows: Option<&RawRows>,
     hash: &str,
+    bucket_hashes: bool,
 ) -> Result<(u64, u64, u64), ImportError> {
-    let existing = db.get_all_entries()?;
-    let existing_map: HashMap<&str, &ReputationFlags> =
-        existing.iter().map(|(k, f)| (k.as_str(), f)).collect();
+    let existing_map: HashMap<Cow<str>, &CsvRecord> =
+        existing.iter().map(|r| (canonical_key(&r.ip), r)).collect();
 
-    let new_keys: HashSet<&str> = new_records.iter().map(|r| r.ip.as_str()).collect();
+    let record_keys: Vec<Cow<str>> = new_records.iter().map(|r| canonical_key(&r.ip)).collect();
+    let new_keys: HashSet<&str> = record_keys.iter().map(AsRef::as_ref).collect();
 
     let mut added = 0u64;
     let mut updated = 0u64;
     let mut deleted = 0u64;
     let mut batch_count = 0;
 
+    let mut trie = IpTrie::clone(&db.trie());
+    let start = Instant::now();
     let mut txn = db.begin_write()?;
+    mark_import_started(db, &mut txn)?;
 
-    for record in new_records {
-        match existing_map.get(record.ip.as_str()) {
+    for (record, key) in new_records.iter().zip(&record_keys) {
+        let changed = match existing_map.get(key.as_ref()) {
             None => {
-                db.insert_record(&mut txn, &record.ip, &record.flags)?;
                 added += 1;
-                batch_count += 1;
+                true
             }
-            Some(existing_flags) if *existing_flags != &record.flags => {
-                db.insert_record(&mut txn, &record.ip, &record.flags)?;
+            Some(previous)
+                if previous.flags != record.flags
+                    || previous.note != record.note
+                    || previous.sources != record.sources =>
+            {
                 updated += 1;
-                batch_count += 1;
+                true
             }
-            Some(_) => {}
+            Some(_) => false,
+        };
+
+        if changed {
+            db.insert_record(&mut txn, &record.ip, &record.flags)?;
+            db.set_note(&mut txn, &record.ip, record.note.as_deref())?;
+            db.set_sources(&mut txn, &record.ip, record.sources.as_deref())?;
+            if let Some(network) = trie_network(db, &record.ip) {
+                trie.replace(network, record.flags);
+            }
+            batch_count += 1;
         }
 
         if batch_count >= BATCH_COMMIT_SIZE {
@@ -204,12 +874,15 @@ fn do_incremental_import(
         }
     }
 
-    for (ip, _) in &existing {
-        if !new_keys.contains(ip.as_str()) {
+    for CsvRecord { ip, .. } in existing {
+        if !new_keys.contains(canonical_key(ip).as_ref()) {
             if db.delete_record(&mut txn, ip)? {
                 deleted += 1;
                 batch_count += 1;
             }
+            if let Some(network) = trie_network(db, ip) {
+                trie.remove(network);
+            }
 
             if batch_count >= BATCH_COMMIT_SIZE {
                 txn.commit()?;
@@ -219,50 +892,421 @@ fn do_incremental_import(
         }
     }
 
-    let metadata = Metadata {
-        last_sync: Some(Utc::now().timestamp()),
-        csv_hash: Some(hash.to_owned()),
-        record_count: new_records.len() as u64,
-    };
+    write_raw_rows(db, &mut txn, rows)?;
+    let mut metadata = db.get_metadata()?;
+    metadata.last_sync = Some(Utc::now().timestamp());
+    metadata.csv_hash = Some(hash.to_owned());
+    metadata.record_count = db.count_entries_txn(&txn)?;
+    metadata.import_in_progress = false;
+    metadata.bucket_hashes = import_bucket_hashes(db, &txn, bucket_hashes)?;
     db.set_metadata(&mut txn, &metadata)?;
 
     txn.commit()?;
-    db.rebuild_trie()?;
+    metrics::record_import_commit_duration(start.elapsed().as_secs_f64());
+    db.refresh_has_notes()?;
+    db.swap_trie(trie);
+    db.set_dataset_hash(metadata.csv_hash);
 
     Ok((added, updated, deleted))
 }
 
+/// The bucket hashes an import stores with its final commit, or `None` to
+/// clear stale ones when `PROXYD_BUCKET_HASHES` is off.
+fn import_bucket_hashes(
+    db: &Database,
+    txn: &heed::RoTxn,
+    enabled: bool,
+) -> Result<Option<BucketHashes>, ImportError> {
+    if !enabled {
+        return Ok(None);
+    }
+    let start = Instant::now();
+    let hashes = db.bucket_hashes_txn(txn)?;
+    info!(
+        "Computed {} bucket hashes in {:?}",
+        hashes.buckets.len(),
+        start.elapsed()
+    );
+    Ok(Some(hashes))
+}
+
+/// Original CSV lines keyed by their IP column.
+type RawRows = HashMap<String, String>;
+
+/// The original line of every CSV row, keyed by its IP column. Rows spanning
+/// several lines keep only their first.
+pub fn raw_rows(content: &str, ip_column: &str) -> Result<RawRows, ImportError> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(true)
+        .flexible(true)
+        .from_reader(content.as_bytes());
+    let headers = reader
+        .headers()
+        .map_err(|e| ImportError::CsvParse(e.to_string()))?
+        .clone();
+    let ip_index = HeaderIndices::from_headers(&headers, ip_column).ip;
+
+    let mut rows = HashMap::new();
+    for record in reader.records().filter_map(Result::ok) {
+        let (Some(ip), Some(position)) = (record.get(ip_index), record.position()) else {
+            continue;
+        };
+        if ip.is_empty() {
+            continue;
+        }
+        // The reported start can fall on the previous row's line terminator.
+        let line = content[position.byte() as usize..].trim_start_matches(['\r', '\n']);
+        let line = line.split('\n').next().unwrap_or_default();
+        rows.insert(ip.to_owned(), line.trim_end_matches('\r').to_owned());
+    }
+    Ok(rows)
+}
+
+/// Writes the raw rows of an import into its final transaction, skipping rows
+/// that are already stored unchanged. Without rows, the ones left by imports
+/// made with `PROXYD_STORE_RAW_ROWS` on are removed.
+fn write_raw_rows(
+    db: &Database,
+    txn: &mut heed::RwTxn,
+    rows: Option<&RawRows>,
+) -> Result<(), ImportError> {
+    let Some(rows) = rows else {
+        db.clear_raw_rows(txn)?;
+        return Ok(());
+    };
+    for (entry, row) in rows {
+        if db.raw_row_txn(txn, entry)?.as_deref() != Some(row.as_str()) {
+            db.set_raw_row(txn, entry, Some(row))?;
+        }
+    }
+    Ok(())
+}
+
+/// The raw rows of the feeds an import was merged from. An entry listed by
+/// several feeds keeps the line of the first.
+fn parse_raw_rows(feeds: &[&str], config: &Config) -> Result<Option<RawRows>, ImportError> {
+    if !config.store_raw_rows {
+        return Ok(None);
+    }
+    let mut rows = RawRows::new();
+    for feed in feeds {
+        for (entry, row) in raw_rows(feed, &config.ip_column)? {
+            rows.entry(entry).or_insert(row);
+        }
+    }
+    Ok(Some(rows))
+}
+
+async fn retain_previous_dataset(config: &Config, hash: &str) {
+    if let Err(e) = history::retain_current(config, hash).await {
+        warn!(
+            "Could not keep the previous dataset for delta exports: {}",
+            e
+        );
+    }
+}
+
+/// Runs LMDB writes and trie construction on the blocking pool so large
+/// imports do not stall the async runtime.
+async fn run_blocking<T, F>(f: F) -> Result<T, ImportError>
+where
+    F: FnOnce() -> Result<T, ImportError> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f).await?
+}
+
+/// Parses `content`, and the original rows of `feeds` when they are kept, on
+/// the blocking pool. Both are copied so the parse can outlive the borrow.
+async fn parse_blocking(
+    content: &str,
+    feeds: &[&str],
+    config: &Config,
+) -> Result<(Vec<CsvRecord>, Option<RawRows>), ImportError> {
+    let rows = raw_rows_blocking(feeds, config).await?;
+    let (content, parse_config) = (content.to_owned(), config.clone());
+    let records = run_blocking(move || timed_parse(&content, &parse_config)).await?;
+    Ok((records, rows))
+}
+
+/// [`parse_raw_rows`] on the blocking pool; the feeds are only copied when
+/// `PROXYD_STORE_RAW_ROWS` keeps their rows.
+async fn raw_rows_blocking(
+    feeds: &[&str],
+    config: &Config,
+) -> Result<Option<RawRows>, ImportError> {
+    if !config.store_raw_rows {
+        return Ok(None);
+    }
+    let feeds: Vec<String> = feeds.iter().map(|feed| (*feed).to_owned()).collect();
+    let config = config.clone();
+    run_blocking(move || {
+        let feeds: Vec<&str> = feeds.iter().map(String::as_str).collect();
+        parse_raw_rows(&feeds, &config)
+    })
+    .await
+}
+
+/// Where an import stages its feed before it is kept as the local CSV.
+fn staging_path(config: &Config) -> PathBuf {
+    config.csv_path().with_extension("incoming")
+}
+
+/// The feed an import keeps as the local CSV once it has committed.
+enum FeedCopy<'a> {
+    Content(&'a str),
+    /// Already written next to the local CSV and moved into place.
+    File(PathBuf),
+}
+
+async fn keep_feed(config: &Config, hash: &str, feed: FeedCopy<'_>) -> Result<(), ImportError> {
+    retain_previous_dataset(config, hash).await;
+    match feed {
+        FeedCopy::Content(content) => save_csv(&config.csv_path(), content).await?,
+        FeedCopy::File(path) => tokio::fs::rename(path, config.csv_path()).await?,
+    }
+    save_hash(&config.csv_hash_path(), hash).await?;
+    Ok(())
+}
+
+async fn apply_full(
+    db: &Arc<Database>,
+    records: Vec<CsvRecord>,
+    rows: Option<RawRows>,
+    hash: &str,
+    config: &Config,
+) -> Result<u64, ImportError> {
+    check_record_minimum(records.len(), config)?;
+    metrics::set_import_flag_counts(&flag_counts(&records));
+    let (db_ref, hash_owned) = (Arc::clone(db), hash.to_owned());
+    let bucket_hashes = config.bucket_hashes;
+    run_blocking(move || {
+        do_full_import(&db_ref, &records, rows.as_ref(), &hash_owned, bucket_hashes)
+    })
+    .await
+}
+
+/// Whether a full import of `content` is parsed and written chunk by chunk
+/// instead of parsing every record first.
+fn streams_chunks(bytes: usize, config: &Config) -> bool {
+    ParseStrategy::for_size(bytes, config) == ParseStrategy::Chunked && !config.aggregate_cidrs
+}
+
+/// Writes each chunk of a full import before the next one is parsed, so at
+/// most a few chunks of records are held however large the feed is. Chunks
+/// are read on one blocking thread and written on another; the database is
+/// only cleared once the feed has passed its checks, including the record
+/// minimum when `check_minimum` is set.
+async fn stream_full_import<R: Read + Send + 'static>(
+    db: &Arc<Database>,
+    source: R,
+    check_minimum: bool,
+    rows: Option<RawRows>,
+    hash: &str,
+    config: &Config,
+) -> Result<u64, ImportError> {
+    info!("Parsing with the chunked strategy, writing each chunk as it is parsed");
+    metrics::inc_import_parse_strategy(ParseStrategy::Chunked.as_str());
+
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<Vec<CsvRecord>>(1);
+    let reader_config = config.clone();
+    let reader = tokio::task::spawn_blocking(move || {
+        let config = reader_config;
+        let mut chunks = CsvChunks::new(source, &config.ip_column, config.import_chunk_rows)?;
+        chunks.check_flag_columns(&config)?;
+
+        let pool = parse_pool(&config);
+        let mut parse_time = Duration::ZERO;
+        let mut next_chunk = || {
+            let start = Instant::now();
+            let chunk = pool.install(|| chunks.next_chunk());
+            parse_time += start.elapsed();
+            chunk
+        };
+
+        let mut first = Vec::new();
+        if check_minimum {
+            while first.len() < config.min_import_records {
+                match next_chunk() {
+                    Some(chunk) => first.extend(chunk),
+                    None => break,
+                }
+            }
+            check_record_minimum(first.len(), &config)?;
+        }
+
+        let mut counts = [0u64; FLAG_BITS.len()];
+        let mut chunk = Some(first);
+        while let Some(records) = chunk {
+            for (total, count) in counts.iter_mut().zip(flag_counts(&records)) {
+                *total += count;
+            }
+            // The writer only hangs up when it has failed; its error is below.
+            if tx.blocking_send(records).is_err() {
+                break;
+            }
+            chunk = next_chunk();
+        }
+        Ok::<_, ImportError>((parse_time, counts))
+    });
+
+    let (db_ref, hash_owned) = (Arc::clone(db), hash.to_owned());
+    let bucket_hashes = config.bucket_hashes;
+    let writer = tokio::task::spawn_blocking(move || {
+        // The reader sends nothing when the feed fails its checks.
+        let Some(first) = rx.blocking_recv() else {
+            return Ok(0);
+        };
+        let mut import = FullImport::begin(&db_ref)?;
+        import.write(&first)?;
+        while let Some(chunk) = rx.blocking_recv() {
+            import.write(&chunk)?;
+        }
+        import.finish(rows.as_ref(), &hash_owned, bucket_hashes)
+    });
+
+    let (parse_time, counts) = reader.await??;
+    let count = writer.await??;
+    metrics::record_import_parse_duration(parse_time.as_secs_f64());
+    metrics::set_import_flag_counts(&counts);
+    Ok(count)
+}
+
+async fn apply_incremental(
+    db: &Arc<Database>,
+    records: Vec<CsvRecord>,
+    rows: Option<RawRows>,
+    hash: &str,
+    config: &Config,
+) -> Result<(u64, u64, u64), ImportError> {
+    let existing = previous_entries(db, config).await?;
+    check_delete_guard(&existing, &records, config)?;
+    metrics::set_import_flag_counts(&flag_counts(&records));
+    let (db_ref, hash_owned) = (Arc::clone(db), hash.to_owned());
+    let bucket_hashes = config.bucket_hashes;
+    run_blocking(move || {
+        do_incremental_import(
+            &db_ref,
+            &existing,
+            &records,
+            rows.as_ref(),
+            &hash_owned,
+            bucket_hashes,
+        )
+    })
+    .await
+}
+
 pub async fn full_import(
     db: &Arc<Database>,
     content: &str,
     hash: &str,
     config: &Config,
 ) -> Result<u64, ImportError> {
-    info!("Starting full import");
+    full_import_merged(db, content, &[content], hash, config).await
+}
 
-    let records = parse_csv_parallel(content)?;
-    let count = do_full_import(db, &records, hash)?;
+/// A full import of `content` merged from `feeds`, whose original lines are
+/// the ones kept with `PROXYD_STORE_RAW_ROWS`.
+pub async fn full_import_merged(
+    db: &Arc<Database>,
+    content: &str,
+    feeds: &[&str],
+    hash: &str,
+    config: &Config,
+) -> Result<u64, ImportError> {
+    let _guard = IMPORT_LOCK.lock().await;
+    info!("Starting full import");
 
-    save_csv(&config.csv_path(), content).await?;
-    save_hash(&config.csv_hash_path(), hash).await?;
+    let count = if streams_chunks(content.len(), config) {
+        // The feed is staged first so the reader thread can own it; it is
+        // moved into place as the local CSV afterwards.
+        let rows = raw_rows_blocking(feeds, config).await?;
+        let staged = staging_path(config);
+        save_csv(&staged, content).await?;
+        let source = std::fs::File::open(&staged)?;
+        match stream_full_import(db, source, true, rows, hash, config).await {
+            Ok(count) => {
+                keep_feed(config, hash, FeedCopy::File(staged)).await?;
+                count
+            }
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&staged).await;
+                return Err(e);
+            }
+        }
+    } else {
+        let (records, rows) = parse_blocking(content, feeds, config).await?;
+        let count = apply_full(db, records, rows, hash, config).await?;
+        keep_feed(config, hash, FeedCopy::Content(content)).await?;
+        count
+    };
 
     info!("Full import complete: {} records", count);
     Ok(count)
 }
 
+pub fn stored_records(db: &Database) -> Result<Vec<CsvRecord>, ImportError> {
+    let rtxn = db.read_txn()?;
+    stored_records_txn(db, &rtxn)
+}
+
+/// The stored records as `txn` sees them.
+pub fn stored_records_txn(db: &Database, txn: &heed::RoTxn) -> Result<Vec<CsvRecord>, ImportError> {
+    let mut notes = db.get_all_notes_txn(txn)?;
+    let mut sources = db.get_all_sources_txn(txn)?;
+    Ok(db
+        .get_all_entries_txn(txn)?
+        .into_iter()
+        .map(|(ip, flags)| CsvRecord {
+            note: notes.remove(&ip),
+            sources: sources.remove(&ip),
+            ip,
+            flags,
+        })
+        .collect())
+}
+
+async fn previous_entries(db: &Database, config: &Config) -> Result<Vec<CsvRecord>, ImportError> {
+    match load_csv(&config.csv_path()).await {
+        Ok(content) => {
+            let config = config.clone();
+            run_blocking(move || parse_for_import(&content, &config)).await
+        }
+        Err(e) => {
+            info!("Local CSV unavailable ({}), diffing against LMDB", e);
+            stored_records(db)
+        }
+    }
+}
+
+/// An incremental import of a single feed. Uploads go through
+/// [`StagedFeed`] and syncs through [`incremental_import_merged`].
+#[cfg(test)]
 pub async fn incremental_import(
     db: &Arc<Database>,
     content: &str,
     hash: &str,
     config: &Config,
 ) -> Result<(u64, u64, u64), ImportError> {
-    info!("Starting incremental import");
+    incremental_import_merged(db, content, &[content], hash, config).await
+}
 
-    let new_records = parse_csv_parallel(content)?;
-    let (added, updated, deleted) = do_incremental_import(db, &new_records, hash)?;
+/// An incremental import of `content` merged from `feeds`; see
+/// [`full_import_merged`].
+pub async fn incremental_import_merged(
+    db: &Arc<Database>,
+    content: &str,
+    feeds: &[&str],
+    hash: &str,
+    config: &Config,
+) -> Result<(u64, u64, u64), ImportError> {
+    let _guard = IMPORT_LOCK.lock().await;
+    info!("Starting incremental import");
 
-    save_csv(&config.csv_path(), content).await?;
-    save_hash(&config.csv_hash_path(), hash).await?;
+    let (new_records, rows) = parse_blocking(content, feeds, config).await?;
+    let (added, updated, deleted) = apply_incremental(db, new_records, rows, hash, config).await?;
+    keep_feed(config, hash, FeedCopy::Content(content)).await?;
 
     info!(
         "Incremental import complete: {} added, {} updated, {} deleted",
@@ -271,6 +1315,169 @@ pub async fn incremental_import(
     Ok((added, updated, deleted))
 }
 
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportMode {
+    Full,
+    Incremental,
+}
+
+impl ImportMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Full => "full",
+            Self::Incremental => "incremental",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportSummary {
+    pub mode: ImportMode,
+    pub hash: String,
+    pub added: u64,
+    pub updated: u64,
+    pub deleted: u64,
+}
+
+/// A feed written to its own staging file as it arrives and hashed on the
+/// way, so an upload is never held in memory whole. The file is removed
+/// unless an import keeps it as the local CSV.
+pub struct StagedFeed {
+    path: PathBuf,
+    file: tokio::io::BufWriter<tokio::fs::File>,
+    hasher: Sha256,
+    len: usize,
+}
+
+impl StagedFeed {
+    pub async fn create(config: &Config) -> Result<Self, ImportError> {
+        // Uploads are staged before the import lock is taken, so each gets
+        // its own file.
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let id = NEXT.fetch_add(1, Ordering::Relaxed);
+        let path = config.csv_path().with_extension(format!("incoming.{id}"));
+
+        let mut options = tokio::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let file = options.open(&path).await?;
+        Ok(Self {
+            path,
+            file: tokio::io::BufWriter::new(file),
+            hasher: Sha256::new(),
+            len: 0,
+        })
+    }
+
+    /// A staging file for records that arrive already parsed, such as the
+    /// gRPC `Import` stream, starting with the header [`Self::write_records`]
+    /// follows.
+    pub async fn create_for_records(config: &Config) -> Result<Self, ImportError> {
+        let mut staged = Self::create(config).await?;
+        staged
+            .write(&write_rows(Vec::new(), &[], true, false)?)
+            .await?;
+        Ok(staged)
+    }
+
+    /// Appends `records` as CSV rows. Their sources are not kept.
+    pub async fn write_records(&mut self, records: &[CsvRecord]) -> Result<(), ImportError> {
+        self.write(&write_rows(Vec::new(), records, false, false)?)
+            .await
+    }
+
+    pub async fn write(&mut self, bytes: &[u8]) -> Result<(), ImportError> {
+        self.file.write_all(bytes).await?;
+        self.hasher.update(bytes);
+        self.len += bytes.len();
+        Ok(())
+    }
+
+    /// Bytes written so far.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Imports the complete feed. A full import above
+    /// `PROXYD_IMPORT_CHUNKED_THRESHOLD_BYTES` reads the staging file in
+    /// chunks; the file becomes the local CSV once the import commits.
+    pub async fn import(
+        mut self,
+        db: &Arc<Database>,
+        mode: Option<ImportMode>,
+        config: &Config,
+    ) -> Result<ImportSummary, ImportError> {
+        self.file.flush().await?;
+        self.file.get_ref().sync_all().await?;
+        let hash = hex::encode(std::mem::take(&mut self.hasher).finalize());
+
+        let _guard = IMPORT_LOCK.lock().await;
+        let mode = resolve_mode(db, mode)?;
+        info!("Starting {} import of {} bytes", mode.as_str(), self.len);
+        let (added, updated, deleted) = match mode {
+            ImportMode::Full if streams_chunks(self.len, config) => {
+                let rows = self.raw_rows(config).await?;
+                let source = std::fs::File::open(&self.path)?;
+                let count = stream_full_import(db, source, true, rows, &hash, config).await?;
+                (count, 0, 0)
+            }
+            mode => {
+                let (path, parse_config) = (self.path.clone(), config.clone());
+                let (records, rows) = run_blocking(move || {
+                    let content = std::fs::read_to_string(path)?;
+                    let records = timed_parse(&content, &parse_config)?;
+                    Ok((records, parse_raw_rows(&[&content], &parse_config)?))
+                })
+                .await?;
+                match mode {
+                    ImportMode::Full => (apply_full(db, records, rows, &hash, config).await?, 0, 0),
+                    ImportMode::Incremental => {
+                        apply_incremental(db, records, rows, &hash, config).await?
+                    }
+                }
+            }
+        };
+        keep_feed(config, &hash, FeedCopy::File(self.path.clone())).await?;
+
+        Ok(ImportSummary {
+            mode,
+            hash,
+            added,
+            updated,
+            deleted,
+        })
+    }
+
+    async fn raw_rows(&self, config: &Config) -> Result<Option<RawRows>, ImportError> {
+        if !config.store_raw_rows {
+            return Ok(None);
+        }
+        let (path, config) = (self.path.clone(), config.clone());
+        run_blocking(move || {
+            let content = std::fs::read_to_string(path)?;
+            parse_raw_rows(&[&content], &config)
+        })
+        .await
+    }
+}
+
+impl Drop for StagedFeed {
+    fn drop(&mut self) {
+        // Already gone when the import kept it as the local CSV.
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn resolve_mode(db: &Database, mode: Option<ImportMode>) -> Result<ImportMode, ImportError> {
+    Ok(match mode {
+        Some(mode) => mode,
+        None if db.is_empty()? => ImportMode::Full,
+        None => ImportMode::Incremental,
+    })
+}
+
 pub async fn rebuild_from_csv(db: &Arc<Database>, config: &Config) -> Result<u64, ImportError> {
     info!("Rebuilding database from local CSV");
 
@@ -287,8 +1494,24 @@ pub async fn rebuild_from_csv(db: &Arc<Database>, config: &Config) -> Result<u64
         .await
         .unwrap_or_else(|| compute_hash(&content));
 
-    let records = parse_csv_parallel(&content)?;
-    let count = do_full_import(db, &records, &hash)?;
+    if streams_chunks(content.len(), config) {
+        let rows = raw_rows_blocking(&[&content], config).await?;
+        let _guard = IMPORT_LOCK.lock().await;
+        let source = std::io::Cursor::new(content);
+        let count = stream_full_import(db, source, false, rows, &hash, config).await?;
+        info!("Database rebuilt: {} records", count);
+        return Ok(count);
+    }
+
+    let (records, rows) = parse_blocking(&content, &[&content], config).await?;
+    metrics::set_import_flag_counts(&flag_counts(&records));
+    let _guard = IMPORT_LOCK.lock().await;
+    let db_ref = Arc::clone(db);
+    let bucket_hashes = config.bucket_hashes;
+    let count = run_blocking(move || {
+        do_full_import(&db_ref, &records, rows.as_ref(), &hash, bucket_hashes)
+    })
+    .await?;
 
     info!("Database rebuilt: {} records", count);
     Ok(count)
@@ -297,6 +1520,7 @@ pub async fn rebuild_from_csv(db: &Arc<Database>, config: &Config) -> Result<u64
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::IP_COLUMN;
 
     #[test]
     fn test_parse_bool_true_values() {
@@ -319,10 +1543,22 @@ mod tests {
         assert!(!parse_bool("invalid"));
     }
 
+    #[test]
+    fn test_flag_counts_per_flag() {
+        let csv = "ip,proxy,vpn,tor\n192.168.1.1,true,false,true\n10.0.0.0/8,true,true,false\n1.1.1.1,false,false,false";
+        let counts = flag_counts(&parse_csv_parallel(csv, IP_COLUMN).unwrap());
+
+        let count = |flag| counts[FLAG_BITS.iter().position(|f| *f == flag).unwrap()];
+        assert_eq!(count("proxy"), 2);
+        assert_eq!(count("vpn"), 1);
+        assert_eq!(count("tor"), 1);
+        assert_eq!(count("cdn"), 0);
+    }
+
     #[test]
     fn test_parse_csv_parallel_basic() {
         let csv = "ip,proxy,vpn,tor\n192.168.1.1,true,false,true\n10.0.0.0/8,false,true,false";
-        let records = parse_csv_parallel(csv).unwrap();
+        let records = parse_csv_parallel(csv, IP_COLUMN).unwrap();
 
         assert_eq!(records.len(), 2);
         assert_eq!(records[0].ip, "192.168.1.1");
@@ -338,7 +1574,7 @@ mod tests {
     #[test]
     fn test_parse_csv_parallel_missing_columns() {
         let csv = "ip,proxy\n192.168.1.1,true";
-        let records = parse_csv_parallel(csv).unwrap();
+        let records = parse_csv_parallel(csv, IP_COLUMN).unwrap();
 
         assert_eq!(records.len(), 1);
         assert!(records[0].flags.proxy);
@@ -349,7 +1585,7 @@ mod tests {
     #[test]
     fn test_parse_csv_parallel_empty_ip_filtered() {
         let csv = "ip,proxy\n,true\n192.168.1.1,true";
-        let records = parse_csv_parallel(csv).unwrap();
+        let records = parse_csv_parallel(csv, IP_COLUMN).unwrap();
 
         assert_eq!(records.len(), 1);
         assert_eq!(records[0].ip, "192.168.1.1");
@@ -358,15 +1594,53 @@ mod tests {
     #[test]
     fn test_parse_csv_parallel_empty() {
         let csv = "ip,proxy,vpn";
-        let records = parse_csv_parallel(csv).unwrap();
+        let records = parse_csv_parallel(csv, IP_COLUMN).unwrap();
         assert!(records.is_empty());
     }
 
+    #[test]
+    fn test_parse_csv_chunked_matches_parallel() {
+        let csv = "ip,proxy,vpn,note\n\
+                   1.2.3.4,true,false,first\n\
+                   ,true,true,\n\
+                   10.0.0.0/8,false,true,\n\
+                   2001:db8::/32,true,true,last";
+        let parallel = records_to_csv(&parse_csv_parallel(csv, IP_COLUMN).unwrap()).unwrap();
+
+        for chunk_rows in [0, 1, 2, 1000] {
+            let chunked = parse_csv_chunked(csv, IP_COLUMN, chunk_rows).unwrap();
+            assert_eq!(chunked.len(), 3);
+            assert_eq!(
+                records_to_csv(&chunked).unwrap(),
+                parallel,
+                "chunk_rows = {chunk_rows}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_csv_ip_column_by_header() {
+        let csv = "proxy,address,vpn\ntrue,192.168.1.1,false\nfalse,10.0.0.0/8,true";
+        let records = parse_csv_parallel(csv, "address").unwrap();
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].ip, "192.168.1.1");
+        assert!(records[0].flags.proxy);
+        assert_eq!(records[1].ip, "10.0.0.0/8");
+        assert!(records[1].flags.vpn);
+
+        // Without a matching header the first column is used
+        let csv = "addr,proxy\n1.2.3.4,true";
+        let records = parse_csv_parallel(csv, "address").unwrap();
+        assert_eq!(records[0].ip, "1.2.3.4");
+        assert!(records[0].flags.proxy);
+    }
+
     #[test]
     fn test_parse_csv_parallel_all_flag_columns() {
         let csv = "ip,anonblock,proxy,vpn,cdn,public-wifi,rangeblock,school-block,tor,webhost\n\
                    1.2.3.4,1,1,1,1,1,1,1,1,1";
-        let records = parse_csv_parallel(csv).unwrap();
+        let records = parse_csv_parallel(csv, IP_COLUMN).unwrap();
 
         assert_eq!(records.len(), 1);
         let flags = &records[0].flags;
@@ -380,4 +1654,687 @@ mod tests {
         assert!(flags.tor);
         assert!(flags.webhost);
     }
+
+    #[test]
+    fn test_require_all_flags_lists_missing_columns() {
+        let csv = "ip,anonblock,vpn,cdn,public-wifi,rangeblock,school-block,webhost\n1.2.3.4,1,1,1,1,1,1,1";
+        let lenient = Config::default();
+        let strict = Config {
+            require_all_flags: true,
+            ..Config::default()
+        };
+
+        assert!(check_flag_columns(csv, &lenient).is_ok());
+        let err = check_flag_columns(csv, &strict).unwrap_err();
+        assert!(matches!(
+            &err,
+            ImportError::MissingFlagColumns(missing) if missing == &["proxy", "tor"]
+        ));
+        assert_eq!(err.to_string(), "CSV is missing flag columns: proxy, tor");
+
+        let complete = records_to_csv(&parse_csv_parallel(csv, IP_COLUMN).unwrap()).unwrap();
+        assert!(check_flag_columns(&complete, &strict).is_ok());
+    }
+
+    #[test]
+    fn test_records_to_csv_round_trip() {
+        let csv = "ip,anonblock,proxy,vpn,cdn,public-wifi,rangeblock,school-block,tor,webhost\n\
+                   1.2.3.4,0,1,0,0,1,0,0,1,0\n\
+                   2001:db8::/32,1,0,0,0,0,0,0,0,1";
+        let records = parse_csv_parallel(csv, IP_COLUMN).unwrap();
+        let written = records_to_csv(&records).unwrap();
+        let reparsed = parse_csv_parallel(&written, IP_COLUMN).unwrap();
+
+        assert_eq!(reparsed.len(), 2);
+        for (a, b) in records.iter().zip(reparsed.iter()) {
+            assert_eq!(a.ip, b.ip);
+            assert_eq!(a.flags, b.flags);
+        }
+    }
+
+    fn sources<'a>(contents: &[&'a str]) -> Vec<MergeSource<'a>> {
+        contents
+            .iter()
+            .map(|content| MergeSource {
+                name: "test",
+                content,
+                allowed_flags: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_merge_sources_unions_entries_and_flags() {
+        let a = "ip,proxy,vpn\n1.1.1.1,true,false\n2.2.2.2,true,false";
+        let b = "ip,tor\n1.1.1.1,true\n3.3.3.3,true";
+        let merged = parse_csv_parallel(
+            &merge_sources(&sources(&[a, b]), IP_COLUMN, MergePolicy::Union).unwrap(),
+            IP_COLUMN,
+        )
+        .unwrap();
+
+        assert_eq!(merged.len(), 3);
+        assert_eq!(merged[0].ip, "1.1.1.1");
+        assert!(merged[0].flags.proxy && merged[0].flags.tor);
+        assert!(!merged[0].flags.vpn);
+        assert_eq!(merged[1].ip, "2.2.2.2");
+        assert_eq!(merged[2].ip, "3.3.3.3");
+
+        // An entry dropped by one source survives while another source lists it
+        let a = "ip,proxy\n2.2.2.2,true";
+        let merged = parse_csv_parallel(
+            &merge_sources(&sources(&[a, b]), IP_COLUMN, MergePolicy::Union).unwrap(),
+            IP_COLUMN,
+        )
+        .unwrap();
+        let ips: Vec<&str> = merged.iter().map(|r| r.ip.as_str()).collect();
+        assert_eq!(ips, ["2.2.2.2", "1.1.1.1", "3.3.3.3"]);
+        assert!(!merged[1].flags.proxy && merged[1].flags.tor);
+    }
+
+    #[test]
+    fn test_merge_sources_intersection_and_priority() {
+        let a = "ip,proxy,vpn\n1.1.1.1,true,true\n2.2.2.2,true,false";
+        let b = "ip,proxy,vpn\n1.1.1.1,true,false\n3.3.3.3,false,true";
+        let merge = |policy| {
+            parse_csv_parallel(
+                &merge_sources(&sources(&[a, b]), IP_COLUMN, policy).unwrap(),
+                IP_COLUMN,
+            )
+            .unwrap()
+        };
+
+        let merged = merge(MergePolicy::Intersection);
+        assert_eq!(merged.len(), 3);
+        assert!(merged[0].flags.proxy && !merged[0].flags.vpn);
+        assert!(merged[1].flags.proxy);
+        assert!(merged[2].flags.vpn);
+
+        let merged = merge(MergePolicy::Priority);
+        assert_eq!(merged.len(), 3);
+        assert!(merged[0].flags.proxy && merged[0].flags.vpn);
+        assert!(merged[2].flags.vpn);
+
+        assert_eq!(
+            MergePolicy::parse(" Priority "),
+            Some(MergePolicy::Priority)
+        );
+        assert_eq!(MergePolicy::parse("vote"), None);
+    }
+
+    #[test]
+    fn test_merge_sources_restricts_flags_to_allowed() {
+        let proxies = "ip,proxy,cdn\n1.1.1.1,true,false\n2.2.2.2,false,true";
+        let tor_exits = "ip,tor,cdn\n1.1.1.1,true,true\n3.3.3.3,true,true\n4.4.4.4,false,true";
+        let merge = |allowed_flags| {
+            let sources = [
+                MergeSource {
+                    name: "proxies",
+                    content: proxies,
+                    allowed_flags: None,
+                },
+                MergeSource {
+                    name: "tor-exits",
+                    content: tor_exits,
+                    allowed_flags,
+                },
+            ];
+            parse_csv_parallel(
+                &merge_sources(&sources, IP_COLUMN, MergePolicy::Union).unwrap(),
+                IP_COLUMN,
+            )
+            .unwrap()
+        };
+
+        let merged = merge(Some(ReputationFlags {
+            tor: true,
+            ..Default::default()
+        }));
+        assert_eq!(merged.len(), 4);
+        assert!(merged[0].flags.proxy && merged[0].flags.tor && !merged[0].flags.cdn);
+        assert!(merged[1].flags.cdn);
+        assert_eq!(merged[2].ip, "3.3.3.3");
+        assert!(merged[2].flags.tor && !merged[2].flags.cdn);
+        // Still listed, but with nothing it may set
+        assert_eq!(merged[3].ip, "4.4.4.4");
+        assert_eq!(merged[3].flags.to_mask(), 0);
+
+        let merged = merge(None);
+        assert!(merged[0].flags.cdn && merged[2].flags.cdn);
+    }
+
+    #[test]
+    fn test_merge_sources_matches_equivalent_entries() {
+        let a = "ip,proxy,vpn\n1.1.1.1,true,true";
+        let b = "ip,proxy,vpn\n1.1.1.1/32,true,false";
+        let merged = parse_csv_parallel(
+            &merge_sources(&sources(&[a, b]), IP_COLUMN, MergePolicy::Intersection).unwrap(),
+            IP_COLUMN,
+        )
+        .unwrap();
+        assert_eq!(merged.len(), 1);
+        assert!(merged[0].flags.proxy && !merged[0].flags.vpn);
+    }
+
+    #[test]
+    fn test_restricted_sources_only_merge_their_flags() {
+        let proxies = "ip,proxy,tor\n1.1.1.1,true,false";
+        let tor_exits = "ip,proxy,tor\n1.1.1.1,false,true";
+        let proxies = || MergeSource {
+            name: "proxies",
+            content: proxies,
+            allowed_flags: None,
+        };
+        let tor_exits = || MergeSource {
+            name: "tor-exits",
+            content: tor_exits,
+            allowed_flags: Some(ReputationFlags {
+                tor: true,
+                ..Default::default()
+            }),
+        };
+        let merge = |sources: &[MergeSource], policy| {
+            parse_csv_parallel(
+                &merge_sources(sources, IP_COLUMN, policy).unwrap(),
+                IP_COLUMN,
+            )
+            .unwrap()
+            .remove(0)
+        };
+        let proxy = ReputationFlags {
+            proxy: true,
+            ..Default::default()
+        };
+
+        // The restricted source's proxy=false does not clear the other's
+        let merged = merge(&[proxies(), tor_exits()], MergePolicy::Intersection);
+        assert_eq!(merged.flags, proxy);
+
+        // Each flag comes from the first source authoritative for it
+        let merged = merge(&[tor_exits(), proxies()], MergePolicy::Priority);
+        assert_eq!(merged.flags, ReputationFlags { tor: true, ..proxy });
+        assert_eq!(
+            merged.sources.as_deref(),
+            Some("proxy=proxies|tor=tor-exits")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_merge_sources_records_flag_provenance() {
+        let hosting = "ip,proxy,vpn\n10.0.0.0/8,true,true\n10.1.0.0/16,true,false";
+        let vpns = "ip,proxy,vpn\n10.0.0.0/8,false,true\n10.1.0.0/16,false,true";
+        let proxies = "ip,proxy,vpn\n10.0.0.0/8,true,true\n192.0.2.1,true,false";
+        let merge = |policy| {
+            let sources: Vec<MergeSource> =
+                [("hosting", hosting), ("vpns", vpns), ("proxies", proxies)]
+                    .into_iter()
+                    .map(|(name, content)| MergeSource {
+                        name,
+                        content,
+                        allowed_flags: None,
+                    })
+                    .collect();
+            merge_sources(&sources, IP_COLUMN, policy).unwrap()
+        };
+        let provenance = |csv: &str| -> Vec<(String, Option<String>)> {
+            parse_csv_parallel(csv, IP_COLUMN)
+                .unwrap()
+                .into_iter()
+                .map(|r| (r.ip, r.sources))
+                .collect()
+        };
+
+        let union = merge(MergePolicy::Union);
+        assert_eq!(
+            provenance(&union),
+            [
+                (
+                    "10.0.0.0/8".to_owned(),
+                    Some("proxy=hosting proxies|vpn=hosting vpns proxies".to_owned())
+                ),
+                (
+                    "10.1.0.0/16".to_owned(),
+                    Some("proxy=hosting|vpn=vpns".to_owned())
+                ),
+                ("192.0.2.1".to_owned(), Some("proxy=proxies".to_owned())),
+            ]
+        );
+        assert_eq!(
+            provenance(&merge(MergePolicy::Intersection))[0],
+            (
+                "10.0.0.0/8".to_owned(),
+                Some("vpn=hosting vpns proxies".to_owned())
+            )
+        );
+        assert_eq!(
+            provenance(&merge(MergePolicy::Priority))[0],
+            (
+                "10.0.0.0/8".to_owned(),
+                Some("proxy=hosting|vpn=hosting".to_owned())
+            )
+        );
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let config = Config {
+            data_dir: dir.path().to_path_buf(),
+            ..Config::default()
+        };
+        let db = Database::open(&config.db_path()).unwrap();
+        full_import(&db, &union, &compute_hash(&union), &config)
+            .await
+            .unwrap();
+        assert_eq!(
+            db.lookup_sources(["10.0.0.0/8", "10.1.0.0/16", "192.0.2.2"])
+                .unwrap(),
+            [
+                Some("proxy=hosting proxies|vpn=hosting vpns proxies".to_owned()),
+                Some("proxy=hosting|vpn=vpns".to_owned()),
+                None,
+            ]
+        );
+
+        // 10.0.0.0/8 keeps its flags and only changes provenance, which an
+        // incremental import still writes; 10.1.0.0/16 loses vpn
+        let priority = merge(MergePolicy::Priority);
+        let (added, updated, deleted) =
+            incremental_import(&db, &priority, &compute_hash(&priority), &config)
+                .await
+                .unwrap();
+        assert_eq!((added, updated, deleted), (0, 2, 0));
+        assert_eq!(
+            db.lookup_sources(["10.0.0.0/8"]).unwrap(),
+            [Some("proxy=hosting|vpn=hosting".to_owned())]
+        );
+    }
+
+    fn import_csv(db: &Arc<Database>, csv: &str) {
+        let records = parse_csv_parallel(csv, IP_COLUMN).unwrap();
+        let existing = stored_records(db).unwrap();
+        do_incremental_import(db, &existing, &records, None, &compute_hash(csv), false).unwrap();
+    }
+
+    #[test]
+    fn test_incremental_trie_matches_rebuild() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let db = Database::open(dir.path()).unwrap();
+
+        let initial = "ip,proxy,vpn\n\
+                       1.2.3.4,true,false\n\
+                       10.0.0.0/8,true,false\n\
+                       10.1.0.0/16,false,true\n\
+                       172.16.0.0/12,true,true\n\
+                       2001:db8::/32,false,true";
+        let records = parse_csv_parallel(initial, IP_COLUMN).unwrap();
+        do_full_import(&db, &records, None, &compute_hash(initial), false).unwrap();
+
+        let next = "ip,proxy,vpn\n\
+                    5.6.7.8,true,false\n\
+                    10.0.0.0/8,false,true\n\
+                    172.16.0.0/12,true,true\n\
+                    192.168.0.0/16,true,false\n\
+                    2001:db8:1::/48,true,false";
+        import_csv(&db, next);
+
+        let incremental = db.trie().entries();
+        db.rebuild_trie().unwrap();
+        let rebuilt = db.trie().entries();
+
+        assert_eq!(incremental, rebuilt);
+        let networks: Vec<String> = rebuilt.iter().map(|(n, _)| n.to_string()).collect();
+        assert_eq!(
+            networks,
+            [
+                "10.0.0.0/8",
+                "172.16.0.0/12",
+                "192.168.0.0/16",
+                "2001:db8:1::/48"
+            ]
+        );
+        assert!(rebuilt[0].1.vpn && !rebuilt[0].1.proxy);
+    }
+
+    #[test]
+    fn test_cidr_only_imports_keep_hosts_in_trie() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let db = Database::open(dir.path()).unwrap();
+        db.set_cidr_only(true);
+
+        let initial = "ip,proxy\n1.2.3.4,true\n10.0.0.0/8,true";
+        let records = parse_csv_parallel(initial, IP_COLUMN).unwrap();
+        do_full_import(&db, &records, None, &compute_hash(initial), false).unwrap();
+        let next = "ip,proxy\n5.6.7.8,true\n10.0.0.0/8,true";
+        import_csv(&db, next);
+
+        let incremental = db.trie().entries();
+        db.rebuild_trie().unwrap();
+        assert_eq!(incremental, db.trie().entries());
+        let networks: Vec<String> = incremental.iter().map(|(n, _)| n.to_string()).collect();
+        assert_eq!(networks, ["5.6.7.8/32", "10.0.0.0/8"]);
+
+        // Stored hosts read back in their bare form, so nothing looks changed
+        let records = parse_csv_parallel(next, IP_COLUMN).unwrap();
+        let existing = stored_records(&db).unwrap();
+        let counts =
+            do_incremental_import(&db, &existing, &records, None, &compute_hash(next), false)
+                .unwrap();
+        assert_eq!(counts, (0, 0, 0));
+    }
+
+    #[test]
+    fn test_equivalent_forms_do_not_churn() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let db = Database::open(dir.path()).unwrap();
+
+        let full = "ip,proxy\n\
+                    2001:0db8:0000:0000:0000:0000:0000:0001,true\n\
+                    2001:0db8:0000::/48,true\n\
+                    10.1.2.3/8,true\n\
+                    1.2.3.4/32,true";
+        import_csv(&db, full);
+        assert_eq!(db.get_all_entries().unwrap().len(), 4);
+
+        let compressed = "ip,proxy\n\
+                          2001:db8::1,true\n\
+                          2001:db8::/48,true\n\
+                          10.0.0.0/8,true\n\
+                          1.2.3.4,true";
+        let records = parse_csv_parallel(compressed, IP_COLUMN).unwrap();
+        let existing = stored_records(&db).unwrap();
+        let changes = do_incremental_import(
+            &db,
+            &existing,
+            &records,
+            None,
+            &compute_hash(compressed),
+            false,
+        )
+        .unwrap();
+        assert_eq!(changes, (0, 0, 0));
+
+        let records = parse_csv_parallel(full, IP_COLUMN).unwrap();
+        let existing = stored_records(&db).unwrap();
+        let changes =
+            do_incremental_import(&db, &existing, &records, None, &compute_hash(full), false)
+                .unwrap();
+        assert_eq!(changes, (0, 0, 0));
+
+        let saved = parse_csv_parallel(full, IP_COLUMN).unwrap();
+        let records = parse_csv_parallel(compressed, IP_COLUMN).unwrap();
+        let changes = do_incremental_import(
+            &db,
+            &saved,
+            &records,
+            None,
+            &compute_hash(compressed),
+            false,
+        )
+        .unwrap();
+        assert_eq!(changes, (0, 0, 0));
+        assert_eq!(db.get_all_entries().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_notes_are_bounded_stored_and_updated() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let db = Database::open(dir.path()).unwrap();
+        let long = "x".repeat(MAX_NOTE_LEN + 10);
+
+        let initial = format!(
+            "ip,proxy,note\n\
+             1.2.3.4,true,reported by abuse desk\n\
+             10.0.0.0/8,true,{long}\n\
+             172.16.0.0/12,true,"
+        );
+        let records = parse_csv_parallel(&initial, IP_COLUMN).unwrap();
+        assert_eq!(
+            records[1].note.as_ref().map(String::len),
+            Some(MAX_NOTE_LEN)
+        );
+        assert_eq!(records[2].note, None);
+        do_full_import(&db, &records, None, &compute_hash(&initial), false).unwrap();
+
+        let notes = db
+            .lookup_notes(["1.2.3.4", "10.9.9.9/8", "172.16.0.0/12"])
+            .unwrap();
+        assert_eq!(notes[0].as_deref(), Some("reported by abuse desk"));
+        assert_eq!(
+            notes[1].as_deref(),
+            Some(long.as_str()).map(|n| &n[..MAX_NOTE_LEN])
+        );
+        assert_eq!(notes[2], None);
+
+        let next = "ip,proxy,comment\n\
+                    1.2.3.4,true,\n\
+                    10.0.0.0/8,true,datacenter range";
+        let records = parse_csv_parallel(next, IP_COLUMN).unwrap();
+        let existing = stored_records(&db).unwrap();
+        let changes =
+            do_incremental_import(&db, &existing, &records, None, &compute_hash(next), false)
+                .unwrap();
+        assert_eq!(changes, (0, 2, 1));
+
+        let notes = db
+            .lookup_notes(["1.2.3.4", "10.0.0.0/8", "172.16.0.0/12"])
+            .unwrap();
+        assert_eq!(notes, [None, Some("datacenter range".to_owned()), None]);
+        assert!(db.has_notes());
+
+        let last = "ip,proxy\n10.0.0.0/8,true";
+        let records = parse_csv_parallel(last, IP_COLUMN).unwrap();
+        let existing = stored_records(&db).unwrap();
+        do_incremental_import(&db, &existing, &records, None, &compute_hash(last), false).unwrap();
+        assert!(!db.has_notes());
+    }
+
+    #[tokio::test]
+    async fn test_delete_guard_keeps_data_on_truncated_feed() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut config = Config {
+            data_dir: dir.path().to_path_buf(),
+            max_delete_fraction: 0.5,
+            allow_mass_delete: false,
+            ..Config::default()
+        };
+        let db = Database::open(&config.db_path()).unwrap();
+
+        let full: String = std::iter::once("ip,proxy".to_owned())
+            .chain((1..=10).map(|i| format!("10.0.0.{i},true")))
+            .collect::<Vec<_>>()
+            .join("\n");
+        full_import(&db, &full, &compute_hash(&full), &config)
+            .await
+            .unwrap();
+
+        let truncated = "ip,proxy\n10.0.0.1,true\n10.0.0.2,true";
+        let err = incremental_import(&db, truncated, &compute_hash(truncated), &config)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ImportError::TooManyDeletes {
+                deleted: 8,
+                existing: 10
+            }
+        ));
+        assert!(db
+            .lookup_ip("10.0.0.10".parse().unwrap())
+            .unwrap()
+            .is_some());
+
+        config.allow_mass_delete = true;
+        let (_, _, deleted) = incremental_import(&db, truncated, &compute_hash(truncated), &config)
+            .await
+            .unwrap();
+        assert_eq!(deleted, 8);
+        assert!(db
+            .lookup_ip("10.0.0.10".parse().unwrap())
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_raw_rows_keep_the_original_feed_lines() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut config = Config {
+            data_dir: dir.path().to_path_buf(),
+            store_raw_rows: true,
+            ..Config::default()
+        };
+        let db = Database::open(&config.db_path()).unwrap();
+        let hosting = "ip,proxy,asn\n10.0.0.0/8,true,AS64500\n";
+        let vpns = "ip,vpn\n10.0.0.0/8,true\n192.0.2.1,true\n";
+        let sources: Vec<MergeSource> = [("hosting", hosting), ("vpns", vpns)]
+            .into_iter()
+            .map(|(name, content)| MergeSource {
+                name,
+                content,
+                allowed_flags: None,
+            })
+            .collect();
+        let merged = merge_sources(&sources, IP_COLUMN, MergePolicy::Union).unwrap();
+
+        full_import_merged(
+            &db,
+            &merged,
+            &[hosting, vpns],
+            &compute_hash(&merged),
+            &config,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            db.raw_row("10.0.0.0/8").unwrap().as_deref(),
+            Some("10.0.0.0/8,true,AS64500")
+        );
+        assert_eq!(
+            db.raw_row("192.0.2.1").unwrap().as_deref(),
+            Some("192.0.2.1,true")
+        );
+
+        config.store_raw_rows = false;
+        incremental_import(&db, vpns, &compute_hash(vpns), &config)
+            .await
+            .unwrap();
+        assert_eq!(db.raw_row("10.0.0.0/8").unwrap(), None);
+        assert_eq!(db.raw_row("192.0.2.1").unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_full_import_refuses_empty_feed() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut config = Config {
+            data_dir: dir.path().to_path_buf(),
+            min_import_records: 1,
+            ..Config::default()
+        };
+        let db = Database::open(&config.db_path()).unwrap();
+
+        let csv = "ip,proxy\n1.2.3.4,true";
+        full_import(&db, csv, &compute_hash(csv), &config)
+            .await
+            .unwrap();
+
+        for empty in ["", "ip,proxy", "ip,proxy\n"] {
+            let err = full_import(&db, empty, &compute_hash(empty), &config)
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                err,
+                ImportError::TooFewRecords {
+                    count: 0,
+                    minimum: 1
+                }
+            ));
+        }
+        assert!(db.lookup_ip("1.2.3.4".parse().unwrap()).unwrap().is_some());
+        assert_eq!(db.get_metadata().unwrap().csv_hash, Some(compute_hash(csv)));
+
+        config.min_import_records = 0;
+        full_import(&db, "ip,proxy", &compute_hash("ip,proxy"), &config)
+            .await
+            .unwrap();
+        assert!(db.is_empty().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_imports_commit_bucket_hashes_with_the_records() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut config = Config {
+            data_dir: dir.path().to_path_buf(),
+            bucket_hashes: true,
+            ..Config::default()
+        };
+        let db = Database::open(&config.db_path()).unwrap();
+
+        let v1 = "ip,proxy\n1.2.3.4,true\n10.0.0.0/8,true";
+        full_import(&db, v1, &compute_hash(v1), &config)
+            .await
+            .unwrap();
+        let stored = db.get_metadata().unwrap().bucket_hashes;
+        assert_eq!(stored, Some(db.bucket_hashes().unwrap()));
+
+        let v2 = "ip,proxy\n1.2.3.4,true\n10.0.0.0/8,false";
+        incremental_import(&db, v2, &compute_hash(v2), &config)
+            .await
+            .unwrap();
+        let meta = db.get_metadata().unwrap();
+        assert_ne!(meta.bucket_hashes, stored);
+        assert_eq!(meta.bucket_hashes, Some(db.bucket_hashes().unwrap()));
+
+        config.bucket_hashes = false;
+        full_import(&db, v1, &compute_hash(v1), &config)
+            .await
+            .unwrap();
+        assert_eq!(db.get_metadata().unwrap().bucket_hashes, None);
+    }
+
+    #[tokio::test]
+    async fn test_chunked_full_import_streams_every_chunk() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let config = Config {
+            data_dir: dir.path().to_path_buf(),
+            import_chunked_threshold: Some(0),
+            import_chunk_rows: 3,
+            min_import_records: 5,
+            ..Config::default()
+        };
+        let db = Database::open(&config.db_path()).unwrap();
+
+        let csv: String = std::iter::once("ip,proxy,vpn".to_owned())
+            .chain((1..=10).map(|i| format!("10.0.0.{i},true,{}", i % 2 == 0)))
+            .chain([
+                "10.0.0.1,false,true".to_owned(),
+                "10.1.0.0/16,true,false".to_owned(),
+            ])
+            .collect::<Vec<_>>()
+            .join("\n");
+        let count = full_import(&db, &csv, &compute_hash(&csv), &config)
+            .await
+            .unwrap();
+        assert_eq!(count, 11);
+        assert_eq!(db.count_entries().unwrap(), 11);
+
+        let later_row = db.lookup_ip("10.0.0.1".parse().unwrap()).unwrap().unwrap();
+        assert!(!later_row.proxy && later_row.vpn);
+        let streamed = db.trie().entries();
+        assert_eq!(streamed.len(), 1);
+        db.rebuild_trie().unwrap();
+        assert_eq!(streamed, db.trie().entries());
+        assert!(!db.get_metadata().unwrap().import_in_progress);
+
+        let short = "ip,proxy\n1.2.3.4,true\n5.6.7.8,true";
+        let err = full_import(&db, short, &compute_hash(short), &config)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ImportError::TooFewRecords {
+                count: 2,
+                minimum: 5
+            }
+        ));
+        assert_eq!(db.count_entries().unwrap(), 11);
+        assert!(!staging_path(&config).exists());
+        assert_eq!(load_csv(&config.csv_path()).await.unwrap(), csv);
+    }
 }
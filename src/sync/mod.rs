@@ -1,5 +1,11 @@
+pub mod aggregate;
 pub mod downloader;
+pub mod error;
+pub mod history;
 pub mod importer;
+pub mod replica;
+#[cfg(feature = "s3")]
+pub mod s3;
 pub mod scheduler;
 
 pub use importer::rebuild_from_csv;
@@ -0,0 +1,59 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::db::{Database, Metadata};
+use crate::readiness::Readiness;
+use crate::sync::scheduler::update_metrics_from_db;
+
+/// Keeps a read-only replica in step with the writer process that shares its
+/// LMDB directory. LMDB readers always see the last committed data; only the
+/// in-memory trie needs rebuilding.
+pub async fn run_replica_refresh(
+    db: Arc<Database>,
+    interval: Duration,
+    readiness: Readiness,
+    cancel_token: CancellationToken,
+) {
+    let mut seen = db.get_metadata().ok();
+    loop {
+        tokio::select! {
+            () = sleep(interval) => {
+                if let Some(meta) = reload(&db, seen.as_ref()).await {
+                    update_metrics_from_db(&db, &meta);
+                    readiness.refresh(&db);
+                    seen = Some(meta);
+                }
+            }
+            () = cancel_token.cancelled() => break,
+        }
+    }
+}
+
+async fn reload(db: &Arc<Database>, seen: Option<&Metadata>) -> Option<Metadata> {
+    let db = Arc::clone(db);
+    let seen = seen.cloned();
+    let reloaded = tokio::task::spawn_blocking(move || db.reload_if_changed(seen.as_ref())).await;
+    match reloaded {
+        Ok(Ok(Some(meta))) => {
+            info!(
+                "Reloaded dataset {} written by another process ({} records)",
+                meta.csv_hash.as_deref().unwrap_or("-"),
+                meta.record_count
+            );
+            Some(meta)
+        }
+        Ok(Ok(None)) => None,
+        Ok(Err(e)) => {
+            warn!("Replica reload failed: {}", e);
+            None
+        }
+        Err(e) => {
+            warn!("Replica reload task failed: {}", e);
+            None
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use aws_sdk_s3::error::SdkError;
+use aws_sdk_s3::primitives::{DateTime, DateTimeFormat};
+use aws_sdk_s3::Client;
+use tokio::sync::OnceCell;
+use tracing::info;
+
+use super::downloader::{
+    compute_hash, extract_csv, hash_bytes, is_tar_gz, DownloadError, DownloadResult,
+    MAX_EXTRACTED_CSV_BYTES,
+};
+
+async fn get_s3_client() -> &'static Client {
+    static CLIENT: OnceCell<Client> = OnceCell::const_new();
+    CLIENT
+        .get_or_init(|| async {
+            let config = aws_config::load_from_env().await;
+            Client::new(&config)
+        })
+        .await
+}
+
+fn parse_s3_url(url: &str) -> Option<(&str, &str)> {
+    let path = url.strip_prefix("s3://")?;
+    let (bucket, key) = path.split_once('/')?;
+    if bucket.is_empty() || key.is_empty() {
+        return None;
+    }
+    Some((bucket, key))
+}
+
+pub async fn download_s3_once(
+    url: &str,
+    last_modified: Option<&str>,
+) -> Result<Option<DownloadResult>, DownloadError> {
+    let (bucket, key) =
+        parse_s3_url(url).ok_or_else(|| DownloadError::InvalidUrl(url.to_owned()))?;
+
+    let mut request = get_s3_client().await.get_object().bucket(bucket).key(key);
+    if let Some(since) =
+        last_modified.and_then(|s| DateTime::from_str(s, DateTimeFormat::HttpDate).ok())
+    {
+        request = request.if_modified_since(since);
+    }
+
+    let output = match request.send().await {
+        Ok(output) => output,
+        Err(SdkError::ServiceError(e)) if e.raw().status().as_u16() == 304 => {
+            info!(
+                "S3 object {} not modified since {}",
+                url,
+                last_modified.unwrap_or("-")
+            );
+            return Ok(None);
+        }
+        Err(e) => return Err(DownloadError::S3(e.to_string())),
+    };
+
+    let last_modified = output
+        .last_modified()
+        .and_then(|t| t.fmt(DateTimeFormat::HttpDate).ok());
+    let archive = is_tar_gz(url, output.content_type());
+    let bytes = output
+        .body
+        .collect()
+        .await
+        .map_err(|e| DownloadError::S3(e.to_string()))?
+        .into_bytes();
+    let (content, archive_hash) = if archive {
+        (
+            extract_csv(&bytes, MAX_EXTRACTED_CSV_BYTES)?,
+            Some(hash_bytes(&bytes)),
+        )
+    } else {
+        let content =
+            String::from_utf8(bytes.to_vec()).map_err(|e| DownloadError::S3(e.to_string()))?;
+        (content, None)
+    };
+
+    let hash = compute_hash(&content);
+    info!("Downloaded CSV from S3, hash: {}", hash);
+
+    Ok(Some(DownloadResult {
+        content,
+        hash,
+        archive_hash,
+        last_modified,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_s3_url() {
+        assert_eq!(
+            parse_s3_url("s3://feeds/proxyd/blocks.csv"),
+            Some(("feeds", "proxyd/blocks.csv"))
+        );
+        assert_eq!(parse_s3_url("s3://feeds"), None);
+        assert_eq!(parse_s3_url("s3://feeds/"), None);
+        assert_eq!(parse_s3_url("s3:///blocks.csv"), None);
+        assert_eq!(parse_s3_url("https://feeds/blocks.csv"), None);
+    }
+}
@@ -1,17 +1,30 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Instant;
 
 use chrono::{Duration, Utc};
+use serde::Serialize;
 use thiserror::Error;
+use tokio::sync::Mutex;
 use tokio::time::{sleep, Duration as TokioDuration};
 use tokio_util::sync::CancellationToken;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 use crate::config::Config;
 use crate::db::{Database, DbError, Metadata};
+use crate::maintenance::Maintenance;
 use crate::metrics;
-use crate::sync::downloader::{download_csv, load_hash, DownloadError};
-use crate::sync::importer::{full_import, incremental_import, ImportError};
+use crate::readiness::Readiness;
+use crate::sync::downloader::{
+    compute_hash, download_csv, download_csv_if_modified, load_csv, load_hash, DownloadError,
+    DownloadResult,
+};
+use crate::sync::error::FailureKind;
+use crate::sync::importer::{
+    check_flag_columns, full_import, full_import_merged, incremental_import_merged, merge_sources,
+    ImportError, ImportMode, ImportSummary, MergeSource,
+};
 
 #[derive(Error, Debug)]
 pub enum SyncError {
@@ -21,6 +34,190 @@ pub enum SyncError {
     Import(#[from] ImportError),
     #[error("Database error: {0}")]
     Database(#[from] DbError),
+    #[error("A sync is already in progress")]
+    AlreadyRunning,
+}
+
+impl SyncError {
+    pub fn kind(&self) -> FailureKind {
+        match self {
+            Self::Download(e) => e.kind(),
+            Self::Import(e) => e.kind(),
+            Self::Database(_) => FailureKind::Internal,
+            Self::AlreadyRunning => FailureKind::Conflict,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum SyncOutcome {
+    NotModified,
+    Unchanged,
+    Imported(ImportSummary),
+}
+
+impl SyncOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::NotModified => "not_modified",
+            Self::Unchanged => "unchanged",
+            Self::Imported(_) => "imported",
+        }
+    }
+}
+
+static SYNC_LOCK: Mutex<()> = Mutex::const_new(());
+static SYNC_HEALTH: SyncHealth = SyncHealth::new();
+static DOWNLOAD_BREAKER: DownloadBreaker = DownloadBreaker::new();
+
+/// Each further failure after the breaker opens doubles the backoff, up to 8x.
+const MAX_BACKOFF_DOUBLINGS: usize = 3;
+
+/// Counts consecutive failed syncs, so that one transient failure does not mark
+/// the service unhealthy while the previously imported data is still served.
+/// Health follows the age of that data instead, via `PROXYD_MAX_DATA_AGE_MS`.
+struct SyncHealth {
+    consecutive_failures: AtomicUsize,
+}
+
+impl SyncHealth {
+    const fn new() -> Self {
+        Self {
+            consecutive_failures: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the number of syncs that have failed in a row.
+    fn record(&self, succeeded: bool) -> usize {
+        if succeeded {
+            self.consecutive_failures.store(0, Ordering::Relaxed);
+            0
+        } else {
+            self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1
+        }
+    }
+
+    fn is_healthy(&self, db: &Database, config: &Config) -> bool {
+        let threshold = config.sync_failure_threshold;
+        db.is_healthy()
+            && db.is_empty().is_ok_and(|empty| !empty)
+            && (threshold == 0 || self.consecutive_failures.load(Ordering::Relaxed) < threshold)
+            && is_fresh(db, config)
+    }
+}
+
+/// Whether the last successful sync is within `PROXYD_MAX_DATA_AGE_MS`.
+fn is_fresh(db: &Database, config: &Config) -> bool {
+    let Some(max_age) = config.max_data_age else {
+        return true;
+    };
+    let Ok(Some(last_sync)) = db.get_metadata().map(|meta| meta.last_sync) else {
+        return false;
+    };
+    let age = (Utc::now().timestamp() - last_sync).max(0);
+    u64::try_from(age).is_ok_and(|age| age <= max_age.as_secs())
+}
+
+/// Updates `proxyd_up` after a sync attempt. Health only degrades once
+/// `PROXYD_SYNC_FAILURE_THRESHOLD` syncs in a row have failed, once the data
+/// is older than `PROXYD_MAX_DATA_AGE_MS`, or when there is no data to serve.
+pub fn record_sync_outcome(db: &Database, config: &Config, succeeded: bool) {
+    let failures = SYNC_HEALTH.record(succeeded);
+    metrics::set_sync_consecutive_failures(failures);
+    let healthy = SYNC_HEALTH.is_healthy(db, config);
+    if failures > 0 && healthy {
+        warn!(
+            "{} consecutive sync failure(s), still serving existing data (unhealthy after {})",
+            failures, config.sync_failure_threshold
+        );
+    }
+    metrics::set_health_status(healthy);
+}
+
+/// Re-evaluates `proxyd_up` between syncs, so it drops once the data ages past
+/// `PROXYD_MAX_DATA_AGE_MS` even when no sync runs to notice.
+pub fn refresh_health(db: &Database, config: &Config) {
+    metrics::set_health_status(SYNC_HEALTH.is_healthy(db, config));
+}
+
+/// Records a sync that found the served data still current, so its age
+/// counts from now.
+fn mark_synced(db: &Database) -> Result<(), DbError> {
+    db.update_metadata(|meta| meta.last_sync = Some(Utc::now().timestamp()))
+}
+
+/// Holds off scheduled syncs once `PROXYD_DOWNLOAD_CIRCUIT_THRESHOLD` downloads
+/// in a row have failed, so a source that is down is not hammered every day.
+struct DownloadBreaker {
+    state: std::sync::Mutex<BreakerState>,
+}
+
+struct BreakerState {
+    consecutive_failures: usize,
+    last_failure: Option<Instant>,
+}
+
+impl DownloadBreaker {
+    const fn new() -> Self {
+        Self {
+            state: std::sync::Mutex::new(BreakerState {
+                consecutive_failures: 0,
+                last_failure: None,
+            }),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, BreakerState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Returns whether the breaker is open after recording a download outcome.
+    fn record(&self, succeeded: bool, threshold: usize, now: Instant) -> bool {
+        let mut state = self.state();
+        if succeeded {
+            if threshold > 0 && state.consecutive_failures >= threshold {
+                info!("Download succeeded, closing the download circuit");
+            }
+            state.consecutive_failures = 0;
+            state.last_failure = None;
+            return false;
+        }
+        state.consecutive_failures += 1;
+        state.last_failure = Some(now);
+        threshold > 0 && state.consecutive_failures >= threshold
+    }
+
+    /// Returns how much longer scheduled syncs are held off, if the breaker is open.
+    fn backoff_remaining(&self, config: &Config, now: Instant) -> Option<TokioDuration> {
+        let threshold = config.download_circuit_threshold;
+        let state = self.state();
+        if threshold == 0 || state.consecutive_failures < threshold {
+            return None;
+        }
+        let doublings = (state.consecutive_failures - threshold).min(MAX_BACKOFF_DOUBLINGS);
+        let backoff = config.download_circuit_backoff * (1 << doublings);
+        let reopens_at = state.last_failure? + backoff;
+        reopens_at
+            .checked_duration_since(now)
+            .filter(|remaining| !remaining.is_zero())
+    }
+}
+
+fn record_download_outcome(
+    breaker: &DownloadBreaker,
+    config: &Config,
+    result: &Result<SyncOutcome, SyncError>,
+) {
+    let succeeded = !matches!(result, Err(SyncError::Download(_)));
+    let open = breaker.record(succeeded, config.download_circuit_threshold, Instant::now());
+    if open {
+        warn!(
+            "{} consecutive download failure(s), scheduled syncs are backing off",
+            breaker.state().consecutive_failures
+        );
+    }
+    metrics::set_download_circuit_open(open);
 }
 
 fn duration_until_next_sync(target_hour: u8) -> TokioDuration {
@@ -43,15 +240,23 @@ fn duration_until_next_sync(target_hour: u8) -> TokioDuration {
     TokioDuration::from_secs(duration_secs)
 }
 
-fn update_metrics_from_db(meta: &Metadata) {
+pub fn update_metrics_from_db(db: &Database, meta: &Metadata) {
     #[allow(clippy::cast_possible_wrap)]
     metrics::set_record_count(meta.record_count as i64);
     if let Some(ts) = meta.last_sync {
         metrics::set_last_sync_timestamp(ts);
     }
+    metrics::set_trie_malformed_keys(db.malformed_key_count());
+    metrics::set_trie_stats(&db.trie_stats());
 }
 
-pub async fn run_scheduler(db: Arc<Database>, config: Config, cancel_token: CancellationToken) {
+pub async fn run_scheduler(
+    db: Arc<Database>,
+    config: Config,
+    readiness: Readiness,
+    maintenance: Maintenance,
+    cancel_token: CancellationToken,
+) {
     loop {
         let sleep_duration = duration_until_next_sync(config.sync_hour_utc);
         info!(
@@ -62,15 +267,8 @@ pub async fn run_scheduler(db: Arc<Database>, config: Config, cancel_token: Canc
 
         tokio::select! {
             () = sleep(sleep_duration) => {
-                info!("Starting scheduled sync at {} UTC", config.sync_hour_utc);
-                let start = Instant::now();
-                if let Err(e) = perform_sync(&db, &config).await {
-                    error!("Sync failed: {}", e);
-                    metrics::inc_sync_failures();
-                } else {
-                    metrics::inc_sync_success();
-                }
-                metrics::record_sync_duration(start.elapsed().as_secs_f64());
+                let _ =
+                    scheduled_sync(&db, &config, &readiness, &maintenance, &DOWNLOAD_BREAKER).await;
             }
             () = cancel_token.cancelled() => {
                 info!("Scheduler received shutdown signal");
@@ -80,27 +278,313 @@ pub async fn run_scheduler(db: Arc<Database>, config: Config, cancel_token: Canc
     }
 }
 
-pub async fn perform_sync(db: &Arc<Database>, config: &Config) -> Result<(), SyncError> {
-    info!("Starting scheduled sync");
+/// Returns `None` without syncing while maintenance mode is enabled or the
+/// download circuit is open.
+async fn scheduled_sync(
+    db: &Arc<Database>,
+    config: &Config,
+    readiness: &Readiness,
+    maintenance: &Maintenance,
+    breaker: &DownloadBreaker,
+) -> Option<Result<SyncOutcome, SyncError>> {
+    if maintenance.is_enabled() {
+        info!("Maintenance mode enabled, skipping scheduled sync");
+        return None;
+    }
+    if let Some(remaining) = breaker.backoff_remaining(config, Instant::now()) {
+        warn!(
+            "Download circuit open, skipping scheduled sync (backing off for another {} minutes)",
+            remaining.as_secs() / 60
+        );
+        return None;
+    }
 
-    let result = download_csv(&config.csv_url).await?;
+    info!("Starting scheduled sync at {} UTC", config.sync_hour_utc);
+    let _guard = SYNC_LOCK.lock().await;
+    let result = timed_sync(db, config, breaker, None).await;
+    readiness.refresh(db);
+    if config.scheduled_compaction && matches!(result, Ok(SyncOutcome::Imported(_))) {
+        stage_compaction(db).await;
+    }
+    Some(result)
+}
 
-    let current_hash = load_hash(&config.csv_hash_path()).await;
-    let is_first_run = db.is_empty()?;
+async fn stage_compaction(db: &Arc<Database>) {
+    let db = Arc::clone(db);
+    match tokio::task::spawn_blocking(move || db.stage_compaction()).await {
+        Ok(Ok(compaction)) => {
+            info!(
+                "Staged compaction: {} -> {} bytes, applied on the next start",
+                compaction.before_bytes, compaction.after_bytes
+            );
+            metrics::record_compaction(compaction.before_bytes, compaction.after_bytes);
+        }
+        Ok(Err(e)) => warn!("Scheduled compaction failed: {}", e),
+        Err(e) => warn!("Scheduled compaction task failed: {}", e),
+    }
+}
+
+async fn timed_sync(
+    db: &Arc<Database>,
+    config: &Config,
+    breaker: &DownloadBreaker,
+    mode: Option<ImportMode>,
+) -> Result<SyncOutcome, SyncError> {
+    let start = Instant::now();
+    let result = perform_sync(db, config, mode).await;
+    match &result {
+        Ok(_) => metrics::inc_sync_success(),
+        Err(e) => {
+            error!("Sync failed: {}", e);
+            metrics::inc_sync_failures();
+        }
+    }
+    record_sync_outcome(db, config, result.is_ok());
+    record_download_outcome(breaker, config, &result);
+    metrics::record_sync_duration(start.elapsed().as_secs_f64());
+    result
+}
 
-    if is_first_run {
-        full_import(db, &result.content, &result.hash, config).await?;
-    } else if current_hash.as_ref() != Some(&result.hash) {
-        incremental_import(db, &result.content, &result.hash, config).await?;
+pub async fn trigger_sync(db: &Arc<Database>, config: &Config) -> Result<SyncOutcome, SyncError> {
+    let Ok(_guard) = SYNC_LOCK.try_lock() else {
+        return Err(SyncError::AlreadyRunning);
+    };
+    info!("Starting on-demand sync");
+    timed_sync(db, config, &DOWNLOAD_BREAKER, None).await
+}
+
+struct SourceDownload {
+    url: String,
+    result: DownloadResult,
+}
+
+async fn download_sources(config: &Config) -> Result<Vec<SourceDownload>, SyncError> {
+    let mut downloads = Vec::with_capacity(config.csv_urls.len());
+    for url in &config.csv_urls {
+        let result = download_csv(url).await?;
+        downloads.push(SourceDownload {
+            url: url.clone(),
+            result,
+        });
+    }
+    Ok(downloads)
+}
+
+async fn download_modified_sources(
+    config: &Config,
+    last_modified: &BTreeMap<String, String>,
+) -> Result<Option<Vec<SourceDownload>>, SyncError> {
+    let mut fetched = Vec::with_capacity(config.csv_urls.len());
+    for url in &config.csv_urls {
+        let since = last_modified.get(url).map(String::as_str);
+        fetched.push(download_csv_if_modified(url, since).await?);
+    }
+
+    if fetched.iter().all(Option::is_none) {
+        return Ok(None);
+    }
+
+    let mut downloads = Vec::with_capacity(fetched.len());
+    for (url, result) in config.csv_urls.iter().zip(fetched) {
+        let result = match result {
+            Some(result) => result,
+            None => download_csv(url).await?,
+        };
+        downloads.push(SourceDownload {
+            url: url.clone(),
+            result,
+        });
+    }
+    Ok(Some(downloads))
+}
+
+fn source_hashes(downloads: &[SourceDownload]) -> BTreeMap<String, String> {
+    downloads
+        .iter()
+        .map(|d| {
+            let hash = d.result.archive_hash.as_ref().unwrap_or(&d.result.hash);
+            (d.url.clone(), hash.clone())
+        })
+        .collect()
+}
+
+fn source_last_modified(downloads: &[SourceDownload]) -> BTreeMap<String, String> {
+    downloads
+        .iter()
+        .filter_map(|d| Some((d.url.clone(), d.result.last_modified.clone()?)))
+        .collect()
+}
+
+fn record_sources(
+    db: &Database,
+    hashes: BTreeMap<String, String>,
+    last_modified: BTreeMap<String, String>,
+) -> Result<(), DbError> {
+    db.update_metadata(|meta| {
+        meta.source_hashes = hashes;
+        meta.last_modified = last_modified;
+    })
+}
+
+/// Merges the downloaded feeds, or `None` when a single unrestricted source
+/// is imported as it is.
+fn combine_sources(
+    downloads: &[SourceDownload],
+    config: &Config,
+) -> Result<Option<DownloadResult>, SyncError> {
+    if downloads.len() == 1 && !config.source_flags.contains_key(&downloads[0].url) {
+        return Ok(None);
+    }
+
+    let sources: Vec<MergeSource> = downloads
+        .iter()
+        .map(|d| MergeSource {
+            name: &d.url,
+            content: &d.result.content,
+            allowed_flags: config.source_flags.get(&d.url).copied(),
+        })
+        .collect();
+    for source in &sources {
+        check_flag_columns(source.content, config).inspect_err(|e| {
+            error!("Source {} rejected: {}", source.name, e);
+        })?;
+    }
+    let content = merge_sources(&sources, &config.ip_column, config.merge_policy)?;
+    let hash = compute_hash(&content);
+    info!(
+        "Merged {} sources into dataset with hash {}",
+        downloads.len(),
+        hash
+    );
+
+    Ok(Some(DownloadResult {
+        content,
+        hash,
+        archive_hash: None,
+        last_modified: None,
+    }))
+}
+
+fn feeds(downloads: &[SourceDownload]) -> Vec<&str> {
+    downloads
+        .iter()
+        .map(|d| d.result.content.as_str())
+        .collect()
+}
+
+/// `Some(ImportMode::Full)` downloads every source and replaces the database
+/// as on a first run, without the delete guard of an incremental import.
+pub async fn perform_sync(
+    db: &Arc<Database>,
+    config: &Config,
+    mode: Option<ImportMode>,
+) -> Result<SyncOutcome, SyncError> {
+    info!("Starting sync");
+
+    let is_first_run = matches!(mode, Some(ImportMode::Full)) || db.is_empty()?;
+    let meta = db.get_metadata()?;
+
+    let downloads = if is_first_run {
+        Some(download_sources(config).await?)
     } else {
-        info!("CSV unchanged, skipping import");
+        download_modified_sources(config, &meta.last_modified).await?
+    };
+
+    let Some(downloads) = downloads else {
+        info!("No source modified since last sync, skipping download");
+        mark_synced(db)?;
+        update_metrics_from_db(db, &db.get_metadata()?);
+        return Ok(SyncOutcome::NotModified);
+    };
+
+    let hashes = source_hashes(&downloads);
+    let last_modified = source_last_modified(&downloads);
+
+    let outcome = if !is_first_run && meta.source_hashes == hashes {
+        info!("No source changed, skipping import");
+        record_sources(db, hashes, last_modified)?;
+        SyncOutcome::Unchanged
+    } else {
+        let merged = combine_sources(&downloads, config)?;
+        let result = merged.as_ref().unwrap_or(&downloads[0].result);
+        let current_hash = load_hash(&config.csv_hash_path()).await;
+
+        let outcome = if is_first_run {
+            let added = full_import_merged(
+                db,
+                &result.content,
+                &feeds(&downloads),
+                &result.hash,
+                config,
+            )
+            .await?;
+            SyncOutcome::Imported(ImportSummary {
+                mode: ImportMode::Full,
+                hash: result.hash.clone(),
+                added,
+                updated: 0,
+                deleted: 0,
+            })
+        } else if current_hash.as_ref() != Some(&result.hash) {
+            let (added, updated, deleted) = incremental_import_merged(
+                db,
+                &result.content,
+                &feeds(&downloads),
+                &result.hash,
+                config,
+            )
+            .await?;
+            SyncOutcome::Imported(ImportSummary {
+                mode: ImportMode::Incremental,
+                hash: result.hash.clone(),
+                added,
+                updated,
+                deleted,
+            })
+        } else {
+            info!("CSV unchanged, skipping import");
+            SyncOutcome::Unchanged
+        };
+
+        record_sources(db, hashes, last_modified)?;
+        outcome
+    };
+    if matches!(outcome, SyncOutcome::Unchanged) {
+        mark_synced(db)?;
     }
 
     if let Ok(meta) = db.get_metadata() {
-        update_metrics_from_db(&meta);
+        update_metrics_from_db(db, &meta);
     }
 
-    Ok(())
+    Ok(outcome)
+}
+
+/// Returns the recorded and live entry counts when they differ by more than
+/// the configured fraction of the recorded count.
+fn count_mismatch(db: &Database, config: &Config) -> Result<Option<(u64, u64)>, DbError> {
+    let recorded = db.get_metadata()?.record_count;
+    let live = db.count_entries()?;
+    let allowed = (recorded as f64 * config.self_heal_tolerance) as u64;
+    Ok((recorded.abs_diff(live) > allowed).then_some((recorded, live)))
+}
+
+/// Fetches the live feed in the background, so a slow or unreachable source
+/// does not hold up startup once the bootstrap data is being served.
+fn spawn_sync_after_bootstrap(db: &Arc<Database>, config: &Config) {
+    let (db, config) = (Arc::clone(db), config.clone());
+    tokio::spawn(async move {
+        let _guard = SYNC_LOCK.lock().await;
+        // The bootstrap CSV may be far behind upstream, so diffing against it
+        // could trip the delete guard.
+        let mode = Some(ImportMode::Full);
+        if timed_sync(&db, &config, &DOWNLOAD_BREAKER, mode)
+            .await
+            .is_err()
+        {
+            warn!("Sync after bootstrap failed, serving bootstrap data");
+        }
+    });
 }
 
 pub async fn initial_sync(db: &Arc<Database>, config: &Config) -> Result<(), SyncError> {
@@ -112,17 +596,45 @@ pub async fn initial_sync(db: &Arc<Database>, config: &Config) -> Result<(), Syn
         if config.csv_path().exists() {
             info!("Database empty but local CSV exists, rebuilding from CSV");
             crate::sync::rebuild_from_csv(db, config).await?;
+        } else if let Some(path) = &config.bootstrap_csv {
+            info!("First run, importing bootstrap CSV {}", path.display());
+            let content = load_csv(path).await?;
+            full_import(db, &content, &compute_hash(&content), config).await?;
+            spawn_sync_after_bootstrap(db, config);
         } else {
             info!("First run, downloading CSV");
-            let result = download_csv(&config.csv_url).await?;
-            full_import(db, &result.content, &result.hash, config).await?;
+            let downloads = download_sources(config).await?;
+            let hashes = source_hashes(&downloads);
+            let last_modified = source_last_modified(&downloads);
+            let merged = combine_sources(&downloads, config)?;
+            let result = merged.as_ref().unwrap_or(&downloads[0].result);
+            full_import_merged(
+                db,
+                &result.content,
+                &feeds(&downloads),
+                &result.hash,
+                config,
+            )
+            .await?;
+            record_sources(db, hashes, last_modified)?;
+        }
+    } else if let Some((recorded, live)) = count_mismatch(db, config)? {
+        warn!(
+            "Metadata records {} entries but LMDB holds {}, the last import was likely interrupted",
+            recorded, live
+        );
+        if config.csv_path().exists() {
+            warn!("Rebuilding database from local CSV");
+            crate::sync::rebuild_from_csv(db, config).await?;
+        } else {
+            warn!("No local CSV to rebuild from, serving the existing data until the next sync");
         }
     } else {
         info!("Database already populated, skipping initial sync");
     }
 
     if let Ok(meta) = db.get_metadata() {
-        update_metrics_from_db(&meta);
+        update_metrics_from_db(db, &meta);
     }
 
     Ok(())
@@ -140,6 +652,346 @@ mod tests {
         assert!(duration.as_secs() <= 24 * 60 * 60);
     }
 
+    #[tokio::test]
+    async fn test_trigger_sync_refuses_concurrent_runs() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let db = Database::open(dir.path()).unwrap();
+        let config = Config {
+            data_dir: dir.path().to_path_buf(),
+            ..Config::default()
+        };
+
+        let _running = SYNC_LOCK.lock().await;
+        assert!(matches!(
+            trigger_sync(&db, &config).await,
+            Err(SyncError::AlreadyRunning)
+        ));
+    }
+
+    async fn db_with_data(config: &Config) -> Arc<Database> {
+        let db = Database::open(&config.db_path()).unwrap();
+        let content = "ip,proxy\n1.2.3.4,true\n";
+        full_import(&db, content, &compute_hash(content), config)
+            .await
+            .unwrap();
+        db
+    }
+
+    #[tokio::test]
+    async fn test_sync_health_follows_failures_and_data_age() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut config = Config {
+            data_dir: dir.path().to_path_buf(),
+            sync_failure_threshold: 3,
+            max_data_age: Some(std::time::Duration::from_secs(3600)),
+            ..Config::default()
+        };
+        let db = db_with_data(&config).await;
+        let health = SyncHealth::new();
+
+        // A single failure keeps serving the fresh data
+        assert_eq!(health.record(false), 1);
+        assert!(health.is_healthy(&db, &config));
+        assert_eq!(health.record(true), 0);
+
+        health.record(false);
+        health.record(false);
+        assert!(health.is_healthy(&db, &config));
+        assert_eq!(health.record(false), 3);
+        assert!(!health.is_healthy(&db, &config));
+
+        // With no threshold, failures alone never degrade health
+        config.sync_failure_threshold = 0;
+        assert!(health.is_healthy(&db, &config));
+
+        // Stale data does, whatever the failure count
+        health.record(true);
+        let old = Utc::now().timestamp() - 7200;
+        db.update_metadata(|meta| meta.last_sync = Some(old))
+            .unwrap();
+        assert!(!health.is_healthy(&db, &config));
+        config.max_data_age = None;
+        assert!(health.is_healthy(&db, &config));
+
+        let empty_dir = tempfile::TempDir::new().unwrap();
+        let empty = Database::open(empty_dir.path()).unwrap();
+        assert!(!SyncHealth::new().is_healthy(&empty, &config));
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_sync_refreshes_data_age() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let config = Config {
+            data_dir: dir.path().to_path_buf(),
+            max_data_age: Some(std::time::Duration::from_secs(3600)),
+            ..Config::default()
+        };
+        let db = db_with_data(&config).await;
+        let old = Utc::now().timestamp() - 7200;
+        db.update_metadata(|meta| meta.last_sync = Some(old))
+            .unwrap();
+        assert!(!is_fresh(&db, &config));
+
+        mark_synced(&db).unwrap();
+        assert!(is_fresh(&db, &config));
+    }
+
+    #[tokio::test]
+    async fn test_scheduled_sync_skipped_in_maintenance() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let config = Config {
+            data_dir: dir.path().to_path_buf(),
+            csv_urls: vec!["http://127.0.0.1:1/proxy_blocks.csv".to_owned()],
+            ..Config::default()
+        };
+        let db = Database::open(&config.db_path()).unwrap();
+        let readiness = Readiness::default();
+        let maintenance = Maintenance::new(true);
+
+        let breaker = DownloadBreaker::new();
+
+        assert!(
+            scheduled_sync(&db, &config, &readiness, &maintenance, &breaker)
+                .await
+                .is_none()
+        );
+
+        maintenance.set(false);
+        assert!(matches!(
+            scheduled_sync(&db, &config, &readiness, &maintenance, &breaker).await,
+            Some(Err(_))
+        ));
+    }
+
+    #[test]
+    fn test_download_breaker_opens_and_closes() {
+        let config = Config {
+            download_circuit_threshold: 3,
+            download_circuit_backoff: TokioDuration::from_secs(3600),
+            ..Config::default()
+        };
+        let breaker = DownloadBreaker::new();
+        let start = Instant::now();
+
+        assert!(!breaker.record(false, 3, start));
+        assert!(!breaker.record(false, 3, start));
+        assert!(breaker.backoff_remaining(&config, start).is_none());
+        assert!(breaker.record(false, 3, start));
+        assert_eq!(
+            breaker.backoff_remaining(&config, start),
+            Some(TokioDuration::from_secs(3600))
+        );
+        let later = start + TokioDuration::from_secs(3600);
+        assert!(breaker.backoff_remaining(&config, later).is_none());
+
+        // The probe after the backoff fails too: hold off twice as long
+        assert!(breaker.record(false, 3, later));
+        assert_eq!(
+            breaker.backoff_remaining(&config, later),
+            Some(TokioDuration::from_secs(7200))
+        );
+
+        assert!(!breaker.record(true, 3, later));
+        assert!(breaker.backoff_remaining(&config, later).is_none());
+        assert!(!breaker.record(false, 3, later));
+
+        let disabled = Config {
+            download_circuit_threshold: 0,
+            ..config
+        };
+        assert!(!breaker.record(false, 0, later));
+        assert!(breaker.backoff_remaining(&disabled, later).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_failed_downloads_open_the_circuit_for_scheduled_syncs() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let config = Config {
+            data_dir: dir.path().to_path_buf(),
+            download_circuit_threshold: 2,
+            ..Config::default()
+        };
+        let db = Database::open(&config.db_path()).unwrap();
+        let readiness = Readiness::default();
+        let maintenance = Maintenance::default();
+        let breaker = DownloadBreaker::new();
+
+        let download_failed = || Err(SyncError::Download(DownloadError::MaxRetriesExceeded(3)));
+        record_download_outcome(&breaker, &config, &download_failed());
+        assert!(breaker.backoff_remaining(&config, Instant::now()).is_none());
+        record_download_outcome(&breaker, &config, &download_failed());
+        assert!(breaker.backoff_remaining(&config, Instant::now()).is_some());
+        assert!(
+            scheduled_sync(&db, &config, &readiness, &maintenance, &breaker)
+                .await
+                .is_none()
+        );
+
+        // Failures past the download, such as an import error, do not keep it open
+        let import_failed = Err(SyncError::Import(ImportError::CsvParse(
+            "bad row".to_owned(),
+        )));
+        record_download_outcome(&breaker, &config, &import_failed);
+        assert!(breaker.backoff_remaining(&config, Instant::now()).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_initial_sync_imports_bootstrap_without_network() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let bootstrap = dir.path().join("bootstrap.csv");
+        std::fs::write(&bootstrap, "ip,proxy\n1.2.3.4,true\n10.0.0.0/8,false\n").unwrap();
+        let config = Config {
+            data_dir: dir.path().to_path_buf(),
+            csv_urls: vec!["http://127.0.0.1:1/proxy_blocks.csv".to_owned()],
+            bootstrap_csv: Some(bootstrap),
+            ..Config::default()
+        };
+        let db = Database::open(&config.db_path()).unwrap();
+
+        initial_sync(&db, &config).await.unwrap();
+
+        let result = crate::ip::lookup_ip(&db, "1.2.3.4").unwrap();
+        assert!(result.found);
+        assert!(result.flags.proxy);
+        assert!(crate::ip::lookup_ip(&db, "10.1.2.3").unwrap().found);
+        assert!(config.csv_path().exists());
+    }
+
+    #[tokio::test]
+    async fn test_full_sync_replaces_a_stale_bootstrap() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/proxy_blocks.csv", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut buf = [0u8; 4096];
+                let _ = socket.read(&mut buf).await;
+                let body = "ip,proxy\n1.2.3.4,true\n";
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let config = Config {
+            data_dir: dir.path().to_path_buf(),
+            csv_urls: vec![url],
+            ..Config::default()
+        };
+        let db = Arc::new(Database::open(&config.db_path()).unwrap());
+        let bootstrap: String = std::iter::once("ip,proxy\n".to_owned())
+            .chain((0..10).map(|i| format!("10.0.0.{i},true\n")))
+            .collect();
+        full_import(&db, &bootstrap, &compute_hash(&bootstrap), &config)
+            .await
+            .unwrap();
+
+        let err = perform_sync(&db, &config, None).await.unwrap_err();
+        assert!(matches!(
+            err,
+            SyncError::Import(ImportError::TooManyDeletes { .. })
+        ));
+
+        let outcome = perform_sync(&db, &config, Some(ImportMode::Full))
+            .await
+            .unwrap();
+        assert!(matches!(
+            outcome,
+            SyncOutcome::Imported(ImportSummary {
+                mode: ImportMode::Full,
+                ..
+            })
+        ));
+        assert_eq!(db.count_entries().unwrap(), 1);
+        assert!(crate::ip::lookup_ip(&db, "1.2.3.4").unwrap().found);
+    }
+
+    #[tokio::test]
+    async fn test_initial_sync_rebuilds_after_interrupted_import() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let config = Config {
+            data_dir: dir.path().to_path_buf(),
+            ..Config::default()
+        };
+        let db = Database::open(&config.db_path()).unwrap();
+        let content: String = std::iter::once("ip,proxy\n".to_owned())
+            .chain((0..40).map(|i| format!("10.0.0.{i},true\n")))
+            .collect();
+        full_import(&db, &content, &compute_hash(&content), &config)
+            .await
+            .unwrap();
+
+        let mut txn = db.begin_write().unwrap();
+        for i in 0..10 {
+            db.delete_record(&mut txn, &format!("10.0.0.{i}")).unwrap();
+        }
+        txn.commit().unwrap();
+        assert_eq!(db.count_entries().unwrap(), 30);
+        assert!(count_mismatch(&db, &config).unwrap().is_some());
+
+        initial_sync(&db, &config).await.unwrap();
+
+        assert_eq!(db.count_entries().unwrap(), 40);
+        assert!(count_mismatch(&db, &config).unwrap().is_none());
+        assert!(crate::ip::lookup_ip(&db, "10.0.0.3").unwrap().found);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_and_invalid_rows_do_not_trip_self_heal() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let config = Config {
+            data_dir: dir.path().to_path_buf(),
+            self_heal_tolerance: 0.0,
+            ..Config::default()
+        };
+        let db = Database::open(&config.db_path()).unwrap();
+        let content: String = std::iter::once("ip,proxy\n".to_owned())
+            .chain((0..20).map(|i| format!("10.0.0.{},true\n", i % 10)))
+            .chain((0..10).map(|i| format!("not-an-ip-{i},true\n")))
+            .collect();
+        full_import(&db, &content, &compute_hash(&content), &config)
+            .await
+            .unwrap();
+
+        assert_eq!(db.count_entries().unwrap(), 10);
+        assert_eq!(db.get_metadata().unwrap().record_count, 10);
+        assert!(count_mismatch(&db, &config).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_source_last_modified_skips_missing_headers() {
+        let download = |url: &str, last_modified: Option<&str>| SourceDownload {
+            url: url.to_owned(),
+            result: DownloadResult {
+                content: String::new(),
+                hash: String::new(),
+                archive_hash: None,
+                last_modified: last_modified.map(str::to_owned),
+            },
+        };
+        let downloads = vec![
+            download(
+                "https://a.example/list.csv",
+                Some("Wed, 21 Oct 2026 07:28:00 GMT"),
+            ),
+            download("https://b.example/list.csv", None),
+        ];
+
+        let last_modified = source_last_modified(&downloads);
+        assert_eq!(last_modified.len(), 1);
+        assert_eq!(
+            last_modified
+                .get("https://a.example/list.csv")
+                .map(String::as_str),
+            Some("Wed, 21 Oct 2026 07:28:00 GMT")
+        );
+    }
+
     #[test]
     #[allow(clippy::cast_possible_truncation)]
     fn test_duration_until_next_sync_same_hour_schedules_tomorrow() {
@@ -0,0 +1,246 @@
+//! Counts heap allocations of lookups and trie builds. Lives in its own test
+//! binary because it installs a global allocator.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::net::{IpAddr, Ipv4Addr};
+
+use proxyd::ip::ReputationFlags;
+use tempfile::TempDir;
+
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn allocations_during(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.with(Cell::get);
+    f();
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+#[test]
+fn test_summary_lookup_allocates_less_on_misses() {
+    let dir = TempDir::new().unwrap();
+    let db = proxyd::db::Database::open(dir.path()).unwrap();
+    let proxy = ReputationFlags {
+        proxy: true,
+        ..Default::default()
+    };
+    let mut txn = db.begin_write().unwrap();
+    for entry in ["10.0.0.0/8", "192.0.2.0/24", "198.51.100.7"] {
+        db.insert_record(&mut txn, entry, &proxy).unwrap();
+    }
+    txn.commit().unwrap();
+    db.rebuild_trie().unwrap();
+
+    // Mostly misses, with the odd hit.
+    let ips: Vec<IpAddr> = (0..1000u32)
+        .map(|i| IpAddr::V4(Ipv4Addr::from(0x2000_0000 + i * 7919)))
+        .chain(["10.1.2.3".parse().unwrap(), "198.51.100.7".parse().unwrap()])
+        .collect();
+    let queries: Vec<String> = ips.iter().map(ToString::to_string).collect();
+
+    let full = allocations_during(|| {
+        for query in &queries {
+            proxyd::ip::lookup_ip(&db, query).unwrap();
+        }
+    });
+    let summary = allocations_during(|| {
+        for ip in &ips {
+            proxyd::ip::lookup_ip_summary(&db, *ip).unwrap();
+        }
+    });
+    assert!(full >= ips.len());
+    assert_eq!(summary, 0);
+
+    let hit = proxyd::ip::lookup_ip_summary(&db, "10.1.2.3".parse().unwrap()).unwrap();
+    assert!(hit.found);
+    assert!(hit.flags.proxy);
+    let miss = proxyd::ip::lookup_ip_summary(&db, ips[0]).unwrap();
+    assert_eq!(miss, proxyd::ip::LookupSummary::default());
+}
+
+/// Deterministic pseudo-random IPv4 CIDRs, /16 to /32.
+fn random_cidrs(count: usize) -> Vec<ipnetwork::IpNetwork> {
+    let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+    (0..count)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let addr = Ipv4Addr::from((state >> 32) as u32);
+            ipnetwork::IpNetwork::new(IpAddr::V4(addr), 16 + (state % 17) as u8).unwrap()
+        })
+        .collect()
+}
+
+#[test]
+fn test_presized_trie_build_allocates_once() {
+    let cidrs = random_cidrs(10_000);
+    let flags = ReputationFlags::default();
+
+    let mut trie = None;
+    let allocations = allocations_during(|| {
+        let mut built = proxyd::ip::IpTrie::with_capacity(cidrs.len());
+        for network in &cidrs {
+            built.insert(*network, flags);
+        }
+        trie = Some(built);
+    });
+    let trie = trie.unwrap();
+
+    assert_eq!(allocations, 1);
+    for network in &cidrs {
+        assert!(trie
+            .find_all_matches(network.ip())
+            .iter()
+            .any(|(found, _)| found.network() == network.network()
+                && found.prefix() == network.prefix()));
+    }
+}
+
+/// `cargo test --release --test allocations -- --ignored --nocapture`
+#[test]
+#[ignore = "benchmark"]
+fn bench_trie_rebuild() {
+    let cidrs = random_cidrs(1_000_000);
+    let flags = ReputationFlags::default();
+    for _ in 0..3 {
+        let start = std::time::Instant::now();
+        let mut trie = proxyd::ip::IpTrie::with_capacity(cidrs.len());
+        for network in &cidrs {
+            trie.insert(*network, flags);
+        }
+        let built = start.elapsed();
+        let start = std::time::Instant::now();
+        drop(trie);
+        println!(
+            "{} CIDRs: build {:?}, drop {:?}",
+            cidrs.len(),
+            built,
+            start.elapsed()
+        );
+    }
+}
+
+/// A database with a mix of exact IPs and CIDRs, for the lookup benchmarks.
+fn bench_db(dir: &TempDir) -> std::sync::Arc<proxyd::db::Database> {
+    let db = proxyd::db::Database::open(dir.path()).unwrap();
+    let proxy = ReputationFlags {
+        proxy: true,
+        ..Default::default()
+    };
+    let mut txn = db.begin_write().unwrap();
+    for network in random_cidrs(10_000) {
+        db.insert_record(&mut txn, &network.to_string(), &proxy)
+            .unwrap();
+    }
+    txn.commit().unwrap();
+    db.rebuild_trie().unwrap();
+    db
+}
+
+/// Times each batch size on the calling thread and spread over the rayon
+/// pool one entry per task, as every batch was before the threshold existed.
+/// `cargo test --release --test allocations -- --ignored --nocapture`
+#[test]
+#[ignore = "benchmark"]
+fn bench_batch_parallel_crossover() {
+    const ROUNDS: u32 = 2_000;
+    let dir = TempDir::new().unwrap();
+    let db = bench_db(&dir);
+    let queries: Vec<String> = random_cidrs(256)
+        .iter()
+        .map(|network| network.ip().to_string())
+        .collect();
+
+    let sequential = proxyd::ip::LookupOptions {
+        sequential: true,
+        ..Default::default()
+    };
+    let parallel = proxyd::ip::LookupOptions {
+        parallel_threshold: 0,
+        batch_chunk_size: 1,
+        ..Default::default()
+    };
+    for size in [1, 2, 4, 8, 16, 32, 64, 128, 256] {
+        let batch: Vec<&str> = queries[..size].iter().map(String::as_str).collect();
+        let time = |options: &proxyd::ip::LookupOptions| {
+            let start = std::time::Instant::now();
+            for _ in 0..ROUNDS {
+                proxyd::ip::lookup_ips_batch_with(&db, &batch, options).unwrap();
+            }
+            start.elapsed() / ROUNDS
+        };
+        println!(
+            "batch of {size}: sequential {:?}, parallel {:?}",
+            time(&sequential),
+            time(&parallel)
+        );
+    }
+}
+
+/// Times the string-building `lookup_ip` an embedder used before against
+/// `match_ip`, over the same queries.
+/// `cargo test --release --test allocations -- --ignored --nocapture`
+#[test]
+#[ignore = "benchmark"]
+fn bench_embedded_lookup() {
+    const LOOKUPS: u32 = 1_000_000;
+    let dir = TempDir::new().unwrap();
+    let db = bench_db(&dir);
+    let ips: Vec<IpAddr> = random_cidrs(1_000)
+        .iter()
+        .map(ipnetwork::IpNetwork::ip)
+        .collect();
+    let queries: Vec<String> = ips.iter().map(ToString::to_string).collect();
+    let options = proxyd::ip::LookupOptions::default();
+
+    for _ in 0..3 {
+        let start = std::time::Instant::now();
+        let mut found = 0;
+        for i in 0..LOOKUPS as usize {
+            let result = proxyd::ip::lookup_ip(&db, &queries[i % queries.len()]).unwrap();
+            found += usize::from(result.found);
+        }
+        let strings = start.elapsed();
+
+        let start = std::time::Instant::now();
+        let mut matched = 0;
+        for i in 0..LOOKUPS as usize {
+            let matches = proxyd::ip::match_ip(&db, ips[i % ips.len()], &options).unwrap();
+            matched += usize::from(matches.found());
+        }
+        let values = start.elapsed();
+
+        assert_eq!(found, matched);
+        println!(
+            "{LOOKUPS} lookups: lookup_ip {:?} ({:?} each), match_ip {:?} ({:?} each)",
+            strings,
+            strings / LOOKUPS,
+            values,
+            values / LOOKUPS
+        );
+    }
+}
@@ -113,6 +113,33 @@ mod database_tests {
         assert_eq!(result.matched_entries.len(), 2, "expected both matches");
     }
 
+    #[test]
+    fn match_ip_returns_networks() {
+        let ctx = TestContext::new();
+
+        let flags = proxyd::ip::ReputationFlags {
+            vpn: true,
+            ..Default::default()
+        };
+        ctx.insert_records(&[("10.0.0.0/8", flags), ("10.1.2.3", flags)]);
+
+        let ip: IpAddr = "10.1.2.3".parse().unwrap();
+        let options = proxyd::ip::LookupOptions::default();
+        let matches = proxyd::ip::match_ip(&ctx.db, ip, &options).unwrap();
+
+        assert!(matches.found());
+        assert!(matches.flags().vpn);
+        let networks: Vec<String> = matches.matches.iter().map(|(n, _)| n.to_string()).collect();
+        assert_eq!(networks, ["10.1.2.3/32", "10.0.0.0/8"]);
+
+        let result = proxyd::ip::lookup_ip(&ctx.db, "10.1.2.3").unwrap();
+        assert_eq!(result.matched_entries[0].entry, "10.1.2.3");
+        assert_eq!(result.matched_entries[1].entry, "10.0.0.0/8");
+
+        let miss = proxyd::ip::match_ip(&ctx.db, "11.0.0.1".parse().unwrap(), &options).unwrap();
+        assert!(!miss.found());
+    }
+
     #[test]
     fn nested_cidr_matching() {
         let ctx = TestContext::new();
@@ -208,6 +235,7 @@ mod database_tests {
             last_sync: Some(1700000000),
             csv_hash: Some("abc123".to_owned()),
             record_count: 1000,
+            ..Default::default()
         };
 
         {
@@ -222,6 +250,60 @@ mod database_tests {
         assert_eq!(retrieved.record_count, 1000);
     }
 
+    #[test]
+    fn update_metadata_preserves_other_fields() {
+        let ctx = TestContext::new();
+
+        ctx.db
+            .update_metadata(|meta| meta.csv_hash = Some("abc123".to_owned()))
+            .unwrap();
+        ctx.db
+            .update_metadata(|meta| {
+                meta.source_hashes
+                    .insert("https://example.com/a.csv".to_owned(), "aaa".to_owned());
+            })
+            .unwrap();
+
+        let retrieved = ctx.db.get_metadata().unwrap();
+        assert_eq!(retrieved.csv_hash.as_deref(), Some("abc123"));
+        assert_eq!(
+            retrieved.source_hashes.get("https://example.com/a.csv"),
+            Some(&"aaa".to_owned())
+        );
+    }
+
+    #[test]
+    fn lookup_reports_dataset_hash_and_check_time() {
+        let dir = TempDir::new().unwrap();
+        let db = proxyd::db::Database::open(dir.path()).unwrap();
+
+        let result = proxyd::ip::lookup_ip(&db, "1.2.3.4").unwrap();
+        assert!(result.checked_at > 0);
+        assert_eq!(result.dataset_hash, None);
+
+        let meta = proxyd::db::Metadata {
+            last_sync: Some(1700000000),
+            csv_hash: Some("abc123".to_owned()),
+            record_count: 0,
+            ..Default::default()
+        };
+        {
+            let mut txn = db.begin_write().unwrap();
+            db.set_metadata(&mut txn, &meta).unwrap();
+            txn.commit().unwrap();
+        }
+        drop(db);
+
+        // The cached hash is loaded from metadata when the database is reopened
+        let db = proxyd::db::Database::open(dir.path()).unwrap();
+        let result = proxyd::ip::lookup_ip(&db, "1.2.3.4").unwrap();
+        assert_eq!(result.dataset_hash.as_deref(), Some("abc123"));
+
+        db.set_dataset_hash(Some("def456".to_owned()));
+        let results = proxyd::ip::lookup_ips_batch(&db, &["1.2.3.4"]).unwrap();
+        assert_eq!(results[0].dataset_hash.as_deref(), Some("def456"));
+    }
+
     #[test]
     fn get_all_entries() {
         let ctx = TestContext::new();
@@ -496,6 +578,178 @@ mod batch_tests {
         assert_eq!(results.len(), 3);
         assert!(results.iter().all(|r| !r.found));
     }
+
+    #[test]
+    fn sequential_and_parallel_batches_match() {
+        let ctx = TestContext::new();
+
+        ctx.insert_records(&[
+            (
+                "10.0.0.0/8",
+                proxyd::ip::ReputationFlags {
+                    proxy: true,
+                    ..Default::default()
+                },
+            ),
+            (
+                "10.1.1.1",
+                proxyd::ip::ReputationFlags {
+                    vpn: true,
+                    ..Default::default()
+                },
+            ),
+        ]);
+
+        let ip_strs: Vec<String> = (0..100).map(|i| format!("10.1.1.{i}")).collect();
+        let ips: Vec<&str> = ip_strs.iter().map(String::as_str).collect();
+
+        let sequential = proxyd::ip::LookupOptions {
+            parallel_threshold: usize::MAX,
+            ..Default::default()
+        };
+        let parallel = proxyd::ip::LookupOptions {
+            parallel_threshold: 0,
+            ..Default::default()
+        };
+        let forced_sequential = proxyd::ip::LookupOptions {
+            parallel_threshold: 0,
+            sequential: true,
+            ..Default::default()
+        };
+
+        let seq = proxyd::ip::lookup_ips_batch_with(&ctx.db, &ips, &sequential).unwrap();
+        let par = proxyd::ip::lookup_ips_batch_with(&ctx.db, &ips, &parallel).unwrap();
+        assert_eq!(seq.len(), par.len());
+        for (a, b) in seq.iter().zip(par.iter()) {
+            assert_eq!(a.query, b.query);
+            assert_eq!(a.flags, b.flags);
+            assert_eq!(a.matched_entries.len(), b.matched_entries.len());
+        }
+        assert!(seq[1].flags.vpn && seq[1].flags.proxy);
+
+        let to_json = |mut results: Vec<proxyd::ip::LookupResult>| {
+            for result in &mut results {
+                result.checked_at = 0;
+            }
+            serde_json::to_vec(&results).unwrap()
+        };
+        let forced = proxyd::ip::lookup_ips_batch_with(&ctx.db, &ips, &forced_sequential).unwrap();
+        assert_eq!(to_json(forced), to_json(par));
+
+        let cidrs = ["10.0.0.0/8", "192.168.0.0/16"];
+        let seq = proxyd::ip::lookup_ranges_batch_with(&ctx.db, &cidrs, &sequential).unwrap();
+        let par = proxyd::ip::lookup_ranges_batch_with(&ctx.db, &cidrs, &parallel).unwrap();
+        assert_eq!(seq[0].found, par[0].found);
+        assert_eq!(seq[1].found, par[1].found);
+    }
+
+    /// Every query matches a chain of nested prefixes, making each lookup
+    /// deliberately expensive.
+    fn slow_dataset() -> (TestContext, Vec<String>) {
+        let ctx = TestContext::new();
+        let cidrs: Vec<String> = (32..=127).map(|len| format!("2001:db8::/{len}")).collect();
+        let records: Vec<(&str, proxyd::ip::ReputationFlags)> = cidrs
+            .iter()
+            .map(|cidr| {
+                (
+                    cidr.as_str(),
+                    proxyd::ip::ReputationFlags {
+                        proxy: true,
+                        ..Default::default()
+                    },
+                )
+            })
+            .collect();
+        ctx.insert_records(&records);
+        let queries = (0..5000).map(|i| format!("2001:db8::{}", i % 2)).collect();
+        (ctx, queries)
+    }
+
+    #[test]
+    fn soft_deadline_returns_partial_results() {
+        use std::time::Duration;
+
+        let (ctx, queries) = slow_dataset();
+        let ips: Vec<&str> = queries.iter().map(String::as_str).collect();
+        let options = |sequential, deadline| proxyd::ip::LookupOptions {
+            parallel_threshold: 100,
+            sequential,
+            batch_chunk_size: 8,
+            batch_soft_deadline: Some(deadline),
+            ..Default::default()
+        };
+
+        let expired =
+            proxyd::ip::lookup_ips_batch_with(&ctx.db, &ips, &options(false, Duration::ZERO))
+                .unwrap();
+        assert_eq!(expired.len(), ips.len());
+        assert!(expired.iter().all(|r| r.timed_out && !r.found));
+        assert_eq!(expired[7].query, ips[7]);
+
+        let partial = proxyd::ip::lookup_ips_batch_with(
+            &ctx.db,
+            &ips,
+            &options(true, Duration::from_millis(1)),
+        )
+        .unwrap();
+        let completed = partial.iter().take_while(|r| !r.timed_out).count();
+        assert!(completed < ips.len());
+        assert!(partial[..completed].iter().all(|r| r.found));
+        assert!(partial[completed..].iter().all(|r| r.timed_out && !r.found));
+
+        let generous = proxyd::ip::lookup_ips_batch_with(
+            &ctx.db,
+            &ips,
+            &options(false, Duration::from_secs(600)),
+        )
+        .unwrap();
+        assert!(generous.iter().all(|r| !r.timed_out && r.found));
+        assert!(serde_json::to_value(&generous[0])
+            .unwrap()
+            .get("timed_out")
+            .is_none());
+
+        let small =
+            proxyd::ip::lookup_ips_batch_with(&ctx.db, &ips[..10], &options(false, Duration::ZERO))
+                .unwrap();
+        assert!(small.iter().all(|r| !r.timed_out && r.found));
+
+        let cidrs = ["2001:db8::/64"; 200];
+        let ranges =
+            proxyd::ip::lookup_ranges_batch_with(&ctx.db, &cidrs, &options(false, Duration::ZERO))
+                .unwrap();
+        assert!(ranges.iter().all(|r| r.timed_out && !r.found));
+    }
+
+    #[test]
+    fn batch_lookups_attach_notes() {
+        let ctx = TestContext::new();
+        let flags = proxyd::ip::ReputationFlags {
+            proxy: true,
+            ..Default::default()
+        };
+        let mut txn = ctx.db.begin_write().unwrap();
+        for (entry, note) in [("1.2.3.4", "abuse desk"), ("10.0.0.0/8", "hosting")] {
+            ctx.db.insert_record(&mut txn, entry, &flags).unwrap();
+            ctx.db.set_note(&mut txn, entry, Some(note)).unwrap();
+        }
+        txn.commit().unwrap();
+        ctx.db.rebuild_trie().unwrap();
+
+        let results = proxyd::ip::lookup_ips_batch(&ctx.db, &["1.2.3.4", "10.1.1.1"]).unwrap();
+        let notes = |result: &proxyd::ip::LookupResult| -> Vec<Option<String>> {
+            result
+                .matched_entries
+                .iter()
+                .map(|e| e.note.clone())
+                .collect()
+        };
+        assert_eq!(notes(&results[0]), [Some("abuse desk".to_owned())]);
+        assert_eq!(notes(&results[1]), [Some("hosting".to_owned())]);
+
+        let ranges = proxyd::ip::lookup_ranges_batch(&ctx.db, &["10.0.0.0/8"]).unwrap();
+        assert_eq!(notes(&ranges[0]), [Some("hosting".to_owned())]);
+    }
 }
 
 mod ipv6_tests {
@@ -586,6 +840,23 @@ mod ipv6_tests {
         assert!(results[1].found && results[1].flags.rangeblock);
         assert!(!results[2].found);
     }
+
+    #[test]
+    fn normalized_query_uses_canonical_form() {
+        let ctx = TestContext::new();
+
+        let full = "2001:0db8:0000:0000:0000:0000:0000:0001";
+        let result = proxyd::ip::lookup_ip(&ctx.db, full).unwrap();
+        assert_eq!(result.query, full);
+        assert_eq!(result.normalized_query, "2001:db8::1");
+
+        let results = proxyd::ip::lookup_ips_batch(&ctx.db, &[full, "1.2.3.4"]).unwrap();
+        assert_eq!(results[0].normalized_query, "2001:db8::1");
+        assert_eq!(results[1].normalized_query, "1.2.3.4");
+
+        let result = proxyd::ip::lookup_range(&ctx.db, "2001:0db8:0000::/32").unwrap();
+        assert_eq!(result.normalized_query, "2001:db8::/32");
+    }
 }
 
 mod trie_tests {
@@ -1141,4 +1412,203 @@ mod range_lookup_tests {
         let result = proxyd::ip::lookup_range(&ctx.db, "2001:db8::/48").unwrap();
         assert!(!result.found);
     }
+
+    #[test]
+    fn aggregate_prefix_lookup() {
+        let ctx = TestContext::new();
+
+        ctx.insert_records(&[
+            (
+                "10.0.0.0/8",
+                proxyd::ip::ReputationFlags {
+                    proxy: true,
+                    ..Default::default()
+                },
+            ),
+            (
+                "10.1.2.128/25",
+                proxyd::ip::ReputationFlags {
+                    vpn: true,
+                    ..Default::default()
+                },
+            ),
+            (
+                "10.1.2.3",
+                proxyd::ip::ReputationFlags {
+                    tor: true,
+                    ..Default::default()
+                },
+            ),
+            (
+                "2001:db8::/32",
+                proxyd::ip::ReputationFlags {
+                    cdn: true,
+                    ..Default::default()
+                },
+            ),
+        ]);
+
+        // Only CIDRs covering the whole /24 match; the /25 and the exact IP do not
+        let result = proxyd::ip::lookup_ip_prefix(&ctx.db, "10.1.2.3", 24).unwrap();
+        assert!(result.found);
+        assert_eq!(result.normalized_query, "10.1.2.0/24");
+        assert_eq!(result.matched_entries.len(), 1);
+        assert_eq!(result.matched_entries[0].entry, "10.0.0.0/8");
+        assert!(result.flags.proxy && !result.flags.vpn && !result.flags.tor);
+
+        let result = proxyd::ip::lookup_ip_prefix(&ctx.db, "10.1.2.200", 25).unwrap();
+        assert!(result.flags.proxy && result.flags.vpn);
+
+        let result = proxyd::ip::lookup_ip_prefix(&ctx.db, "2001:db8::1", 48).unwrap();
+        assert_eq!(result.normalized_query, "2001:db8::/48");
+        assert!(result.flags.cdn);
+
+        assert!(proxyd::ip::lookup_ip_prefix(&ctx.db, "10.1.2.3", 33).is_err());
+        assert!(proxyd::ip::lookup_ip_prefix(&ctx.db, "2001:db8::1", 129).is_err());
+        assert!(proxyd::ip::lookup_ip_prefix(&ctx.db, "nope", 24).is_err());
+    }
+
+    #[test]
+    fn batch_range_with_supernets() {
+        let ctx = TestContext::new();
+
+        ctx.insert_records(&[
+            (
+                "10.0.0.0/8",
+                proxyd::ip::ReputationFlags {
+                    proxy: true,
+                    ..Default::default()
+                },
+            ),
+            (
+                "10.1.0.0/16",
+                proxyd::ip::ReputationFlags {
+                    vpn: true,
+                    ..Default::default()
+                },
+            ),
+        ]);
+
+        let cidrs = ["10.1.0.0/16", "10.1.2.0/24", "192.168.0.0/16"];
+        let options = proxyd::ip::LookupOptions::default();
+
+        let exact = proxyd::ip::lookup_ranges_batch_with(&ctx.db, &cidrs, &options).unwrap();
+        assert!(exact[0].found);
+        assert_eq!(exact[0].matched_entries.len(), 1);
+        assert!(!exact[1].found);
+
+        let covering =
+            proxyd::ip::lookup_ranges_batch_with_supernets(&ctx.db, &cidrs, &options).unwrap();
+        let entries = |i: usize| -> Vec<&str> {
+            covering[i]
+                .matched_entries
+                .iter()
+                .map(|e| e.entry.as_str())
+                .collect()
+        };
+        assert_eq!(entries(0), ["10.1.0.0/16", "10.0.0.0/8"]);
+        assert_eq!(entries(1), ["10.0.0.0/8", "10.1.0.0/16"]);
+        assert!(covering[1].found);
+        assert!(covering[1].flags.proxy && covering[1].flags.vpn);
+        assert!(!covering[2].found);
+    }
+}
+
+mod cidr_only_tests {
+    use super::*;
+
+    fn flags(proxy: bool, vpn: bool) -> proxyd::ip::ReputationFlags {
+        proxyd::ip::ReputationFlags {
+            proxy,
+            vpn,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn host_entries_match_through_trie() {
+        let ctx = TestContext::new();
+        ctx.db.set_cidr_only(true);
+        ctx.insert_records(&[
+            ("1.2.3.4", flags(true, false)),
+            ("2001:db8::1/128", flags(true, false)),
+            ("1.2.0.0/16", flags(false, true)),
+        ]);
+
+        let host: IpAddr = "1.2.3.4".parse().unwrap();
+        assert!(ctx
+            .db
+            .trie()
+            .find_all_matches(host)
+            .iter()
+            .any(|(network, _)| network.prefix() == 32));
+
+        let result = proxyd::ip::lookup_ip(&ctx.db, "1.2.3.4").unwrap();
+        assert!(result.found && result.flags.proxy && result.flags.vpn);
+        let entries: Vec<&str> = result
+            .matched_entries
+            .iter()
+            .map(|e| e.entry.as_str())
+            .collect();
+        assert_eq!(entries, ["1.2.0.0/16", "1.2.3.4"]);
+
+        let batch = proxyd::ip::lookup_ips_batch(&ctx.db, &["1.2.3.4", "2001:db8::1"]).unwrap();
+        assert_eq!(batch[0].matched_entries.len(), 2);
+        assert!(batch[0]
+            .matched_entries
+            .iter()
+            .any(|e| e.entry == "1.2.3.4"));
+        assert!(batch[1].found && batch[1].flags.proxy);
+
+        // Nothing was written to the exact-IP tables
+        ctx.db.set_cidr_only(false);
+        assert!(ctx.db.lookup_ip(host).unwrap().is_none());
+        ctx.db.set_cidr_only(true);
+
+        let mut entries: Vec<String> = ctx
+            .db
+            .get_all_entries()
+            .unwrap()
+            .into_iter()
+            .map(|(entry, _)| entry)
+            .collect();
+        entries.sort();
+        assert_eq!(entries, ["1.2.0.0/16", "1.2.3.4", "2001:db8::1"]);
+
+        let mut txn = ctx.db.begin_write().unwrap();
+        assert!(ctx.db.delete_record(&mut txn, "1.2.3.4").unwrap());
+        txn.commit().unwrap();
+        ctx.db.rebuild_trie().unwrap();
+        let result = proxyd::ip::lookup_ip(&ctx.db, "1.2.3.4").unwrap();
+        assert!(!result.flags.proxy && result.flags.vpn);
+    }
+
+    #[test]
+    fn switching_modes_migrates_host_entries() {
+        let ctx = TestContext::new();
+        ctx.insert_records(&[
+            ("1.2.3.4", flags(true, false)),
+            ("10.0.0.0/8", flags(false, true)),
+        ]);
+        assert_eq!(ctx.db.migrate_host_entries().unwrap(), 0);
+
+        ctx.db.set_cidr_only(true);
+        assert_eq!(ctx.db.migrate_host_entries().unwrap(), 1);
+        let result = proxyd::ip::lookup_ip(&ctx.db, "1.2.3.4").unwrap();
+        assert!(result.found && result.flags.proxy);
+        assert_eq!(result.matched_entries.len(), 1);
+        assert_eq!(ctx.db.trie().len(), 2);
+
+        ctx.db.set_cidr_only(false);
+        assert_eq!(ctx.db.migrate_host_entries().unwrap(), 1);
+        assert_eq!(ctx.db.trie().len(), 1);
+        let result = proxyd::ip::lookup_ip(&ctx.db, "1.2.3.4").unwrap();
+        assert!(result.found && result.flags.proxy);
+        assert_eq!(result.matched_entries.len(), 1);
+        assert!(ctx
+            .db
+            .lookup_ip("1.2.3.4".parse().unwrap())
+            .unwrap()
+            .is_some());
+    }
 }